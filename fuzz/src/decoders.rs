@@ -1,6 +1,6 @@
 #[macro_use]
 extern crate afl;
-extern crate rawloader;
+extern crate rawloader2 as rawloader;
 
 fn main() {
     rawloader::force_initialization();
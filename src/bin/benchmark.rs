@@ -32,7 +32,7 @@ fn main() {
     let buffer = match rawloader2::Buffer::new(&mut f) {
         Ok(val) => val,
         Err(e) => {
-            error(&e);
+            error(&e.to_string());
             return;
         }
     };
@@ -43,13 +43,13 @@ fn main() {
             let decoder = match rawloader2.get_decoder(&buffer) {
                 Ok(val) => val,
                 Err(e) => {
-                    error(&e);
+                    error(&e.to_string());
                     return;
                 }
             };
             match decoder.image(false) {
                 Ok(_) => {}
-                Err(e) => error(&e),
+                Err(e) => error(&e.to_string()),
             }
         }
     }
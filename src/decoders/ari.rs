@@ -2,7 +2,7 @@ use crate::decoders::basics::*;
 use crate::decoders::*;
 
 pub fn is_ari(buf: &[u8]) -> bool {
-    buf[0..4] == b"ARRI"[..]
+    buf.starts_with(b"ARRI")
 }
 
 #[derive(Debug, Clone)]
@@ -21,28 +21,37 @@ impl<'a> AriDecoder<'a> {
 }
 
 impl<'a> Decoder for AriDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let offset = LEu32(self.buffer, 8) as usize;
         let width = LEu32(self.buffer, 20) as usize;
         let height = LEu32(self.buffer, 24) as usize;
-        let model = String::from_utf8_lossy(&self.buffer[668..])
+        let model = String::from_utf8_lossy(from_offset(self.buffer, 668))
             .split_terminator("\0")
             .next()
             .unwrap_or("")
             .to_string();
-        let camera = self
+        let mut camera = self
             .rawloader
             .check_supported_with_everything("ARRI", &model, "")?;
-        let src = &self.buffer[offset..];
+        camera.set_iso(self.exif().iso);
+        let src = from_offset(self.buffer, offset);
 
-        let image = decode_12be_msb32(src, width, height, dummy);
+        let image = decode_12be_msb32(src, width, height, dummy)?;
 
         ok_image(camera, width, height, self.get_wb()?, image)
     }
+
+    fn exif(&self) -> Exif {
+        let iso = LEu32(self.buffer, 92);
+        Exif {
+            iso: if iso > 0 { Some(iso) } else { None },
+            ..Default::default()
+        }
+    }
 }
 
 impl<'a> AriDecoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         Ok([
             LEf32(self.buffer, 100),
             LEf32(self.buffer, 104),
@@ -1,8 +1,10 @@
 use std::cmp;
 
 use crate::decoders::basics::*;
+use crate::decoders::ljpeg::*;
 use crate::decoders::tiff::*;
 use crate::decoders::*;
+use rayon::prelude::*;
 
 #[derive(Debug, Clone)]
 pub struct ArwDecoder<'a> {
@@ -22,8 +24,14 @@ impl<'a> ArwDecoder<'a> {
 }
 
 impl<'a> Decoder for ArwDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
+        let tiled = self.tiff.find_ifds_with_tag(Tag::TileOffsets);
+        if let Some(raw) = tiled.first()
+            && fetch_tag!(raw, Tag::Compression).get_u32(0) == 7
+        {
+            return self.image_ljpeg(camera, raw, dummy);
+        }
         let data = self.tiff.find_ifds_with_tag(Tag::StripOffsets);
         if data.is_empty() {
             if camera.model == "DSLR-A100" {
@@ -50,25 +58,34 @@ impl<'a> Decoder for ArwDecoder<'a> {
         };
         let mut white = camera.whitelevels[0];
         let mut black = camera.blacklevels[0];
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
 
+        let window = Window::current();
         let image = match compression {
             1 => {
                 if camera.model == "DSC-R1" {
-                    decode_14be_unpacked(src, width, height, dummy)
+                    decode_14be_unpacked(src, width, height, window.as_ref(), dummy)?
                 } else {
-                    decode_16le(src, width, height, dummy)
+                    decode_16le(src, width, height, window.as_ref(), dummy)?
                 }
             }
             32767 => {
-                if (width * height * bps) != count * 8 {
+                if width.saturating_mul(height).saturating_mul(bps) != count.saturating_mul(8) {
                     height += 8;
-                    ArwDecoder::decode_arw1(src, width, height, dummy)
+                    let image = ArwDecoder::decode_arw1(src, width, height, dummy)?;
+                    crop_window(image, width, window.as_ref())?
                 } else {
                     match bps {
                         8 => {
                             let curve = ArwDecoder::get_curve(raw)?;
-                            ArwDecoder::decode_arw2(src, width, height, &curve, dummy)
+                            ArwDecoder::decode_arw2(
+                                src,
+                                width,
+                                height,
+                                &curve,
+                                window.as_ref(),
+                                dummy,
+                            )?
                         }
                         12 => {
                             /*
@@ -81,48 +98,52 @@ impl<'a> Decoder for ArwDecoder<'a> {
                             */
                             white >>= 2;
                             black >>= 2;
-                            decode_12le(src, width, height, dummy)
+                            decode_12le(src, width, height, window.as_ref(), dummy)?
                         }
                         _ => {
-                            return Err(format!(
-                                "ARW2: Don't know how to decode images with {} bps",
-                                bps
-                            ));
+                            return Err(RawLoaderError::compression("ARW", bps as u32));
                         }
                     }
                 }
             }
             _ => {
-                return Err(
-                    format!("ARW: Don't know how to decode type {}", compression).to_string(),
-                );
+                return Err(RawLoaderError::compression("ARW", compression));
             }
         };
 
         ok_image_with_black_white(camera, width, height, self.get_wb()?, black, white, image)
+            .map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
 
 impl<'a> ArwDecoder<'a> {
-    fn image_a100(&self, camera: Camera, dummy: bool) -> Result<RawImage, String> {
+    fn image_a100(&self, camera: Camera, dummy: bool) -> Result<RawImage, RawLoaderError> {
         // We've caught the elusive A100 in the wild, a transitional format
         // between the simple sanity of the MRW custom format and the wordly
         // wonderfullness of the Tiff-based ARW format, let's shoot from the hip
         let data = self.tiff.find_ifds_with_tag(Tag::SubIFDs);
         if data.is_empty() {
-            return Err("ARW: Couldn't find the data IFD!".to_string());
+            return Err(RawLoaderError::corrupt("ARW: Couldn't find the data IFD!"));
         }
         let raw = data[0];
         let width = 3881;
         let height = 2608;
         let offset = fetch_tag!(raw, Tag::SubIFDs).get_usize(0);
 
-        let src = &self.buffer[offset..];
-        let image = ArwDecoder::decode_arw1(src, width, height, dummy);
+        let src = from_offset(self.buffer, offset);
+        let image = ArwDecoder::decode_arw1(src, width, height, dummy)?;
 
         // Get the WB the MRW way
         let priv_offset = fetch_tag!(self.tiff, Tag::DNGPrivateArea).get_force_u32(0) as usize;
-        let buf = &self.buffer[priv_offset..];
+        let buf = from_offset(self.buffer, priv_offset);
         let mut currpos: usize = 8;
         let mut wb_coeffs: [f32; 4] = [0.0, 0.0, 0.0, f32::NAN];
         // At most we read 20 bytes from currpos so check we don't step outside that
@@ -142,15 +163,141 @@ impl<'a> ArwDecoder<'a> {
         ok_image(camera, width, height, wb_coeffs, image)
     }
 
-    fn image_srf(&self, camera: Camera, dummy: bool) -> Result<RawImage, String> {
+    // The lossless compressed ARWs of the A1/A7 IV generation are tiled with each tile
+    // a separate LJPEG stream that packs every 2x2 block of the mosaic into one line
+    fn image_ljpeg(
+        &self,
+        camera: Camera,
+        raw: &TiffIFD,
+        dummy: bool,
+    ) -> Result<RawImage, RawLoaderError> {
+        let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
+        let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
+        let window = Window::current();
+        let image = self.decode_ljpeg_tiles(raw, window.as_ref(), dummy)?;
+
+        ok_image(camera, width, height, self.get_wb()?, image)
+            .map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn decode_ljpeg_tiles(
+        &self,
+        raw: &TiffIFD,
+        window: Option<&Window>,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
+        let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
+        let offsets = fetch_tag!(raw, Tag::TileOffsets);
+        let twidth = fetch_tag!(raw, Tag::TileWidth).get_usize(0);
+        let tlength = fetch_tag!(raw, Tag::TileLength).get_usize(0);
+        if twidth == 0 || tlength == 0 || twidth % 2 != 0 || tlength % 2 != 0 {
+            return Err(RawLoaderError::corrupt(format!(
+                "ARW: invalid tile size {}x{}",
+                twidth, tlength
+            )));
+        }
+        check_image_size(twidth, tlength)?;
+        let coltiles = width.div_ceil(twidth);
+        let rowtiles = height.div_ceil(tlength);
+        if coltiles * rowtiles != offsets.count() {
+            return Err(RawLoaderError::corrupt(format!(
+                "ARW: trying to decode {} tiles from {} offsets",
+                coltiles * rowtiles,
+                offsets.count()
+            )));
+        }
+
+        if dummy {
+            Ok(alloc_image_plain!(width, height, dummy))
+        } else {
+            let tiles = (0..offsets.count())
+                .into_par_iter()
+                .map(|i| {
+                    let (top, left) = ((i / coltiles) * tlength, (i % coltiles) * twidth);
+                    if window.is_some_and(|w| !w.intersects(top, left, twidth, tlength)) {
+                        return Ok(Vec::new());
+                    }
+                    self.decode_ljpeg_tile(offsets.get_usize(i), twidth, tlength)
+                })
+                .collect::<Result<Vec<Vec<u16>>, RawLoaderError>>()?;
+
+            decode_threaded(
+                width,
+                height,
+                window,
+                dummy,
+                &(|out: &mut [u16], row| {
+                    let trow = row / tlength;
+                    let tilerow = (row % tlength) * twidth;
+                    for (tcol, line) in out.chunks_mut(twidth).enumerate() {
+                        // Tiles outside of a region decode are empty and stay black
+                        let tile = &tiles[trow * coltiles + tcol];
+                        if let Some(src) = tile.get(tilerow..tilerow + line.len()) {
+                            line.copy_from_slice(src);
+                        }
+                    }
+                }),
+            )
+        }
+    }
+
+    fn decode_ljpeg_tile(
+        &self,
+        offset: usize,
+        twidth: usize,
+        tlength: usize,
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        if offset >= self.buffer.len() {
+            return Err(RawLoaderError::truncated(
+                "ARW: tile offset past the end of the file",
+            ));
+        }
+        let decompressor = LjpegDecompressor::new(from_offset(self.buffer, offset))?;
+        let jwidth = decompressor.width();
+        let jheight = decompressor.height();
+        if jwidth * jheight != twidth * tlength {
+            return Err(RawLoaderError::corrupt(format!(
+                "ARW: LJPEG tile is {}x{} but should hold {}x{}",
+                jwidth, jheight, twidth, tlength
+            )));
+        }
+        let mut data = vec![0u16; jwidth * jheight];
+        decompressor.decode(&mut data, 0, jwidth, jwidth, jheight, false)?;
+        if jwidth == twidth {
+            return Ok(data);
+        }
+        if jwidth != twidth * 2 {
+            return Err(RawLoaderError::corrupt(format!(
+                "ARW: don't know how to unpack {} wide tiles",
+                jwidth
+            )));
+        }
+
+        // Every group of four values in a line is a 2x2 block of the tile
+        let mut out = vec![0u16; twidth * tlength];
+        for (jrow, line) in data.chunks_exact(jwidth).enumerate() {
+            for (block, vals) in line.chunks_exact(4).enumerate() {
+                let pos = jrow * 2 * twidth + block * 2;
+                out[pos] = vals[0];
+                out[pos + 1] = vals[1];
+                out[pos + twidth] = vals[2];
+                out[pos + twidth + 1] = vals[3];
+            }
+        }
+        Ok(out)
+    }
+
+    fn image_srf(&self, camera: Camera, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let data = self.tiff.find_ifds_with_tag(Tag::ImageWidth);
         if data.is_empty() {
-            return Err("ARW: Couldn't find the data IFD!".to_string());
+            return Err(RawLoaderError::corrupt("ARW: Couldn't find the data IFD!"));
         }
         let raw = data[0];
 
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
         let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
+        check_image_size(width, height)?;
 
         let image = if dummy {
             vec![0]
@@ -163,21 +310,29 @@ impl<'a> ArwDecoder<'a> {
             let head_off: usize = 164600;
 
             // Replicate the dcraw contortions to get the "decryption" key
-            let offset = (self.buffer[key_off] as usize) * 4;
+            let offset = (self.buffer.get(key_off).copied().unwrap_or(0) as usize) * 4;
             let first_key = BEu32(self.buffer, key_off + offset);
             let head = ArwDecoder::sony_decrypt(self.buffer, head_off, 40, first_key);
             let second_key = LEu32(&head, 22);
 
             // "Decrypt" the whole image buffer
             let image_data = ArwDecoder::sony_decrypt(self.buffer, off, len, second_key);
-            decode_16be(&image_data, width, height, dummy)
+            decode_16be(&image_data, width, height, None, dummy)?
         };
 
         ok_image(camera, width, height, [f32::NAN; 4], image)
     }
 
-    pub(crate) fn decode_arw1(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+    pub(crate) fn decode_arw1(
+        buf: &[u8],
+        width: usize,
+        height: usize,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let mut out: Vec<u16> = alloc_image!(width, height, dummy);
+        if height == 0 {
+            return Ok(out);
+        }
         let mut pump = BitPumpMSB::new(buf);
 
         let mut sum: i32 = 0;
@@ -198,15 +353,15 @@ impl<'a> ArwDecoder<'a> {
                     pump.get_bits(cmp::min(13, zeros + 1));
                 }
                 let diff: i32 = pump.get_ibits(len);
-                sum += diff;
+                sum = sum.wrapping_add(diff);
                 if len > 0 && (diff & (1 << (len - 1))) == 0 {
-                    sum -= (1 << len) - 1;
+                    sum = sum.wrapping_sub((1 << len) - 1);
                 }
                 out[row * width + col] = sum as u16;
                 row += 2
             }
         }
-        out
+        Ok(out)
     }
 
     pub(crate) fn decode_arw2(
@@ -214,14 +369,16 @@ impl<'a> ArwDecoder<'a> {
         width: usize,
         height: usize,
         curve: &LookupTable,
+        window: Option<&Window>,
         dummy: bool,
-    ) -> Vec<u16> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         decode_threaded(
             width,
             height,
+            window,
             dummy,
             &(|out: &mut [u16], row| {
-                let mut pump = BitPumpLSB::new(&buf[(row * width)..]);
+                let mut pump = BitPumpLSB::new(from_offset(buf, row * width));
 
                 let mut random = pump.peek_bits(16);
                 for out in out.chunks_exact_mut(32) {
@@ -229,7 +386,7 @@ impl<'a> ArwDecoder<'a> {
                     for j in 0..2 {
                         let max = pump.get_bits(11);
                         let min = pump.get_bits(11);
-                        let delta = max - min;
+                        let delta = max.wrapping_sub(min);
                         // Calculate the size of the data shift needed by how large the delta is
                         // A delta with 11 bits requires a shift of 4, 10 bits of 3, etc
                         let delta_shift: u32 =
@@ -253,7 +410,7 @@ impl<'a> ArwDecoder<'a> {
         )
     }
 
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         let priv_offset = fetch_tag!(self.tiff, Tag::DNGPrivateArea).get_force_u32(0) as usize;
         let priv_tiff = TiffIFD::new(self.buffer, priv_offset, 0, 0, 0, LITTLE_ENDIAN)?;
         let sony_offset = fetch_tag!(priv_tiff, Tag::SonyOffset).get_usize(0);
@@ -263,8 +420,7 @@ impl<'a> ArwDecoder<'a> {
         let decrypted_buf =
             ArwDecoder::sony_decrypt(self.buffer, sony_offset, sony_length, sony_key);
 
-        let decrypted_tiff =
-            TiffIFD::new(&decrypted_buf, 0, sony_offset, 0, 0, LITTLE_ENDIAN).unwrap();
+        let decrypted_tiff = TiffIFD::new(&decrypted_buf, 0, sony_offset, 0, 0, LITTLE_ENDIAN)?;
 
         let grgb_levels = decrypted_tiff.find_entry(Tag::SonyGRBG);
         let rggb_levels = decrypted_tiff.find_entry(Tag::SonyRGGB);
@@ -284,11 +440,13 @@ impl<'a> ArwDecoder<'a> {
                 f32::NAN,
             ])
         } else {
-            Err("ARW: Couldn't find GRGB or RGGB levels".to_string())
+            Err(RawLoaderError::corrupt(
+                "ARW: Couldn't find GRGB or RGGB levels",
+            ))
         }
     }
 
-    fn get_curve(raw: &TiffIFD) -> Result<LookupTable, String> {
+    fn get_curve(raw: &TiffIFD) -> Result<LookupTable, RawLoaderError> {
         let centry = fetch_tag!(raw, Tag::SonyCurve);
         let mut curve: [usize; 6] = [0, 0, 0, 0, 0, 4095];
 
@@ -331,6 +489,8 @@ impl<'a> ArwDecoder<'a> {
             *item = u32::from_be(*item);
         }
 
+        // Past the end of the file there's nothing to decrypt
+        let length = length.min(buf.len().saturating_sub(offset));
         let mut out = Vec::with_capacity(length + 4);
         for i in 0..(length / 4 + 1) {
             let p = i + 127;
@@ -344,3 +504,142 @@ impl<'a> ArwDecoder<'a> {
         out
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(row: usize, col: usize) -> u16 {
+        (1000 + 100 * row + col) as u16
+    }
+
+    // Lossless JPEG with two components and predictor 1 that codes every difference
+    // length with the same 5 bit code
+    fn ljpeg(width: usize, height: usize, values: &[u16]) -> Vec<u8> {
+        let mut out = vec![0xff, 0xd8, 0xff, 0xc4, 0, 36, 0];
+        let mut bits = [0u8; 16];
+        bits[4] = 17;
+        out.extend_from_slice(&bits);
+        out.extend(0..17u8);
+        out.extend_from_slice(&[0xff, 0xc3, 0, 14, 14]);
+        out.extend_from_slice(&(height as u16).to_be_bytes());
+        out.extend_from_slice(&(width as u16).to_be_bytes());
+        out.extend_from_slice(&[2, 1, 0x11, 0, 2, 0x11, 0]);
+        out.extend_from_slice(&[0xff, 0xda, 0, 10, 2, 1, 0, 2, 0, 1, 0, 0]);
+
+        let line = width * 2;
+        let (mut acc, mut nbits) = (0u64, 0);
+        let mut bytes = Vec::new();
+        for (i, &v) in values.iter().enumerate() {
+            let pred = match (i / line, i % line) {
+                (0, 0 | 1) => 1 << 13,
+                (_, 0 | 1) => values[i - line],
+                _ => values[i - 2],
+            };
+            let diff = v as i32 - pred as i32;
+            let len = 32 - diff.unsigned_abs().leading_zeros();
+            let extra = if diff < 0 {
+                diff + (1 << len) - 1
+            } else {
+                diff
+            };
+            acc = (acc << (5 + len)) | ((len as u64) << len) | extra as u64;
+            nbits += 5 + len;
+            while nbits >= 8 {
+                nbits -= 8;
+                bytes.push((acc >> nbits) as u8);
+            }
+        }
+        if nbits > 0 {
+            bytes.push(((acc << (8 - nbits)) | ((1 << (8 - nbits)) - 1)) as u8);
+        }
+        for b in bytes {
+            out.push(b);
+            if b == 0xff {
+                out.push(0);
+            }
+        }
+        out.extend_from_slice(&[0xff, 0xd9]);
+        out
+    }
+
+    // A 4x4 tile stored as 2 lines of 2x2 blocks
+    fn tile_lines(top: usize, left: usize) -> Vec<u16> {
+        let mut values = Vec::new();
+        for jrow in 0..2 {
+            for block in 0..2 {
+                for (dr, dc) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                    values.push(value(top + jrow * 2 + dr, left + block * 2 + dc));
+                }
+            }
+        }
+        values
+    }
+
+    fn tile(top: usize, left: usize) -> Vec<u8> {
+        ljpeg(4, 2, &tile_lines(top, left))
+    }
+
+    // A 6x6 image in 4x4 tiles so the right and bottom tiles are partial
+    fn tiled_arw() -> Vec<u8> {
+        let tiles: Vec<Vec<u8>> = [(0, 0), (0, 4), (4, 0), (4, 4)]
+            .iter()
+            .map(|&(top, left)| tile(top, left))
+            .collect();
+        let entries: [(u16, u16, u32, u32); 5] = [
+            (0x0100, 4, 1, 6),
+            (0x0101, 4, 1, 6),
+            (0x0142, 4, 1, 4),
+            (0x0143, 4, 1, 4),
+            (0x0144, 4, 4, 8 + 2 + 5 * 12 + 4),
+        ];
+        let mut buf = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        buf.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (tag, typ, count, value) in entries {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&typ.to_le_bytes());
+            buf.extend_from_slice(&count.to_le_bytes());
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf.extend_from_slice(&[0; 4]);
+        let mut offset = buf.len() + 16;
+        for tile in &tiles {
+            buf.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += tile.len();
+        }
+        for tile in &tiles {
+            buf.extend_from_slice(tile);
+        }
+        buf
+    }
+
+    #[test]
+    fn ljpeg_tiles_unpack_the_2x2_blocks() {
+        let buf = tiled_arw();
+        let raw = TiffIFD::new_file(&buf).unwrap();
+        let loader = RawLoader::new();
+        let decoder = ArwDecoder::new(&buf, TiffIFD::new_file(&buf).unwrap(), &loader);
+
+        let offset = raw.find_entry(Tag::TileOffsets).unwrap().get_usize(0);
+        let tile = decoder.decode_ljpeg_tile(offset, 4, 4).unwrap();
+        let expected: Vec<u16> = (0..16).map(|i| value(i / 4, i % 4)).collect();
+        assert_eq!(tile, expected);
+
+        // Lines that already match the tile width are used as they are, other widths fail
+        let lines = tile_lines(0, 0);
+        assert_eq!(decoder.decode_ljpeg_tile(offset, 8, 2).unwrap(), lines);
+        assert!(decoder.decode_ljpeg_tile(offset, 2, 8).is_err());
+    }
+
+    #[test]
+    fn ljpeg_tiles_fill_the_image() {
+        let buf = tiled_arw();
+        let raw = TiffIFD::new_file(&buf).unwrap();
+        let loader = RawLoader::new();
+        let decoder = ArwDecoder::new(&buf, TiffIFD::new_file(&buf).unwrap(), &loader);
+
+        let image = decoder.decode_ljpeg_tiles(&raw, None, false).unwrap();
+        let expected: Vec<u16> = (0..36).map(|i| value(i / 6, i % 6)).collect();
+        assert_eq!(image, expected);
+    }
+}
@@ -1,5 +1,10 @@
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use rayon::prelude::*;
+use std::cell::Cell;
+use std::cmp;
+use std::ops::Range;
+
+use crate::decoders::RawLoaderError;
 
 pub use crate::decoders::packed::*;
 pub use crate::decoders::pumps::*;
@@ -54,83 +59,306 @@ impl Endian {
 pub static BIG_ENDIAN: Endian = Endian { big: true };
 pub static LITTLE_ENDIAN: Endian = Endian { big: false };
 
+// Anything past the end of the buffer reads as zeros, the same as the padding at the end
+// of Buffer, so corrupted offsets and truncated files never read out of bounds
+#[inline(always)]
+fn read_at<T: Default>(buf: &[u8], pos: usize, size: usize, read: fn(&[u8]) -> T) -> T {
+    match buf.get(pos..).and_then(|b| b.get(..size)) {
+        Some(bytes) => read(bytes),
+        None => T::default(),
+    }
+}
+
 #[allow(non_snake_case)]
 #[inline]
 pub fn BEi32(buf: &[u8], pos: usize) -> i32 {
-    BigEndian::read_i32(&buf[pos..pos + 4])
+    read_at(buf, pos, 4, BigEndian::read_i32)
 }
 
 #[allow(non_snake_case)]
 #[inline]
 pub fn LEi32(buf: &[u8], pos: usize) -> i32 {
-    LittleEndian::read_i32(&buf[pos..pos + 4])
+    read_at(buf, pos, 4, LittleEndian::read_i32)
 }
 
 #[allow(non_snake_case)]
 #[inline]
 pub fn BEu32(buf: &[u8], pos: usize) -> u32 {
-    BigEndian::read_u32(&buf[pos..pos + 4])
+    read_at(buf, pos, 4, BigEndian::read_u32)
 }
 
 #[allow(non_snake_case)]
 #[inline]
 pub fn LEu32(buf: &[u8], pos: usize) -> u32 {
-    LittleEndian::read_u32(&buf[pos..pos + 4])
+    read_at(buf, pos, 4, LittleEndian::read_u32)
 }
 
 #[allow(non_snake_case)]
 #[inline]
 pub fn LEf32(buf: &[u8], pos: usize) -> f32 {
-    LittleEndian::read_f32(&buf[pos..pos + 4])
+    read_at(buf, pos, 4, LittleEndian::read_f32)
 }
 
 #[allow(non_snake_case)]
 #[inline]
 pub fn BEu16(buf: &[u8], pos: usize) -> u16 {
-    BigEndian::read_u16(&buf[pos..pos + 2])
+    read_at(buf, pos, 2, BigEndian::read_u16)
 }
 
 #[allow(non_snake_case)]
 #[inline]
 pub fn LEu16(buf: &[u8], pos: usize) -> u16 {
-    LittleEndian::read_u16(&buf[pos..pos + 2])
+    read_at(buf, pos, 2, LittleEndian::read_u16)
 }
 
-pub fn decode_threaded<F>(width: usize, height: usize, dummy: bool, closure: &F) -> Vec<u16>
+// The data from an offset to the end of the buffer, empty when the offset is past the end
+// so truncated files decode as far as they go and leave the rest of the image black
+#[inline(always)]
+pub fn from_offset(buf: &[u8], offset: usize) -> &[u8] {
+    buf.get(offset..).unwrap_or(&[])
+}
+
+pub fn decode_threaded<F>(
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+    closure: &F,
+) -> Result<Vec<u16>, RawLoaderError>
 where
     F: Fn(&mut [u16], usize) + Sync,
 {
-    let mut out: Vec<u16> = alloc_image!(width, height, dummy);
-    out.par_chunks_mut(width)
-        .enumerate()
-        .for_each(|(row, line)| {
-            closure(line, row);
-        });
-    out
+    decode_blocks(
+        width,
+        height,
+        1,
+        width,
+        window,
+        dummy,
+        &|out: &mut [u16], row, _| closure(out, row),
+    )
 }
 
 pub fn decode_threaded_multiline<F>(
     width: usize,
     height: usize,
     lines: usize,
+    window: Option<&Window>,
     dummy: bool,
     closure: &F,
-) -> Vec<u16>
+) -> Result<Vec<u16>, RawLoaderError>
 where
     F: Fn(&mut [u16], usize) + Sync,
 {
-    let mut out: Vec<u16> = alloc_image!(width, height, dummy);
-    out.par_chunks_mut(width * lines)
-        .enumerate()
-        .for_each(|(row, line)| {
-            closure(line, row * lines);
-        });
+    decode_blocks(
+        width,
+        height,
+        lines,
+        width,
+        window,
+        dummy,
+        &|out: &mut [u16], row, _| closure(out, row),
+    )
+}
+
+// For formats where a row can be unpacked starting at any multiple of `group` values. The
+// closure gets the row and the column its output starts at, so a window only unpacks the
+// groups it has values from
+pub fn decode_threaded_cols<F>(
+    width: usize,
+    height: usize,
+    group: usize,
+    window: Option<&Window>,
+    dummy: bool,
+    closure: &F,
+) -> Result<Vec<u16>, RawLoaderError>
+where
+    F: Fn(&mut [u16], usize, usize) + Sync,
+{
+    decode_blocks(width, height, 1, group, window, dummy, closure)
+}
+
+// Decodes an image in blocks of `lines` rows at a time. With a window the output is only the
+// window, and blocks without any of its rows don't get decoded.
+fn decode_blocks<F>(
+    width: usize,
+    height: usize,
+    lines: usize,
+    group: usize,
+    window: Option<&Window>,
+    dummy: bool,
+    closure: &F,
+) -> Result<Vec<u16>, RawLoaderError>
+where
+    F: Fn(&mut [u16], usize, usize) + Sync,
+{
+    let Some(window) = window else {
+        let mut out: Vec<u16> = alloc_image!(width, height, dummy);
+        if width == 0 || lines == 0 {
+            return Ok(out);
+        }
+        out.par_chunks_mut(width * lines)
+            .enumerate()
+            .for_each(|(row, line)| closure(line, row * lines, 0));
+        return Ok(out);
+    };
+    window.check(width, height)?;
+    let mut out: Vec<u16> = alloc_image!(window.width(), window.height(), dummy);
+    if lines == 0 {
+        return Ok(out);
+    }
+
+    // Only the groups of values that have some of the window in them get unpacked
+    let cols = window.cols();
+    let group = group.max(1);
+    let first = cols.start / group * group;
+    let last = cmp::min(cols.end.div_ceil(group) * group, width);
+
+    // Split the output at the edges of the blocks of lines
+    let mut blocks = Vec::new();
+    let mut rest = out.as_mut_slice();
+    let mut row = window.top;
+    while row < window.bottom {
+        let end = cmp::min((row / lines + 1) * lines, window.bottom);
+        let (block, tail) = rest.split_at_mut((end - row) * window.width());
+        blocks.push((row, end, block));
+        rest = tail;
+        row = end;
+    }
+
+    blocks.into_par_iter().for_each_init(
+        || vec![0u16; (last - first) * lines],
+        |scratch, (row, end, block)| {
+            if !(row..end).any(|row| window.wants_row(row)) {
+                return;
+            }
+            let start = row / lines * lines;
+            let scratch = &mut scratch[..(last - first) * cmp::min(lines, height - start)];
+            scratch.fill(0);
+            closure(scratch, start, first);
+            for (i, dst) in block.chunks_exact_mut(window.width()).enumerate() {
+                let pos = (row - start + i) * (last - first) + cols.start - first;
+                dst.copy_from_slice(&scratch[pos..pos + dst.len()]);
+            }
+        },
+    );
+    Ok(out)
+}
+
+thread_local! {
+    // Whether new lookup tables dither, set for the length of a decode from DecodeOptions
+    // as the tables get built deep inside the decoders
+    static DITHER: Cell<bool> = const { Cell::new(true) };
+}
+
+pub(crate) fn with_dither<T>(dither: bool, func: impl FnOnce() -> T) -> T {
+    let old = DITHER.replace(dither);
+    let out = func();
+    DITHER.set(old);
     out
 }
 
+/// The part of the image a region or downscaled decode needs, in pixels. Decoders that pass
+/// it on output only that part of the image, with the rows of it that aren't needed left
+/// black, and fix up the image to match with `RawImage::windowed()`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Window {
+    pub top: usize,
+    pub left: usize,
+    pub bottom: usize,
+    pub right: usize,
+    // values per pixel of the image
+    pub cpp: usize,
+    // only the first `keep` rows of every `step` starting at the top are needed
+    pub step: usize,
+    pub keep: usize,
+}
+
+thread_local! {
+    // The window of the decode in progress, set from DecodeOptions for decoders to pass on
+    // to the functions that decode the image
+    static WINDOW: Cell<Option<Window>> = const { Cell::new(None) };
+}
+
+impl Window {
+    pub(crate) fn current() -> Option<Window> {
+        WINDOW.get()
+    }
+
+    pub(crate) fn with<T>(window: Option<Window>, func: impl FnOnce() -> T) -> T {
+        let old = WINDOW.replace(window);
+        let out = func();
+        WINDOW.set(old);
+        out
+    }
+
+    // Width of the window in values
+    pub(crate) fn width(&self) -> usize {
+        (self.right - self.left) * self.cpp
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        self.bottom - self.top
+    }
+
+    // The values of each row of the image that are in the window
+    pub(crate) fn cols(&self) -> Range<usize> {
+        self.left * self.cpp..self.right * self.cpp
+    }
+
+    // An image of width values by height rows has to have all of the window in it
+    pub(crate) fn check(&self, width: usize, height: usize) -> Result<(), RawLoaderError> {
+        if self.right * self.cpp > width || self.bottom > height {
+            return Err(RawLoaderError::corrupt(format!(
+                "{}x{} image is smaller than the region to decode",
+                width, height
+            )));
+        }
+        Ok(())
+    }
+
+    // Copies the window out of a full image of width values per row, for decoders that can
+    // only decode all of it
+    pub(crate) fn crop<T: Copy>(&self, data: &[T], width: usize) -> Result<Vec<T>, RawLoaderError> {
+        self.check(width, data.len() / width.max(1))?;
+        Ok(data
+            .chunks_exact(width)
+            .skip(self.top)
+            .take(self.height())
+            .flat_map(|row| row[self.cols()].iter().copied())
+            .collect())
+    }
+
+    pub(crate) fn wants_row(&self, row: usize) -> bool {
+        row >= self.top && row < self.bottom && (row - self.top) % self.step < self.keep
+    }
+
+    // Whether a rectangle of the image in pixels has any of the window in it
+    pub(crate) fn intersects(&self, top: usize, left: usize, width: usize, height: usize) -> bool {
+        top < self.bottom
+            && top + height > self.top
+            && left < self.right
+            && left + width > self.left
+    }
+}
+
+// Gets the window out of an image that had to be decoded in full, so that decoders that pass
+// the window on for some of their formats output just the window for all of them
+pub(crate) fn crop_window(
+    data: Vec<u16>,
+    width: usize,
+    window: Option<&Window>,
+) -> Result<Vec<u16>, RawLoaderError> {
+    match window {
+        Some(window) => window.crop(&data, width),
+        None => Ok(data),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct LookupTable {
     table: Vec<(u16, u16, u16)>,
+    dither: bool,
 }
 
 impl LookupTable {
@@ -144,15 +372,15 @@ impl LookupTable {
             } else {
                 center
             };
-            let base = if center == 0 {
-                0
-            } else {
-                center - ((upper - lower + 2) / 4)
-            };
-            let delta = upper - lower;
+            // Curves from corrupted files aren't necessarily increasing
+            let delta = upper.saturating_sub(lower);
+            let base = (center as u32).saturating_sub((delta as u32 + 2) / 4) as u16;
             tbl[i] = (center, base, delta);
         }
-        LookupTable { table: tbl }
+        LookupTable {
+            table: tbl,
+            dither: DITHER.get(),
+        }
     }
 
     //  pub fn lookup(&self, value: u16) -> u16 {
@@ -162,7 +390,14 @@ impl LookupTable {
 
     #[inline(always)]
     pub fn dither(&self, value: u16, rand: &mut u32) -> u16 {
-        let (_, sbase, sdelta) = self.table[value as usize];
+        // Values past the end of the curve get its last entry
+        let (center, sbase, sdelta) = match self.table.get(value as usize).or(self.table.last()) {
+            Some(entry) => *entry,
+            None => return value,
+        };
+        if !self.dither {
+            return center;
+        }
         let base = sbase as u32;
         let delta = sdelta as u32;
         let pixel = base + ((delta * (*rand & 2047) + 1024) >> 12);
@@ -170,3 +405,120 @@ impl LookupTable {
         pixel as u16
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Packed =
+        fn(&[u8], usize, usize, Option<&Window>, bool) -> Result<Vec<u16>, RawLoaderError>;
+
+    fn window(top: usize, left: usize, bottom: usize, right: usize) -> Window {
+        Window {
+            top,
+            left,
+            bottom,
+            right,
+            cpp: 1,
+            step: 1,
+            keep: 1,
+        }
+    }
+
+    // Bytes that don't repeat along a row so every value of the image is different
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x12345678u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packed_windows_match_the_full_image() {
+        let decoders: [(&str, Packed); 19] = [
+            ("10le_lsb16", decode_10le_lsb16),
+            ("10le", decode_10le),
+            ("12be", decode_12be),
+            ("12le_16bitaligned", decode_12le_16bitaligned),
+            ("12le_wcontrol", decode_12le_wcontrol),
+            ("12be_wcontrol", decode_12be_wcontrol),
+            ("12be_interlaced", decode_12be_interlaced),
+            (
+                "12be_interlaced_unaligned",
+                decode_12be_interlaced_unaligned,
+            ),
+            ("12le", decode_12le),
+            ("12le_unpacked", decode_12le_unpacked),
+            ("12be_unpacked", decode_12be_unpacked),
+            (
+                "12be_unpacked_left_aligned",
+                decode_12be_unpacked_left_aligned,
+            ),
+            (
+                "12le_unpacked_left_aligned",
+                decode_12le_unpacked_left_aligned,
+            ),
+            ("14le_unpacked", decode_14le_unpacked),
+            ("14be_unpacked", decode_14be_unpacked),
+            ("16le", decode_16le),
+            ("16le_skiplines", decode_16le_skiplines),
+            ("16be", decode_16be),
+            ("8bit_wtable", |buf, width, height, window, dummy| {
+                let table = LookupTable::new(&(0..256).map(|i| i * 3).collect::<Vec<u16>>());
+                with_dither(false, || {
+                    decode_8bit_wtable(buf, &table, width, height, window, dummy)
+                })
+            }),
+        ];
+        let (width, height) = (40, 6);
+        let buf = noise(width * height * 4);
+        for (name, decode) in decoders {
+            let full = decode(&buf, width, height, None, false).unwrap();
+            for w in [
+                window(1, 3, 5, 27),
+                window(0, 0, 6, 40),
+                window(5, 39, 6, 40),
+            ] {
+                let out = decode(&buf, width, height, Some(&w), false).unwrap();
+                assert_eq!(out, w.crop(&full, width).unwrap(), "{} in {:?}", name, w);
+            }
+        }
+    }
+
+    #[test]
+    fn windows_skip_the_unwanted_rows() {
+        let closure = |out: &mut [u16], row, col| {
+            for (i, o) in out.iter_mut().enumerate() {
+                *o = (row * 100 + col + i) as u16;
+            }
+        };
+        let w = Window {
+            step: 2,
+            keep: 1,
+            ..window(1, 2, 5, 4)
+        };
+        let out = decode_threaded_cols(6, 6, 2, Some(&w), false, &closure).unwrap();
+        assert_eq!(out, vec![102, 103, 0, 0, 302, 303, 0, 0]);
+
+        // Blocks of lines get decoded whole when any of their rows is wanted
+        let closure = |out: &mut [u16], row| {
+            for (i, o) in out.iter_mut().enumerate() {
+                *o = (row * 100 + i) as u16;
+            }
+        };
+        let out =
+            decode_threaded_multiline(4, 6, 4, Some(&window(3, 1, 6, 3)), false, &closure).unwrap();
+        assert_eq!(out, vec![13, 14, 401, 402, 405, 406]);
+    }
+
+    #[test]
+    fn windows_outside_the_image_fail() {
+        let closure = |_: &mut [u16], _| {};
+        assert!(decode_threaded(4, 4, Some(&window(0, 0, 4, 5)), false, &closure).is_err());
+        assert!(decode_threaded(4, 4, Some(&window(2, 0, 5, 4)), false, &closure).is_err());
+        assert!(decode_threaded(4, 4, Some(&window(2, 1, 4, 4)), false, &closure).is_ok());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::decoders::Buffer;
+use crate::decoders::RawLoaderError;
+use crate::decoders::basics::*;
+
+// Canon stores its TIFF-style metadata (CMT1-4) and thumbnail in this uuid box inside moov
+const CANON_UUID: [u8; 16] = [
+    0x85, 0xc0, 0xb6, 0x87, 0x82, 0x0f, 0x11, 0xe0, 0x81, 0x11, 0xf4, 0xce, 0x46, 0x2b, 0x6a, 0x48,
+];
+
+// Top level uuid box that holds the PRVW preview
+const PREVIEW_UUID: [u8; 16] = [
+    0xea, 0xf4, 0x2b, 0x5e, 0x1c, 0x98, 0x4b, 0x88, 0xb9, 0xfb, 0xb7, 0xdc, 0x40, 0x6e, 0x4d, 0x16,
+];
+
+const MAX_SAMPLES: usize = 1 << 20;
+
+pub fn is_cr3(buf: &[u8]) -> bool {
+    buf.get(4..12) == Some(&b"ftypcrx "[..])
+}
+
+/// A box from an ISO base media file (the container behind MP4 and Canon's CR3)
+#[derive(Debug, Clone)]
+pub struct BmffBox<'a> {
+    pub typ: [u8; 4],
+    /// offset of the box contents in the file
+    pub offset: usize,
+    /// the box contents, without the size and type header
+    pub data: &'a [u8],
+    children: Vec<BmffBox<'a>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BmffFile<'a> {
+    boxes: Vec<BmffBox<'a>>,
+}
+
+impl<'a> BmffFile<'a> {
+    pub fn new(buf: &'a Buffer) -> Result<BmffFile<'a>, RawLoaderError> {
+        let boxes = BmffBox::parse_list(&buf.buf, 0, buf.size, 0)?;
+        Ok(BmffFile { boxes })
+    }
+
+    pub fn find_box(&self, typ: &[u8; 4]) -> Option<&BmffBox<'a>> {
+        self.boxes.iter().find_map(|b| b.find_box(typ))
+    }
+
+    pub fn find_boxes(&self, typ: &[u8; 4]) -> Vec<&BmffBox<'a>> {
+        self.boxes.iter().flat_map(|b| b.find_boxes(typ)).collect()
+    }
+}
+
+impl<'a> BmffBox<'a> {
+    fn parse_list(
+        buf: &'a [u8],
+        start: usize,
+        end: usize,
+        depth: u32,
+    ) -> Result<Vec<BmffBox<'a>>, RawLoaderError> {
+        let mut boxes = Vec::new();
+        let mut pos = start;
+
+        while pos + 8 <= end {
+            let mut size = BEu32(buf, pos) as usize;
+            let typ = [buf[pos + 4], buf[pos + 5], buf[pos + 6], buf[pos + 7]];
+            let mut header = 8;
+            if size == 1 {
+                if pos + 16 > end {
+                    return Err(RawLoaderError::truncated(
+                        "BMFF: truncated large box header",
+                    ));
+                }
+                size = ((BEu32(buf, pos + 8) as u64) << 32 | BEu32(buf, pos + 12) as u64) as usize;
+                header = 16;
+            } else if size == 0 {
+                // Box extends to the end of its container
+                size = end - pos;
+            }
+            if size < header || size > end - pos {
+                return Err(RawLoaderError::corrupt_at(
+                    format!(
+                        "BMFF: box {} has invalid size {}",
+                        String::from_utf8_lossy(&typ),
+                        size
+                    ),
+                    pos,
+                ));
+            }
+
+            let offset = pos + header;
+            let data = &buf[offset..pos + size];
+            let children = match BmffBox::children_offset(&typ, data) {
+                Some(skip) if depth < 10 && skip <= data.len() => {
+                    // Avoid infinite looping boxes
+                    BmffBox::parse_list(buf, offset + skip, pos + size, depth + 1)?
+                }
+                _ => Vec::new(),
+            };
+
+            boxes.push(BmffBox {
+                typ,
+                offset,
+                data,
+                children,
+            });
+            pos += size;
+        }
+
+        Ok(boxes)
+    }
+
+    // Where the child boxes start inside the contents of a container box, None for leaf boxes
+    fn children_offset(typ: &[u8; 4], data: &[u8]) -> Option<usize> {
+        match typ {
+            b"moov" | b"trak" | b"mdia" | b"minf" | b"dinf" | b"stbl" => Some(0),
+            // Full box header plus the entry count
+            b"stsd" => Some(8),
+            // Canon's VisualSampleEntry is 82 bytes before the CMP1 and friends
+            b"CRAW" => Some(82),
+            b"uuid" if data.len() >= 16 => {
+                if data[0..16] == CANON_UUID {
+                    Some(16)
+                } else if data[0..16] == PREVIEW_UUID {
+                    Some(24)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn find_box(&self, typ: &[u8; 4]) -> Option<&BmffBox<'a>> {
+        if &self.typ == typ {
+            Some(self)
+        } else {
+            self.children.iter().find_map(|b| b.find_box(typ))
+        }
+    }
+
+    pub fn find_boxes(&self, typ: &[u8; 4]) -> Vec<&BmffBox<'a>> {
+        let mut boxes = Vec::new();
+        if &self.typ == typ {
+            boxes.push(self);
+        }
+        for child in &self.children {
+            boxes.extend(child.find_boxes(typ));
+        }
+        boxes
+    }
+
+    /// Returns the offset and size of every sample in a trak box, resolving the chunk
+    /// offsets (stco/co64), samples per chunk (stsc) and sample sizes (stsz)
+    pub fn samples(&self) -> Result<Vec<(usize, usize)>, RawLoaderError> {
+        let stsz = self
+            .find_box(b"stsz")
+            .ok_or_else(|| RawLoaderError::corrupt("BMFF: couldn't find stsz box"))?
+            .data;
+        let fixed_size = BEu32(stsz, 4) as usize;
+        let count = BEu32(stsz, 8) as usize;
+        // Raw files only carry a handful of samples per track, so anything huge is
+        // a corrupted box that would otherwise allocate gigabytes
+        if count > MAX_SAMPLES {
+            return Err(RawLoaderError::limit(format!(
+                "BMFF: {} samples in a track",
+                count
+            )));
+        }
+        if fixed_size == 0 && stsz.len() < 12 + count * 4 {
+            return Err(RawLoaderError::truncated("BMFF: truncated stsz box"));
+        }
+        let sizes: Vec<usize> = (0..count)
+            .map(|i| {
+                if fixed_size != 0 {
+                    fixed_size
+                } else {
+                    BEu32(stsz, 12 + i * 4) as usize
+                }
+            })
+            .collect();
+
+        let chunks: Vec<usize> = if let Some(stco) = self.find_box(b"stco") {
+            let num = BEu32(stco.data, 4) as usize;
+            if stco.data.len() < 8 + num * 4 {
+                return Err(RawLoaderError::truncated("BMFF: truncated stco box"));
+            }
+            (0..num)
+                .map(|i| BEu32(stco.data, 8 + i * 4) as usize)
+                .collect()
+        } else if let Some(co64) = self.find_box(b"co64") {
+            let num = BEu32(co64.data, 4) as usize;
+            if co64.data.len() < 8 + num * 8 {
+                return Err(RawLoaderError::truncated("BMFF: truncated co64 box"));
+            }
+            (0..num)
+                .map(|i| {
+                    let pos = 8 + i * 8;
+                    ((BEu32(co64.data, pos) as u64) << 32 | BEu32(co64.data, pos + 4) as u64)
+                        as usize
+                })
+                .collect()
+        } else {
+            return Err(RawLoaderError::corrupt("BMFF: couldn't find chunk offsets"));
+        };
+
+        // Without a stsc box every chunk holds a single sample
+        let mut runs = Vec::new();
+        if let Some(stsc) = self.find_box(b"stsc") {
+            let num = BEu32(stsc.data, 4) as usize;
+            if stsc.data.len() < 8 + num * 12 {
+                return Err(RawLoaderError::truncated("BMFF: truncated stsc box"));
+            }
+            for i in 0..num {
+                let first_chunk = BEu32(stsc.data, 8 + i * 12) as usize;
+                let per_chunk = BEu32(stsc.data, 12 + i * 12) as usize;
+                runs.push((first_chunk.max(1) - 1, per_chunk));
+            }
+        }
+        if runs.is_empty() {
+            runs.push((0, 1));
+        }
+
+        let mut samples = Vec::with_capacity(count);
+        let mut sample = 0;
+        for (chunk, &offset) in chunks.iter().enumerate() {
+            let per_chunk = runs
+                .iter()
+                .rev()
+                .find(|(first, _)| *first <= chunk)
+                .map(|(_, n)| *n)
+                .unwrap_or(1);
+            let mut pos = offset;
+            for _ in 0..per_chunk {
+                if sample >= count {
+                    break;
+                }
+                samples.push((pos, sizes[sample]));
+                pos = pos.saturating_add(sizes[sample]);
+                sample += 1;
+            }
+        }
+
+        Ok(samples)
+    }
+}
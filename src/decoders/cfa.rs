@@ -1,5 +1,6 @@
 use std::fmt;
 
+use crate::decoders::RawLoaderError;
 use crate::decoders::tiff::*;
 
 /// Representation of the color filter array pattern in raw cameras
@@ -32,17 +33,42 @@ pub struct CFA {
 
 impl CFA {
     #[doc(hidden)]
-    pub fn new_from_tag(pat: &TiffEntry) -> CFA {
+    pub fn new_from_tag(pat: &TiffEntry) -> Result<CFA, RawLoaderError> {
+        if !matches!(pat.count(), 0 | 4 | 16 | 36 | 144) {
+            return Err(RawLoaderError::corrupt(format!(
+                "CFA: unknown pattern size {}",
+                pat.count()
+            )));
+        }
         let mut patname = String::new();
         for i in 0..pat.count() {
             patname.push(match pat.get_u32(i) {
                 0 => 'R',
                 1 => 'G',
                 2 => 'B',
-                _ => 'U',
+                c => {
+                    return Err(RawLoaderError::corrupt(format!("CFA: unknown color {}", c)));
+                }
             });
         }
-        CFA::new(&patname)
+        Ok(CFA::new(&patname))
+    }
+
+    // Same as new() but with an error instead of a panic for patterns that aren't valid
+    pub(crate) fn from_name(patname: &str) -> Result<CFA, RawLoaderError> {
+        if !matches!(patname.len(), 0 | 4 | 16 | 36 | 144) {
+            return Err(RawLoaderError::corrupt(format!(
+                "CFA: unknown pattern size \"{}\"",
+                patname
+            )));
+        }
+        if let Some(c) = patname.chars().find(|c| !"RGBEMY".contains(*c)) {
+            return Err(RawLoaderError::corrupt(format!(
+                "CFA: unknown color \"{}\" in pattern \"{}\"",
+                c, patname
+            )));
+        }
+        Ok(CFA::new(patname))
     }
 
     /// Create a new CFA from a string describing it. For simplicity the pattern is specified
@@ -66,22 +92,24 @@ impl CFA {
 
         if width > 0 {
             // copy the pattern into the top left
-            for (i, c) in patname.bytes().enumerate() {
-                pattern[i / width][i % width] = match c {
-                    b'R' => 0,
-                    b'G' => 1,
-                    b'B' => 2,
-                    b'E' => 3,
-                    b'M' => 1,
-                    b'Y' => 3,
-                    _ => {
-                        let unknown_char = patname[i..].chars().next().unwrap();
-                        panic!(
-                            "Unknown CFA color \"{}\" in pattern \"{}\"",
-                            unknown_char, patname
-                        )
-                    }
-                };
+            for (row, line) in patname.as_bytes().chunks(width).enumerate() {
+                for (col, &c) in line.iter().enumerate() {
+                    pattern[row][col] = match c {
+                        b'R' => 0,
+                        b'G' => 1,
+                        b'B' => 2,
+                        b'E' => 3,
+                        b'M' => 1,
+                        b'Y' => 3,
+                        _ => {
+                            let unknown_char = patname[row * width + col..].chars().next().unwrap();
+                            panic!(
+                                "Unknown CFA color \"{}\" in pattern \"{}\"",
+                                unknown_char, patname
+                            )
+                        }
+                    };
+                }
             }
 
             // extend the pattern into the full matrix
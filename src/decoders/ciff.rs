@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use crate::decoders::Buffer;
+use crate::decoders::RawLoaderError;
 use crate::decoders::basics::*;
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -8,13 +9,18 @@ pub enum CiffTag {
     //Null         = 0x0000,
     ColorInfo1 = 0x0032,
     MakeModel = 0x080a,
-    //ShotInfo     = 0x102a,
+    OwnerName = 0x0810,
+    FocalLength = 0x1029,
+    ShotInfo = 0x102a,
     ColorInfo2 = 0x102c,
     WhiteBalance = 0x10a9,
     SensorInfo = 0x1031,
+    SerialNumber = 0x180b,
+    CapturedTime = 0x180e,
     //ImageInfo    = 0x1810,
     DecoderTable = 0x1835,
     //RawData      = 0x2005,
+    JpgFromRaw = 0x2007,
     //SubIFD       = 0x300a,
     //Exif         = 0x300b,
 }
@@ -40,19 +46,30 @@ pub struct CiffIFD<'a> {
 }
 
 pub fn is_ciff(buf: &[u8]) -> bool {
-    buf[6..14] == b"HEAPCCDR"[..]
+    buf.get(6..14) == Some(&b"HEAPCCDR"[..])
 }
 
 impl<'a> CiffIFD<'a> {
-    pub fn new_file(buf: &'a Buffer) -> Result<CiffIFD<'a>, String> {
+    pub fn new_file(buf: &'a Buffer) -> Result<CiffIFD<'a>, RawLoaderError> {
         let data = &buf.buf;
         CiffIFD::new(data, LEu32(data, 2) as usize, buf.size, 1)
     }
 
-    pub fn new(buf: &'a [u8], start: usize, end: usize, depth: u32) -> Result<CiffIFD<'a>, String> {
+    pub fn new(
+        buf: &'a [u8],
+        start: usize,
+        end: usize,
+        depth: u32,
+    ) -> Result<CiffIFD<'a>, RawLoaderError> {
         let mut entries = HashMap::new();
         let mut subifds = Vec::new();
 
+        if end < start + 4 {
+            return Err(RawLoaderError::corrupt_at(
+                "CIFF: directory is too small",
+                start,
+            ));
+        }
         let valuedata_size = LEu32(buf, end - 4) as usize;
         let dircount = LEu16(buf, start + valuedata_size) as usize;
 
@@ -95,7 +112,11 @@ impl<'a> CiffIFD<'a> {
 }
 
 impl<'a> CiffEntry<'a> {
-    pub fn new(buf: &'a [u8], value_data: usize, offset: usize) -> Result<CiffEntry<'a>, String> {
+    pub fn new(
+        buf: &'a [u8],
+        value_data: usize,
+        offset: usize,
+    ) -> Result<CiffEntry<'a>, RawLoaderError> {
         let p = LEu16(buf, offset);
         let tag = p & 0x3fff;
         let datalocation = (p & 0xc000) as usize;
@@ -110,10 +131,17 @@ impl<'a> CiffEntry<'a> {
             // Data is stored directly in entry
             0x4000 => (8, offset + 2),
             val => {
-                return Err(format!("CIFF: Don't know about data location {:x}", val).to_string());
+                return Err(RawLoaderError::corrupt_at(
+                    format!("CIFF: Don't know about data location {:x}", val),
+                    offset,
+                ));
             }
         };
-        let data = &buf[data_offset..data_offset + bytesize];
+        let data = buf
+            .get(data_offset..data_offset.saturating_add(bytesize))
+            .ok_or_else(|| {
+                RawLoaderError::truncated("CIFF: entry data past the end of the file")
+            })?;
         let count = bytesize >> CiffEntry::element_shift(typ);
 
         Ok(CiffEntry {
@@ -148,10 +176,11 @@ impl<'a> CiffEntry<'a> {
 
     pub fn get_u32(&self, idx: usize) -> u32 {
         match self.typ {
-            0x0000 | 0x8000 => self.data[idx] as u32,
+            0x0000 | 0x8000 => self.data.get(idx).copied().unwrap_or(0) as u32,
             0x1000 => LEu16(self.data, idx * 2) as u32,
             0x1800 | 0x2000 | 0x2800 | 0x3000 => LEu32(self.data, idx * 4),
-            _ => panic!("Trying to read typ {} for a u32", self.typ),
+            // Not an integer type, only a corrupted file would ask for it
+            _ => 0,
         }
     }
 
@@ -21,20 +21,25 @@ impl<'a> Cr2Decoder<'a> {
 }
 
 impl<'a> Decoder for Cr2Decoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
+        self.frame(0, dummy)
+    }
+
+    fn frame_count(&self) -> usize {
+        self.frames().map_or(1, |frames| frames.len())
+    }
+
+    fn frame(&self, index: usize, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
-        let (raw, offset) = {
-            if let Some(raw) = self.tiff.find_first_ifd(Tag::Cr2Id) {
-                (raw, fetch_tag!(raw, Tag::StripOffsets).get_usize(0))
-            } else if let Some(raw) = self.tiff.find_first_ifd(Tag::CFAPattern) {
-                (raw, fetch_tag!(raw, Tag::StripOffsets).get_usize(0))
-            } else if let Some(off) = self.tiff.find_entry(Tag::Cr2OldOffset) {
-                (&self.tiff, off.get_usize(0))
-            } else {
-                return Err("CR2: Couldn't find raw info".to_string());
-            }
-        };
-        let src = &self.buffer[offset..];
+        let frames = self.frames()?;
+        let (raw, offset) = *frames
+            .get(index)
+            .ok_or_else(|| no_frame(index, frames.len()))?;
+        // The extra frames are laid out in stripes just like the main one
+        let stripes = raw
+            .find_entry(Tag::Cr2StripeWidths)
+            .or_else(|| frames[0].0.find_entry(Tag::Cr2StripeWidths));
+        let src = from_offset(self.buffer, offset);
 
         let (width, height, cpp, image) = {
             let decompressor = LjpegDecompressor::new(src)?;
@@ -59,7 +64,7 @@ impl<'a> Decoder for Cr2Decoder<'a> {
                     LookupTable::new(&t)
                 };
 
-                let mut random = ljpegout[0] as u32;
+                let mut random = ljpegout.first().copied().unwrap_or(0) as u32;
                 for o in ljpegout.chunks_exact_mut(1) {
                     o[0] = table.dither(o[0], &mut random);
                 }
@@ -84,11 +89,14 @@ impl<'a> Decoder for Cr2Decoder<'a> {
             // Take each of the vertical fields and put them into the right location
             // FIXME: Doing this at the decode would reduce about 5% in runtime but I haven't
             //        been able to do it without hairy code
-            if let Some(canoncol) = raw.find_entry(Tag::Cr2StripeWidths) {
+            if let Some(canoncol) = stripes {
                 if canoncol.get_usize(0) == 0 {
                     (width, height, cpp, ljpegout)
                 } else {
                     let mut out = alloc_image_plain!(width, height, dummy);
+                    if canoncol.get_usize(0) > width {
+                        return Err(RawLoaderError::corrupt("CR2: more stripes than columns"));
+                    }
                     if !dummy {
                         let mut fieldwidths = Vec::new();
                         for _ in 0..canoncol.get_usize(0) {
@@ -106,12 +114,10 @@ impl<'a> Decoder for Cr2Decoder<'a> {
                                 for row in (0..height).step_by(2) {
                                     for col in (0..fieldwidth).step_by(3) {
                                         let outpos = row * width + fieldstart + col;
-                                        out[outpos..outpos + 3]
-                                            .copy_from_slice(&ljpegout[inpos..inpos + 3]);
+                                        copy_pixels(&mut out, outpos, &ljpegout, inpos, 3)?;
                                         let outpos = (row + 1) * width + fieldstart + col;
                                         let inpos2 = inpos + ljpegwidth;
-                                        out[outpos..outpos + 3]
-                                            .copy_from_slice(&ljpegout[inpos2..inpos2 + 3]);
+                                        copy_pixels(&mut out, outpos, &ljpegout, inpos2, 3)?;
                                         inpos += 3;
                                         if inpos % ljpegwidth == 0 {
                                             // we've used a full input line and we're reading 2 by 2 so skip one
@@ -122,7 +128,7 @@ impl<'a> Decoder for Cr2Decoder<'a> {
                                 fieldstart += fieldwidth;
                             }
                         } else {
-                            let sh = decompressor.super_h();
+                            let sh = decompressor.super_h().max(1);
                             let mut fieldstart = 0;
                             let mut fieldpos = 0;
                             for fieldwidth in fieldwidths {
@@ -130,9 +136,7 @@ impl<'a> Decoder for Cr2Decoder<'a> {
                                 for row in 0..height {
                                     let outpos = row * width + fieldstart;
                                     let inpos = fieldpos + row * fieldwidth;
-                                    let outb = &mut out[outpos..outpos + fieldwidth];
-                                    let inb = &ljpegout[inpos..inpos + fieldwidth];
-                                    outb.copy_from_slice(inb);
+                                    copy_pixels(&mut out, outpos, &ljpegout, inpos, fieldwidth)?;
                                 }
                                 fieldstart += fieldwidth;
                                 fieldpos += fieldwidth * height;
@@ -148,20 +152,55 @@ impl<'a> Decoder for Cr2Decoder<'a> {
         };
 
         let wb = self.get_wb(&camera)?;
-        let mut img = RawImage::new(camera, width, height, wb, image, dummy);
+        let mut img = RawImage::with_cpp(camera, width / cpp, height, cpp, wb, image, dummy);
         if cpp == 3 {
-            img.cpp = 3;
-            img.width /= 3;
             img.crops = [0, 0, 0, 0];
             img.blacklevels = [0, 0, 0, 0];
             img.whitelevels = [65535, 65535, 65535, 65535];
         }
         Ok(img)
     }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
+    }
 }
 
 impl<'a> Cr2Decoder<'a> {
-    fn get_wb(&self, cam: &Camera) -> Result<[f32; 4], String> {
+    // The main raw image followed by any other lossless JPEG ones, like the second one of
+    // the dual pixel files
+    fn frames(&self) -> Result<Vec<(&TiffIFD<'_>, usize)>, RawLoaderError> {
+        let main = if let Some(raw) = self.tiff.find_first_ifd(Tag::Cr2Id) {
+            (raw, fetch_tag!(raw, Tag::StripOffsets).get_usize(0))
+        } else if let Some(raw) = self.tiff.find_first_ifd(Tag::CFAPattern) {
+            (raw, fetch_tag!(raw, Tag::StripOffsets).get_usize(0))
+        } else if let Some(off) = self.tiff.find_entry(Tag::Cr2OldOffset) {
+            (&self.tiff, off.get_usize(0))
+        } else {
+            return Err(RawLoaderError::corrupt("CR2: Couldn't find raw info"));
+        };
+
+        let mut frames = vec![main];
+        for ifd in self.tiff.ifds() {
+            let Some(offsets) = ifd.find_own_entry(Tag::StripOffsets) else {
+                continue;
+            };
+            let offset = offsets.get_usize(0);
+            // The previews are baseline JPEGs that the lossless decompressor refuses
+            if frames.iter().all(|&(_, off)| off != offset)
+                && LjpegDecompressor::new(from_offset(self.buffer, offset)).is_ok()
+            {
+                frames.push((ifd, offset));
+            }
+        }
+        Ok(frames)
+    }
+
+    fn get_wb(&self, cam: &Camera) -> Result<[f32; 4], RawLoaderError> {
         if let Some(levels) = self.tiff.find_entry(Tag::Cr2ColorData) {
             let offset = if cam.wb_offset != 0 {
                 cam.wb_offset
@@ -194,7 +233,12 @@ impl<'a> Cr2Decoder<'a> {
         }
     }
 
-    fn convert_to_rgb(&self, cam: &Camera, image: &mut [u16], dummy: bool) -> Result<(), String> {
+    fn convert_to_rgb(
+        &self,
+        cam: &Camera,
+        image: &mut [u16],
+        dummy: bool,
+    ) -> Result<(), RawLoaderError> {
         let coeffs = self.get_wb(cam)?;
         if dummy {
             return Ok(());
@@ -211,9 +255,9 @@ impl<'a> Cr2Decoder<'a> {
             let cb = pix[1] as i32 - 16383;
             let cr = pix[2] as i32 - 16383;
 
-            let r = c1 * (y + cr);
-            let g = c2 * (y + ((-778 * cb - (cr << 11)) >> 12));
-            let b = c3 * (y + cb);
+            let r = c1.wrapping_mul(y + cr);
+            let g = c2.wrapping_mul(y + ((-778 * cb - (cr << 11)) >> 12));
+            let b = c3.wrapping_mul(y + cb);
 
             pix[0] = clampbits(r >> 8, 16);
             pix[1] = clampbits(g >> 8, 16);
@@ -222,3 +266,26 @@ impl<'a> Cr2Decoder<'a> {
         Ok(())
     }
 }
+
+// Copies a run of pixels between the slice layouts, stripe sizes from a corrupted file
+// can point outside of either image
+fn copy_pixels(
+    out: &mut [u16],
+    outpos: usize,
+    src: &[u16],
+    inpos: usize,
+    len: usize,
+) -> Result<(), RawLoaderError> {
+    match (
+        out.get_mut(outpos..outpos + len),
+        src.get(inpos..inpos + len),
+    ) {
+        (Some(outb), Some(inb)) => {
+            outb.copy_from_slice(inb);
+            Ok(())
+        }
+        _ => Err(RawLoaderError::corrupt(
+            "CR2: slices don't fit inside the image",
+        )),
+    }
+}
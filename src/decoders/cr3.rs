@@ -0,0 +1,181 @@
+use crate::decoders::basics::*;
+use crate::decoders::bmff::*;
+use crate::decoders::crx::*;
+use crate::decoders::exif::*;
+use crate::decoders::tiff::*;
+use crate::decoders::*;
+
+#[derive(Debug, Clone)]
+pub struct Cr3Decoder<'a> {
+    buffer: &'a [u8],
+    rawloader: &'a RawLoader,
+    bmff: BmffFile<'a>,
+}
+
+impl<'a> Cr3Decoder<'a> {
+    pub fn new(buf: &'a [u8], bmff: BmffFile<'a>, rawloader: &'a RawLoader) -> Cr3Decoder<'a> {
+        Cr3Decoder {
+            buffer: buf,
+            bmff,
+            rawloader,
+        }
+    }
+}
+
+impl<'a> Decoder for Cr3Decoder<'a> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
+        self.frame(0, dummy)
+    }
+
+    fn frame_count(&self) -> usize {
+        self.raw_frames().map_or(1, |frames| frames.len().max(1))
+    }
+
+    fn frame(&self, index: usize, dummy: bool) -> Result<RawImage, RawLoaderError> {
+        let tiff = self.metadata()?;
+        let camera = self.rawloader.check_supported(&tiff)?;
+        let frames = self.raw_frames()?;
+        if frames.is_empty() {
+            return Err(RawLoaderError::corrupt("CR3: couldn't find a raw track"));
+        }
+        let (header, offset, size) = *frames
+            .get(index)
+            .ok_or_else(|| no_frame(index, frames.len()))?;
+        if offset.saturating_add(size) > self.buffer.len() {
+            return Err(RawLoaderError::truncated(
+                "CR3: raw data past the end of the file",
+            ));
+        }
+        let image = decode_crx(from_offset(self.buffer, offset), &header, dummy)?;
+        let wb = self.get_wb(&tiff, &camera)?;
+        ok_image(camera, header.width, header.height, wb, image)
+    }
+
+    fn exif(&self) -> Exif {
+        let mut exif = match self.metadata() {
+            Ok(tiff) => Exif::new(&tiff),
+            Err(_) => return Exif::default(),
+        };
+        // CMT4 is the GPS IFD on its own instead of being pointed to from IFD0
+        if let Some(cmt4) = self.bmff.find_box(b"CMT4")
+            && let Ok(gps) = TiffIFD::new_gps_root(self.buffer, cmt4.offset)
+        {
+            exif.gps = Gps::from_ifd(&gps);
+        }
+        exif
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        let orientation = match self.metadata() {
+            Ok(tiff) => Orientation::from_tiff(&tiff),
+            Err(_) => Orientation::Unknown,
+        };
+        let mut previews = Vec::new();
+
+        // The PRVW and THMB boxes have a small header of sizes before the JPEG
+        for name in [b"PRVW", b"THMB"] {
+            if let Some(b) = self.bmff.find_box(name)
+                && let Some(start) =
+                    (0..b.data.len().min(32)).find(|&i| b.data[i..].starts_with(&[0xFF, 0xD8]))
+            {
+                previews.extend(preview::jpeg_at(
+                    b.data,
+                    start,
+                    b.data.len() - start,
+                    orientation,
+                ));
+            }
+        }
+
+        // The full size JPEG is the sample of the first track, the one without a CMP1 box
+        for trak in self.bmff.find_boxes(b"trak") {
+            if trak.find_box(b"CMP1").is_some() {
+                continue;
+            }
+            if let Ok(samples) = trak.samples()
+                && let Some(&(offset, size)) = samples.first()
+            {
+                previews.extend(preview::jpeg_at(self.buffer, offset, size, orientation));
+            }
+        }
+        Ok(previews)
+    }
+}
+
+impl<'a> Cr3Decoder<'a> {
+    // The CMT1-4 boxes are each a full TIFF file with IFD0, the Exif IFD, the Canon
+    // makernote and the GPS IFD, so join them into a single tree
+    fn metadata(&self) -> Result<TiffIFD<'_>, RawLoaderError> {
+        let mut offsets = Vec::new();
+        for name in [b"CMT1", b"CMT2", b"CMT3", b"CMT4"] {
+            if let Some(cmt) = self.bmff.find_box(name) {
+                offsets.push(cmt.offset);
+            }
+        }
+        if offsets.is_empty() {
+            return Err(RawLoaderError::corrupt(
+                "CR3: couldn't find the CMT metadata boxes",
+            ));
+        }
+        TiffIFD::new_multi_root(self.buffer, &offsets)
+    }
+
+    // Files can have several CRX coded tracks (the full raw, a small raw for previews and
+    // for dual pixel files a second full size frame). Every four plane track of the largest
+    // size is a frame and so is every sample in it, as burst files store several per track.
+    fn raw_frames(&self) -> Result<Vec<(CrxHeader, usize, usize)>, RawLoaderError> {
+        let mut tracks = Vec::new();
+        for trak in self.bmff.find_boxes(b"trak") {
+            let cmp1 = match trak.find_box(b"CMP1") {
+                Some(val) => val,
+                None => continue,
+            };
+            let header = match CrxHeader::new(cmp1.data) {
+                Ok(val) => val,
+                Err(_) => continue,
+            };
+            if header.nplanes != 4 {
+                continue;
+            }
+            tracks.push((header, trak.samples()?));
+        }
+        let area = tracks
+            .iter()
+            .map(|(h, _)| h.width * h.height)
+            .max()
+            .unwrap_or(0);
+        Ok(tracks
+            .into_iter()
+            .filter(|(h, _)| h.width * h.height == area)
+            .flat_map(|(h, samples)| samples.into_iter().map(move |(o, s)| (h, o, s)))
+            .collect())
+    }
+
+    fn get_wb(&self, tiff: &TiffIFD, cam: &Camera) -> Result<[f32; 4], RawLoaderError> {
+        if let Some(levels) = tiff.find_entry(Tag::Cr2ColorData) {
+            let offset = if cam.wb_offset != 0 {
+                cam.wb_offset
+            } else {
+                match levels.count() {
+                    1273 | 1275 => 0x47,
+                    1312 | 1313 | 1316 | 1506 => 0x55,
+                    1816 | 1820 | 1824 => 0x69,
+                    _ => 0x3f,
+                }
+            };
+            if offset + 3 >= levels.count() {
+                return Err(RawLoaderError::corrupt(
+                    "CR3: white balance offset past the end of ColorData",
+                ));
+            }
+            Ok([
+                levels.get_force_u16(offset) as f32,
+                levels.get_force_u16(offset + 1) as f32,
+                levels.get_force_u16(offset + 3) as f32,
+                f32::NAN,
+            ])
+        } else {
+            Ok([f32::NAN, f32::NAN, f32::NAN, f32::NAN])
+        }
+    }
+}
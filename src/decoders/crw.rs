@@ -81,20 +81,23 @@ impl<'a> CrwDecoder<'a> {
 }
 
 impl<'a> Decoder for CrwDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let makemodel = fetch_tag!(self.ciff, CiffTag::MakeModel).get_strings();
         if makemodel.len() < 2 {
-            return Err("CRW: MakeModel tag needs to have 2 strings".to_string());
+            return Err(RawLoaderError::corrupt(
+                "CRW: MakeModel tag needs to have 2 strings",
+            ));
         }
-        let camera =
+        let mut camera =
             self.rawloader
                 .check_supported_with_everything(&makemodel[0], &makemodel[1], "")?;
+        camera.set_iso(self.exif().iso);
 
         let (width, height, image) = if camera.model == "Canon PowerShot Pro70" {
             (
                 1552,
                 1024,
-                decode_10le_lsb16(&self.buffer[26..], 1552, 1024, dummy),
+                decode_10le_lsb16(from_offset(self.buffer, 26), 1552, 1024, None, dummy)?,
             )
         } else {
             let sensorinfo = fetch_tag!(self.ciff, CiffTag::SensorInfo);
@@ -110,10 +113,62 @@ impl<'a> Decoder for CrwDecoder<'a> {
         let wb = self.get_wb(&camera)?;
         ok_image(camera, width, height, wb, image)
     }
+
+    fn exif(&self) -> Exif {
+        let mut exif = Exif::default();
+        if let Some(owner) = self.ciff.find_entry(CiffTag::OwnerName) {
+            exif.artist = owner.get_strings().into_iter().find(|s| !s.is_empty());
+        }
+        if let Some(serial) = self.ciff.find_entry(CiffTag::SerialNumber) {
+            exif.serial_number = Some(serial.get_u32(0).to_string());
+        }
+        // Local time as a unix timestamp, the time zone and a flag for its validity
+        if let Some(time) = self.ciff.find_entry(CiffTag::CapturedTime)
+            && time.count >= 3
+        {
+            exif.set_timestamp(time.get_u32(0) as u64);
+            if time.get_u32(2) & 0x80000000 != 0 {
+                exif.set_utc_offset(time.get_u32(1) as i32 as i64);
+            }
+        }
+        if let Some(focal) = self.ciff.find_entry(CiffTag::FocalLength)
+            && focal.count >= 2
+            && focal.get_force_u16(1) > 0
+        {
+            exif.focal_length = Some(focal.get_force_u16(1) as f32);
+        }
+        // ISO, aperture and shutter speed are stored in Canon's APEX style units
+        if let Some(shot) = self.ciff.find_entry(CiffTag::ShotInfo)
+            && shot.count >= 6
+        {
+            let val = |idx| shot.get_force_u16(idx) as i16 as f32;
+            if val(2) != 0.0 {
+                exif.iso = Some((50.0 * (val(2) / 32.0 - 4.0).exp2()).round() as u32);
+            }
+            if val(4) != 0.0 {
+                exif.fnumber = Some((val(4) / 64.0).exp2());
+            }
+            if val(5) != 0.0 {
+                exif.exposure_time = Some((-val(5) / 32.0).exp2());
+            }
+        }
+        exif
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(self
+            .ciff
+            .find_entry(CiffTag::JpgFromRaw)
+            .and_then(|entry| {
+                preview::jpeg_at(entry.data, 0, entry.data.len(), Orientation::Unknown)
+            })
+            .into_iter()
+            .collect())
+    }
 }
 
 impl<'a> CrwDecoder<'a> {
-    fn get_wb(&self, cam: &Camera) -> Result<[f32; 4], String> {
+    fn get_wb(&self, cam: &Camera) -> Result<[f32; 4], RawLoaderError> {
         if let Some(levels) = self.ciff.find_entry(CiffTag::WhiteBalance) {
             let offset = cam.wb_offset;
             return Ok([
@@ -182,8 +237,8 @@ impl<'a> CrwDecoder<'a> {
             htable.bits[i + 1] = *item as u32;
         }
 
-        for (i, item) in table.iter().enumerate() {
-            htable.huffval[i - 16] = *item as u32;
+        for (i, item) in table.iter().skip(16).enumerate() {
+            htable.huffval[i] = *item as u32;
         }
 
         htable.disable_cache = true;
@@ -198,20 +253,13 @@ impl<'a> CrwDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Result<Vec<u16>, String> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let lowbits = !cam.find_hint("nolowbits");
         let dectable = fetch_tag!(self.ciff, CiffTag::DecoderTable).get_usize(0);
         if dectable > 2 {
-            return Err(format!("CRW: Unknown decoder table {}", dectable).to_string());
+            return Err(RawLoaderError::compression("CRW", dectable as u32));
         }
-        Ok(Self::do_decode(
-            self.buffer,
-            lowbits,
-            dectable,
-            width,
-            height,
-            dummy,
-        ))
+        Self::do_decode(self.buffer, lowbits, dectable, width, height, dummy)
     }
 
     pub(crate) fn do_decode(
@@ -221,12 +269,12 @@ impl<'a> CrwDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Vec<u16> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let mut out = alloc_image!(width, height, dummy);
 
         let htables = Self::create_hufftables(dectable);
         let offset = 540 + (lowbits as usize) * height * width / 4;
-        let mut pump = BitPumpJPEG::new(&buffer[offset..]);
+        let mut pump = BitPumpJPEG::new(from_offset(buffer, offset));
 
         let mut carry: i32 = 0;
         let mut base = [0_i32; 2];
@@ -260,7 +308,7 @@ impl<'a> CrwDecoder<'a> {
                 }
                 i += 1;
             }
-            diffbuf[0] += carry;
+            diffbuf[0] = diffbuf[0].wrapping_add(carry);
             carry = diffbuf[0];
 
             // Save those differences to 64 pixels adjusting the predictor as we go
@@ -271,7 +319,7 @@ impl<'a> CrwDecoder<'a> {
                     base[1] = 512;
                 }
                 pnum += 1;
-                base[i & 1] += diffbuf[i];
+                base[i & 1] = base[i & 1].wrapping_add(diffbuf[i]);
                 pixout[i] = base[i & 1] as u16;
             }
         }
@@ -279,7 +327,7 @@ impl<'a> CrwDecoder<'a> {
         if lowbits {
             // Add the uncompressed 2 low bits to the decoded 8 high bits
             for (i, o) in out.chunks_exact_mut(4).enumerate() {
-                let c = buffer[26 + i] as u16;
+                let c = buffer.get(26 + i).copied().unwrap_or(0) as u16;
                 o[0] = o[0] << 2 | (c) & 0x03;
                 o[1] = o[1] << 2 | (c >> 2) & 0x03;
                 o[2] = o[2] << 2 | (c >> 4) & 0x03;
@@ -301,6 +349,6 @@ impl<'a> CrwDecoder<'a> {
                 }
             }
         }
-        out
+        Ok(out)
     }
 }
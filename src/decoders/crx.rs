@@ -0,0 +1,1596 @@
+// Canon's CRX codec used in CR3 files. Each of the four CFA planes is split into tiles that
+// are coded independently, either directly (lossless) or as up to three levels of a 5/3
+// integer wavelet (lossy C-RAW). The coefficients are coded line by line with an adaptive
+// Golomb-Rice coder with run-length modes, similar to JPEG-LS.
+
+use rayon::prelude::*;
+
+use crate::decoders::RawLoaderError;
+use crate::decoders::basics::*;
+use crate::decoders::check_image_size;
+
+const TILE_RIGHT: u32 = 1;
+const TILE_LEFT: u32 = 2;
+const TILE_BOTTOM: u32 = 4;
+const TILE_TOP: u32 = 8;
+
+const Q_STEP_TBL: [u32; 6] = [0x28, 0x2D, 0x33, 0x39, 0x40, 0x48];
+
+// Run lengths for each run mode state and the number of bits used to code the remainder
+const JS: [u32; 32] = [
+    1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 8, 8, 8, 8, 16, 16, 32, 32, 64, 64, 128, 128, 256, 512,
+    1024, 2048, 4096, 8192, 16384, 32768,
+];
+const J: [u32; 32] = [
+    0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 9, 10, 11, 12, 13,
+    14, 15,
+];
+
+/// The image parameters from the CMP1 box of a CR3 track
+#[derive(Debug, Clone, Copy)]
+pub struct CrxHeader {
+    pub version: u16,
+    pub width: usize,
+    pub height: usize,
+    pub tile_width: usize,
+    pub tile_height: usize,
+    pub nbits: u32,
+    pub nplanes: usize,
+    pub cfa_layout: usize,
+    pub enc_type: u32,
+    pub levels: usize,
+    pub mdat_hdr_size: usize,
+    pub median_bits: u32,
+}
+
+impl CrxHeader {
+    pub fn new(data: &[u8]) -> Result<CrxHeader, RawLoaderError> {
+        if data.len() < 0x30 || BEu16(data, 2) != 0x30 {
+            return Err(RawLoaderError::corrupt("CRX: invalid CMP1 header size"));
+        }
+
+        let version = BEu16(data, 4);
+        let nbits = data[24] as u32;
+        let nplanes = (data[25] >> 4) as usize;
+        let enc_type = (data[26] >> 4) as u32;
+        let ext_header = data[32] & 0x80 != 0;
+        let mut median_bits = nbits;
+        if ext_header && data.len() >= 57 && nplanes == 4 {
+            let use_median = (data[56] >> 6) & 1 == 1;
+            if use_median && data.len() >= 85 {
+                median_bits = data[84] as u32;
+            }
+        }
+
+        let hdr = CrxHeader {
+            version,
+            width: BEu32(data, 8) as usize,
+            height: BEu32(data, 12) as usize,
+            tile_width: BEu32(data, 16) as usize,
+            tile_height: BEu32(data, 20) as usize,
+            nbits,
+            nplanes,
+            cfa_layout: (data[25] & 0xf) as usize,
+            enc_type,
+            levels: (data[26] & 0xf) as usize,
+            mdat_hdr_size: BEu32(data, 28) as usize,
+            median_bits,
+        };
+        hdr.validate()?;
+        Ok(hdr)
+    }
+
+    fn validate(&self) -> Result<(), RawLoaderError> {
+        if self.version != 0x100 && self.version != 0x200 {
+            return Err(RawLoaderError::compression("CRX", self.version as u32));
+        }
+        if self.mdat_hdr_size == 0
+            || self.width == 0
+            || self.height == 0
+            || self.tile_width == 0
+            || self.tile_height == 0
+            || self.tile_width > self.width
+            || self.tile_height > self.height
+        {
+            return Err(RawLoaderError::corrupt("CRX: invalid image dimensions"));
+        }
+        check_image_size(self.width, self.height)?;
+        if self.nbits > 15 || self.median_bits == 0 || self.median_bits > 15 {
+            return Err(RawLoaderError::corrupt(format!(
+                "CRX: invalid bit depth {}",
+                self.nbits
+            )));
+        }
+        if self.cfa_layout > 3 || self.levels > 3 {
+            return Err(RawLoaderError::corrupt(
+                "CRX: invalid CFA layout or number of levels",
+            ));
+        }
+        match self.nplanes {
+            1 => {
+                if self.cfa_layout != 0 || self.enc_type != 0 || self.nbits != 8 {
+                    return Err(RawLoaderError::corrupt(
+                        "CRX: unsupported single plane encoding",
+                    ));
+                }
+            }
+            4 => {
+                if self.width & 1 != 0
+                    || self.height & 1 != 0
+                    || self.tile_width & 1 != 0
+                    || self.tile_height & 1 != 0
+                    || self.nbits <= 8
+                {
+                    return Err(RawLoaderError::corrupt("CRX: invalid 4 plane dimensions"));
+                }
+            }
+            n => {
+                return Err(RawLoaderError::corrupt(format!(
+                    "CRX: invalid number of planes {}",
+                    n
+                )));
+            }
+        }
+        if self.enc_type != 0 && self.enc_type != 1 && self.enc_type != 3 {
+            return Err(RawLoaderError::compression("CRX", self.enc_type));
+        }
+        if self.enc_type == 3 && self.nplanes != 4 {
+            return Err(RawLoaderError::corrupt(
+                "CRX: encoding type 3 needs 4 planes",
+            ));
+        }
+        Ok(())
+    }
+
+    fn plane_dims(&self) -> (usize, usize, usize, usize) {
+        if self.nplanes == 4 {
+            (
+                self.width / 2,
+                self.height / 2,
+                self.tile_width / 2,
+                self.tile_height / 2,
+            )
+        } else {
+            (self.width, self.height, self.tile_width, self.tile_height)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Band {
+    width: usize,
+    height: usize,
+    data_offset: usize,
+    data_size: usize,
+    // v1 quantization, optionally updated at the start of every line
+    q_param: i32,
+    q_update: bool,
+    // v2 quantization, modulated by the per tile QP table
+    q_step_base: u32,
+    q_step_mult: u32,
+    new_quant: bool,
+    // where the band's own samples are, past the coefficients borrowed from neighbours
+    col_start: usize,
+    col_len: usize,
+    row_start: usize,
+    row_len: usize,
+    level: usize,
+    level_shift: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Comp {
+    data_offset: usize,
+    supports_partial: bool,
+    rounded_mask: i32,
+    bands: Vec<Band>,
+}
+
+#[derive(Debug, Clone)]
+struct Tile {
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    flags: u32,
+    data_offset: usize,
+    qp_size: usize,
+    extra_size: usize,
+    has_qp: bool,
+    // output size of every wavelet level, coarsest first
+    level_dims: Vec<(usize, usize)>,
+    comps: Vec<Comp>,
+}
+
+#[derive(Debug, Clone)]
+struct QStep {
+    table: Vec<u32>,
+    width: usize,
+    height: usize,
+}
+
+// Sizes of the low and high bands for each wavelet level, finest first, as
+// (low, high, low_nominal, high_nominal). Tiles with a neighbour after them carry enough
+// extra coefficients to reconstruct their last samples without the neighbour, tiles with a
+// neighbour before them carry the high coefficient just before their first sample.
+fn band_sizes(
+    size: usize,
+    levels: usize,
+    before: bool,
+    after: bool,
+) -> Vec<(usize, usize, usize, usize)> {
+    let mut sizes = Vec::with_capacity(levels);
+    let mut n = size;
+    let mut extra = 0;
+    for _ in 0..levels {
+        let (exlow, exhigh) = if after {
+            let needed = (n + extra) / 2 + 1;
+            (needed - n.div_ceil(2), needed - n / 2)
+        } else {
+            (0, 0)
+        };
+        let low = n.div_ceil(2);
+        let high = n / 2;
+        sizes.push((low + exlow, high + exhigh + before as usize, low, high));
+        extra = exlow;
+        n = low;
+    }
+    sizes
+}
+
+fn q_step(q: i32) -> u32 {
+    let q = q.max(0) as u32;
+    let base = Q_STEP_TBL[(q % 6) as usize];
+    if q / 6 >= 6 {
+        base.wrapping_shl(q / 6 - 6)
+    } else {
+        base >> (6 - q / 6)
+    }
+}
+
+#[inline(always)]
+fn predict_k(k: u32, code: u32, max: u32) -> u32 {
+    let half = 1u32.checked_shl(k).unwrap_or(0) >> 1;
+    let high = code.checked_shr(k).unwrap_or(0);
+    let newk =
+        (k as i32 - (code < half) as i32 + (high > 2) as i32 + (high > 5) as i32).max(0) as u32;
+    if max == 0 || newk < max { newk } else { max }
+}
+
+#[inline(always)]
+fn sign(code: u32) -> i32 {
+    -((code & 1) as i32) ^ ((code >> 1) as i32)
+}
+
+// Median edge detector, a is the left sample, b the top one and c the top left one
+#[inline(always)]
+fn med(a: i32, b: i32, c: i32) -> i32 {
+    let delta = b.wrapping_sub(c);
+    let symb = [a.wrapping_add(delta), a.wrapping_add(delta), a, b];
+    symb[((((c < a) ^ (delta < 0)) as usize) << 1) | (((a < b) ^ (delta < 0)) as usize)]
+}
+
+struct CrxBits<'a> {
+    pump: BitPumpMSB<'a>,
+    maxbits: usize,
+}
+
+impl<'a> CrxBits<'a> {
+    fn new(data: &'a [u8]) -> CrxBits<'a> {
+        CrxBits {
+            pump: BitPumpMSB::new(data),
+            maxbits: data.len() * 8,
+        }
+    }
+
+    #[inline(always)]
+    fn get_bits(&mut self, num: u32) -> u32 {
+        self.pump.get_bits(num)
+    }
+
+    #[inline(always)]
+    fn get_zeros(&mut self) -> Result<u32, RawLoaderError> {
+        let mut zeros = 0;
+        loop {
+            let bits = self.pump.peek_bits(32);
+            if bits != 0 {
+                let lz = bits.leading_zeros();
+                self.pump.consume_bits(lz + 1);
+                return Ok(zeros + lz);
+            }
+            self.pump.consume_bits(32);
+            zeros += 32;
+            if zeros as usize > self.maxbits {
+                return Err(RawLoaderError::truncated("CRX: ran out of data"));
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn read_code(&mut self, k: u32) -> Result<u32, RawLoaderError> {
+        let zeros = self.get_zeros()?;
+        if zeros >= 41 {
+            Ok(self.get_bits(21))
+        } else if k == 0 {
+            Ok(zeros)
+        } else if k > 24 {
+            Err(RawLoaderError::corrupt("CRX: invalid Golomb parameter"))
+        } else {
+            Ok((zeros << k) | self.get_bits(k))
+        }
+    }
+}
+
+struct LineDecoder<'a> {
+    bits: CrxBits<'a>,
+    width: usize,
+    line: usize,
+    k: u32,
+    s: usize,
+    partial: bool,
+    rounded_mask: i32,
+    rounded_bits: u32,
+    // previous and current lines, with a sample of padding on each side
+    prev: Vec<i32>,
+    cur: Vec<i32>,
+    // k parameters of the previous line for the non-predicted bands
+    ks: Vec<i32>,
+}
+
+impl<'a> LineDecoder<'a> {
+    fn new(data: &'a [u8], width: usize, partial: bool, rounded_mask: i32) -> LineDecoder<'a> {
+        LineDecoder {
+            bits: CrxBits::new(data),
+            width,
+            line: 0,
+            k: 0,
+            s: 0,
+            partial,
+            rounded_mask,
+            rounded_bits: 0,
+            prev: vec![0; width + 2],
+            cur: vec![0; width + 2],
+            ks: if partial {
+                Vec::new()
+            } else {
+                vec![0; width + 2]
+            },
+        }
+    }
+
+    fn decode_line(&mut self) -> Result<&[i32], RawLoaderError> {
+        if self.line == 0 {
+            self.s = 0;
+            self.k = 0;
+            if self.partial {
+                if self.rounded_mask <= 0 {
+                    self.decode_top_line()?;
+                } else {
+                    self.rounded_bits = 32 - (self.rounded_mask as u32).leading_zeros();
+                    self.decode_top_line_rounded()?;
+                }
+            } else {
+                self.decode_top_line_noref()?;
+            }
+        } else {
+            std::mem::swap(&mut self.prev, &mut self.cur);
+            if !self.partial {
+                self.decode_line_noref()?;
+            } else if self.rounded_mask <= 0 {
+                self.decode_line_predicted()?;
+            } else {
+                self.decode_line_rounded()?;
+            }
+        }
+        self.line += 1;
+        Ok(&self.cur[1..self.width + 1])
+    }
+
+    fn read_run(&mut self, length: isize) -> Result<isize, RawLoaderError> {
+        let mut n: isize = 1;
+        while self.bits.get_bits(1) == 1 {
+            n += JS[self.s] as isize;
+            if n > length {
+                n = length;
+                break;
+            }
+            if self.s < 31 {
+                self.s += 1;
+            }
+            if n == length {
+                break;
+            }
+        }
+        if n < length {
+            if J[self.s] != 0 {
+                n += self.bits.get_bits(J[self.s]) as isize;
+            }
+            if self.s > 0 {
+                self.s -= 1;
+            }
+            if n > length {
+                return Err(RawLoaderError::corrupt("CRX: run past the end of the line"));
+            }
+        }
+        Ok(n)
+    }
+
+    fn decode_top_line(&mut self) -> Result<(), RawLoaderError> {
+        let mut p = 0;
+        let mut length = self.width as isize;
+        self.cur[0] = 0;
+        while length > 1 {
+            if self.cur[p] != 0 {
+                self.cur[p + 1] = self.cur[p];
+            } else {
+                if self.bits.get_bits(1) == 1 {
+                    let n = self.read_run(length)?;
+                    length -= n;
+                    for _ in 0..n {
+                        self.cur[p + 1] = self.cur[p];
+                        p += 1;
+                    }
+                    if length <= 0 {
+                        break;
+                    }
+                }
+                self.cur[p + 1] = 0;
+            }
+            let code = self.bits.read_code(self.k)?;
+            self.cur[p + 1] = self.cur[p + 1].wrapping_add(sign(code));
+            self.k = predict_k(self.k, code, 15);
+            p += 1;
+            length -= 1;
+        }
+        if length == 1 {
+            self.cur[p + 1] = self.cur[p];
+            let code = self.bits.read_code(self.k)?;
+            self.cur[p + 1] = self.cur[p + 1].wrapping_add(sign(code));
+            self.k = predict_k(self.k, code, 15);
+            p += 1;
+        }
+        self.cur[p + 1] = self.cur[p].wrapping_add(1);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn decode_symbol(
+        &mut self,
+        p: usize,
+        do_median: bool,
+        not_eol: bool,
+    ) -> Result<(), RawLoaderError> {
+        self.cur[p + 1] = if do_median {
+            med(self.cur[p], self.prev[p + 1], self.prev[p])
+        } else {
+            self.prev[p + 1]
+        };
+        let mut code = self.bits.read_code(self.k)?;
+        self.cur[p + 1] = self.cur[p + 1].wrapping_add(sign(code));
+        if not_eol {
+            let delta = self.prev[p + 2]
+                .wrapping_sub(self.prev[p + 1])
+                .wrapping_shl(1);
+            code = code.wrapping_add(delta.unsigned_abs()) >> 1;
+        }
+        self.k = predict_k(self.k, code, 15);
+        Ok(())
+    }
+
+    fn decode_line_predicted(&mut self) -> Result<(), RawLoaderError> {
+        let mut p = 0;
+        let mut length = self.width as isize;
+        self.cur[0] = self.prev[1];
+        while length > 1 {
+            if self.cur[p] != self.prev[p + 1] || self.cur[p] != self.prev[p + 2] {
+                self.decode_symbol(p, true, true)?;
+                p += 1;
+            } else {
+                if self.bits.get_bits(1) == 1 {
+                    let n = self.read_run(length)?;
+                    length -= n;
+                    for _ in 0..n {
+                        self.cur[p + 1] = self.cur[p];
+                        p += 1;
+                    }
+                }
+                if length > 1 {
+                    self.decode_symbol(p, false, true)?;
+                    p += 1;
+                } else if length == 1 {
+                    self.decode_symbol(p, false, false)?;
+                    p += 1;
+                }
+            }
+            length -= 1;
+        }
+        if length == 1 {
+            self.decode_symbol(p, true, false)?;
+            p += 1;
+        }
+        self.cur[p + 1] = self.cur[p].wrapping_add(1);
+        Ok(())
+    }
+
+    fn decode_top_line_rounded(&mut self) -> Result<(), RawLoaderError> {
+        let mask = self.rounded_mask;
+        let mut p = 0;
+        let mut length = self.width as isize;
+        self.cur[0] = 0;
+        while length > 1 {
+            if self.cur[p].wrapping_abs() > mask {
+                self.cur[p + 1] = self.cur[p];
+            } else {
+                if self.bits.get_bits(1) == 1 {
+                    let n = self.read_run(length)?;
+                    length -= n;
+                    for _ in 0..n {
+                        self.cur[p + 1] = self.cur[p];
+                        p += 1;
+                    }
+                    if length <= 0 {
+                        break;
+                    }
+                }
+                self.cur[p + 1] = 0;
+            }
+            let code = self.bits.read_code(self.k)?;
+            let val = sign(code);
+            self.cur[p + 1] = self.cur[p + 1]
+                .wrapping_add(mask.wrapping_mul(2).wrapping_mul(val))
+                .wrapping_add(val >> 31);
+            self.k = predict_k(self.k, code, 15);
+            p += 1;
+            length -= 1;
+        }
+        if length == 1 {
+            self.cur[p + 1] = self.cur[p];
+            let code = self.bits.read_code(self.k)?;
+            let val = sign(code);
+            self.cur[p + 1] = self.cur[p + 1]
+                .wrapping_add(mask.wrapping_mul(2).wrapping_mul(val))
+                .wrapping_add(val >> 31);
+            self.k = predict_k(self.k, code, 15);
+            p += 1;
+        }
+        self.cur[p + 1] = self.cur[p].wrapping_add(1);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn decode_symbol_rounded(
+        &mut self,
+        p: usize,
+        do_sym: bool,
+        do_code: bool,
+    ) -> Result<(), RawLoaderError> {
+        let mask = self.rounded_mask;
+        let sym = if do_sym {
+            med(self.cur[p], self.prev[p + 1], self.prev[p])
+        } else {
+            self.prev[p + 1]
+        };
+        let code_bits = self.bits.read_code(self.k)?;
+        let code = sign(code_bits);
+        self.cur[p + 1] = mask
+            .wrapping_mul(2)
+            .wrapping_mul(code)
+            .wrapping_add(code >> 31)
+            .wrapping_add(sym);
+        if do_code {
+            let (next, cur) = (self.prev[p + 2], self.prev[p + 1]);
+            let code = if next > cur {
+                next.wrapping_sub(cur).wrapping_add(mask - 1) >> self.rounded_bits
+            } else {
+                -(cur.wrapping_sub(next).wrapping_add(mask) >> self.rounded_bits)
+            };
+            let code = code_bits.wrapping_add(2u32.wrapping_mul(code.unsigned_abs())) >> 1;
+            self.k = predict_k(self.k, code, 15);
+        } else {
+            self.k = predict_k(self.k, code_bits, 15);
+        }
+        Ok(())
+    }
+
+    fn decode_line_rounded(&mut self) -> Result<(), RawLoaderError> {
+        let mask = self.rounded_mask;
+        let mut value_reached = false;
+        let mut p = 0;
+        let mut length = self.width as isize;
+        self.prev[0] = self.prev[1];
+        self.cur[0] = self.prev[1];
+        while length > 1 {
+            if self.prev[p + 2]
+                .wrapping_sub(self.prev[p + 1])
+                .wrapping_abs()
+                > mask
+            {
+                self.decode_symbol_rounded(p, true, true)?;
+                p += 1;
+                value_reached = true;
+            } else if value_reached || self.prev[p].wrapping_sub(self.cur[p]).wrapping_abs() > mask
+            {
+                self.decode_symbol_rounded(p, true, true)?;
+                p += 1;
+                value_reached = false;
+            } else {
+                if self.bits.get_bits(1) == 1 {
+                    let n = self.read_run(length)?;
+                    length -= n;
+                    for _ in 0..n {
+                        self.cur[p + 1] = self.cur[p];
+                        p += 1;
+                    }
+                }
+                if length > 1 {
+                    self.decode_symbol_rounded(p, false, true)?;
+                    p += 1;
+                    value_reached =
+                        self.prev[p + 1].wrapping_sub(self.prev[p]).wrapping_abs() > mask;
+                } else if length == 1 {
+                    self.decode_symbol_rounded(p, false, false)?;
+                    p += 1;
+                }
+            }
+            length -= 1;
+        }
+        if length == 1 {
+            self.decode_symbol_rounded(p, true, false)?;
+            p += 1;
+        }
+        self.cur[p + 1] = self.cur[p].wrapping_add(1);
+        Ok(())
+    }
+
+    fn decode_top_line_noref(&mut self) -> Result<(), RawLoaderError> {
+        let mut p = 0;
+        let mut length = self.width as isize;
+        self.prev[0] = 0;
+        self.cur[0] = 0;
+        while length > 1 {
+            if self.cur[p] != 0 {
+                let code = self.bits.read_code(self.k)?;
+                self.cur[p + 1] = sign(code);
+                self.k = predict_k(self.k, code, 0);
+            } else {
+                if self.bits.get_bits(1) == 1 {
+                    let n = self.read_run(length)?;
+                    length -= n;
+                    for _ in 0..n {
+                        self.ks[p] = 0;
+                        self.cur[p + 1] = 0;
+                        p += 1;
+                    }
+                    if length <= 0 {
+                        return Ok(());
+                    }
+                }
+                let code = self.bits.read_code(self.k)?;
+                self.cur[p + 1] = sign(code.wrapping_add(1));
+                self.k = predict_k(self.k, code, 0);
+            }
+            self.ks[p] = self.k as i32;
+            p += 1;
+            length -= 1;
+        }
+        if length == 1 {
+            let code = self.bits.read_code(self.k)?;
+            self.cur[p + 1] = sign(code);
+            self.k = predict_k(self.k, code, 0);
+            self.ks[p] = self.k as i32;
+            p += 1;
+        }
+        self.cur[p + 1] = 0;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn adjust_k(&mut self, i: usize) {
+        if self.ks[i + 1] - (self.k as i32) <= 1 {
+            if self.k >= 15 {
+                self.k = 15;
+            }
+        } else {
+            self.k += 1;
+        }
+    }
+
+    fn decode_line_noref(&mut self) -> Result<(), RawLoaderError> {
+        let width = self.width;
+        let mut i = 0;
+        while i + 1 < width {
+            if self.prev[i + 2] | self.prev[i + 1] | self.cur[i] != 0 {
+                let code = self.bits.read_code(self.k)?;
+                self.cur[i + 1] = sign(code);
+                self.k = predict_k(self.k, code, 0);
+                self.adjust_k(i);
+            } else {
+                let mut n = 0;
+                if self.bits.get_bits(1) == 1 {
+                    n = 1;
+                    while self.bits.get_bits(1) == 1 {
+                        n += JS[self.s] as usize;
+                        if i + n > width {
+                            n = width - i;
+                            break;
+                        }
+                        if self.s < 31 {
+                            self.s += 1;
+                        }
+                        if i + n == width {
+                            break;
+                        }
+                    }
+                    if i + n < width {
+                        if J[self.s] != 0 {
+                            n += self.bits.get_bits(J[self.s]) as usize;
+                        }
+                        if self.s > 0 {
+                            self.s -= 1;
+                        }
+                    }
+                    if i + n > width {
+                        return Err(RawLoaderError::corrupt("CRX: run past the end of the line"));
+                    }
+                }
+                if n > 0 {
+                    self.cur[i + 1..i + 1 + n].fill(0);
+                    self.ks[i..i + n].fill(0);
+                    i += n;
+                }
+                if i + 1 >= width {
+                    if i + 1 == width {
+                        let code = self.bits.read_code(self.k)?;
+                        self.cur[i + 1] = sign(code.wrapping_add(1));
+                        self.k = predict_k(self.k, code, 15);
+                        self.ks[i] = self.k as i32;
+                    }
+                    i += 1;
+                    continue;
+                }
+                let code = self.bits.read_code(self.k)?;
+                self.cur[i + 1] = sign(code.wrapping_add(1));
+                self.k = predict_k(self.k, code, 0);
+                self.adjust_k(i);
+            }
+            self.ks[i] = self.k as i32;
+            i += 1;
+        }
+        if i + 1 == width {
+            let code = self.bits.read_code(self.k)?;
+            self.cur[i + 1] = sign(code);
+            self.k = predict_k(self.k, code, 15);
+            self.ks[i] = self.k as i32;
+        }
+        Ok(())
+    }
+
+    // Old style bands can change their quantizer at the start of every line
+    fn update_qparam(&mut self, q: &mut i32, k: &mut u32) -> Result<(), RawLoaderError> {
+        let mut code = self.bits.get_zeros()?;
+        if code >= 23 {
+            code = self.bits.get_bits(8);
+        } else if *k > 0 {
+            if *k > 24 {
+                return Err(RawLoaderError::corrupt("CRX: invalid Golomb parameter"));
+            }
+            code = (code << *k) | self.bits.get_bits(*k);
+        }
+        *q = q.wrapping_add(sign(code));
+        *k = predict_k(*k, code, 0);
+        Ok(())
+    }
+}
+
+// One dimensional inverse 5/3 wavelet. The high band starts with the coefficient just
+// before the first sample when the tile has a neighbour before it, and both bands carry
+// the coefficients past the end when there's a neighbour after. Missing coefficients at
+// the image edges are mirrored.
+fn idwt_53(low: &[i32], high: &[i32], out: &mut [i32], before: bool) {
+    let w = out.len();
+    if high.is_empty() || low.is_empty() {
+        for (i, o) in out.iter_mut().enumerate() {
+            *o = if i & 1 == 0 {
+                low.get(i / 2).copied().unwrap_or(0)
+            } else {
+                0
+            };
+        }
+        return;
+    }
+    let hmax = high.len() as isize - 1;
+    let hi = |k: isize| -> i32 { high[(k + before as isize).clamp(0, hmax) as usize] };
+    let lo = |k: usize| -> i32 { low[k.min(low.len() - 1)] };
+    let even = |k: usize| -> i32 {
+        let k = k as isize;
+        lo(k as usize).wrapping_sub(hi(k - 1).wrapping_add(hi(k)).wrapping_add(2) >> 2)
+    };
+
+    for k in 0..w.div_ceil(2) {
+        out[2 * k] = even(k);
+    }
+    for k in 0..w / 2 {
+        let next = if 2 * k + 2 < w {
+            out[2 * k + 2]
+        } else if low.len() > k + 1 {
+            even(k + 1)
+        } else {
+            out[2 * k]
+        };
+        out[2 * k + 1] = hi(k as isize).wrapping_add(out[2 * k].wrapping_add(next) >> 1);
+    }
+}
+
+// Reads the per tile table of quantizers and turns it into a step table for each level
+fn read_qsteps(data: &[u8], tile: &Tile, levels: usize) -> Result<Vec<QStep>, RawLoaderError> {
+    if levels < 1 {
+        return Err(RawLoaderError::corrupt(
+            "CRX: QP data without wavelet levels",
+        ));
+    }
+    let qp_width = tile.width.div_ceil(8);
+    let qp_height = tile.height.div_ceil(2);
+    let qp_height4 = tile.height.div_ceil(4);
+    let qp_height8 = tile.height.div_ceil(8);
+
+    let mut bits = CrxBits::new(data);
+    let mut table = vec![0i32; qp_width * qp_height];
+    let mut prev = vec![0i32; qp_width + 2];
+    let mut cur = vec![0i32; qp_width + 2];
+    let mut k = 0;
+    for row in 0..qp_height {
+        if row == 0 {
+            cur[0] = 0;
+            for i in 0..qp_width {
+                cur[i + 1] = cur[i];
+                let code = bits.read_code(k)?;
+                cur[i + 1] = cur[i + 1].wrapping_add(sign(code));
+                k = predict_k(k, code, 7);
+            }
+        } else {
+            std::mem::swap(&mut prev, &mut cur);
+            cur[0] = prev[1];
+            for i in 0..qp_width {
+                cur[i + 1] = med(cur[i], prev[i + 1], prev[i]);
+                let code = bits.read_code(k)?;
+                cur[i + 1] = cur[i + 1].wrapping_add(sign(code));
+                k = if i + 1 < qp_width {
+                    let delta = prev[i + 2].wrapping_sub(prev[i + 1]);
+                    predict_k(k, code.wrapping_add(2 * delta.unsigned_abs()) >> 1, 7)
+                } else {
+                    predict_k(k, code, 7)
+                };
+            }
+        }
+        cur[qp_width + 1] = cur[qp_width].wrapping_add(1);
+        for i in 0..qp_width {
+            table[row * qp_width + i] = cur[i + 1] + 4;
+        }
+    }
+
+    let avg_rows = |rows: usize, height: usize| -> QStep {
+        let mut steps = Vec::with_capacity(qp_width * height);
+        for qrow in 0..height {
+            for col in 0..qp_width {
+                let mut sum = 0;
+                for r in 0..rows {
+                    sum += table[(rows * qrow + r).min(qp_height - 1) * qp_width + col];
+                }
+                let quant = match rows {
+                    4 => ((sum < 0) as i32 * 3 + sum) >> 2,
+                    2 => sum / 2,
+                    _ => sum,
+                };
+                steps.push(q_step(quant));
+            }
+        }
+        QStep {
+            table: steps,
+            width: qp_width,
+            height,
+        }
+    };
+
+    let mut qsteps = Vec::with_capacity(levels);
+    if levels >= 3 {
+        qsteps.push(avg_rows(4, qp_height8));
+    }
+    if levels >= 2 {
+        qsteps.push(avg_rows(2, qp_height4));
+    }
+    qsteps.push(avg_rows(1, qp_height));
+    Ok(qsteps)
+}
+
+struct CrxImage<'a> {
+    hdr: &'a CrxHeader,
+    data: &'a [u8],
+    plane_width: usize,
+    plane_height: usize,
+    tiles: Vec<Tile>,
+}
+
+impl<'a> CrxImage<'a> {
+    fn new(buf: &'a [u8], hdr: &'a CrxHeader) -> Result<CrxImage<'a>, RawLoaderError> {
+        if buf.len() < hdr.mdat_hdr_size {
+            return Err(RawLoaderError::truncated(
+                "CRX: image data smaller than its header",
+            ));
+        }
+        let (plane_width, plane_height, tile_width, tile_height) = hdr.plane_dims();
+        let cols = plane_width.div_ceil(tile_width);
+        let rows = plane_height.div_ceil(tile_height);
+        let last_width = plane_width - tile_width * (cols - 1);
+        let last_height = plane_height - tile_height * (rows - 1);
+        if last_width < 0x16 || last_height < 0x16 {
+            return Err(RawLoaderError::corrupt("CRX: tiles are too small"));
+        }
+        // Every tile has at least a 12 byte header
+        if cols * rows * 12 > hdr.mdat_hdr_size {
+            return Err(RawLoaderError::truncated(
+                "CRX: header too small for its tiles",
+            ));
+        }
+
+        let mut tiles = Vec::with_capacity(cols * rows);
+        for row in 0..rows {
+            for col in 0..cols {
+                let mut flags = 0;
+                if col > 0 {
+                    flags |= TILE_LEFT;
+                }
+                if col + 1 < cols {
+                    flags |= TILE_RIGHT;
+                }
+                if row > 0 {
+                    flags |= TILE_TOP;
+                }
+                if row + 1 < rows {
+                    flags |= TILE_BOTTOM;
+                }
+                let width = if col + 1 < cols {
+                    tile_width
+                } else {
+                    last_width
+                };
+                let height = if row + 1 < rows {
+                    tile_height
+                } else {
+                    last_height
+                };
+                tiles.push(CrxImage::setup_tile(
+                    hdr,
+                    width,
+                    height,
+                    col * tile_width,
+                    row * tile_height,
+                    flags,
+                ));
+            }
+        }
+
+        let mut img = CrxImage {
+            hdr,
+            data: &buf[hdr.mdat_hdr_size..],
+            plane_width,
+            plane_height,
+            tiles,
+        };
+        img.read_headers(&buf[..hdr.mdat_hdr_size])?;
+        Ok(img)
+    }
+
+    fn setup_tile(
+        hdr: &CrxHeader,
+        width: usize,
+        height: usize,
+        x: usize,
+        y: usize,
+        flags: u32,
+    ) -> Tile {
+        let levels = hdr.levels;
+        let hsizes = band_sizes(
+            width,
+            levels,
+            flags & TILE_LEFT != 0,
+            flags & TILE_RIGHT != 0,
+        );
+        let vsizes = band_sizes(
+            height,
+            levels,
+            flags & TILE_TOP != 0,
+            flags & TILE_BOTTOM != 0,
+        );
+        let left = (flags & TILE_LEFT != 0) as usize;
+        let top = (flags & TILE_TOP != 0) as usize;
+
+        let band =
+            |w: (usize, usize, usize), h: (usize, usize, usize), level: usize, shift: u32| Band {
+                width: w.0,
+                height: h.0,
+                data_offset: 0,
+                data_size: 0,
+                q_param: 0,
+                q_update: false,
+                q_step_base: 0,
+                q_step_mult: 0,
+                new_quant: false,
+                col_start: w.1,
+                col_len: w.2,
+                row_start: h.1,
+                row_len: h.2,
+                level,
+                level_shift: shift,
+            };
+
+        let mut bands = Vec::with_capacity(3 * levels + 1);
+        let mut level_dims = Vec::with_capacity(levels);
+        if levels == 0 {
+            bands.push(band((width, 0, width), (height, 0, height), 0, 0));
+        } else {
+            let (lw, _, nlw, _) = hsizes[levels - 1];
+            let (lh, _, nlh, _) = vsizes[levels - 1];
+            bands.push(band((lw, 0, nlw), (lh, 0, nlh), 0, 3 - levels as u32));
+            for level in 0..levels {
+                let depth = levels - 1 - level;
+                let (lw, hw, nlw, nhw) = hsizes[depth];
+                let (lh, hh, nlh, nhh) = vsizes[depth];
+                let shift = 2 - depth as u32;
+                bands.push(band((hw, left, nhw), (lh, 0, nlh), level, shift));
+                bands.push(band((lw, 0, nlw), (hh, top, nhh), level, shift));
+                bands.push(band((hw, left, nhw), (hh, top, nhh), level, shift));
+                level_dims.push(if depth == 0 {
+                    (width, height)
+                } else {
+                    (hsizes[depth - 1].0, vsizes[depth - 1].0)
+                });
+            }
+        }
+
+        let comp = Comp {
+            data_offset: 0,
+            supports_partial: false,
+            rounded_mask: 0,
+            bands,
+        };
+        Tile {
+            width,
+            height,
+            x,
+            y,
+            flags,
+            data_offset: 0,
+            qp_size: 0,
+            extra_size: 0,
+            has_qp: false,
+            level_dims,
+            comps: vec![comp; hdr.nplanes],
+        }
+    }
+
+    fn read_headers(&mut self, hdr: &[u8]) -> Result<(), RawLoaderError> {
+        let check = |pos: usize, size: usize| -> Result<(), RawLoaderError> {
+            if pos + 4 + size > hdr.len() {
+                Err(RawLoaderError::truncated("CRX: truncated tile headers"))
+            } else {
+                Ok(())
+            }
+        };
+
+        let mut pos = 0;
+        let mut tile_offset = 0;
+        for tile in self.tiles.iter_mut() {
+            check(pos, 0)?;
+            let (sig, size) = (BEu16(hdr, pos), BEu16(hdr, pos + 2) as usize);
+            if !matches!((sig, size), (0xFF01, 8) | (0xFF11, 8) | (0xFF11, 16)) {
+                return Err(RawLoaderError::corrupt(format!(
+                    "CRX: invalid tile header {:#x}",
+                    sig
+                )));
+            }
+            check(pos, size)?;
+            tile.data_offset = tile_offset;
+            tile_offset += BEu32(hdr, pos + 4) as usize;
+            if size == 16 {
+                tile.has_qp = true;
+                tile.qp_size = BEu32(hdr, pos + 12) as usize;
+                tile.extra_size = BEu16(hdr, pos + 16) as usize;
+            }
+            pos += 4 + size;
+
+            let mut comp_offset = 0;
+            for (num, comp) in tile.comps.iter_mut().enumerate() {
+                check(pos, 0)?;
+                let (sig, size) = (BEu16(hdr, pos), BEu16(hdr, pos + 2) as usize);
+                if (sig != 0xFF02 && sig != 0xFF12) || size != 8 {
+                    return Err(RawLoaderError::corrupt(format!(
+                        "CRX: invalid plane header {:#x}",
+                        sig
+                    )));
+                }
+                check(pos, size)?;
+                let flags = hdr[pos + 8];
+                if (flags >> 4) as usize != num {
+                    return Err(RawLoaderError::corrupt("CRX: plane headers out of order"));
+                }
+                comp.data_offset = comp_offset;
+                comp_offset += BEu32(hdr, pos + 4) as usize;
+                comp.supports_partial = flags & 8 != 0;
+                let rounded = (flags >> 1) & 3;
+                comp.rounded_mask = if rounded > 0 { 1 << (rounded - 1) } else { 0 };
+                pos += 4 + size;
+
+                let mut band_offset = 0;
+                for band in comp.bands.iter_mut() {
+                    check(pos, 0)?;
+                    let (sig, size) = (BEu16(hdr, pos), BEu16(hdr, pos + 2) as usize);
+                    match (sig, size) {
+                        (0xFF03, 8) => {
+                            check(pos, size)?;
+                            let band_size = BEu32(hdr, pos + 4) as usize;
+                            let bit_data = BEu32(hdr, pos + 8);
+                            band.data_offset = band_offset;
+                            band.data_size = band_size
+                                .checked_sub((bit_data & 0x7FFFF) as usize)
+                                .ok_or_else(|| {
+                                RawLoaderError::corrupt("CRX: invalid band size")
+                            })?;
+                            band.q_update = bit_data & 0x8000000 != 0;
+                            band.q_param = ((bit_data >> 19) & 0xFF) as i32;
+                            band_offset += band_size;
+                        }
+                        (0xFF13, 16) => {
+                            check(pos, size)?;
+                            let band_size = BEu32(hdr, pos + 4) as usize;
+                            band.data_offset = band_offset;
+                            band.data_size = band_size
+                                .checked_sub(BEu16(hdr, pos + 16) as usize)
+                                .ok_or_else(|| {
+                                RawLoaderError::corrupt("CRX: invalid band size")
+                            })?;
+                            band.q_step_mult = BEu16(hdr, pos + 10) as u32;
+                            band.q_step_base = BEu32(hdr, pos + 12);
+                            band.new_quant = true;
+                            band_offset += band_size;
+                        }
+                        _ => {
+                            return Err(RawLoaderError::corrupt(format!(
+                                "CRX: invalid band header {:#x}",
+                                sig
+                            )));
+                        }
+                    }
+                    pos += 4 + size;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_band(
+        &self,
+        tile: &Tile,
+        comp: &Comp,
+        bandnum: usize,
+        qsteps: Option<&Vec<QStep>>,
+    ) -> Result<Vec<i32>, RawLoaderError> {
+        let band = &comp.bands[bandnum];
+        let mut out = vec![0i32; band.width * band.height];
+        if band.data_size == 0 || band.width == 0 {
+            return Ok(out);
+        }
+
+        let offset =
+            tile.data_offset + tile.qp_size + tile.extra_size + comp.data_offset + band.data_offset;
+        if offset + band.data_size > self.data.len() {
+            return Err(RawLoaderError::truncated(
+                "CRX: band data past the end of the file",
+            ));
+        }
+        let partial = bandnum == 0 && comp.supports_partial;
+        let mut decoder =
+            LineDecoder::new(&self.data[offset..], band.width, partial, comp.rounded_mask);
+        let quantize = self.hdr.levels > 0;
+        let mut q_param = band.q_param;
+        let mut q_k = 0;
+
+        for (row, line) in out.chunks_exact_mut(band.width).enumerate() {
+            if quantize && band.q_update && qsteps.is_none() {
+                decoder.update_qparam(&mut q_param, &mut q_k)?;
+            }
+            line.copy_from_slice(decoder.decode_line()?);
+            if !quantize {
+                continue;
+            }
+
+            if let Some(qsteps) = qsteps {
+                let qstep = &qsteps[band.level];
+                let last_row = band.row_start + band.row_len.max(1) - 1;
+                let qrow =
+                    (row.clamp(band.row_start, last_row) - band.row_start).min(qstep.height - 1);
+                let steps = &qstep.table[qrow * qstep.width..(qrow + 1) * qstep.width];
+                let last = band.col_start + band.col_len.max(1) - 1;
+                for (col, val) in line.iter_mut().enumerate() {
+                    let idx = ((col.clamp(band.col_start, last) - band.col_start)
+                        >> band.level_shift)
+                        .min(qstep.width - 1);
+                    let quant = band
+                        .q_step_base
+                        .wrapping_add(steps[idx].wrapping_mul(band.q_step_mult) >> 3)
+                        .clamp(1, 0x168000);
+                    *val = val.wrapping_mul(quant as i32);
+                }
+            } else {
+                let quant = if band.new_quant {
+                    band.q_step_base.clamp(1, 0x168000)
+                } else {
+                    q_step(q_param)
+                };
+                if quant != 1 {
+                    for val in line.iter_mut() {
+                        *val = val.wrapping_mul(quant as i32);
+                    }
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn decode_tile(
+        &self,
+        tile: &Tile,
+        plane: usize,
+        qsteps: Option<&Vec<QStep>>,
+    ) -> Result<Vec<i32>, RawLoaderError> {
+        let comp = &tile.comps[plane];
+        let levels = self.hdr.levels;
+        let mut bands = (0..comp.bands.len())
+            .map(|i| self.decode_band(tile, comp, i, qsteps))
+            .collect::<Result<Vec<Vec<i32>>, RawLoaderError>>()?;
+
+        let mut low = std::mem::take(&mut bands[0]);
+        let (mut lw, mut lh) = (comp.bands[0].width, comp.bands[0].height);
+        for level in 0..levels {
+            let (ow, oh) = tile.level_dims[level];
+            let b1 = &comp.bands[3 * level + 1];
+            let b2 = &comp.bands[3 * level + 2];
+            let b3 = &comp.bands[3 * level + 3];
+            if b1.height != lh || b2.height != b3.height || lw == 0 || b2.width == 0 {
+                return Err(RawLoaderError::corrupt(
+                    "CRX: inconsistent wavelet band sizes",
+                ));
+            }
+
+            // Horizontal pass on the vertically low and high halves
+            let mut rows = vec![0i32; ow * (lh + b2.height)];
+            for row in 0..lh {
+                idwt_53(
+                    &low[row * lw..(row + 1) * lw],
+                    &bands[3 * level + 1][row * b1.width..(row + 1) * b1.width],
+                    &mut rows[row * ow..(row + 1) * ow],
+                    tile.flags & TILE_LEFT != 0,
+                );
+            }
+            for row in 0..b2.height {
+                let out = lh + row;
+                idwt_53(
+                    &bands[3 * level + 2][row * b2.width..(row + 1) * b2.width],
+                    &bands[3 * level + 3][row * b3.width..(row + 1) * b3.width],
+                    &mut rows[out * ow..(out + 1) * ow],
+                    tile.flags & TILE_LEFT != 0,
+                );
+            }
+
+            // Vertical pass
+            let mut out = vec![0i32; ow * oh];
+            let mut lcol = vec![0i32; lh];
+            let mut hcol = vec![0i32; b2.height];
+            let mut res = vec![0i32; oh];
+            for col in 0..ow {
+                for (row, val) in lcol.iter_mut().enumerate() {
+                    *val = rows[row * ow + col];
+                }
+                for (row, val) in hcol.iter_mut().enumerate() {
+                    *val = rows[(lh + row) * ow + col];
+                }
+                idwt_53(&lcol, &hcol, &mut res, tile.flags & TILE_TOP != 0);
+                for (row, val) in res.iter().enumerate() {
+                    out[row * ow + col] = *val;
+                }
+            }
+
+            low = out;
+            lw = ow;
+            lh = oh;
+        }
+
+        if lw != tile.width || lh != tile.height {
+            return Err(RawLoaderError::corrupt(
+                "CRX: tile decoded to the wrong size",
+            ));
+        }
+        Ok(low)
+    }
+
+    fn decode_plane(
+        &self,
+        plane: usize,
+        qsteps: &[Option<Vec<QStep>>],
+    ) -> Result<Vec<i32>, RawLoaderError> {
+        let mut out = vec![0i32; self.plane_width * self.plane_height];
+        for (tile, qsteps) in self.tiles.iter().zip(qsteps) {
+            let data = self.decode_tile(tile, plane, qsteps.as_ref())?;
+            for (row, line) in data.chunks_exact(tile.width).take(tile.height).enumerate() {
+                let start = (tile.y + row) * self.plane_width + tile.x;
+                out[start..start + tile.width].copy_from_slice(line);
+            }
+        }
+        Ok(out)
+    }
+
+    fn decode(&self, dummy: bool) -> Result<Vec<u16>, RawLoaderError> {
+        let hdr = self.hdr;
+        if dummy {
+            return Ok(alloc_image!(hdr.width, hdr.height, dummy));
+        }
+
+        let qsteps = self
+            .tiles
+            .iter()
+            .map(|tile| {
+                if tile.has_qp {
+                    if tile.data_offset >= self.data.len() {
+                        return Err(RawLoaderError::truncated(
+                            "CRX: tile data past the end of the file",
+                        ));
+                    }
+                    read_qsteps(&self.data[tile.data_offset..], tile, hdr.levels).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect::<Result<Vec<Option<Vec<QStep>>>, RawLoaderError>>()?;
+
+        let planes = (0..hdr.nplanes)
+            .into_par_iter()
+            .map(|plane| self.decode_plane(plane, &qsteps))
+            .collect::<Result<Vec<Vec<i32>>, RawLoaderError>>()?;
+
+        let pw = self.plane_width;
+        if hdr.nplanes == 1 {
+            return Ok(planes[0]
+                .iter()
+                .map(|v| (*v).clamp(0, 255) as u16)
+                .collect());
+        }
+
+        let maxval = (1i64 << hdr.nbits) - 1;
+        let median = 1i64 << (hdr.nbits - 1);
+        let median_ex = (1i64 << (hdr.median_bits - 1)) << 10;
+        let layout = hdr.cfa_layout;
+        decode_threaded(
+            hdr.width,
+            hdr.height,
+            None,
+            dummy,
+            &(|out: &mut [u16], row| {
+                let prow = (row / 2) * pw;
+                let pos_row = (row & 1) << 1;
+                for (col, pix) in out.iter_mut().enumerate() {
+                    let pos = pos_row | (col & 1);
+                    let idx = prow + col / 2;
+                    let val = match hdr.enc_type {
+                        1 => {
+                            let v = planes[pos ^ layout][idx] as i64;
+                            v.clamp(-median, median - 1)
+                        }
+                        3 => {
+                            let p0 = planes[0][idx] as i64;
+                            let p1 = planes[1][idx] as i64;
+                            let p2 = planes[2][idx] as i64;
+                            let p3 = planes[3][idx] as i64;
+                            let gr = || {
+                                let gr = median_ex + (p0 << 10) - 168 * p1 - 585 * p3;
+                                let rounded = ((gr.abs() + 512) >> 9) & !1;
+                                if gr < 0 { -rounded } else { rounded }
+                            };
+                            let v = match pos ^ layout {
+                                0 => (median_ex + (p0 << 10) + 1510 * p3 + 512) >> 10,
+                                1 => (p2 + gr() + 1) >> 1,
+                                2 => (gr() - p2 + 1) >> 1,
+                                _ => (median_ex + (p0 << 10) + 1927 * p1 + 512) >> 10,
+                            };
+                            v.clamp(0, maxval)
+                        }
+                        _ => (median + planes[pos ^ layout][idx] as i64).clamp(0, maxval),
+                    };
+                    *pix = val as u16;
+                }
+            }),
+        )
+    }
+}
+
+/// Decodes a CRX coded sample, with `buf` starting at the sample's header
+pub fn decode_crx(buf: &[u8], hdr: &CrxHeader, dummy: bool) -> Result<Vec<u16>, RawLoaderError> {
+    let img = CrxImage::new(buf, hdr)?;
+    img.decode(dummy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The CMP1 box of a 44x44 image with a single tile and four 14 bit planes
+    fn cmp1(levels: u8) -> Vec<u8> {
+        let mut data = vec![0u8; 0x30];
+        data[2..4].copy_from_slice(&0x30u16.to_be_bytes());
+        data[4..6].copy_from_slice(&0x100u16.to_be_bytes());
+        for pos in [8, 12, 16, 20] {
+            data[pos..pos + 4].copy_from_slice(&44u32.to_be_bytes());
+        }
+        data[24] = 14;
+        data[25] = 0x40;
+        data[26] = levels;
+        let bands = 3 * levels as u32 + 1;
+        let header = 12 + 4 * (12 + 12 * bands);
+        data[28..32].copy_from_slice(&header.to_be_bytes());
+        data
+    }
+
+    // A sample with 16 bytes for every band. All ones codes every line of a band as a
+    // single run of zeros, and starting the first band of the first plane with 0001
+    // makes its first value 1 instead, which the runs then repeat.
+    fn sample(levels: u8, q_param: u32) -> Vec<u8> {
+        let bands = 3 * levels as usize + 1;
+        let mut buf = vec![0xff, 0x01, 0x00, 0x08];
+        buf.extend_from_slice(&(64 * bands as u32).to_be_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        for plane in 0..4u8 {
+            buf.extend_from_slice(&[0xff, 0x02, 0x00, 0x08]);
+            buf.extend_from_slice(&(16 * bands as u32).to_be_bytes());
+            buf.extend_from_slice(&[plane << 4 | 8, 0, 0, 0]);
+            for _ in 0..bands {
+                buf.extend_from_slice(&[0xff, 0x03, 0x00, 0x08]);
+                buf.extend_from_slice(&16u32.to_be_bytes());
+                buf.extend_from_slice(&(q_param << 19).to_be_bytes());
+            }
+        }
+        for plane in 0..4 {
+            for band in 0..bands {
+                let mut data = [0xff; 16];
+                if plane == 0 && band == 0 {
+                    data[0] = 0x1f;
+                }
+                buf.extend_from_slice(&data);
+            }
+        }
+        buf
+    }
+
+    fn check_image(image: &[u16], first: u16) {
+        for (row, line) in image.chunks_exact(44).enumerate() {
+            for (col, val) in line.iter().enumerate() {
+                let expected = if row & 1 == 0 && col & 1 == 0 {
+                    first
+                } else {
+                    8192
+                };
+                assert_eq!(*val, expected, "at {}x{}", col, row);
+            }
+        }
+    }
+
+    #[test]
+    fn header() {
+        let hdr = CrxHeader::new(&cmp1(3)).unwrap();
+        assert_eq!(
+            (hdr.width, hdr.height, hdr.tile_width, hdr.tile_height),
+            (44, 44, 44, 44)
+        );
+        assert_eq!(
+            (hdr.nbits, hdr.nplanes, hdr.levels, hdr.enc_type),
+            (14, 4, 3, 0)
+        );
+        assert_eq!((hdr.mdat_hdr_size, hdr.median_bits), (12 + 4 * 132, 14));
+
+        for (pos, val) in [
+            (5, 0x01),
+            (11, 45),
+            (24, 8),
+            (25, 0x20),
+            (26, 4),
+            (26, 0x20),
+        ] {
+            let mut bad = cmp1(0);
+            bad[pos] = val;
+            assert!(CrxHeader::new(&bad).is_err(), "byte {} set to {}", pos, val);
+        }
+    }
+
+    #[test]
+    fn lossless_image() {
+        let hdr = CrxHeader::new(&cmp1(0)).unwrap();
+        // The planes are centered on the median of the bit depth
+        check_image(&decode_crx(&sample(0, 0), &hdr, false).unwrap(), 8193);
+    }
+
+    #[test]
+    fn craw_image() {
+        // One wavelet level where the flat low band is scaled by the quantizer and then
+        // spread over the full plane without any detail from the high bands
+        let hdr = CrxHeader::new(&cmp1(1)).unwrap();
+        assert_eq!(q_step(40), 64);
+        check_image(&decode_crx(&sample(1, 40), &hdr, false).unwrap(), 8192 + 64);
+    }
+
+    #[test]
+    fn truncated_sample() {
+        let hdr = CrxHeader::new(&cmp1(1)).unwrap();
+        let buf = sample(1, 40);
+        assert!(decode_crx(&buf[..100], &hdr, false).is_err());
+        assert!(decode_crx(&buf[..buf.len() - 100], &hdr, false).is_err());
+    }
+
+    #[test]
+    fn quantizer_steps() {
+        for (q, step) in [
+            (-5, 0),
+            (0, 0),
+            (35, 0x24),
+            (36, 0x28),
+            (37, 0x2d),
+            (42, 0x50),
+        ] {
+            assert_eq!(q_step(q), step, "step of {}", q);
+        }
+    }
+
+    #[test]
+    fn symbols() {
+        assert_eq!([0, 1, 2, 3, 4].map(sign), [0, -1, 1, -2, 2]);
+        // The median edge detector picks the extreme when the corner is outside the
+        // neighbours and the gradient otherwise
+        assert_eq!(med(10, 20, 5), 20);
+        assert_eq!(med(10, 20, 25), 10);
+        assert_eq!(med(10, 20, 15), 15);
+        assert_eq!(predict_k(2, 1, 15), 1);
+        assert_eq!(predict_k(2, 12, 15), 3);
+        assert_eq!(predict_k(2, 24, 15), 4);
+        assert_eq!(predict_k(2, 24, 3), 3);
+    }
+
+    #[test]
+    fn golomb_codes() {
+        // 0001 with k=0, 001 10 with k=2 and an escape of 41 zeros, a one and 21 raw bits
+        let mut data = vec![0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0xab, 0xcd];
+        data.extend_from_slice(&[0; 8]);
+        let mut bits = CrxBits::new(&data);
+        assert_eq!(bits.read_code(0).unwrap(), 3);
+        assert_eq!(bits.read_code(2).unwrap(), 0b1010);
+        assert_eq!(bits.read_code(2).unwrap(), 0x1abcd);
+    }
+
+    #[test]
+    fn inverse_wavelet() {
+        // Forward 5/3 transforms with mirrored edges of even and odd length signals
+        for (signal, low, high) in [
+            (
+                &[10, 12, 14, 20, 30, 31, 29, 10][..],
+                &[10, 14, 30, 25][..],
+                &[0, -2, 2, -19][..],
+            ),
+            (
+                &[10, 12, 14, 20, 30, 31, 29, 10, 5],
+                &[10, 14, 30, 28, 2],
+                &[0, -2, 2, -7],
+            ),
+            (&[-7, 3, 100, -50, 0, 1], &[-28, 64, -25], &[-43, -100, 1]),
+        ] {
+            let mut out = vec![0; signal.len()];
+            idwt_53(low, high, &mut out, false);
+            assert_eq!(out, signal);
+        }
+    }
+}
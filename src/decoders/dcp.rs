@@ -0,0 +1,577 @@
+use std::cmp;
+
+use crate::decoders::RawLoaderError;
+use crate::decoders::dngcolor::*;
+use crate::decoders::tiff::*;
+
+/// A table of hue shifts and saturation and value scales indexed by hue, saturation and
+/// (optionally) value, as used by the ProfileHueSatMap and ProfileLookTable tags
+#[derive(Debug, Clone, PartialEq)]
+pub struct HueSatMap {
+    /// number of hue divisions of the table
+    pub hue_divisions: usize,
+    /// number of saturation divisions of the table
+    pub sat_divisions: usize,
+    /// number of value divisions of the table, 1 for a 2.5D table
+    pub val_divisions: usize,
+    /// the entries as (hue shift in degrees, saturation scale, value scale) ordered by
+    /// value, then hue and then saturation
+    pub data: Vec<(f32, f32, f32)>,
+    /// the value axis is indexed with the sRGB gamma instead of linearly
+    pub srgb_gamma: bool,
+}
+
+/// A camera profile as found in DCP files or embedded in DNGs
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraProfile {
+    /// name of the profile
+    pub name: String,
+    /// the camera model the profile is for
+    pub unique_camera_model: String,
+    /// the profile copyright notice
+    pub copyright: String,
+    /// the signature that matches the profile to a CameraCalibrationSignature
+    pub calibration_signature: String,
+    /// usage restrictions of the profile, 0 allows copying
+    pub embed_policy: u32,
+    /// the color matrices of the profile, without any as shot white balance
+    pub color: DngColor,
+    /// the hue/saturation maps for each of the illuminants
+    pub hue_sat_maps: [Option<HueSatMap>; 2],
+    /// the look table applied after the hue/saturation map
+    pub look_table: Option<HueSatMap>,
+    /// the tone curve as (input, output) points between 0 and 1, empty if there's none
+    pub tone_curve: Vec<(f32, f32)>,
+    /// exposure offset to add to the BaselineExposure of the image
+    pub baseline_exposure_offset: f32,
+    /// how to render the blacks, 0 for automatic and 1 for none
+    pub default_black_render: u32,
+}
+
+fn rgb_to_hsv(r: f32, g: f32, b: f32) -> (f32, f32, f32) {
+    let v = r.max(g).max(b);
+    let gap = v - r.min(g).min(b);
+    if gap <= 0.0 {
+        return (0.0, 0.0, v);
+    }
+    let h = if r == v {
+        let h = (g - b) / gap;
+        if h < 0.0 { h + 6.0 } else { h }
+    } else if g == v {
+        2.0 + (b - r) / gap
+    } else {
+        4.0 + (r - g) / gap
+    };
+    (h, gap / v, v)
+}
+
+fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (f32, f32, f32) {
+    if s <= 0.0 {
+        return (v, v, v);
+    }
+    let h = h.rem_euclid(6.0);
+    let i = cmp::min(h as usize, 5);
+    let f = h - i as f32;
+    let p = v * (1.0 - s);
+    let q = v * (1.0 - s * f);
+    let t = v * (1.0 - s * (1.0 - f));
+    match i {
+        0 => (v, t, p),
+        1 => (q, v, p),
+        2 => (p, v, t),
+        3 => (p, q, v),
+        4 => (t, p, v),
+        _ => (v, p, q),
+    }
+}
+
+fn srgb_encode(x: f32) -> f32 {
+    let x = x.clamp(0.0, 1.0);
+    if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn srgb_decode(x: f32) -> f32 {
+    let x = x.clamp(0.0, 1.0);
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+impl HueSatMap {
+    fn new(
+        dims: &TiffEntry,
+        data: &TiffEntry,
+        encoding: Option<&TiffEntry>,
+    ) -> Result<HueSatMap, RawLoaderError> {
+        if dims.count() < 2 {
+            return Err(RawLoaderError::corrupt(
+                "DCP: invalid hue/sat map dimensions",
+            ));
+        }
+        let hue_divisions = dims.get_usize(0);
+        let sat_divisions = dims.get_usize(1);
+        let val_divisions = if dims.count() > 2 {
+            cmp::max(dims.get_usize(2), 1)
+        } else {
+            1
+        };
+        let entries = hue_divisions
+            .checked_mul(sat_divisions)
+            .and_then(|v| v.checked_mul(val_divisions))
+            .filter(|v| *v > 0 && v * 3 == data.count())
+            .ok_or_else(|| {
+                RawLoaderError::corrupt("DCP: hue/sat map doesn't match its dimensions")
+            })?;
+        Ok(HueSatMap {
+            hue_divisions,
+            sat_divisions,
+            val_divisions,
+            data: (0..entries)
+                .map(|i| {
+                    (
+                        data.get_f32(i * 3),
+                        data.get_f32(i * 3 + 1),
+                        data.get_f32(i * 3 + 2),
+                    )
+                })
+                .collect(),
+            srgb_gamma: encoding.is_some_and(|e| e.get_u32(0) == 1),
+        })
+    }
+
+    // Linear blend of two maps with the same dimensions, weight is for self
+    fn blend(&self, other: &HueSatMap, weight: f32) -> HueSatMap {
+        let mut out = self.clone();
+        for (a, b) in out.data.iter_mut().zip(other.data.iter()) {
+            a.0 = a.0 * weight + b.0 * (1.0 - weight);
+            a.1 = a.1 * weight + b.1 * (1.0 - weight);
+            a.2 = a.2 * weight + b.2 * (1.0 - weight);
+        }
+        out
+    }
+
+    fn entry(&self, val: usize, hue: usize, sat: usize) -> (f32, f32, f32) {
+        self.data[(val * self.hue_divisions + hue) * self.sat_divisions + sat]
+    }
+
+    /// Applies the map to interleaved linear RGB data, which in DNG terms should be in the
+    /// linear ProPhoto space
+    pub fn apply(&self, rgb: &mut [f32]) {
+        let hue_scale = if self.hue_divisions < 2 {
+            0.0
+        } else {
+            self.hue_divisions as f32 / 6.0
+        };
+        let sat_scale = (self.sat_divisions - 1) as f32;
+        let val_scale = (self.val_divisions - 1) as f32;
+        let max_hue = self.hue_divisions - 1;
+        let max_sat = self.sat_divisions.saturating_sub(2);
+        let max_val = self.val_divisions.saturating_sub(2);
+
+        for pixel in rgb.chunks_exact_mut(3) {
+            let (h, s, v) = rgb_to_hsv(pixel[0], pixel[1], pixel[2]);
+            let v_encoded = if self.srgb_gamma { srgb_encode(v) } else { v };
+
+            let h_scaled = h * hue_scale;
+            let s_scaled = s * sat_scale;
+            let mut h0 = h_scaled as usize;
+            let s0 = cmp::min(s_scaled as usize, max_sat);
+            let mut h1 = h0 + 1;
+            if h0 >= max_hue {
+                h0 = max_hue;
+                h1 = 0;
+            }
+            let hf1 = h_scaled - h0 as f32;
+            let sf1 = if self.sat_divisions < 2 {
+                0.0
+            } else {
+                s_scaled - s0 as f32
+            };
+            let s1 = cmp::min(s0 + 1, self.sat_divisions - 1);
+            let (hf0, sf0) = (1.0 - hf1, 1.0 - sf1);
+
+            // Interpolate along hue and saturation for a given value index
+            let plane = |val: usize| -> (f32, f32, f32) {
+                let e00 = self.entry(val, h0, s0);
+                let e01 = self.entry(val, h1, s0);
+                let e10 = self.entry(val, h0, s1);
+                let e11 = self.entry(val, h1, s1);
+                let lerp = |a: f32, b: f32, c: f32, d: f32| {
+                    sf0 * (hf0 * a + hf1 * b) + sf1 * (hf0 * c + hf1 * d)
+                };
+                (
+                    lerp(e00.0, e01.0, e10.0, e11.0),
+                    lerp(e00.1, e01.1, e10.1, e11.1),
+                    lerp(e00.2, e01.2, e10.2, e11.2),
+                )
+            };
+
+            let (hue_shift, sat_mul, val_mul) = if self.val_divisions < 2 {
+                plane(0)
+            } else {
+                let v_scaled = v_encoded * val_scale;
+                let v0 = cmp::min(v_scaled as usize, max_val);
+                let vf1 = v_scaled - v0 as f32;
+                let vf0 = 1.0 - vf1;
+                let a = plane(v0);
+                let b = plane(v0 + 1);
+                (
+                    vf0 * a.0 + vf1 * b.0,
+                    vf0 * a.1 + vf1 * b.1,
+                    vf0 * a.2 + vf1 * b.2,
+                )
+            };
+
+            let h = h + hue_shift * (6.0 / 360.0);
+            let s = (s * sat_mul).min(1.0);
+            let v_encoded = (v_encoded * val_mul).clamp(0.0, 1.0);
+            let v = if self.srgb_gamma {
+                srgb_decode(v_encoded)
+            } else {
+                v_encoded
+            };
+            let (r, g, b) = hsv_to_rgb(h, s, v);
+            pixel[0] = r;
+            pixel[1] = g;
+            pixel[2] = b;
+        }
+    }
+}
+
+// Natural cubic spline through the tone curve points
+struct Spline {
+    x: Vec<f32>,
+    y: Vec<f32>,
+    s: Vec<f32>,
+}
+
+impl Spline {
+    fn new(points: &[(f32, f32)]) -> Spline {
+        let n = points.len();
+        let x: Vec<f32> = points.iter().map(|p| p.0).collect();
+        let y: Vec<f32> = points.iter().map(|p| p.1).collect();
+        let mut s = vec![0.0f32; n];
+        if n > 2 {
+            // Solve the tridiagonal system for the second derivatives
+            let mut c = vec![0.0f32; n];
+            let mut d = vec![0.0f32; n];
+            for i in 1..n - 1 {
+                let h0 = x[i] - x[i - 1];
+                let h1 = x[i + 1] - x[i];
+                if h0 <= 0.0 || h1 <= 0.0 {
+                    continue;
+                }
+                let a = h0;
+                let b = 2.0 * (h0 + h1);
+                let r = 6.0 * ((y[i + 1] - y[i]) / h1 - (y[i] - y[i - 1]) / h0);
+                let m = b - a * c[i - 1];
+                c[i] = h1 / m;
+                d[i] = (r - a * d[i - 1]) / m;
+            }
+            for i in (1..n - 1).rev() {
+                s[i] = d[i] - c[i] * s[i + 1];
+            }
+        }
+        Spline { x, y, s }
+    }
+
+    fn eval(&self, val: f32) -> f32 {
+        let n = self.x.len();
+        if n == 0 {
+            return val;
+        }
+        if val <= self.x[0] {
+            return self.y[0];
+        }
+        if val >= self.x[n - 1] {
+            return self.y[n - 1];
+        }
+        let i = self.x.partition_point(|x| *x <= val).clamp(1, n - 1);
+        let h = self.x[i] - self.x[i - 1];
+        if h <= 0.0 {
+            return self.y[i];
+        }
+        let a = (self.x[i] - val) / h;
+        let b = (val - self.x[i - 1]) / h;
+        a * self.y[i - 1]
+            + b * self.y[i]
+            + ((a * a * a - a) * self.s[i - 1] + (b * b * b - b) * self.s[i]) * h * h / 6.0
+    }
+}
+
+impl CameraProfile {
+    /// Parses a DCP file
+    pub fn from_dcp(buffer: &[u8]) -> Result<CameraProfile, RawLoaderError> {
+        if buffer.len() < 8 || (&buffer[0..4] != b"IIRC" && &buffer[0..4] != b"MMCR") {
+            return Err(RawLoaderError::corrupt("DCP: not a camera profile"));
+        }
+        let tiff = TiffIFD::new_root(buffer, 0)?;
+        CameraProfile::from_tiff(&tiff)?
+            .ok_or_else(|| RawLoaderError::corrupt("DCP: profile has no color matrix"))
+    }
+
+    // Reads the profile tags from a DCP or the main IFD of a DNG, returning None when
+    // there's no color matrix
+    pub(crate) fn from_tiff(tiff: &TiffIFD) -> Result<Option<CameraProfile>, RawLoaderError> {
+        let channels = match tiff.find_entry(Tag::ColorMatrix1) {
+            Some(matrix) if matrix.count() >= 3 && matrix.count() <= 12 => matrix.count() / 3,
+            _ => return Ok(None),
+        };
+        let values = |tag: Tag| match tiff.find_entry(tag) {
+            Some(entry) => (0..entry.count()).map(|i| entry.get_f32(i)).collect(),
+            None => Vec::new(),
+        };
+        let string = |tag: Tag| match tiff.find_entry(tag) {
+            Some(entry) => {
+                let data = entry.get_data();
+                let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+                String::from_utf8_lossy(&data[..len]).trim().to_string()
+            }
+            None => String::new(),
+        };
+        let illuminant = |tag: Tag| tiff.find_entry(tag).map_or(0, |e| e.get_u16(0));
+
+        let hue_sat_map = |tag: Tag| match (
+            tiff.find_entry(Tag::ProfileHueSatMapDims),
+            tiff.find_entry(tag),
+        ) {
+            (Some(dims), Some(data)) => {
+                HueSatMap::new(dims, data, tiff.find_entry(Tag::ProfileHueSatMapEncoding)).map(Some)
+            }
+            _ => Ok(None),
+        };
+        let look_table = match (
+            tiff.find_entry(Tag::ProfileLookTableDims),
+            tiff.find_entry(Tag::ProfileLookTableData),
+        ) {
+            (Some(dims), Some(data)) => Some(HueSatMap::new(
+                dims,
+                data,
+                tiff.find_entry(Tag::ProfileLookTableEncoding),
+            )?),
+            _ => None,
+        };
+        let curve: Vec<f32> = values(Tag::ProfileToneCurve);
+
+        Ok(Some(CameraProfile {
+            name: string(Tag::ProfileName),
+            unique_camera_model: string(Tag::UniqueCameraModel),
+            copyright: string(Tag::ProfileCopyright),
+            calibration_signature: string(Tag::ProfileCalibrationSignature),
+            embed_policy: tiff
+                .find_entry(Tag::ProfileEmbedPolicy)
+                .map_or(0, |e| e.get_u32(0)),
+            color: DngColor {
+                channels,
+                illuminants: [
+                    illuminant(Tag::CalibrationIlluminant1),
+                    illuminant(Tag::CalibrationIlluminant2),
+                ],
+                color_matrices: [values(Tag::ColorMatrix1), values(Tag::ColorMatrix2)],
+                forward_matrices: [values(Tag::ForwardMatrix1), values(Tag::ForwardMatrix2)],
+                camera_calibrations: [Vec::new(), Vec::new()],
+                analog_balance: Vec::new(),
+                as_shot_neutral: Vec::new(),
+                as_shot_white_xy: None,
+            },
+            hue_sat_maps: [
+                hue_sat_map(Tag::ProfileHueSatMapData1)?,
+                hue_sat_map(Tag::ProfileHueSatMapData2)?,
+            ],
+            look_table,
+            tone_curve: curve.chunks_exact(2).map(|p| (p[0], p[1])).collect(),
+            baseline_exposure_offset: tiff
+                .find_entry(Tag::BaselineExposureOffset)
+                .map_or(0.0, |e| e.get_f32(0)),
+            default_black_render: tiff
+                .find_entry(Tag::DefaultBlackRender)
+                .map_or(0, |e| e.get_u32(0)),
+        }))
+    }
+
+    /// The hue/saturation map for a given white chromaticity, interpolated between the
+    /// two illuminants the same way as the color matrices
+    pub fn hue_sat_map(&self, white: (f64, f64)) -> Option<HueSatMap> {
+        match &self.hue_sat_maps {
+            [Some(map1), Some(map2)]
+                if map1.data.len() == map2.data.len()
+                    && map1.hue_divisions == map2.hue_divisions
+                    && map1.sat_divisions == map2.sat_divisions =>
+            {
+                let weight = self.color.weight(white) as f32;
+                if weight >= 1.0 {
+                    Some(map1.clone())
+                } else if weight <= 0.0 {
+                    Some(map2.clone())
+                } else {
+                    Some(map1.blend(map2, weight))
+                }
+            }
+            [map1, map2] => map1.clone().or(map2.clone()),
+        }
+    }
+
+    /// Applies the hue/saturation map for a given white chromaticity to interleaved
+    /// linear ProPhoto RGB data
+    pub fn apply_hue_sat_map(&self, rgb: &mut [f32], white: (f64, f64)) {
+        if let Some(map) = self.hue_sat_map(white) {
+            map.apply(rgb);
+        }
+    }
+
+    /// Applies the look table to interleaved linear ProPhoto RGB data
+    pub fn apply_look_table(&self, rgb: &mut [f32]) {
+        if let Some(table) = &self.look_table {
+            table.apply(rgb);
+        }
+    }
+
+    /// Applies the tone curve to interleaved linear ProPhoto RGB data, preserving the hue
+    /// of each pixel the way Adobe does
+    pub fn apply_tone_curve(&self, rgb: &mut [f32]) {
+        if self.tone_curve.len() < 2 {
+            return;
+        }
+        let spline = Spline::new(&self.tone_curve);
+        let curve = |v: f32| spline.eval(v.clamp(0.0, 1.0));
+        // The middle channel is interpolated between the curved min and max ones
+        let mid = |lo: f32, hi: f32, lo1: f32, hi1: f32, v: f32| {
+            if hi > lo {
+                lo1 + (hi1 - lo1) * (v - lo) / (hi - lo)
+            } else {
+                lo1
+            }
+        };
+        for pixel in rgb.chunks_exact_mut(3) {
+            let (r, g, b) = (
+                pixel[0].clamp(0.0, 1.0),
+                pixel[1].clamp(0.0, 1.0),
+                pixel[2].clamp(0.0, 1.0),
+            );
+            let (r1, g1, b1) = if r >= g {
+                if g > b {
+                    let (r1, b1) = (curve(r), curve(b));
+                    (r1, mid(b, r, b1, r1, g), b1)
+                } else if b > r {
+                    let (b1, g1) = (curve(b), curve(g));
+                    (mid(g, b, g1, b1, r), g1, b1)
+                } else if b > g {
+                    let (r1, g1) = (curve(r), curve(g));
+                    (r1, g1, mid(g, r, g1, r1, b))
+                } else {
+                    let (r1, g1) = (curve(r), curve(g));
+                    (r1, g1, g1)
+                }
+            } else if r >= b {
+                let (g1, b1) = (curve(g), curve(b));
+                (mid(b, g, b1, g1, r), g1, b1)
+            } else if b > g {
+                let (b1, r1) = (curve(b), curve(r));
+                (r1, mid(r, b, r1, b1, g), b1)
+            } else {
+                let (g1, r1) = (curve(g), curve(r));
+                (r1, g1, mid(r, g, r1, g1, b))
+            };
+            pixel[0] = r1;
+            pixel[1] = g1;
+            pixel[2] = b1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A map with the same entry everywhere except where `entry` returns another one
+    fn map(
+        divisions: (usize, usize, usize),
+        entry: impl Fn(usize, usize, usize) -> (f32, f32, f32),
+    ) -> HueSatMap {
+        let (hues, sats, vals) = divisions;
+        HueSatMap {
+            hue_divisions: hues,
+            sat_divisions: sats,
+            val_divisions: vals,
+            data: (0..vals)
+                .flat_map(|v| (0..hues).flat_map(move |h| (0..sats).map(move |s| (v, h, s))))
+                .map(|(v, h, s)| entry(v, h, s))
+                .collect(),
+            srgb_gamma: false,
+        }
+    }
+
+    fn assert_rgb(rgb: &[f32], expected: &[f32]) {
+        for (a, b) in rgb.iter().zip(expected) {
+            assert!((a - b).abs() < 1e-5, "{:?} instead of {:?}", rgb, expected);
+        }
+    }
+
+    #[test]
+    fn identity_map() {
+        let mut rgb = [0.2, 0.5, 0.9, 0.7, 0.1, 0.3, 0.4, 0.4, 0.4];
+        map((6, 3, 1), |_, _, _| (0.0, 1.0, 1.0)).apply(&mut rgb);
+        assert_rgb(&rgb, &[0.2, 0.5, 0.9, 0.7, 0.1, 0.3, 0.4, 0.4, 0.4]);
+    }
+
+    #[test]
+    fn hue_shift() {
+        // A third of the way around the hue circle takes red to green and green to blue
+        let mut rgb = [1.0, 0.0, 0.0, 0.0, 0.5, 0.0];
+        map((6, 2, 1), |_, _, _| (120.0, 1.0, 1.0)).apply(&mut rgb);
+        assert_rgb(&rgb, &[0.0, 1.0, 0.0, 0.0, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn saturation_interpolation() {
+        // Half saturated is halfway between the unchanged grey and halving at full
+        let mut rgb = [1.0, 0.5, 0.5];
+        map((6, 2, 1), |_, _, s| (0.0, 1.0 - 0.5 * s as f32, 1.0)).apply(&mut rgb);
+        assert_rgb(&rgb, &[1.0, 0.625, 0.625]);
+    }
+
+    #[test]
+    fn hue_interpolation_wraps_around() {
+        // Pink is halfway between the last hue division and the first one, so gets half
+        // of the shift of the first and ends up red
+        let mut rgb = [1.0, 0.0, 0.5];
+        map((6, 2, 1), |_, h, _| {
+            (if h == 0 { 60.0 } else { 0.0 }, 1.0, 1.0)
+        })
+        .apply(&mut rgb);
+        assert_rgb(&rgb, &[1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn value_interpolation() {
+        let mut grey = [0.5, 0.5, 0.5];
+        let linear = map((1, 2, 2), |v, _, _| (0.0, 1.0, 1.0 - 0.5 * v as f32));
+        linear.apply(&mut grey);
+        assert_rgb(&grey, &[0.375, 0.375, 0.375]);
+
+        // With the sRGB gamma the value axis is indexed and scaled encoded
+        let mut grey = [0.5, 0.5, 0.5];
+        let encoded = srgb_encode(0.5);
+        let expected = srgb_decode(encoded * (1.0 - 0.5 * encoded));
+        HueSatMap {
+            srgb_gamma: true,
+            ..linear
+        }
+        .apply(&mut grey);
+        assert_rgb(&grey, &[expected, expected, expected]);
+    }
+
+    #[test]
+    fn blend_maps() {
+        let a = map((2, 2, 1), |_, _, _| (10.0, 2.0, 1.0));
+        let b = map((2, 2, 1), |_, _, _| (-10.0, 1.0, 0.5));
+        let blended = a.blend(&b, 0.25);
+        assert!(blended.data.iter().all(|e| *e == (-5.0, 1.25, 0.625)));
+    }
+}
@@ -22,13 +22,13 @@ impl<'a> DcrDecoder<'a> {
 }
 
 impl<'a> Decoder for DcrDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
         let raw = fetch_ifd!(&self.tiff, Tag::CFAPattern);
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
         let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
         let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
 
         let linearization = fetch_tag!(self.tiff, Tag::DcrLinearization);
         let curve = {
@@ -39,14 +39,22 @@ impl<'a> Decoder for DcrDecoder<'a> {
             LookupTable::new(&points)
         };
 
-        let image = DcrDecoder::decode_kodak65000(src, &curve, width, height, dummy);
+        let image = DcrDecoder::decode_kodak65000(src, &curve, width, height, dummy)?;
 
         ok_image(camera, width, height, self.get_wb()?, image)
     }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
+    }
 }
 
 impl<'a> DcrDecoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         let dcrwb = fetch_tag!(self.tiff, Tag::DcrWB);
         if dcrwb.count() >= 46 {
             let levels = dcrwb.get_data();
@@ -67,7 +75,7 @@ impl<'a> DcrDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Vec<u16> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let mut out: Vec<u16> = alloc_image!(width, height, dummy);
         let mut input = ByteStream::new(buf, LITTLE_ENDIAN);
 
@@ -79,14 +87,14 @@ impl<'a> DcrDecoder<'a> {
                 for (i, val) in buf.iter().enumerate() {
                     pred[i & 1] += *val;
                     if pred[i & 1] < 0 {
-                        panic!("Found a negative pixel!");
+                        return Err(RawLoaderError::corrupt("DCR: Found a negative pixel"));
                     }
                     out[row * width + col + i] = curve.dither(pred[i & 1] as u16, &mut random);
                 }
             }
         }
 
-        out
+        Ok(out)
     }
 
     fn decode_segment(input: &mut ByteStream, size: usize) -> Vec<i32> {
@@ -20,17 +20,20 @@ impl<'a> DcsDecoder<'a> {
 }
 
 impl<'a> Decoder for DcsDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
         let data = self.tiff.find_ifds_with_tag(Tag::StripOffsets);
         let raw = data
             .iter()
-            .find(|&&ifd| ifd.find_entry(Tag::ImageWidth).unwrap().get_u32(0) > 1000)
-            .unwrap();
+            .find(|&&ifd| {
+                ifd.find_entry(Tag::ImageWidth)
+                    .is_some_and(|e| e.get_u32(0) > 1000)
+            })
+            .ok_or_else(|| RawLoaderError::corrupt("DCS: Couldn't find the raw image"))?;
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
         let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
         let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
         let linearization = fetch_tag!(self.tiff, Tag::GrayResponse);
 
         let table = {
@@ -43,7 +46,17 @@ impl<'a> Decoder for DcsDecoder<'a> {
             LookupTable::new(&t)
         };
 
-        let image = decode_8bit_wtable(src, &table, width, height, dummy);
+        let window = Window::current();
+        let image = decode_8bit_wtable(src, &table, width, height, window.as_ref(), dummy)?;
         ok_image(camera, width, height, [f32::NAN; 4], image)
+            .map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
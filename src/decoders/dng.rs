@@ -2,9 +2,14 @@ use std::cmp;
 
 use crate::decoders::basics::*;
 use crate::decoders::cfa::*;
+use crate::decoders::dcp::*;
+use crate::decoders::dngcolor::*;
+use crate::decoders::inflate::*;
 use crate::decoders::ljpeg::*;
+use crate::decoders::opcodes::*;
 use crate::decoders::tiff::*;
 use crate::decoders::*;
+use rayon::prelude::*;
 
 #[derive(Debug, Clone)]
 pub struct DngDecoder<'a> {
@@ -14,6 +19,26 @@ pub struct DngDecoder<'a> {
 }
 
 impl<'a> DngDecoder<'a> {
+    // Every full resolution image in the file, which besides the main raw can be the
+    // enhanced one or other exposures. Previews, transparency masks and depth maps are
+    // left out
+    fn raw_ifds(&self) -> Vec<&TiffIFD<'_>> {
+        self.tiff
+            .ifds()
+            .into_iter()
+            .filter(|ifd| {
+                let Some(compression) = ifd.find_own_entry(Tag::Compression) else {
+                    return false;
+                };
+                let subfile = ifd
+                    .find_own_entry(Tag::NewSubFileType)
+                    .map_or(0, |e| e.get_u32(0));
+                subfile & 0b1101 == 0
+                    && matches!(compression.get_u32(0), 1 | 7 | 8 | 32946 | 0x884c)
+            })
+            .collect()
+    }
+
     pub fn new(buf: &'a [u8], tiff: TiffIFD<'a>, rawloader: &'a RawLoader) -> DngDecoder<'a> {
         DngDecoder {
             buffer: buf,
@@ -24,36 +49,116 @@ impl<'a> DngDecoder<'a> {
 }
 
 impl<'a> Decoder for DngDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
-        let ifds = self
-            .tiff
-            .find_ifds_with_tag(Tag::Compression)
-            .into_iter()
-            .filter(|ifd| {
-                let compression = (**ifd).find_entry(Tag::Compression).unwrap().get_u32(0);
-                let subsampled = match (**ifd).find_entry(Tag::NewSubFileType) {
-                    Some(e) => e.get_u32(0) & 1 != 0,
-                    None => false,
-                };
-                !subsampled && (compression == 7 || compression == 1 || compression == 0x884c)
-            })
-            .collect::<Vec<&TiffIFD>>();
-        let raw = ifds[0];
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
+        self.frame(0, dummy)
+    }
+
+    fn frame_count(&self) -> usize {
+        self.raw_ifds().len().max(1)
+    }
+
+    fn frame(&self, index: usize, dummy: bool) -> Result<RawImage, RawLoaderError> {
+        let ifds = self.raw_ifds();
+        if ifds.is_empty() {
+            return Err(RawLoaderError::corrupt("DNG: Couldn't find the raw image"));
+        }
+        let raw = *ifds.get(index).ok_or_else(|| no_frame(index, ifds.len()))?;
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
         let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
         let cpp = fetch_tag!(raw, Tag::SamplesPerPixel).get_usize(0);
+        if !(1..=4).contains(&cpp) {
+            return Err(RawLoaderError::corrupt(format!(
+                "DNG: {} samples per pixel",
+                cpp
+            )));
+        }
+        if width == 0 || height == 0 {
+            return Err(RawLoaderError::corrupt(format!(
+                "DNG: image of {}x{}",
+                width, height
+            )));
+        }
+        check_image_size(width * cpp, height)?;
         let linear = fetch_tag!(raw, Tag::PhotometricInt).get_usize(0) == 34892;
+        let float = raw
+            .find_entry(Tag::SampleFormat)
+            .is_some_and(|e| e.get_u32(0) == 3);
+
+        let opcodes = [
+            self.get_opcodes(raw, Tag::OpcodeList1)?,
+            self.get_opcodes(raw, Tag::OpcodeList2)?,
+            self.get_opcodes(raw, Tag::OpcodeList3)?,
+        ];
+        // OpcodeList1 works on the stored values so when there's one the table is kept
+        // with the image for apply_opcodes() to use after the list
+        let table = self.get_linearization(raw);
+        let stored_opcodes = !float && table.is_some() && !opcodes[0].is_empty();
+        let decode_table = if stored_opcodes {
+            None
+        } else {
+            table.as_deref()
+        };
 
-        let image = match fetch_tag!(raw, Tag::Compression).get_u32(0) {
-            1 => self.decode_uncompressed(raw, width * cpp, height, dummy)?,
-            7 => self.decode_compressed(raw, width * cpp, height, cpp, dummy)?,
+        let window = Window::current();
+        let window = window.as_ref();
+        let compression = fetch_tag!(raw, Tag::Compression).get_u32(0);
+        let image = match compression {
+            1 if float => self.decode_deflate(raw, width, height, cpp, false, window, dummy)?,
+            1 => RawImageData::Integer(self.decode_uncompressed(
+                raw,
+                width,
+                height,
+                cpp,
+                decode_table,
+                window,
+                dummy,
+            )?),
+            7 => RawImageData::Integer(
+                self.decode_compressed(raw, width, height, cpp, window, dummy)?,
+            ),
+            8 | 32946 => self.decode_deflate(raw, width, height, cpp, true, window, dummy)?,
+            34892 => RawImageData::Integer(self.decode_lossy(
+                raw,
+                width,
+                height,
+                cpp,
+                decode_table,
+                window,
+                dummy,
+            )?),
             c => {
-                return Err(
-                    format!("Don't know how to read DNGs with compression {}", c).to_string(),
-                );
+                return Err(RawLoaderError::compression("DNG", c));
             }
         };
 
+        // 8 bit and lossy images already went through the table while decoding so they
+        // could be dithered
+        let bps = raw
+            .find_entry(Tag::BitsPerSample)
+            .map_or(16, |e| e.get_usize(0));
+        let (table, linearization) = if stored_opcodes {
+            (None, table)
+        } else {
+            let table =
+                table.filter(|_| !dummy && compression != 34892 && !(compression == 1 && bps == 8));
+            (table, None)
+        };
+
+        let cfa = if linear {
+            CFA::new("")
+        } else {
+            self.get_cfa(raw)?
+        };
+        let crops = self.get_crops(raw, width, height)?;
+        let black = self.get_black(raw, cpp, &crops)?;
+        let averages = black.averages(&cfa);
+        let blacklevels = [
+            averages[0].round().clamp(0.0, 65535.0) as u16,
+            averages[1].round().clamp(0.0, 65535.0) as u16,
+            averages[2].round().clamp(0.0, 65535.0) as u16,
+            averages[3].round().clamp(0.0, 65535.0) as u16,
+        ];
+
         let (make, model, clean_make, clean_model, orientation) = {
             match self.rawloader.check_supported(&self.tiff) {
                 Ok(cam) => (
@@ -78,7 +183,7 @@ impl<'a> Decoder for DngDecoder<'a> {
             }
         };
 
-        Ok(RawImage {
+        let mut img = RawImage {
             make,
             model,
             clean_make,
@@ -87,24 +192,41 @@ impl<'a> Decoder for DngDecoder<'a> {
             height,
             cpp,
             wb_coeffs: self.get_wb()?,
-            data: RawImageData::Integer(image),
-            blacklevels: self.get_blacklevels(raw)?,
-            whitelevels: self.get_whitelevels(raw)?,
+            data: image,
+            blacklevels,
+            black: Some(black),
+            whitelevels: self.get_whitelevels(raw, float)?,
             xyz_to_cam: self.get_color_matrix()?,
-            cfa: if linear {
-                CFA::new("")
-            } else {
-                self.get_cfa(raw)?
-            },
-            crops: self.get_crops(raw, width, height)?,
+            dng_color: self.get_dng_color()?,
+            profiles: self.get_profiles(),
+            cfa,
+            crops,
             blackareas: self.get_masked_areas(raw),
             orientation,
-        })
+            exif: Exif::default(),
+            opcodes,
+            linearization,
+        };
+
+        if let (Some(table), RawImageData::Integer(data)) = (table, &mut img.data) {
+            data.par_iter_mut().for_each(|val| {
+                *val = table[cmp::min(*val as usize, table.len() - 1)];
+            });
+        }
+        Ok(img.windowed(window))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
 
 impl<'a> DngDecoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         if let Some(levels) = self.tiff.find_entry(Tag::AsShotNeutral) {
             Ok([
                 1.0 / levels.get_f32(0),
@@ -117,40 +239,96 @@ impl<'a> DngDecoder<'a> {
         }
     }
 
-    fn get_blacklevels(&self, raw: &TiffIFD) -> Result<[u16; 4], String> {
-        if let Some(levels) = raw.find_entry(Tag::BlackLevels) {
-            if levels.count() < 4 {
-                let black = levels.get_f32(0) as u16;
-                Ok([black, black, black, black])
-            } else {
-                Ok([
-                    levels.get_f32(0) as u16,
-                    levels.get_f32(1) as u16,
-                    levels.get_f32(2) as u16,
-                    levels.get_f32(3) as u16,
-                ])
-            }
-        } else {
-            Ok([0, 0, 0, 0])
+    // The black level repeats in a pattern starting at the top left of the active area and
+    // can also vary per row and per column of the active area
+    fn get_black(
+        &self,
+        raw: &TiffIFD,
+        cpp: usize,
+        crops: &[usize; 4],
+    ) -> Result<BlackLevel, RawLoaderError> {
+        let repeat = match raw.find_entry(Tag::BlackLevelRepeatDim) {
+            Some(dim) if dim.count() >= 2 => (dim.get_usize(0), dim.get_usize(1)),
+            _ => (1, 1),
+        };
+        if repeat.0 == 0 || repeat.1 == 0 || repeat.0 * repeat.1 > 256 {
+            return Err(RawLoaderError::corrupt(format!(
+                "DNG: invalid black level repeat {:?}",
+                repeat
+            )));
+        }
+        let levels = match raw.find_entry(Tag::BlackLevels) {
+            // A single value applies to the whole pattern
+            Some(levels) if levels.count() > 0 => (0..repeat.0 * repeat.1 * cpp)
+                .map(|i| levels.get_f32(cmp::min(i, levels.count() - 1)))
+                .collect(),
+            _ => vec![0.0; repeat.0 * repeat.1 * cpp],
+        };
+        let deltas = |tag: Tag| match raw.find_entry(tag) {
+            Some(deltas) => (0..deltas.count()).map(|i| deltas.get_f32(i)).collect(),
+            None => Vec::new(),
+        };
+        Ok(BlackLevel {
+            origin: (crops[0], crops[3]),
+            repeat,
+            cpp,
+            levels,
+            delta_h: deltas(Tag::BlackLevelDeltaH),
+            delta_v: deltas(Tag::BlackLevelDeltaV),
+        })
+    }
+
+    // The linearization table maps the stored values back to the sensor values, values
+    // past the end of the table map to its last entry
+    fn get_linearization(&self, raw: &TiffIFD) -> Option<Vec<u16>> {
+        let table = raw
+            .find_entry(Tag::Linearization)
+            .or_else(|| self.tiff.find_entry(Tag::Linearization))?;
+        if table.count() == 0 {
+            return None;
         }
+        Some(
+            (0..cmp::min(table.count(), 65536))
+                .map(|i| table.get_u32(i) as u16)
+                .collect(),
+        )
     }
 
-    fn get_whitelevels(&self, raw: &TiffIFD) -> Result<[u16; 4], String> {
-        let level = fetch_tag!(raw, Tag::WhiteLevel).get_u32(0) as u16;
+    fn get_whitelevels(&self, raw: &TiffIFD, float: bool) -> Result<[u16; 4], RawLoaderError> {
+        let level = if float {
+            // Float images are usually normalized so default to a white of 1
+            raw.find_entry(Tag::WhiteLevel)
+                .map_or(1.0, |e| e.get_f32(0))
+                .round() as u16
+        } else {
+            fetch_tag!(raw, Tag::WhiteLevel).get_u32(0) as u16
+        };
         Ok([level, level, level, level])
     }
 
-    fn get_cfa(&self, raw: &TiffIFD) -> Result<CFA, String> {
+    fn get_cfa(&self, raw: &TiffIFD) -> Result<CFA, RawLoaderError> {
         let pattern = fetch_tag!(raw, Tag::CFAPattern);
-        Ok(CFA::new_from_tag(pattern))
+        CFA::new_from_tag(pattern)
     }
 
-    fn get_crops(&self, raw: &TiffIFD, width: usize, height: usize) -> Result<[usize; 4], String> {
+    fn get_opcodes(&self, raw: &TiffIFD, tag: Tag) -> Result<Vec<DngOpcode>, RawLoaderError> {
+        match raw.find_entry(tag) {
+            Some(entry) => DngOpcode::parse_list(entry.get_data()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn get_crops(
+        &self,
+        raw: &TiffIFD,
+        width: usize,
+        height: usize,
+    ) -> Result<[usize; 4], RawLoaderError> {
         if let Some(crops) = raw.find_entry(Tag::ActiveArea) {
             Ok([
                 crops.get_usize(0),
-                width - crops.get_usize(3),
-                height - crops.get_usize(2),
+                width.saturating_sub(crops.get_usize(3)),
+                height.saturating_sub(crops.get_usize(2)),
                 crops.get_usize(1),
             ])
         } else {
@@ -176,7 +354,7 @@ impl<'a> DngDecoder<'a> {
         areas
     }
 
-    fn get_color_matrix(&self) -> Result<[[f32; 3]; 4], String> {
+    fn get_color_matrix(&self) -> Result<[[f32; 3]; 4], RawLoaderError> {
         let mut matrix: [[f32; 3]; 4] = [[0.0; 3]; 4];
         let cmatrix = {
             if let Some(c) = self.tiff.find_entry(Tag::ColorMatrix2) {
@@ -194,7 +372,10 @@ impl<'a> DngDecoder<'a> {
             }
         };
         if cmatrix.count() > 12 {
-            Err(format!("color matrix supposedly has {} components", cmatrix.count()).to_string())
+            Err(RawLoaderError::corrupt(format!(
+                "color matrix supposedly has {} components",
+                cmatrix.count()
+            )))
         } else {
             for i in 0..cmatrix.count() {
                 matrix[i / 3][i % 3] = cmatrix.get_f32(i);
@@ -203,36 +384,131 @@ impl<'a> DngDecoder<'a> {
         }
     }
 
+    fn get_dng_color(&self) -> Result<Option<DngColor>, RawLoaderError> {
+        let channels = match self.tiff.find_entry(Tag::ColorMatrix1) {
+            Some(matrix) if matrix.count() >= 3 && matrix.count() <= 12 => matrix.count() / 3,
+            _ => return Ok(None),
+        };
+        let values = |tag: Tag| match self.tiff.find_entry(tag) {
+            Some(entry) => (0..entry.count()).map(|i| entry.get_f32(i)).collect(),
+            None => Vec::new(),
+        };
+        let illuminant = |tag: Tag| self.tiff.find_entry(tag).map_or(0, |e| e.get_u16(0));
+        Ok(Some(DngColor {
+            channels,
+            illuminants: [
+                illuminant(Tag::CalibrationIlluminant1),
+                illuminant(Tag::CalibrationIlluminant2),
+            ],
+            color_matrices: [values(Tag::ColorMatrix1), values(Tag::ColorMatrix2)],
+            forward_matrices: [values(Tag::ForwardMatrix1), values(Tag::ForwardMatrix2)],
+            camera_calibrations: [
+                values(Tag::CameraCalibration1),
+                values(Tag::CameraCalibration2),
+            ],
+            analog_balance: values(Tag::AnalogBalance),
+            as_shot_neutral: values(Tag::AsShotNeutral),
+            as_shot_white_xy: self
+                .tiff
+                .find_entry(Tag::AsShotWhiteXY)
+                .filter(|e| e.count() >= 2)
+                .map(|e| (e.get_f32(0), e.get_f32(1))),
+        }))
+    }
+
+    // A broken profile shouldn't prevent decoding the image so those are just skipped
+    fn get_profiles(&self) -> Vec<CameraProfile> {
+        let mut profiles: Vec<CameraProfile> = CameraProfile::from_tiff(&self.tiff)
+            .ok()
+            .flatten()
+            .into_iter()
+            .collect();
+        if let Some(extra) = self.tiff.find_entry(Tag::ExtraCameraProfiles) {
+            for i in 0..extra.count() {
+                let offset = extra.get_usize(i);
+                if offset + 8 > self.buffer.len() {
+                    continue;
+                }
+                if let Ok(tiff) = TiffIFD::new_root(self.buffer, offset)
+                    && let Ok(Some(profile)) = CameraProfile::from_tiff(&tiff)
+                {
+                    profiles.push(profile);
+                }
+            }
+        }
+        profiles
+    }
+
     pub fn decode_uncompressed(
         &self,
         raw: &TiffIFD,
         width: usize,
         height: usize,
+        cpp: usize,
+        table: Option<&[u16]>,
+        window: Option<&Window>,
         dummy: bool,
-    ) -> Result<Vec<u16>, String> {
-        let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
-        let src = &self.buffer[offset..];
-
-        match fetch_tag!(raw, Tag::BitsPerSample).get_u32(0) {
-            16 => Ok(decode_16le(src, width, height, dummy)),
-            12 => Ok(decode_12be(src, width, height, dummy)),
-            10 => Ok(decode_10le(src, width, height, dummy)),
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        let tiles = DngTiles::new(raw, width, height)?;
+        let bps = fetch_tag!(raw, Tag::BitsPerSample).get_usize(0);
+        let curve = match bps {
+            16 | 12 | 10 => None,
             8 => {
-                // It's 8 bit so there will be linearization involved surely!
-                let linearization = fetch_tag!(self.tiff, Tag::Linearization);
-                let curve = {
-                    let mut points = vec![0_u16; 256];
-
-                    for (i, point) in points.iter_mut().enumerate().take(256) {
-                        *point = linearization.get_u32(i) as u16;
-                    }
+                // 8 bit images are always linearized, default to the identity
+                let table = table.unwrap_or_default();
+                let points = (0..256)
+                    .map(|i| table.get(i).or(table.last()).copied().unwrap_or(i as u16))
+                    .collect::<Vec<u16>>();
+                Some(LookupTable::new(&points))
+            }
+            bps => return Err(RawLoaderError::compression("DNG", bps as u32)),
+        };
 
-                    LookupTable::new(&points)
-                };
-                Ok(decode_8bit_wtable(src, &curve, width, height, dummy))
+        let decode_tile = |i: usize, width: usize, height: usize, window, dummy| {
+            let src = tiles.data(self.buffer, i)?;
+            let size = width * height * bps / 8;
+            if src.len() < size || tiles.counts.get(i).is_some_and(|c| *c < size) {
+                return Err(RawLoaderError::truncated("DNG: image data is too short"));
             }
-            bps => Err(format!("DNG: Don't know about {} bps images", bps).to_string()),
+            Ok(match curve {
+                Some(ref curve) => decode_8bit_wtable(src, curve, width, height, window, dummy)?,
+                None => match bps {
+                    16 => decode_16le(src, width, height, window, dummy)?,
+                    12 => decode_12be(src, width, height, window, dummy)?,
+                    _ => decode_10le(src, width, height, window, dummy)?,
+                },
+            })
+        };
+
+        if tiles.len() == 1 {
+            return decode_tile(0, width * cpp, height, window, dummy);
         }
+        if dummy {
+            return Ok(alloc_image_plain!(width * cpp, height, dummy));
+        }
+        let decoded = (0..tiles.len())
+            .into_par_iter()
+            .map(|i| {
+                if tiles.skip(window, i) {
+                    return Ok(Vec::new());
+                }
+                decode_tile(
+                    i,
+                    tiles.width * cpp,
+                    tiles.size(i, width, height).1,
+                    None,
+                    false,
+                )
+            })
+            .collect::<Result<Vec<Vec<u16>>, RawLoaderError>>()?;
+        Ok(assemble_tiles(
+            &decoded,
+            width * cpp,
+            height,
+            tiles.width * cpp,
+            tiles.length,
+            window,
+        ))
     }
 
     pub fn decode_compressed(
@@ -241,56 +517,773 @@ impl<'a> DngDecoder<'a> {
         width: usize,
         height: usize,
         cpp: usize,
+        window: Option<&Window>,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        let tiles = DngTiles::new(raw, width, height)?;
+        if tiles.len() == 1 {
+            let mut out = alloc_image!(width * cpp, height, dummy);
+            let decompressor = LjpegDecompressor::new(tiles.data(self.buffer, 0)?)?;
+            decompressor.decode(&mut out, 0, width * cpp, width * cpp, height, dummy)?;
+            return crop_window(out, width * cpp, window);
+        }
+        if dummy {
+            return Ok(alloc_image_plain!(width * cpp, height, dummy));
+        }
+
+        let twidth = tiles.width * cpp;
+        let decoded = (0..tiles.len())
+            .into_par_iter()
+            .map(|i| {
+                if tiles.skip(window, i) {
+                    return Ok(Vec::new());
+                }
+                let (bwidth, blength) = tiles.size(i, width, height);
+                let mut out = vec![0u16; twidth * blength];
+                let decompressor = LjpegDecompressor::new(tiles.data(self.buffer, i)?)?;
+                decompressor.decode(&mut out, 0, twidth, bwidth * cpp, blength, false)?;
+                Ok(out)
+            })
+            .collect::<Result<Vec<Vec<u16>>, RawLoaderError>>()?;
+        Ok(assemble_tiles(
+            &decoded,
+            width * cpp,
+            height,
+            twidth,
+            tiles.length,
+            window,
+        ))
+    }
+
+    // Lossy DNGs store every tile (or strip) as a separate baseline 8 bit JPEG that
+    // usually needs the linearization table to get back to the sensor values
+    pub fn decode_lossy(
+        &self,
+        raw: &TiffIFD,
+        width: usize,
+        height: usize,
+        cpp: usize,
+        table: Option<&[u16]>,
+        window: Option<&Window>,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        let tiles = DngTiles::new(raw, width, height)?;
+        if dummy {
+            return Ok(alloc_image_plain!(width * cpp, height, dummy));
+        }
+
+        let decoded = (0..tiles.len())
+            .into_par_iter()
+            .map(|i| {
+                if tiles.skip(window, i) {
+                    return Ok((0, Vec::new()));
+                }
+                let decompressor = DctDecompressor::new(tiles.data(self.buffer, i)?)?;
+                if decompressor.cps() != cpp {
+                    return Err(RawLoaderError::corrupt(format!(
+                        "DNG: lossy tile has {} components instead of {}",
+                        decompressor.cps(),
+                        cpp
+                    )));
+                }
+                let (bwidth, blength) = tiles.size(i, width, height);
+                if decompressor.width() < bwidth || decompressor.height() < blength {
+                    return Err(RawLoaderError::truncated(
+                        "DNG: lossy tile is smaller than the tile size",
+                    ));
+                }
+                Ok((decompressor.width(), decompressor.decode()?))
+            })
+            .collect::<Result<Vec<(usize, Vec<u16>)>, RawLoaderError>>()?;
+
+        let curve = table.map(|table| {
+            let points = (0..256)
+                .map(|i| table[cmp::min(i, table.len() - 1)])
+                .collect::<Vec<u16>>();
+            LookupTable::new(&points)
+        });
+
+        decode_threaded(
+            width * cpp,
+            height,
+            window,
+            dummy,
+            &(|out: &mut [u16], row| {
+                let trow = row / tiles.length;
+                let mut random = (row as u32).wrapping_mul(0x9e3779b9);
+                for (tcol, line) in out.chunks_mut(tiles.width * cpp).enumerate() {
+                    let (jwidth, tile) = &decoded[trow * tiles.cols + tcol];
+                    let start = (row % tiles.length) * jwidth * cpp;
+                    let Some(src) = tile.get(start..start + line.len()) else {
+                        continue;
+                    };
+                    match curve {
+                        Some(ref curve) => {
+                            for (o, i) in line.iter_mut().zip(src) {
+                                *o = curve.dither(*i, &mut random);
+                            }
+                        }
+                        None => line.copy_from_slice(src),
+                    }
+                }
+            }),
+        )
+    }
+
+    // Deflate compressed DNGs, and the floating point ones whether compressed or not,
+    // store their tiles as plain bytes that may need a predictor undone
+    pub fn decode_deflate(
+        &self,
+        raw: &TiffIFD,
+        width: usize,
+        height: usize,
+        cpp: usize,
+        deflated: bool,
+        window: Option<&Window>,
         dummy: bool,
-    ) -> Result<Vec<u16>, String> {
-        if let Some(offsets) = raw.find_entry(Tag::StripOffsets) {
-            // We're in a normal offset situation
-            if offsets.count() != 1 {
-                return Err("DNG: files with more than one slice not supported yet".to_string());
+    ) -> Result<RawImageData, RawLoaderError> {
+        let float = raw
+            .find_entry(Tag::SampleFormat)
+            .is_some_and(|e| e.get_u32(0) == 3);
+        let bps = fetch_tag!(raw, Tag::BitsPerSample).get_usize(0);
+        match (float, bps) {
+            (true, 16) | (true, 24) | (true, 32) | (false, 8) | (false, 16) => {}
+            _ => {
+                return Err(RawLoaderError::corrupt(format!(
+                    "DNG: Don't know about {} bps {} images",
+                    bps,
+                    if float { "float" } else { "integer" }
+                )));
             }
-            let offset = offsets.get_usize(0);
-            let src = &self.buffer[offset..];
-            let mut out = alloc_image_ok!(width, height, dummy);
-            let decompressor = LjpegDecompressor::new(src)?;
-            decompressor.decode(&mut out, 0, width, width, height, dummy)?;
-            Ok(out)
-        } else if let Some(offsets) = raw.find_entry(Tag::TileOffsets) {
-            // They've gone with tiling
-            let twidth = fetch_tag!(raw, Tag::TileWidth).get_usize(0) * cpp;
-            let tlength = fetch_tag!(raw, Tag::TileLength).get_usize(0);
-            let coltiles = (width - 1) / twidth + 1;
-            let rowtiles = (height - 1) / tlength + 1;
-            if coltiles * rowtiles != offsets.count() {
-                return Err(format!(
-                    "DNG: trying to decode {} tiles from {} offsets",
-                    coltiles * rowtiles,
-                    offsets.count()
-                )
-                .to_string());
+        }
+        let predictor = raw.find_entry(Tag::Predictor).map_or(1, |e| e.get_u32(0));
+        let (stride, fp_predictor) = match predictor {
+            1 => (0, false),
+            2 => (cpp, false),
+            34892 => (cpp * 2, false),
+            34893 => (cpp * 4, false),
+            3 => (cpp, true),
+            34894 => (cpp * 2, true),
+            34895 => (cpp * 4, true),
+            p => return Err(RawLoaderError::compression("DNG", p)),
+        };
+        if fp_predictor && !float {
+            return Err(RawLoaderError::corrupt(
+                "DNG: floating point predictor in an integer image",
+            ));
+        }
+
+        let tiles = DngTiles::new(raw, width, height)?;
+        if dummy {
+            return Ok(if float {
+                RawImageData::Float(vec![0.0])
+            } else {
+                RawImageData::Integer(vec![0])
+            });
+        }
+
+        let bytes = bps / 8;
+        let line = tiles.width * cpp;
+        let tlength = tiles.length;
+        let little = self.tiff.little_endian();
+        let tile_bytes = |i: usize| -> Result<Vec<u8>, RawLoaderError> {
+            let size = line * tlength * bytes;
+            let mut src = tiles.data(self.buffer, i)?;
+            if let Some(count) = tiles.counts.get(i) {
+                src = &src[..cmp::min(*count, src.len())];
+            }
+            let data = if deflated {
+                inflate_zlib(src, size)?
+            } else {
+                src.to_vec()
+            };
+            if data.len() < size {
+                return Err(RawLoaderError::truncated("DNG: tile data is too short"));
             }
+            Ok(data)
+        };
 
-            Ok(decode_threaded_multiline(
-                width,
+        if float {
+            let decoded = (0..tiles.len())
+                .into_par_iter()
+                .map(|i| {
+                    if tiles.skip(window, i) {
+                        return Ok(Vec::new());
+                    }
+                    let mut data = tile_bytes(i)?;
+                    let mut out = vec![0f32; line * tlength];
+                    let mut row = vec![0u8; line * bytes];
+                    for (src, dst) in data
+                        .chunks_exact_mut(line * bytes)
+                        .zip(out.chunks_exact_mut(line))
+                    {
+                        if fp_predictor {
+                            // Undo the byte differencing and then put back together the
+                            // bytes of each value that are stored most significant first
+                            for j in stride..src.len() {
+                                src[j] = src[j].wrapping_add(src[j - stride]);
+                            }
+                            for s in 0..line {
+                                for b in 0..bytes {
+                                    row[s * bytes + b] = src[b * line + s];
+                                }
+                            }
+                        } else {
+                            row.copy_from_slice(src);
+                        }
+                        for (value, pix) in dst.iter_mut().zip(row.chunks_exact(bytes)) {
+                            let bits = if fp_predictor || !little {
+                                pix.iter().fold(0u32, |acc, b| acc << 8 | *b as u32)
+                            } else {
+                                pix.iter().rev().fold(0u32, |acc, b| acc << 8 | *b as u32)
+                            };
+                            *value = match bytes {
+                                2 => f16_to_f32(bits as u16),
+                                3 => f24_to_f32(bits),
+                                _ => f32::from_bits(bits),
+                            };
+                        }
+                        if !fp_predictor && stride > 0 {
+                            for i in stride..dst.len() {
+                                dst[i] += dst[i - stride];
+                            }
+                        }
+                    }
+                    Ok(out)
+                })
+                .collect::<Result<Vec<Vec<f32>>, RawLoaderError>>()?;
+            Ok(RawImageData::Float(assemble_tiles(
+                &decoded,
+                width * cpp,
                 height,
+                line,
                 tlength,
-                dummy,
-                &(|strip: &mut [u16], row| {
-                    let row = row / tlength;
-                    for col in 0..coltiles {
-                        let offset = offsets.get_usize(row * coltiles + col);
-                        let src = &self.buffer[offset..];
-                        let decompressor = LjpegDecompressor::new(src).unwrap();
-                        let bwidth = cmp::min(width, (col + 1) * twidth) - col * twidth;
-                        let blength = cmp::min(height, (row + 1) * tlength) - row * tlength;
-                        // FIXME: instead of unwrap() we need to propagate the error
-                        decompressor
-                            .decode(strip, col * twidth, width, bwidth, blength, dummy)
-                            .unwrap();
+                window,
+            )))
+        } else {
+            let decoded = (0..tiles.len())
+                .into_par_iter()
+                .map(|i| {
+                    if tiles.skip(window, i) {
+                        return Ok(Vec::new());
+                    }
+                    let data = tile_bytes(i)?;
+                    let mut out: Vec<u16> = data
+                        .chunks_exact(bytes)
+                        .take(line * tlength)
+                        .map(|pix| match (bytes, little) {
+                            (1, _) => pix[0] as u16,
+                            (_, true) => u16::from_le_bytes([pix[0], pix[1]]),
+                            (_, false) => u16::from_be_bytes([pix[0], pix[1]]),
+                        })
+                        .collect();
+                    if stride > 0 {
+                        for row in out.chunks_exact_mut(line) {
+                            for i in stride..line {
+                                row[i] = row[i].wrapping_add(row[i - stride]);
+                            }
+                        }
                     }
-                }),
-            ))
+                    Ok(out)
+                })
+                .collect::<Result<Vec<Vec<u16>>, RawLoaderError>>()?;
+            Ok(RawImageData::Integer(assemble_tiles(
+                &decoded,
+                width * cpp,
+                height,
+                line,
+                tlength,
+                window,
+            )))
+        }
+    }
+}
+
+// The image data is either in tiles or in strips, which are handled here as tiles
+// that are as wide as the image
+struct DngTiles {
+    offsets: Vec<usize>,
+    counts: Vec<usize>,
+    width: usize,
+    length: usize,
+    cols: usize,
+}
+
+impl DngTiles {
+    fn new(raw: &TiffIFD, width: usize, height: usize) -> Result<DngTiles, RawLoaderError> {
+        let (offsets, counts, twidth, tlength) =
+            if let Some(offsets) = raw.find_entry(Tag::TileOffsets) {
+                let twidth = fetch_tag!(raw, Tag::TileWidth).get_usize(0);
+                let tlength = fetch_tag!(raw, Tag::TileLength).get_usize(0);
+                (
+                    offsets,
+                    raw.find_entry(Tag::TileByteCounts),
+                    twidth,
+                    tlength,
+                )
+            } else if let Some(offsets) = raw.find_entry(Tag::StripOffsets) {
+                // A single strip is always the whole image whatever RowsPerStrip says
+                let rows = match raw.find_entry(Tag::RowsPerStrip) {
+                    Some(rows) if offsets.count() > 1 => cmp::min(rows.get_usize(0), height),
+                    _ => height,
+                };
+                (offsets, raw.find_entry(Tag::StripByteCounts), width, rows)
+            } else {
+                return Err(RawLoaderError::corrupt("DNG: didn't find tiles or strips"));
+            };
+        if twidth == 0 || tlength == 0 {
+            return Err(RawLoaderError::corrupt("DNG: invalid tile size"));
+        }
+        check_image_size(twidth, tlength)?;
+        let cols = width.div_ceil(twidth);
+        let rows = height.div_ceil(tlength);
+        if cols * rows != offsets.count() {
+            return Err(RawLoaderError::corrupt(format!(
+                "DNG: trying to decode {} tiles from {} offsets",
+                cols * rows,
+                offsets.count()
+            )));
+        }
+        let counts = match counts {
+            Some(counts) if counts.count() == offsets.count() => {
+                (0..counts.count()).map(|i| counts.get_usize(i)).collect()
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(DngTiles {
+            offsets: (0..offsets.count()).map(|i| offsets.get_usize(i)).collect(),
+            counts,
+            width: twidth,
+            length: tlength,
+            cols,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    // The size in pixels of the part of a tile that's inside the image
+    fn size(&self, i: usize, width: usize, height: usize) -> (usize, usize) {
+        let (col, row) = (i % self.cols, i / self.cols);
+        (
+            cmp::min(width, (col + 1) * self.width) - col * self.width,
+            cmp::min(height, (row + 1) * self.length) - row * self.length,
+        )
+    }
+
+    // Tiles outside of a region decode don't need decoding
+    fn skip(&self, window: Option<&Window>, i: usize) -> bool {
+        let (col, row) = (i % self.cols, i / self.cols);
+        window.is_some_and(|w| {
+            !w.intersects(row * self.length, col * self.width, self.width, self.length)
+        })
+    }
+
+    // The data from the start of a tile to the end of the file
+    fn data<'b>(&self, buf: &'b [u8], i: usize) -> Result<&'b [u8], RawLoaderError> {
+        buf.get(self.offsets[i]..)
+            .ok_or_else(|| RawLoaderError::truncated("DNG: tile offset past the end of the file"))
+    }
+}
+
+// Put together an image out of tiles that are twidth x tlength values each. With a window
+// only the window gets put together
+fn assemble_tiles<T: Copy + Default + Send + Sync>(
+    tiles: &[Vec<T>],
+    width: usize,
+    height: usize,
+    twidth: usize,
+    tlength: usize,
+    window: Option<&Window>,
+) -> Vec<T> {
+    let coltiles = width.div_ceil(twidth);
+    let (rows, cols) = match window {
+        Some(w) => (w.top..w.bottom, w.cols()),
+        None => (0..height, 0..width),
+    };
+    let mut out = vec![T::default(); rows.len() * cols.len()];
+    if cols.is_empty() {
+        return out;
+    }
+    out.par_chunks_mut(cols.len())
+        .zip(rows)
+        .for_each(|(line, row)| {
+            let trow = row / tlength;
+            let start = (row % tlength) * twidth;
+            let mut col = cols.start;
+            while col < cols.end {
+                let tcol = col / twidth;
+                let end = cmp::min((tcol + 1) * twidth, cols.end);
+                let from = start + col % twidth;
+                // Skipped tiles are empty and stay black
+                if let Some(src) = tiles[trow * coltiles + tcol].get(from..from + end - col) {
+                    line[col - cols.start..end - cols.start].copy_from_slice(src);
+                }
+                col = end;
+            }
+        });
+    out
+}
+
+fn f16_to_f32(val: u16) -> f32 {
+    let sign = if val & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = ((val >> 10) & 0x1f) as i32;
+    let mantissa = (val & 0x3ff) as f32;
+    match exp {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exp - 15),
+    }
+}
+
+fn f24_to_f32(val: u32) -> f32 {
+    let sign = if val & 0x800000 != 0 { -1.0 } else { 1.0 };
+    let exp = ((val >> 16) & 0x7f) as i32;
+    let mantissa = (val & 0xffff) as f32;
+    match exp {
+        0 => sign * mantissa * 2f32.powi(-78),
+        0x7f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x7f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 65536.0) * 2f32.powi(exp - 63),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A little endian TIFF with a single IFD and its data right after it
+    fn dng_tiff(entries: &[(u16, u16, u32, u32)], data: &[u8]) -> Vec<u8> {
+        let mut buf = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        buf.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for &(tag, typ, count, value) in entries {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&typ.to_le_bytes());
+            buf.extend_from_slice(&count.to_le_bytes());
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn decode_strip(
+        size: (u32, u32),
+        format: (u32, u32),
+        predictor: u32,
+        deflated: bool,
+        data: &[u8],
+    ) -> RawImageData {
+        let offset = 8 + 2 + 7 * 12 + 4;
+        let buf = dng_tiff(
+            &[
+                (0x0100, 4, 1, size.0),
+                (0x0101, 4, 1, size.1),
+                (0x0102, 3, 1, format.0),
+                (0x0111, 4, 1, offset),
+                (0x0117, 4, 1, data.len() as u32),
+                (0x013D, 3, 1, predictor),
+                (0x0153, 3, 1, format.1),
+            ],
+            data,
+        );
+        let raw = TiffIFD::new_file(&buf).unwrap();
+        let loader = RawLoader::new();
+        let decoder = DngDecoder::new(&buf, TiffIFD::new_file(&buf).unwrap(), &loader);
+        decoder
+            .decode_deflate(
+                &raw,
+                size.0 as usize,
+                size.1 as usize,
+                1,
+                deflated,
+                None,
+                false,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn black_level_pattern_and_deltas() {
+        // A 4x4 pattern over the 2x2 CFA, 3 column deltas and 2 row deltas
+        let offset = 8 + 2 + 4 * 12 + 4;
+        let mut data = Vec::new();
+        for level in 100..116u16 {
+            data.extend_from_slice(&level.to_le_bytes());
+        }
+        for (num, den) in [(1i32, 2i32), (-1, 1), (3, 2), (2, 1), (-5, 2)] {
+            data.extend_from_slice(&num.to_le_bytes());
+            data.extend_from_slice(&den.to_le_bytes());
+        }
+        let buf = dng_tiff(
+            &[
+                (0xC619, 3, 2, 4 | 4 << 16),
+                (0xC61A, 3, 16, offset),
+                (0xC61B, 10, 3, offset + 32),
+                (0xC61C, 10, 2, offset + 56),
+            ],
+            &data,
+        );
+        let raw = TiffIFD::new_file(&buf).unwrap();
+        let loader = RawLoader::new();
+        let decoder = DngDecoder::new(&buf, TiffIFD::new_file(&buf).unwrap(), &loader);
+
+        let black = decoder.get_black(&raw, 1, &[2, 0, 0, 1]).unwrap();
+        assert_eq!(black.origin, (2, 1));
+        assert_eq!(black.repeat, (4, 4));
+        let levels: Vec<f32> = (100..116).map(|v| v as f32).collect();
+        assert_eq!(black.levels, levels);
+        assert_eq!(black.delta_h, [0.5, -1.0, 1.5]);
+        assert_eq!(black.delta_v, [2.0, -2.5]);
+        // Rows and columns of the deltas start at the origin too
+        assert_eq!(black.at(3, 2, 0), 105.0 - 1.0 - 2.5);
+    }
+
+    #[test]
+    fn black_level_single_value() {
+        let buf = dng_tiff(&[(0xC619, 3, 2, 2 | 2 << 16), (0xC61A, 3, 1, 64)], &[]);
+        let raw = TiffIFD::new_file(&buf).unwrap();
+        let loader = RawLoader::new();
+        let decoder = DngDecoder::new(&buf, TiffIFD::new_file(&buf).unwrap(), &loader);
+        let black = decoder.get_black(&raw, 3, &[0; 4]).unwrap();
+        assert_eq!(black.levels, [64.0; 12]);
+        assert!(black.delta_h.is_empty() && black.delta_v.is_empty());
+
+        let buf = dng_tiff(&[(0xC619, 3, 2, 2 << 16)], &[]);
+        let raw = TiffIFD::new_file(&buf).unwrap();
+        assert!(decoder.get_black(&raw, 1, &[0; 4]).is_err());
+    }
+
+    // 16 bit values of 1000 + 10 * row + col split into tiles of the given size, padded past
+    // the edges of the image, with the tags for them
+    fn tiled_dng(
+        tiled: bool,
+        width: usize,
+        height: usize,
+        twidth: usize,
+        tlength: usize,
+    ) -> Vec<u8> {
+        let (cols, rows) = (width.div_ceil(twidth), height.div_ceil(tlength));
+        let mut tiles = Vec::new();
+        for tile in 0..cols * rows {
+            let (top, left) = ((tile / cols) * tlength, (tile % cols) * twidth);
+            // Tiles are always whole but the last strip stops at the end of the image
+            let bottom = if tiled {
+                top + tlength
+            } else {
+                cmp::min(top + tlength, height)
+            };
+            let mut data = Vec::new();
+            for row in top..bottom {
+                for col in left..left + twidth {
+                    let value = if row < height && col < width {
+                        1000 + 10 * row + col
+                    } else {
+                        0xffff
+                    };
+                    data.extend_from_slice(&(value as u16).to_le_bytes());
+                }
+            }
+            tiles.push(data);
+        }
+
+        // The offsets and byte counts go right after the IFD followed by the tiles
+        let count = tiles.len() as u32;
+        let list = 8 + 2 + if tiled { 5 } else { 4 } * 12 + 4;
+        let mut data = Vec::new();
+        let mut start = list + 8 * count;
+        for tile in &tiles {
+            data.extend_from_slice(&start.to_le_bytes());
+            start += tile.len() as u32;
+        }
+        for tile in &tiles {
+            data.extend_from_slice(&(tile.len() as u32).to_le_bytes());
+        }
+        for tile in &tiles {
+            data.extend_from_slice(tile);
+        }
+        let entries = if tiled {
+            vec![
+                (0x0102, 3, 1, 16),
+                (0x0142, 4, 1, twidth as u32),
+                (0x0143, 4, 1, tlength as u32),
+                (0x0144, 4, count, list),
+                (0x0145, 4, count, list + 4 * count),
+            ]
         } else {
-            Err("DNG: didn't find tiles or strips".to_string())
+            vec![
+                (0x0102, 3, 1, 16),
+                (0x0111, 4, count, list),
+                (0x0116, 4, 1, tlength as u32),
+                (0x0117, 4, count, list + 4 * count),
+            ]
+        };
+        dng_tiff(&entries, &data)
+    }
+
+    fn decode_tiles(buf: &[u8], width: usize, height: usize, window: Option<&Window>) -> Vec<u16> {
+        let raw = TiffIFD::new_file(buf).unwrap();
+        let loader = RawLoader::new();
+        let decoder = DngDecoder::new(buf, TiffIFD::new_file(buf).unwrap(), &loader);
+        decoder
+            .decode_uncompressed(&raw, width, height, 1, None, window, false)
+            .unwrap()
+    }
+
+    fn values(top: usize, left: usize, bottom: usize, right: usize) -> Vec<u16> {
+        (top..bottom)
+            .flat_map(|row| (left..right).map(move |col| (1000 + 10 * row + col) as u16))
+            .collect()
+    }
+
+    #[test]
+    fn strips_with_a_short_last_strip() {
+        // Strips of 2 rows in a 5 row image, the last strip only has its one row stored
+        let buf = tiled_dng(false, 3, 5, 3, 2);
+        assert_eq!(decode_tiles(&buf, 3, 5, None), values(0, 0, 5, 3));
+    }
+
+    #[test]
+    fn tiles_past_the_edges() {
+        // A 5x3 image in 4x2 tiles so the right and bottom tiles are mostly padding
+        let buf = tiled_dng(true, 5, 3, 4, 2);
+        assert_eq!(decode_tiles(&buf, 5, 3, None), values(0, 0, 3, 5));
+
+        let window = Window {
+            top: 1,
+            left: 3,
+            bottom: 3,
+            right: 5,
+            cpp: 1,
+            step: 1,
+            keep: 1,
+        };
+        assert_eq!(decode_tiles(&buf, 5, 3, Some(&window)), values(1, 3, 3, 5));
+    }
+
+    #[test]
+    fn assemble_tiles_in_a_window() {
+        // A 5x3 image in 3x2 tiles, so the right and bottom tiles only have part of their
+        // values in the image
+        let tiles: Vec<Vec<u16>> = (0..4)
+            .map(|tile| (0..6).map(|i| tile * 10 + i).collect())
+            .collect();
+        let full = assemble_tiles(&tiles, 5, 3, 3, 2, None);
+        assert_eq!(
+            full,
+            vec![0, 1, 2, 10, 11, 3, 4, 5, 13, 14, 20, 21, 22, 30, 31]
+        );
+        for (top, left, bottom, right) in [(0, 0, 3, 5), (1, 2, 3, 4), (2, 4, 3, 5), (0, 1, 1, 2)] {
+            let window = Window {
+                top,
+                left,
+                bottom,
+                right,
+                cpp: 1,
+                step: 1,
+                keep: 1,
+            };
+            assert_eq!(
+                assemble_tiles(&tiles, 5, 3, 3, 2, Some(&window)),
+                window.crop(&full, 5).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn deflate_horizontal_predictor() {
+        // Two rows of 16 bit differences that wrap around, compressed with zlib
+        let data = [
+            0x78, 0xda, 0x4b, 0x61, 0xe0, 0x62, 0x78, 0xf3, 0xbf, 0x8f, 0x99, 0x95, 0xe1, 0xd7,
+            0x7f, 0x46, 0x06, 0x76, 0x06, 0x00, 0x2d, 0x99, 0x04, 0xf1,
+        ];
+        match decode_strip((4, 2), (16, 1), 2, true, &data) {
+            RawImageData::Integer(data) => {
+                assert_eq!(data, vec![100, 110, 90, 1000, 5, 65535, 0, 7])
+            }
+            RawImageData::Float(_) => panic!("integer image decoded as float"),
+        }
+    }
+
+    #[test]
+    fn floating_point_predictor() {
+        // The bytes of 1.0, -2.5 and 0.15625 split into planes and then differenced
+        let data = [
+            0x3f, 0x81, 0x7e, 0x42, 0xa0, 0x00, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        match decode_strip((3, 1), (32, 3), 3, false, &data) {
+            RawImageData::Float(data) => assert_eq!(data, vec![1.0, -2.5, 0.15625]),
+            RawImageData::Integer(_) => panic!("float image decoded as integer"),
+        }
+    }
+
+    #[test]
+    fn f16_and_f24_values() {
+        assert_eq!(f16_to_f32(0x3c00), 1.0);
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+        assert!(f16_to_f32(0x7c01).is_nan());
+        assert_eq!(f24_to_f32(0x3f0000), 1.0);
+        assert_eq!(f24_to_f32(0xc08000), -3.0);
+    }
+
+    #[test]
+    fn stored_opcodes_go_before_the_table() {
+        let mut img = RawImage::new(Camera::new(), 2, 2, [1.0; 4], vec![100; 4], false);
+        img.opcodes[0] = vec![DngOpcode {
+            optional: false,
+            preview_skip: false,
+            op: Opcode::DeltaPerRow {
+                area: OpcodeArea {
+                    top: 0,
+                    left: 0,
+                    bottom: 2,
+                    right: 2,
+                    plane: 0,
+                    planes: 1,
+                    row_pitch: 1,
+                    col_pitch: 1,
+                },
+                deltas: vec![10.0 / 65535.0, 20.0 / 65535.0],
+            },
+        }];
+        img.linearization = Some((0..1024).map(|i| i * 2).collect());
+        img.apply_opcodes(1).unwrap();
+        assert!(img.opcodes[0].is_empty());
+        assert!(img.linearization.is_none());
+        match img.data {
+            RawImageData::Integer(data) => assert_eq!(data, vec![220, 220, 240, 240]),
+            RawImageData::Float(_) => panic!("linearized image isn't integer"),
+        }
+    }
+
+    #[test]
+    fn unapplied_opcodes_keep_the_table() {
+        // An opcode that can't be applied only fails when the list is applied, and the
+        // table then stays for another try
+        let mut img = RawImage::new(Camera::new(), 2, 2, [1.0; 4], vec![100; 4], false);
+        img.opcodes[0] = vec![DngOpcode {
+            optional: false,
+            preview_skip: false,
+            op: Opcode::Unknown {
+                id: 99,
+                data: Vec::new(),
+            },
+        }];
+        img.linearization = Some(vec![0, 1, 2]);
+        assert!(img.apply_opcodes(1).is_err());
+        assert!(img.linearization.is_some());
+
+        // Region decodes drop the opcodes but still linearize the values
+        img.crop(0, 1, 1, 2);
+        assert!(img.linearization.is_none());
+        match img.data {
+            RawImageData::Integer(data) => assert_eq!(data, vec![2, 2]),
+            RawImageData::Float(_) => panic!("linearized image isn't integer"),
         }
     }
 }
@@ -0,0 +1,679 @@
+use std::cmp;
+
+use crate::decoders::RawLoaderError;
+
+// D50 is the white of the profile connection space
+const D50_XY: (f64, f64) = (0.3457, 0.3585);
+
+// Bradford chromatic adaptation
+const BRADFORD: [f64; 9] = [
+    0.8951, 0.2664, -0.1614, -0.7502, 1.7135, 0.0367, 0.0389, -0.0685, 1.0296,
+];
+
+// Robertson's isotherms as (1e6/temperature, u, v, slope)
+const ISOTHERMS: [(f64, f64, f64, f64); 31] = [
+    (0.0, 0.18006, 0.26352, -0.24341),
+    (10.0, 0.18066, 0.26589, -0.25479),
+    (20.0, 0.18133, 0.26846, -0.26876),
+    (30.0, 0.18208, 0.27119, -0.28539),
+    (40.0, 0.18293, 0.27407, -0.30470),
+    (50.0, 0.18388, 0.27709, -0.32675),
+    (60.0, 0.18494, 0.28021, -0.35156),
+    (70.0, 0.18611, 0.28342, -0.37915),
+    (80.0, 0.18740, 0.28668, -0.40955),
+    (90.0, 0.18880, 0.28997, -0.44278),
+    (100.0, 0.19032, 0.29326, -0.47888),
+    (125.0, 0.19462, 0.30141, -0.58204),
+    (150.0, 0.19962, 0.30921, -0.70471),
+    (175.0, 0.20525, 0.31647, -0.84901),
+    (200.0, 0.21142, 0.32312, -1.0182),
+    (225.0, 0.21807, 0.32909, -1.2168),
+    (250.0, 0.22511, 0.33439, -1.4512),
+    (275.0, 0.23247, 0.33904, -1.7298),
+    (300.0, 0.24010, 0.34308, -2.0637),
+    (325.0, 0.24792, 0.34655, -2.4681),
+    (350.0, 0.25591, 0.34951, -2.9641),
+    (375.0, 0.26400, 0.35200, -3.5814),
+    (400.0, 0.27218, 0.35407, -4.3633),
+    (425.0, 0.28039, 0.35577, -5.3762),
+    (450.0, 0.28863, 0.35714, -6.7262),
+    (475.0, 0.29685, 0.35823, -8.5955),
+    (500.0, 0.30505, 0.35907, -11.324),
+    (525.0, 0.31320, 0.35968, -15.628),
+    (550.0, 0.32129, 0.36011, -23.325),
+    (575.0, 0.32931, 0.36038, -40.770),
+    (600.0, 0.33724, 0.36051, -116.45),
+];
+
+/// The color calibration of a DNG (or a DCP profile), with the matrices for the two
+/// calibration illuminants. Matrices are stored by rows and are empty when not present.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DngColor {
+    /// number of color channels of the camera
+    pub channels: usize,
+    /// the EXIF light source of each of the calibrations (CalibrationIlluminant1/2)
+    pub illuminants: [u16; 2],
+    /// XYZ to camera matrices with `channels` rows and 3 columns (ColorMatrix1/2)
+    pub color_matrices: [Vec<f32>; 2],
+    /// white balanced camera to XYZ(D50) matrices with 3 rows and `channels` columns
+    /// (ForwardMatrix1/2)
+    pub forward_matrices: [Vec<f32>; 2],
+    /// matrices from the reference camera to this specific camera, `channels` square
+    /// (CameraCalibration1/2)
+    pub camera_calibrations: [Vec<f32>; 2],
+    /// per channel gain applied before the raw values were stored (AnalogBalance)
+    pub analog_balance: Vec<f32>,
+    /// the camera values of a neutral color at the time of the shot (AsShotNeutral)
+    pub as_shot_neutral: Vec<f32>,
+    /// the white chromaticity at the time of the shot when not given as a neutral
+    /// (AsShotWhiteXY)
+    pub as_shot_white_xy: Option<(f32, f32)>,
+}
+
+#[derive(Debug, Clone)]
+struct Matrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+
+impl Matrix {
+    fn new(rows: usize, cols: usize, data: Vec<f64>) -> Matrix {
+        Matrix { rows, cols, data }
+    }
+
+    fn from_f32(rows: usize, cols: usize, data: &[f32]) -> Matrix {
+        Matrix::new(rows, cols, data.iter().map(|v| *v as f64).collect())
+    }
+
+    fn identity(size: usize) -> Matrix {
+        Matrix::diagonal(&vec![1.0; size])
+    }
+
+    fn diagonal(values: &[f64]) -> Matrix {
+        let size = values.len();
+        let mut data = vec![0.0; size * size];
+        for (i, val) in values.iter().enumerate() {
+            data[i * size + i] = *val;
+        }
+        Matrix::new(size, size, data)
+    }
+
+    fn at(&self, row: usize, col: usize) -> f64 {
+        self.data[row * self.cols + col]
+    }
+
+    fn mul(&self, other: &Matrix) -> Matrix {
+        let mut data = vec![0.0; self.rows * other.cols];
+        for row in 0..self.rows {
+            for col in 0..other.cols {
+                data[row * other.cols + col] = (0..self.cols)
+                    .map(|k| self.at(row, k) * other.at(k, col))
+                    .sum();
+            }
+        }
+        Matrix::new(self.rows, other.cols, data)
+    }
+
+    fn mulv(&self, vector: &[f64]) -> Vec<f64> {
+        (0..self.rows)
+            .map(|row| (0..self.cols).map(|k| self.at(row, k) * vector[k]).sum())
+            .collect()
+    }
+
+    fn scale(&self, factor: f64) -> Matrix {
+        Matrix::new(
+            self.rows,
+            self.cols,
+            self.data.iter().map(|v| v * factor).collect(),
+        )
+    }
+
+    fn blend(&self, other: &Matrix, weight: f64) -> Matrix {
+        let data = self
+            .data
+            .iter()
+            .zip(other.data.iter())
+            .map(|(a, b)| a * weight + b * (1.0 - weight))
+            .collect();
+        Matrix::new(self.rows, self.cols, data)
+    }
+
+    fn transpose(&self) -> Matrix {
+        let mut data = vec![0.0; self.data.len()];
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                data[col * self.rows + row] = self.at(row, col);
+            }
+        }
+        Matrix::new(self.cols, self.rows, data)
+    }
+
+    // Gauss-Jordan for square matrices and the pseudo-inverse for the rest
+    fn invert(&self) -> Result<Matrix, RawLoaderError> {
+        if self.rows != self.cols {
+            let t = self.transpose();
+            return if self.rows > self.cols {
+                Ok(t.mul(self).invert()?.mul(&t))
+            } else {
+                Ok(t.mul(&self.mul(&t).invert()?))
+            };
+        }
+        let size = self.rows;
+        let mut temp = self.data.clone();
+        let mut out = Matrix::identity(size).data;
+        for i in 0..size {
+            let pivot = (i..size)
+                .max_by(|a, b| {
+                    temp[a * size + i]
+                        .abs()
+                        .total_cmp(&temp[b * size + i].abs())
+                })
+                .unwrap_or(i);
+            if temp[pivot * size + i].abs() < 1e-12 {
+                return Err(RawLoaderError::corrupt(
+                    "DNG: color matrix can't be inverted",
+                ));
+            }
+            for col in 0..size {
+                temp.swap(i * size + col, pivot * size + col);
+                out.swap(i * size + col, pivot * size + col);
+            }
+            let div = temp[i * size + i];
+            for col in 0..size {
+                temp[i * size + col] /= div;
+                out[i * size + col] /= div;
+            }
+            for row in 0..size {
+                if row == i {
+                    continue;
+                }
+                let factor = temp[row * size + i];
+                for col in 0..size {
+                    temp[row * size + col] -= factor * temp[i * size + col];
+                    out[row * size + col] -= factor * out[i * size + col];
+                }
+            }
+        }
+        Ok(Matrix::new(size, size, out))
+    }
+}
+
+fn xy_to_xyz(xy: (f64, f64)) -> Vec<f64> {
+    let x = xy.0.clamp(0.000001, 0.999999);
+    let y = xy.1.clamp(0.000001, 0.999999);
+    let (x, y) = if x + y > 0.999999 {
+        let scale = 0.999999 / (x + y);
+        (x * scale, y * scale)
+    } else {
+        (x, y)
+    };
+    vec![x / y, 1.0, (1.0 - x - y) / y]
+}
+
+fn xyz_to_xy(xyz: &[f64]) -> (f64, f64) {
+    let total = xyz[0] + xyz[1] + xyz[2];
+    if total > 0.0 {
+        (xyz[0] / total, xyz[1] / total)
+    } else {
+        D50_XY
+    }
+}
+
+// Bradford adaptation from one white to another
+fn map_white_matrix(from: (f64, f64), to: (f64, f64)) -> Result<Matrix, RawLoaderError> {
+    let bradford = Matrix::new(3, 3, BRADFORD.to_vec());
+    let w1 = bradford.mulv(&xy_to_xyz(from));
+    let w2 = bradford.mulv(&xy_to_xyz(to));
+    let gains: Vec<f64> = (0..3)
+        .map(|i| {
+            if w1[i] > 0.0 {
+                (w2[i] / w1[i]).clamp(0.1, 10.0)
+            } else {
+                10.0
+            }
+        })
+        .collect();
+    Ok(bradford
+        .invert()?
+        .mul(&Matrix::diagonal(&gains))
+        .mul(&bradford))
+}
+
+// Color temperatures of the EXIF light sources
+fn illuminant_temperature(illuminant: u16) -> f64 {
+    match illuminant {
+        17 | 3 => 2850.0,
+        24 => 3200.0,
+        23 => 5000.0,
+        20 | 1 | 9 | 4 | 18 => 5500.0,
+        21 | 19 | 10 => 6500.0,
+        22 | 11 => 7500.0,
+        12 => 6400.0,
+        13 => 5050.0,
+        14 | 2 => 4150.0,
+        15 => 3525.0,
+        16 => 2925.0,
+        _ => 0.0,
+    }
+}
+
+impl DngColor {
+    /// Finds the correlated color temperature and tint of a white chromaticity using
+    /// Robertson's method
+    pub fn xy_to_temperature(x: f64, y: f64) -> (f64, f64) {
+        let div = 1.5 - x + 6.0 * y;
+        let (u, v) = (2.0 * x / div, 3.0 * y / div);
+        let (mut last_dt, mut last_du, mut last_dv) = (0.0, 0.0, 0.0);
+        for idx in 1..ISOTHERMS.len() {
+            let (_, iu, iv, slope) = ISOTHERMS[idx];
+            let len = (1.0 + slope * slope).sqrt();
+            let (du, dv) = (1.0 / len, slope / len);
+            let (uu, vv) = (u - iu, v - iv);
+            let mut dt = -uu * dv + vv * du;
+            if dt <= 0.0 || idx == ISOTHERMS.len() - 1 {
+                if dt > 0.0 {
+                    dt = 0.0;
+                }
+                dt = -dt;
+                let f = if idx == 1 { 0.0 } else { dt / (last_dt + dt) };
+                let temperature = 1.0e6 / (ISOTHERMS[idx - 1].0 * f + ISOTHERMS[idx].0 * (1.0 - f));
+                let uu = u - (ISOTHERMS[idx - 1].1 * f + iu * (1.0 - f));
+                let vv = v - (ISOTHERMS[idx - 1].2 * f + iv * (1.0 - f));
+                let mut du = du * (1.0 - f) + last_du * f;
+                let mut dv = dv * (1.0 - f) + last_dv * f;
+                let len = (du * du + dv * dv).sqrt();
+                du /= len;
+                dv /= len;
+                let tint = (uu * du + vv * dv) * -3000.0;
+                return (temperature, tint);
+            }
+            last_dt = dt;
+            last_du = du;
+            last_dv = dv;
+        }
+        (0.0, 0.0)
+    }
+
+    fn matrix(
+        &self,
+        data: &[f32],
+        rows: usize,
+        cols: usize,
+    ) -> Result<Option<Matrix>, RawLoaderError> {
+        if data.is_empty() {
+            return Ok(None);
+        }
+        if data.len() != rows * cols {
+            return Err(RawLoaderError::corrupt(format!(
+                "DNG: color matrix has {} values instead of {}",
+                data.len(),
+                rows * cols
+            )));
+        }
+        Ok(Some(Matrix::from_f32(rows, cols, data)))
+    }
+
+    // The color matrices are scaled so that D50 just reaches 1 in the camera space
+    fn color_matrix(&self, idx: usize) -> Result<Option<Matrix>, RawLoaderError> {
+        Ok(self
+            .matrix(&self.color_matrices[idx], self.channels, 3)?
+            .map(|m| {
+                let max = m
+                    .mulv(&xy_to_xyz(D50_XY))
+                    .iter()
+                    .fold(f64::MIN, |a, b| a.max(*b));
+                if max > 0.0 && !(0.99..=1.01).contains(&max) {
+                    m.scale(1.0 / max)
+                } else {
+                    m
+                }
+            }))
+    }
+
+    // The forward matrices are scaled so that the camera white maps to D50
+    fn forward_matrix(&self, idx: usize) -> Result<Option<Matrix>, RawLoaderError> {
+        Ok(self
+            .matrix(&self.forward_matrices[idx], 3, self.channels)?
+            .map(|m| {
+                let xyz = m.mulv(&vec![1.0; self.channels]);
+                let d50 = xy_to_xyz(D50_XY);
+                let gains: Vec<f64> = (0..3)
+                    .map(|i| if xyz[i] != 0.0 { d50[i] / xyz[i] } else { 1.0 })
+                    .collect();
+                Matrix::diagonal(&gains).mul(&m)
+            }))
+    }
+
+    fn calibration(&self, idx: usize) -> Result<Matrix, RawLoaderError> {
+        Ok(self
+            .matrix(&self.camera_calibrations[idx], self.channels, self.channels)?
+            .unwrap_or_else(|| Matrix::identity(self.channels)))
+    }
+
+    fn analog_balance(&self) -> Matrix {
+        if self.analog_balance.len() == self.channels {
+            Matrix::diagonal(
+                &self
+                    .analog_balance
+                    .iter()
+                    .map(|v| *v as f64)
+                    .collect::<Vec<f64>>(),
+            )
+        } else {
+            Matrix::identity(self.channels)
+        }
+    }
+
+    // How much of the first calibration to use for a given white, interpolating by the
+    // inverse of the temperature
+    pub(crate) fn weight(&self, white: (f64, f64)) -> f64 {
+        let t1 = illuminant_temperature(self.illuminants[0]);
+        let t2 = illuminant_temperature(self.illuminants[1]);
+        if t1 <= 0.0 || t2 <= 0.0 || t1 == t2 || self.color_matrices[1].is_empty() {
+            return 1.0;
+        }
+        let (temperature, _) = Self::xy_to_temperature(white.0, white.1);
+        // Work with the calibrations ordered by temperature
+        let (low, high, swapped) = if t1 < t2 {
+            (t1, t2, false)
+        } else {
+            (t2, t1, true)
+        };
+        let g = if temperature <= low {
+            1.0
+        } else if temperature >= high {
+            0.0
+        } else {
+            (1.0 / temperature - 1.0 / high) / (1.0 / low - 1.0 / high)
+        };
+        if swapped { 1.0 - g } else { g }
+    }
+
+    // Returns the XYZ to camera matrix for a given white, as well as the interpolated
+    // forward matrix and camera calibration
+    fn find_xyz_to_camera(
+        &self,
+        white: (f64, f64),
+    ) -> Result<(Matrix, Option<Matrix>, Matrix), RawLoaderError> {
+        let g = self.weight(white);
+        let cm1 = self
+            .color_matrix(0)?
+            .ok_or_else(|| RawLoaderError::corrupt("DNG: no color matrix"))?;
+        let (color, forward, calibration) = if g >= 1.0 {
+            (cm1, self.forward_matrix(0)?, self.calibration(0)?)
+        } else {
+            let cm2 = self
+                .color_matrix(1)?
+                .ok_or_else(|| RawLoaderError::corrupt("DNG: no second color matrix"))?;
+            let forward = match (self.forward_matrix(0)?, self.forward_matrix(1)?) {
+                (Some(f1), Some(f2)) => Some(f1.blend(&f2, g)),
+                (f1, f2) => f1.or(f2),
+            };
+            (
+                cm1.blend(&cm2, g),
+                forward,
+                self.calibration(0)?.blend(&self.calibration(1)?, g),
+            )
+        };
+        let xyz_to_camera = self.analog_balance().mul(&calibration).mul(&color);
+        Ok((xyz_to_camera, forward, calibration))
+    }
+
+    /// Finds the white chromaticity that matches a camera neutral, iterating as the
+    /// matrices to use depend on the white itself
+    pub fn neutral_to_xy(&self, neutral: &[f32]) -> Result<(f64, f64), RawLoaderError> {
+        if self.channels == 1 {
+            return Ok(D50_XY);
+        }
+        if neutral.len() != self.channels {
+            return Err(RawLoaderError::corrupt(
+                "DNG: neutral doesn't match the number of channels",
+            ));
+        }
+        let neutral: Vec<f64> = neutral.iter().map(|v| *v as f64).collect();
+        let passes = 30;
+        let mut last = D50_XY;
+        for pass in 0..passes {
+            let (xyz_to_camera, _, _) = self.find_xyz_to_camera(last)?;
+            let mut next = xyz_to_xy(&xyz_to_camera.invert()?.mulv(&neutral));
+            if (next.0 - last.0).abs() + (next.1 - last.1).abs() < 0.0000001 {
+                return Ok(next);
+            }
+            // Without convergence it's most likely oscillating between two values
+            if pass == passes - 1 {
+                next = ((last.0 + next.0) * 0.5, (last.1 + next.1) * 0.5);
+            }
+            last = next;
+        }
+        Ok(last)
+    }
+
+    /// Camera to XYZ(D50) matrix for a given white chromaticity, following the DNG spec.
+    /// The output has 3 rows and a column for each camera channel.
+    pub fn camera_to_xyz_d50_for_white(
+        &self,
+        white: (f64, f64),
+    ) -> Result<[[f32; 4]; 3], RawLoaderError> {
+        if self.channels == 0 || self.channels > 4 {
+            return Err(RawLoaderError::corrupt(format!(
+                "DNG: can't handle {} color channels",
+                self.channels
+            )));
+        }
+        let (xyz_to_camera, forward, calibration) = self.find_xyz_to_camera(white)?;
+
+        let camera_to_pcs = match forward {
+            Some(forward) => {
+                let mut camera_white = xyz_to_camera.mulv(&xy_to_xyz(white));
+                let max = camera_white.iter().fold(f64::MIN, |a, b| a.max(*b));
+                let scale = if max > 0.0 { 1.0 / max } else { 1.0 };
+                for val in camera_white.iter_mut() {
+                    *val = (*val * scale).clamp(0.001, 1.0);
+                }
+                let individual_to_reference = self.analog_balance().mul(&calibration).invert()?;
+                let reference_white = individual_to_reference.mulv(&camera_white);
+                let inv_white: Vec<f64> = reference_white
+                    .iter()
+                    .map(|v| if *v != 0.0 { 1.0 / v } else { 1.0 })
+                    .collect();
+                forward
+                    .mul(&Matrix::diagonal(&inv_white))
+                    .mul(&individual_to_reference)
+            }
+            None => {
+                // Scale so that D50 just reaches 1 when the first channel saturates
+                let pcs_to_camera = xyz_to_camera.mul(&map_white_matrix(D50_XY, white)?);
+                let scale = pcs_to_camera.mulv(&xy_to_xyz(D50_XY));
+                let max = scale.iter().fold(f64::MIN, |a, b| a.max(*b));
+                let pcs_to_camera = if max > 0.0 {
+                    pcs_to_camera.scale(1.0 / max)
+                } else {
+                    pcs_to_camera
+                };
+                pcs_to_camera.invert()?
+            }
+        };
+
+        let mut out = [[0f32; 4]; 3];
+        for (row, line) in out.iter_mut().enumerate() {
+            for (col, val) in line.iter_mut().enumerate().take(cmp::min(self.channels, 4)) {
+                *val = camera_to_pcs.at(row, col) as f32;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Camera to XYZ(D50) matrix for a given camera neutral (the inverse of the white
+    /// balance multipliers), interpolating the calibrations by color temperature
+    pub fn camera_to_xyz_d50(&self, neutral: &[f32]) -> Result<[[f32; 4]; 3], RawLoaderError> {
+        let white = self.neutral_to_xy(neutral)?;
+        self.camera_to_xyz_d50_for_white(white)
+    }
+
+    /// Camera to XYZ(D50) matrix for the white balance at the time of the shot, using
+    /// D50 when the file doesn't have one
+    pub fn as_shot_camera_to_xyz_d50(&self) -> Result<[[f32; 4]; 3], RawLoaderError> {
+        if self.as_shot_neutral.len() == self.channels {
+            self.camera_to_xyz_d50(&self.as_shot_neutral)
+        } else if let Some((x, y)) = self.as_shot_white_xy {
+            self.camera_to_xyz_d50_for_white((x as f64, y as f64))
+        } else {
+            self.camera_to_xyz_d50_for_white(D50_XY)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Standard A and D65 calibrations where the second matrix adds some X to Z
+    fn dual_illuminant() -> DngColor {
+        DngColor {
+            channels: 3,
+            illuminants: [17, 21],
+            color_matrices: [
+                vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+                vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.1, 0.0, 1.0],
+            ],
+            forward_matrices: [Vec::new(), Vec::new()],
+            camera_calibrations: [Vec::new(), Vec::new()],
+            analog_balance: Vec::new(),
+            as_shot_neutral: Vec::new(),
+            as_shot_white_xy: None,
+        }
+    }
+
+    #[test]
+    fn temperature_of_standard_whites() {
+        for (xy, cct) in [
+            ((0.44757, 0.40745), 2856.0),
+            ((0.3457, 0.3585), 5003.0),
+            ((0.3127, 0.3290), 6504.0),
+        ] {
+            let (temperature, tint) = DngColor::xy_to_temperature(xy.0, xy.1);
+            assert!(
+                (temperature - cct).abs() < 10.0,
+                "{} for {}",
+                temperature,
+                cct
+            );
+            assert!(tint.abs() < 15.0, "tint {} for {}", tint, cct);
+        }
+    }
+
+    #[test]
+    fn weight_by_inverse_temperature() {
+        let mut color = dual_illuminant();
+        let (temperature, _) = DngColor::xy_to_temperature(D50_XY.0, D50_XY.1);
+        let expected = (1.0 / temperature - 1.0 / 6500.0) / (1.0 / 2850.0 - 1.0 / 6500.0);
+        assert!((color.weight(D50_XY) - expected).abs() < 1e-9);
+        assert!((color.weight(D50_XY) - 0.2336).abs() < 1e-3);
+        // Whites past either calibration use only that one
+        assert_eq!(color.weight((0.46, 0.41)), 1.0);
+        assert_eq!(color.weight((0.3, 0.3)), 0.0);
+
+        // The calibrations can come in either order
+        color.illuminants = [21, 17];
+        color.color_matrices.swap(0, 1);
+        assert!((color.weight(D50_XY) - (1.0 - expected)).abs() < 1e-9);
+
+        // A single calibration is used as is
+        color.color_matrices[1].clear();
+        assert_eq!(color.weight(D50_XY), 1.0);
+    }
+
+    #[test]
+    fn interpolated_matrix_and_neutral() {
+        let color = dual_illuminant();
+        let g = color.weight(D50_XY);
+        let (xyz_to_camera, forward, _) = color.find_xyz_to_camera(D50_XY).unwrap();
+        assert!(forward.is_none());
+        assert!((xyz_to_camera.at(0, 0) - 1.0).abs() < 1e-9);
+        assert!((xyz_to_camera.at(2, 0) - 0.1 * (1.0 - g)).abs() < 1e-6);
+
+        // The neutral of a white finds that white back through the interpolation
+        let neutral: Vec<f32> = xyz_to_camera
+            .mulv(&xy_to_xyz(D50_XY))
+            .iter()
+            .map(|v| *v as f32)
+            .collect();
+        let (x, y) = color.neutral_to_xy(&neutral).unwrap();
+        assert!((x - D50_XY.0).abs() < 1e-4 && (y - D50_XY.1).abs() < 1e-4);
+    }
+
+    // Around 2000K, warmer than Standard A, and 10000K, cooler than D65
+    const WARM_XY: (f64, f64) = (0.5267, 0.4133);
+    const COOL_XY: (f64, f64) = (0.2807, 0.2883);
+
+    // The camera values of a white through the matrices used for that white
+    fn camera_white(color: &DngColor, white: (f64, f64)) -> Vec<f32> {
+        let (xyz_to_camera, _, _) = color.find_xyz_to_camera(white).unwrap();
+        xyz_to_camera
+            .mulv(&xy_to_xyz(white))
+            .iter()
+            .map(|v| *v as f32)
+            .collect()
+    }
+
+    #[test]
+    fn whites_past_the_calibrations_use_the_endpoints() {
+        let color = dual_illuminant();
+        for (white, weight) in [(WARM_XY, 1.0), (COOL_XY, 0.0)] {
+            // The search starts from D50, between the calibrations, and has to settle on
+            // the matrix of one of them
+            let (x, y) = color.neutral_to_xy(&camera_white(&color, white)).unwrap();
+            assert!((x - white.0).abs() < 1e-4 && (y - white.1).abs() < 1e-4);
+            assert_eq!(color.weight((x, y)), weight);
+        }
+    }
+
+    #[test]
+    fn forward_matrices_map_the_white_to_d50() {
+        let mut color = dual_illuminant();
+        color.forward_matrices = [
+            vec![0.6, 0.3, 0.1, 0.2, 0.7, 0.1, 0.0, 0.1, 0.7],
+            vec![0.7, 0.2, 0.1, 0.3, 0.6, 0.1, 0.0, 0.0, 0.8],
+        ];
+        let d50 = xy_to_xyz(D50_XY);
+        for white in [D50_XY, WARM_XY] {
+            let neutral = camera_white(&color, white);
+            let max = neutral.iter().fold(0f32, |a, b| a.max(*b));
+            let matrix = color.camera_to_xyz_d50_for_white(white).unwrap();
+            for (line, expected) in matrix.iter().zip(d50.iter()) {
+                let xyz: f64 = (0..3).map(|c| (line[c] * neutral[c] / max) as f64).sum();
+                assert!((xyz - expected).abs() < 1e-4, "{} for {}", xyz, expected);
+            }
+        }
+
+        // Past Standard A only the first forward matrix counts
+        let warm = color.camera_to_xyz_d50_for_white(WARM_XY).unwrap();
+        let between = color.camera_to_xyz_d50_for_white(D50_XY).unwrap();
+        color.forward_matrices[1] = vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+        assert_eq!(color.camera_to_xyz_d50_for_white(WARM_XY).unwrap(), warm);
+        assert_ne!(color.camera_to_xyz_d50_for_white(D50_XY).unwrap(), between);
+    }
+
+    #[test]
+    fn camera_calibrations_are_interpolated() {
+        let mut color = dual_illuminant();
+        color.camera_calibrations = [
+            vec![2.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+            vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+        ];
+        let g = color.weight(D50_XY);
+        let (xyz_to_camera, _, calibration) = color.find_xyz_to_camera(D50_XY).unwrap();
+        assert!((calibration.at(0, 0) - (1.0 + g)).abs() < 1e-9);
+        assert!((xyz_to_camera.at(0, 0) - (1.0 + g)).abs() < 1e-9);
+
+        // The neutral of this camera finds its white back, while without the calibration
+        // the same neutral reads as a redder white
+        let white = D50_XY;
+        let neutral = camera_white(&color, white);
+        let (x, y) = color.neutral_to_xy(&neutral).unwrap();
+        assert!((x - white.0).abs() < 1e-4 && (y - white.1).abs() < 1e-4);
+        let (x, _) = dual_illuminant().neutral_to_xy(&neutral).unwrap();
+        assert!(x > white.0 + 0.01);
+    }
+}
@@ -20,24 +20,34 @@ impl<'a> ErfDecoder<'a> {
 }
 
 impl<'a> Decoder for ErfDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
         let raw = fetch_ifd!(&self.tiff, Tag::CFAPattern);
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
         let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
         let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
 
-        let image = decode_12be_wcontrol(src, width, height, dummy);
+        let window = Window::current();
+        let image = decode_12be_wcontrol(src, width, height, window.as_ref(), dummy)?;
         ok_image(camera, width, height, self.get_wb()?, image)
+            .map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
 
 impl<'a> ErfDecoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         let levels = fetch_tag!(self.tiff, Tag::EpsonWB);
         if levels.count() != 256 {
-            Err("ERF: Levels count is off".to_string())
+            Err(RawLoaderError::corrupt("ERF: Levels count is off"))
         } else {
             let r = BEu16(levels.get_data(), 48) as f32;
             let b = BEu16(levels.get_data(), 50) as f32;
@@ -0,0 +1,120 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+static SAMPLE: &str = "\nPlease submit samples at https://raw.pixls.us/";
+static BUG: &str =
+    "\nPlease file a bug with a sample file at https://github.com/pedrocr/rawloader/issues/new";
+
+/// Error type for any reason for the decode to fail
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RawLoaderError {
+    /// reading the input failed
+    Io(io::Error),
+    /// the input isn't in any of the formats we know about
+    UnknownFormat(String),
+    /// the format is known but the camera isn't, so there's no way to process the file
+    UnsupportedCamera {
+        /// make as encoded in the file
+        make: String,
+        /// model as encoded in the file
+        model: String,
+        /// mode of the camera for formats that have several, empty otherwise
+        mode: String,
+    },
+    /// the file uses a compression or encoding variant we don't know how to decode
+    UnsupportedCompression {
+        /// short name of the format, like "NEF" or "DNG"
+        format: &'static str,
+        /// the compression, bit depth or encoding type found in the file
+        value: u32,
+    },
+    /// the file is inconsistent with its format, at a given offset when that's known
+    CorruptData {
+        /// what was wrong with the file
+        msg: String,
+        /// where in the file the problem was found, when that's known
+        offset: Option<usize>,
+    },
+    /// the file ends before all the data we need
+    Truncated(String),
+    /// the file asks for more than we're willing to allocate or process
+    LimitExceeded(String),
+    /// the call doesn't apply to this image, like asking for data it doesn't have
+    InvalidArgument(String),
+    /// the decoder hit a bug that was caught before it took the process down, only
+    /// possible when built with panic = "unwind"
+    InternalPanic,
+}
+
+impl RawLoaderError {
+    pub(crate) fn corrupt<S: Into<String>>(msg: S) -> RawLoaderError {
+        RawLoaderError::CorruptData {
+            msg: msg.into(),
+            offset: None,
+        }
+    }
+
+    pub(crate) fn corrupt_at<S: Into<String>>(msg: S, offset: usize) -> RawLoaderError {
+        RawLoaderError::CorruptData {
+            msg: msg.into(),
+            offset: Some(offset),
+        }
+    }
+
+    pub(crate) fn truncated<S: Into<String>>(msg: S) -> RawLoaderError {
+        RawLoaderError::Truncated(msg.into())
+    }
+
+    pub(crate) fn limit<S: Into<String>>(msg: S) -> RawLoaderError {
+        RawLoaderError::LimitExceeded(msg.into())
+    }
+
+    pub(crate) fn compression(format: &'static str, value: u32) -> RawLoaderError {
+        RawLoaderError::UnsupportedCompression { format, value }
+    }
+}
+
+impl fmt::Display for RawLoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RawLoaderError::Io(err) => write!(f, "IOError: {}", err),
+            RawLoaderError::UnknownFormat(msg) => write!(f, "{}{}", msg, SAMPLE),
+            RawLoaderError::UnsupportedCamera { make, model, mode } => write!(
+                f,
+                "Couldn't find camera \"{}\" \"{}\" mode \"{}\".{}",
+                make, model, mode, SAMPLE
+            ),
+            RawLoaderError::UnsupportedCompression { format, value } => write!(
+                f,
+                "{}: Don't know how to decode compression or encoding {}.{}",
+                format, value, SAMPLE
+            ),
+            RawLoaderError::CorruptData {
+                msg,
+                offset: Some(offset),
+            } => write!(f, "{} (at offset {})", msg, offset),
+            RawLoaderError::CorruptData { msg, offset: None } => write!(f, "{}", msg),
+            RawLoaderError::Truncated(msg) => write!(f, "{}", msg),
+            RawLoaderError::LimitExceeded(msg) => write!(f, "{}", msg),
+            RawLoaderError::InvalidArgument(msg) => write!(f, "{}", msg),
+            RawLoaderError::InternalPanic => write!(f, "Caught a panic while decoding.{}", BUG),
+        }
+    }
+}
+
+impl Error for RawLoaderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RawLoaderError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RawLoaderError {
+    fn from(err: io::Error) -> RawLoaderError {
+        RawLoaderError::Io(err)
+    }
+}
@@ -0,0 +1,249 @@
+use crate::decoders::tiff::*;
+
+/// Capture metadata of a raw file, from its EXIF or from the format's own header for
+/// the ones that don't use TIFF. Anything the file doesn't have is left as None.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Exif {
+    /// ISO speed of the capture
+    pub iso: Option<u32>,
+    /// exposure time in seconds
+    pub exposure_time: Option<f32>,
+    /// aperture as an f-number
+    pub fnumber: Option<f32>,
+    /// focal length in millimeters
+    pub focal_length: Option<f32>,
+    /// make of the lens
+    pub lens_make: Option<String>,
+    /// model of the lens
+    pub lens_model: Option<String>,
+    /// capture date and time as "YYYY:MM:DD HH:MM:SS" in the camera's local time
+    pub date_time_original: Option<String>,
+    /// fraction of a second of the capture time, as the digits after the decimal point
+    pub subsec_time_original: Option<String>,
+    /// offset of the camera's local time from UTC as "+HH:MM" or "-HH:MM"
+    pub offset_time_original: Option<String>,
+    /// photographer or owner of the camera
+    pub artist: Option<String>,
+    /// copyright notice
+    pub copyright: Option<String>,
+    /// serial number of the camera body
+    pub serial_number: Option<String>,
+    /// position the image was taken at
+    pub gps: Option<Gps>,
+}
+
+/// GPS position of a capture
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Gps {
+    /// latitude in degrees, negative for the southern hemisphere
+    pub latitude: f64,
+    /// longitude in degrees, negative west of Greenwich
+    pub longitude: f64,
+    /// altitude in meters, negative below sea level
+    pub altitude: Option<f64>,
+}
+
+fn string(tiff: &TiffIFD, tag: Tag) -> Option<String> {
+    let data = tiff.find_entry(tag)?.get_data();
+    let len = data.iter().position(|&x| x == 0).unwrap_or(data.len());
+    let val = String::from_utf8_lossy(&data[..len]).trim().to_string();
+    if val.is_empty() { None } else { Some(val) }
+}
+
+fn float(tiff: &TiffIFD, tag: Tag) -> Option<f32> {
+    let entry = tiff.find_entry(tag)?;
+    if entry.count() == 0 {
+        return None;
+    }
+    let val = entry.get_f32(0);
+    if val.is_finite() && val > 0.0 {
+        Some(val)
+    } else {
+        None
+    }
+}
+
+// The ISO speed of the capture from the EXIF tags of a TIFF tree
+pub(crate) fn iso(tiff: &TiffIFD) -> Option<u32> {
+    // Cameras that go past 65535 set ISOSpeedRatings to that and use the newer tag
+    match float(tiff, Tag::ISOSpeedRatings) {
+        Some(iso) if iso < 65535.0 => Some(iso as u32),
+        _ => float(tiff, Tag::RecommendedExposureIndex).map(|iso| iso as u32),
+    }
+}
+
+impl Exif {
+    /// Reads the EXIF tags from a TIFF tree, without the GPS position
+    pub(crate) fn new(tiff: &TiffIFD) -> Exif {
+        let iso = iso(tiff);
+        let mut serial_number =
+            string(tiff, Tag::BodySerialNumber).or_else(|| string(tiff, Tag::CameraSerialNumber));
+        if serial_number.is_none()
+            && string(tiff, Tag::Make).is_some_and(|make| make.starts_with("NIKON"))
+        {
+            serial_number = string(tiff, Tag::NefSerial);
+        }
+
+        Exif {
+            iso,
+            exposure_time: float(tiff, Tag::ExposureTime),
+            fnumber: float(tiff, Tag::FNumber),
+            focal_length: float(tiff, Tag::FocalLength),
+            lens_make: string(tiff, Tag::LensMake),
+            lens_model: string(tiff, Tag::LensModel),
+            date_time_original: string(tiff, Tag::DateTimeOriginal)
+                .or_else(|| string(tiff, Tag::DateTime)),
+            subsec_time_original: string(tiff, Tag::SubSecTimeOriginal),
+            offset_time_original: string(tiff, Tag::OffsetTimeOriginal),
+            artist: string(tiff, Tag::Artist),
+            copyright: string(tiff, Tag::Copyright),
+            serial_number,
+            gps: None,
+        }
+    }
+
+    /// Reads the EXIF tags from a TIFF tree including the GPS IFD. `buf` needs to be the
+    /// buffer the tree was parsed from.
+    pub(crate) fn from_tiff<'a>(buf: &'a [u8], tiff: &TiffIFD<'a>) -> Exif {
+        let mut exif = Exif::new(tiff);
+        exif.gps = tiff.gps_ifd(buf).and_then(|ifd| Gps::from_ifd(&ifd));
+        exif
+    }
+
+    /// Capture date and time from a unix timestamp in the camera's local time
+    pub(crate) fn set_timestamp(&mut self, secs: u64) {
+        let days = (secs / 86400) as i64;
+        let secs = secs % 86400;
+
+        // Days since the epoch to a gregorian date
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        self.date_time_original = Some(format!(
+            "{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        ));
+    }
+
+    /// Offset of the camera's local time from UTC in seconds
+    pub(crate) fn set_utc_offset(&mut self, offset: i64) {
+        let sign = if offset < 0 { '-' } else { '+' };
+        let mins = offset.abs() / 60;
+        self.offset_time_original = Some(format!("{}{:02}:{:02}", sign, mins / 60, mins % 60));
+    }
+}
+
+impl Gps {
+    /// Reads the position from a GPS IFD
+    pub(crate) fn from_ifd(ifd: &TiffIFD) -> Option<Gps> {
+        let degrees = |tag: GpsTag| -> Option<f64> {
+            let entry = ifd.find_gps_entry(tag)?;
+            if entry.count() < 3 {
+                return None;
+            }
+            let val = entry.get_f32(0) as f64
+                + entry.get_f32(1) as f64 / 60.0
+                + entry.get_f32(2) as f64 / 3600.0;
+            if val.is_finite() { Some(val) } else { None }
+        };
+        let sign = |tag: GpsTag, negative: u8| match ifd.find_gps_entry(tag) {
+            Some(entry) if entry.get_data().first() == Some(&negative) => -1.0,
+            _ => 1.0,
+        };
+
+        let latitude = degrees(GpsTag::Latitude)? * sign(GpsTag::LatitudeRef, b'S');
+        let longitude = degrees(GpsTag::Longitude)? * sign(GpsTag::LongitudeRef, b'W');
+        let altitude = ifd
+            .find_gps_entry(GpsTag::Altitude)
+            .map(|entry| entry.get_f32(0) as f64)
+            .filter(|alt| alt.is_finite())
+            .map(|alt| match ifd.find_gps_entry(GpsTag::AltitudeRef) {
+                Some(entry) if entry.get_data().first() == Some(&1) => -alt,
+                _ => alt,
+            });
+
+        Some(Gps {
+            latitude,
+            longitude,
+            altitude,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A little endian TIFF with IFD0 pointing to a GPS IFD at offset 26
+    fn gps_tiff(entries: &[(u16, u16, u32, [u8; 4])], data: &[u8]) -> Vec<u8> {
+        let mut buf = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        buf.extend_from_slice(&1u16.to_le_bytes());
+        for val in [0x8825u16, 4] {
+            buf.extend_from_slice(&val.to_le_bytes());
+        }
+        for val in [1u32, 26, 0] {
+            buf.extend_from_slice(&val.to_le_bytes());
+        }
+        buf.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for &(tag, typ, count, value) in entries {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&typ.to_le_bytes());
+            buf.extend_from_slice(&count.to_le_bytes());
+            buf.extend_from_slice(&value);
+        }
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn rationals(vals: &[(u32, u32)]) -> Vec<u8> {
+        vals.iter()
+            .flat_map(|&(num, den)| [num.to_le_bytes(), den.to_le_bytes()])
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn gps_position() {
+        let data = 26 + 2 + 6 * 12 + 4;
+        let offset = |pos: u32| (data + pos).to_le_bytes();
+        let mut values = rationals(&[(33, 1), (30, 1), (36, 1)]);
+        values.extend(rationals(&[(151, 1), (12, 1), (0, 1)]));
+        values.extend(rationals(&[(58, 2)]));
+        let buf = gps_tiff(
+            &[
+                (0x0001, 2, 2, *b"S\0\0\0"),
+                (0x0002, 5, 3, offset(0)),
+                (0x0003, 2, 2, *b"W\0\0\0"),
+                (0x0004, 5, 3, offset(24)),
+                (0x0005, 1, 1, [1, 0, 0, 0]),
+                (0x0006, 5, 1, offset(48)),
+            ],
+            &values,
+        );
+        let tiff = TiffIFD::new_file(&buf).unwrap();
+        let gps = Exif::from_tiff(&buf, &tiff).gps.unwrap();
+        assert!((gps.latitude + 33.51).abs() < 1e-6);
+        assert!((gps.longitude + 151.2).abs() < 1e-6);
+        assert_eq!(gps.altitude, Some(-29.0));
+    }
+
+    #[test]
+    fn gps_needs_both_coordinates() {
+        let buf = gps_tiff(&[(0x0001, 2, 2, *b"N\0\0\0")], &[]);
+        let tiff = TiffIFD::new_file(&buf).unwrap();
+        assert_eq!(Exif::from_tiff(&buf, &tiff).gps, None);
+    }
+}
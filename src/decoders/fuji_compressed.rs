@@ -0,0 +1,848 @@
+// Fuji's compressed RAF format. The image is split into vertical stripes that are coded
+// independently, six lines at a time, with each color kept in its own line buffers and
+// predicted from the lines above in a way similar to JPEG-LS. Lossy files change the
+// quantization at every six line block and use extra tables for flat areas.
+
+use rayon::prelude::*;
+
+use crate::decoders::RawLoaderError;
+use crate::decoders::basics::*;
+use crate::decoders::cfa::CFA;
+
+const R0: usize = 0;
+const R1: usize = 1;
+const R2: usize = 2;
+const R3: usize = 3;
+const R4: usize = 4;
+const G0: usize = 5;
+const G1: usize = 6;
+const G2: usize = 7;
+const G3: usize = 8;
+const G4: usize = 9;
+const G5: usize = 10;
+const G6: usize = 11;
+const G7: usize = 12;
+const B0: usize = 13;
+const B1: usize = 14;
+const B2: usize = 15;
+const B3: usize = 16;
+const B4: usize = 17;
+const LTOTAL: usize = 18;
+
+#[derive(Debug, Clone, Copy)]
+struct FujiHeader {
+    lossless: bool,
+    xtrans: bool,
+    raw_bits: u32,
+    raw_height: usize,
+    raw_width: usize,
+    block_size: usize,
+    blocks_in_row: usize,
+    total_lines: usize,
+}
+
+impl FujiHeader {
+    fn new(buf: &[u8]) -> Result<FujiHeader, RawLoaderError> {
+        if buf.len() < 16 || BEu16(buf, 0) != 0x4953 {
+            return Err(RawLoaderError::corrupt(
+                "RAF: invalid compressed header signature",
+            ));
+        }
+        let lossless = buf[2];
+        let raw_type = buf[3];
+        let hdr = FujiHeader {
+            lossless: lossless == 1,
+            xtrans: raw_type == 16,
+            raw_bits: buf[4] as u32,
+            raw_height: BEu16(buf, 5) as usize,
+            raw_width: BEu16(buf, 9) as usize,
+            block_size: BEu16(buf, 11) as usize,
+            blocks_in_row: buf[13] as usize,
+            total_lines: BEu16(buf, 14) as usize,
+        };
+        let raw_rounded_width = BEu16(buf, 7) as usize;
+
+        if lossless > 1
+            || (raw_type != 0 && raw_type != 16)
+            || (hdr.raw_bits != 12 && hdr.raw_bits != 14)
+            || hdr.raw_height == 0
+            || hdr.raw_height > 0x3000
+            || !hdr.raw_height.is_multiple_of(6)
+            || hdr.raw_width == 0
+            || hdr.raw_width > 0x3000
+            || hdr.block_size != 0x300
+            || raw_rounded_width > 0x3000
+            || raw_rounded_width < hdr.block_size
+            || !raw_rounded_width.is_multiple_of(hdr.block_size)
+            || raw_rounded_width - hdr.raw_width >= hdr.block_size
+            || hdr.blocks_in_row == 0
+            || hdr.blocks_in_row > 0x10
+            || hdr.blocks_in_row != raw_rounded_width / hdr.block_size
+            || hdr.total_lines == 0
+            || hdr.total_lines > 0x800
+            || hdr.total_lines != hdr.raw_height / 6
+        {
+            return Err(RawLoaderError::corrupt("RAF: invalid compressed header"));
+        }
+        Ok(hdr)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct QTable {
+    table: Vec<i8>,
+    raw_bits: u32,
+    total_values: i32,
+    max_grad: i32,
+    q_grad_mult: i32,
+    q_base: i32,
+}
+
+#[derive(Debug, Clone)]
+struct Params {
+    qt: [QTable; 4],
+    max_bits: u32,
+    max_value: i32,
+    line_width: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Grads {
+    grads: [(i32, i32); 41],
+    lossy: [[(i32, i32); 5]; 3],
+}
+
+fn log2ceil(val: i32) -> u32 {
+    if val <= 1 {
+        0
+    } else {
+        32 - ((val - 1) as u32).leading_zeros()
+    }
+}
+
+fn setup_qlut(max_value: i32, qp: [i32; 4]) -> Vec<i8> {
+    (-max_value..=max_value)
+        .map(|val| {
+            if val <= -qp[3] {
+                -4
+            } else if val <= -qp[2] {
+                -3
+            } else if val <= -qp[1] {
+                -2
+            } else if val < -qp[0] {
+                -1
+            } else if val <= qp[0] {
+                0
+            } else if val < qp[1] {
+                1
+            } else if val < qp[2] {
+                2
+            } else if val < qp[3] {
+                3
+            } else {
+                4
+            }
+        })
+        .collect()
+}
+
+impl Params {
+    fn new(hdr: &FujiHeader) -> Params {
+        let max_value = (1 << hdr.raw_bits) - 1;
+        let line_width = if hdr.xtrans {
+            hdr.block_size * 2 / 3
+        } else {
+            hdr.block_size / 2
+        };
+
+        // Tables used by lossy files for flat areas, with thresholds clipped to the range
+        let lossy_table = |max_grad: i32, points: [i32; 3]| -> QTable {
+            let qp1 = if max_value >= points[0] { points[0] } else { 1 };
+            let qp2 = if max_value >= points[1] {
+                points[1]
+            } else {
+                qp1
+            };
+            let qp3 = if max_value >= points[2] {
+                points[2]
+            } else {
+                qp2
+            };
+            let total_values = max_value + 1;
+            QTable {
+                table: setup_qlut(max_value, [0, qp1, qp2, qp3]),
+                raw_bits: log2ceil(total_values),
+                total_values,
+                max_grad,
+                q_grad_mult: 3,
+                q_base: 0,
+            }
+        };
+
+        let mut params = Params {
+            qt: [
+                lossy_table(0, [0x12, 0x43, 0x114]),
+                lossy_table(5, [0x12, 0x43, 0x114]),
+                lossy_table(6, [0x15, 0x48, 0x11B]),
+                lossy_table(7, [0x18, 0x4D, 0x122]),
+            ],
+            max_bits: 0,
+            max_value,
+            line_width,
+        };
+        params.init_main_qtable(0);
+        params
+    }
+
+    fn init_main_qtable(&mut self, q_base: i32) {
+        let max_value = self.max_value;
+        let mut qp = [
+            q_base,
+            3 * q_base + 0x12,
+            5 * q_base + 0x43,
+            7 * q_base + 0x114,
+        ];
+        if qp[1] > max_value || qp[1] < q_base + 1 {
+            qp[1] = q_base + 1;
+        }
+        if qp[2] < qp[1] || qp[2] > max_value {
+            qp[2] = qp[1];
+        }
+        if qp[3] < qp[2] || qp[3] > max_value {
+            qp[3] = qp[2];
+        }
+
+        let total_values = (max_value + 2 * q_base) / (2 * q_base + 1) + 1;
+        self.qt[0] = QTable {
+            table: setup_qlut(max_value, qp),
+            raw_bits: log2ceil(total_values),
+            total_values,
+            max_grad: 0,
+            q_grad_mult: 9,
+            q_base,
+        };
+        self.max_bits = 4 * log2ceil(max_value + 1);
+    }
+
+    fn q(&self, table: usize, diff: i32) -> i32 {
+        self.qt[table].table[(self.max_value + diff) as usize] as i32
+    }
+}
+
+fn bit_diff(value1: i32, value2: i32) -> u32 {
+    let mut bits = 0;
+    if value2 < value1 {
+        while bits <= 14 {
+            bits += 1;
+            if (value2 << bits) >= value1 {
+                break;
+            }
+        }
+    }
+    bits
+}
+
+struct StripDecoder<'a> {
+    params: Params,
+    pump: BitPumpMSB<'a>,
+    maxbits: usize,
+    xtrans: bool,
+    lines: Vec<u16>,
+    even: [Grads; 3],
+    odd: [Grads; 3],
+}
+
+impl<'a> StripDecoder<'a> {
+    fn new(params: &Params, data: &'a [u8], xtrans: bool) -> StripDecoder<'a> {
+        let mut decoder = StripDecoder {
+            params: params.clone(),
+            pump: BitPumpMSB::new(data),
+            maxbits: data.len() * 8,
+            xtrans,
+            lines: vec![0; LTOTAL * (params.line_width + 2)],
+            even: [Grads {
+                grads: [(0, 0); 41],
+                lossy: [[(0, 0); 5]; 3],
+            }; 3],
+            odd: [Grads {
+                grads: [(0, 0); 41],
+                lossy: [[(0, 0); 5]; 3],
+            }; 3],
+        };
+        decoder.reset_grads(true);
+        decoder
+    }
+
+    fn reset_grads(&mut self, lossy: bool) {
+        let max_diff = |qt: &QTable| (((qt.total_values + 0x20) >> 6).max(2), 1);
+        let main = max_diff(&self.params.qt[0]);
+        let tables = [
+            max_diff(&self.params.qt[1]),
+            max_diff(&self.params.qt[2]),
+            max_diff(&self.params.qt[3]),
+        ];
+        for grads in self.even.iter_mut().chain(self.odd.iter_mut()) {
+            grads.grads = [main; 41];
+            if lossy {
+                for (table, val) in grads.lossy.iter_mut().zip(tables.iter()) {
+                    *table = [*val; 5];
+                }
+            }
+        }
+    }
+
+    // Index of the first sample of a line buffer, there's a sample of padding on each side
+    fn base(&self, line: usize) -> usize {
+        line * (self.params.line_width + 2) + 1
+    }
+
+    fn zerobits(&mut self) -> Result<u32, RawLoaderError> {
+        let mut zeros = 0;
+        loop {
+            let bits = self.pump.peek_bits(32);
+            if bits != 0 {
+                let lz = bits.leading_zeros();
+                self.pump.consume_bits(lz + 1);
+                return Ok(zeros + lz);
+            }
+            self.pump.consume_bits(32);
+            zeros += 32;
+            if zeros as usize > self.maxbits {
+                return Err(RawLoaderError::truncated("RAF: ran out of compressed data"));
+            }
+        }
+    }
+
+    fn select_table(&self, diff: i32) -> usize {
+        let q_base = self.params.qt[0].q_base;
+        (1..4)
+            .take_while(|i| q_base >= *i as i32)
+            .find(|i| diff <= self.params.qt[*i].max_grad)
+            .unwrap_or(0)
+    }
+
+    fn decode_value(
+        &mut self,
+        table: usize,
+        grad: i32,
+        set: usize,
+        even: bool,
+    ) -> Result<i32, RawLoaderError> {
+        let gradient = grad.unsigned_abs() as usize;
+        let raw_bits = self.params.qt[table].raw_bits;
+        let sample = self.zerobits()?;
+        let grads = if even {
+            &mut self.even[set]
+        } else {
+            &mut self.odd[set]
+        };
+        let g = if table == 0 {
+            &mut grads.grads[gradient]
+        } else {
+            &mut grads.lossy[table - 1][gradient]
+        };
+
+        let code = if sample + raw_bits + 1 < self.params.max_bits {
+            let bits = bit_diff(g.0, g.1);
+            ((sample << bits) + self.pump.get_bits(bits)) as i32
+        } else {
+            self.pump.get_bits(raw_bits) as i32 + 1
+        };
+        let code = if code & 1 != 0 {
+            -1 - code / 2
+        } else {
+            code / 2
+        };
+
+        g.0 += code.abs();
+        if g.1 == 0x40 {
+            g.0 >>= 1;
+            g.1 >>= 1;
+        }
+        g.1 += 1;
+
+        Ok(code)
+    }
+
+    fn store(&mut self, pos: usize, table: usize, grad: i32, interp: i32, code: i32) {
+        let qt = &self.params.qt[table];
+        let step = 2 * qt.q_base + 1;
+        let mut val = if grad < 0 {
+            interp - code * step
+        } else {
+            interp + code * step
+        };
+        if val < -qt.q_base {
+            val += qt.total_values * step;
+        } else if val > qt.q_base + self.params.max_value {
+            val -= qt.total_values * step;
+        }
+        self.lines[pos] = val.clamp(0, self.params.max_value) as u16;
+    }
+
+    fn decode_sample_even(
+        &mut self,
+        line: usize,
+        pos: usize,
+        set: usize,
+    ) -> Result<(), RawLoaderError> {
+        let lw = self.params.line_width;
+        let cur = self.base(line) + pos;
+        let rb = self.lines[cur - 2 - lw] as i32;
+        let rc = self.lines[cur - 3 - lw] as i32;
+        let rd = self.lines[cur - 1 - lw] as i32;
+        let rf = self.lines[cur - 4 - 2 * lw] as i32;
+
+        let diff_rc_rb = (rc - rb).abs();
+        let diff_rf_rb = (rf - rb).abs();
+        let diff_rd_rb = (rd - rb).abs();
+
+        let table = self.select_table(diff_rf_rb + diff_rc_rb);
+        let grad = self.params.qt[table].q_grad_mult * self.params.q(table, rb - rf)
+            + self.params.q(table, rc - rb);
+
+        let interp = if diff_rc_rb > diff_rf_rb && diff_rc_rb > diff_rd_rb {
+            rf + rd + 2 * rb
+        } else if diff_rd_rb > diff_rc_rb && diff_rd_rb > diff_rf_rb {
+            rf + rc + 2 * rb
+        } else {
+            rd + rc + 2 * rb
+        };
+
+        let code = self.decode_value(table, grad, set, true)?;
+        self.store(cur, table, grad, interp >> 2, code);
+        Ok(())
+    }
+
+    fn decode_sample_odd(
+        &mut self,
+        line: usize,
+        pos: usize,
+        set: usize,
+    ) -> Result<(), RawLoaderError> {
+        let lw = self.params.line_width;
+        let cur = self.base(line) + pos;
+        let ra = self.lines[cur - 1] as i32;
+        let rb = self.lines[cur - 2 - lw] as i32;
+        let rc = self.lines[cur - 3 - lw] as i32;
+        let rd = self.lines[cur - 1 - lw] as i32;
+        let rg = self.lines[cur + 1] as i32;
+
+        let table = self.select_table((rb - rc).abs() + (rc - ra).abs());
+        let grad = self.params.qt[table].q_grad_mult * self.params.q(table, rb - rc)
+            + self.params.q(table, rc - ra);
+
+        let interp = if (rb > rc && rb > rd) || (rb < rc && rb < rd) {
+            (rg + ra + 2 * rb) >> 2
+        } else {
+            (ra + rg) >> 1
+        };
+
+        let code = self.decode_value(table, grad, set, false)?;
+        self.store(cur, table, grad, interp, code);
+        Ok(())
+    }
+
+    // X-Trans files don't code some of the samples, they get interpolated from above
+    fn interpolate_even(&mut self, line: usize, pos: usize) {
+        let lw = self.params.line_width;
+        let cur = self.base(line) + pos;
+        let rb = self.lines[cur - 2 - lw] as i32;
+        let rc = self.lines[cur - 3 - lw] as i32;
+        let rd = self.lines[cur - 1 - lw] as i32;
+        let rf = self.lines[cur - 4 - 2 * lw] as i32;
+
+        let diff_rc_rb = (rc - rb).abs();
+        let diff_rf_rb = (rf - rb).abs();
+        let diff_rd_rb = (rd - rb).abs();
+
+        self.lines[cur] = (if diff_rc_rb > diff_rf_rb && diff_rc_rb > diff_rd_rb {
+            (rf + rd + 2 * rb) >> 2
+        } else if diff_rd_rb > diff_rc_rb && diff_rd_rb > diff_rf_rb {
+            (rf + rc + 2 * rb) >> 2
+        } else {
+            (rd + rc + 2 * rb) >> 2
+        }) as u16;
+    }
+
+    // Decodes two lines together, with the odd samples trailing the even ones. The
+    // interpolation functions say which even positions aren't coded in X-Trans files.
+    fn decode_pass(
+        &mut self,
+        lines: [usize; 2],
+        set: usize,
+        interpolated: [fn(usize) -> bool; 2],
+    ) -> Result<(), RawLoaderError> {
+        let lw = self.params.line_width;
+        let mut even = 0;
+        let mut odd = 1;
+        while even < lw || odd < lw {
+            if even < lw {
+                for (line, interp) in lines.iter().zip(interpolated.iter()) {
+                    if self.xtrans && interp(even) {
+                        self.interpolate_even(*line, even);
+                    } else {
+                        self.decode_sample_even(*line, even, set)?;
+                    }
+                }
+                even += 2;
+            }
+            if even > 8 {
+                for line in lines.iter() {
+                    self.decode_sample_odd(*line, odd, set)?;
+                }
+                odd += 2;
+            }
+        }
+        Ok(())
+    }
+
+    fn extend(&mut self, start: usize, end: usize) {
+        let lw = self.params.line_width;
+        for line in start..=end {
+            let cur = self.base(line);
+            let prev = self.base(line - 1);
+            self.lines[cur - 1] = self.lines[prev];
+            self.lines[cur + lw] = self.lines[prev + lw - 1];
+        }
+    }
+
+    fn decode_block(&mut self) -> Result<(), RawLoaderError> {
+        let never: fn(usize) -> bool = |_| false;
+        let always: fn(usize) -> bool = |_| true;
+        let at0: fn(usize) -> bool = |pos| pos & 3 == 0;
+        let at2: fn(usize) -> bool = |pos| pos & 3 == 2;
+
+        self.decode_pass([R2, G2], 0, [never, never])?;
+        self.extend(R2, R4);
+        self.extend(G2, G7);
+        self.decode_pass([G3, B2], 1, [never, never])?;
+        self.extend(G2, G7);
+        self.extend(B2, B4);
+        self.decode_pass([R3, G4], 2, [at0, always])?;
+        self.extend(R2, R4);
+        self.extend(G2, G7);
+        self.decode_pass([G5, B3], 0, [never, at2])?;
+        self.extend(G2, G7);
+        self.extend(B2, B4);
+        self.decode_pass([R4, G6], 1, [at2, never])?;
+        self.extend(R2, R4);
+        self.extend(G2, G7);
+        self.decode_pass([G7, B4], 2, [always, at0])?;
+        self.extend(G2, G7);
+        self.extend(B2, B4);
+        Ok(())
+    }
+
+    // Moves the last lines of every color up to serve as context for the next block
+    fn advance(&mut self) {
+        let size = self.params.line_width + 2;
+        for (to, from) in [(R0, R3), (R1, R4), (G0, G6), (G1, G7), (B0, B3), (B1, B4)] {
+            self.lines
+                .copy_within(from * size..(from + 1) * size, to * size);
+        }
+        for (start, count) in [(R2, 3), (G2, 6), (B2, 3)] {
+            self.lines[start * size..(start + count) * size].fill(0);
+            let cur = self.base(start);
+            let prev = self.base(start - 1);
+            self.lines[cur - 1] = self.lines[prev];
+            self.lines[cur + size - 2] = self.lines[prev + size - 3];
+        }
+    }
+
+    // Value at row (0-5) and column of the current block given the color there
+    fn sample(&self, color: usize, row: usize, col: usize) -> u16 {
+        let (line, pos) = if self.xtrans {
+            let pos = (((col * 2 / 3) & !1) | ((col % 3) & 1)) + ((col % 3) >> 1);
+            match color {
+                0 => (R2 + (row >> 1), pos),
+                2 => (B2 + (row >> 1), pos),
+                _ => (G2 + row, pos),
+            }
+        } else {
+            match color {
+                0 => (R2 + (row >> 1), col >> 1),
+                2 => (B2 + (row >> 1), col >> 1),
+                _ => (G2 + row, col >> 1),
+            }
+        };
+        self.lines[self.base(line) + pos]
+    }
+}
+
+fn decode_strip(
+    hdr: &FujiHeader,
+    params: &Params,
+    cfa: &CFA,
+    data: &[u8],
+    q_bases: Option<&[u8]>,
+    strip: usize,
+    width: usize,
+) -> Result<Vec<u16>, RawLoaderError> {
+    let mut decoder = StripDecoder::new(params, data, hdr.xtrans);
+    let height = hdr.total_lines * 6;
+    let mut out = vec![0u16; width * height];
+    let col_offset = strip * hdr.block_size;
+
+    for line in 0..hdr.total_lines {
+        if let Some(q_bases) = q_bases
+            && (line == 0 || q_bases[line] != q_bases[line - 1])
+        {
+            decoder.params.init_main_qtable(q_bases[line] as i32);
+            decoder.reset_grads(false);
+        }
+
+        decoder.decode_block()?;
+
+        for row in 0..6 {
+            let out_row = line * 6 + row;
+            for col in 0..width {
+                let color = cfa.color_at(out_row, col_offset + col);
+                out[out_row * width + col] = decoder.sample(color, row, col);
+            }
+        }
+
+        decoder.advance();
+    }
+    Ok(out)
+}
+
+pub fn decode_fuji_compressed(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    cfa: &CFA,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    let hdr = FujiHeader::new(buf)?;
+    if hdr.raw_width != width || hdr.raw_height != height {
+        return Err(RawLoaderError::corrupt(format!(
+            "RAF: compressed size {}x{} doesn't match the image size {}x{}",
+            hdr.raw_width, hdr.raw_height, width, height
+        )));
+    }
+    if dummy {
+        return Ok(alloc_image!(width, height, dummy));
+    }
+    let params = Params::new(&hdr);
+
+    let sizes_start = 16;
+    if buf.len() < sizes_start + hdr.blocks_in_row * 4 {
+        return Err(RawLoaderError::truncated(
+            "RAF: truncated compressed header",
+        ));
+    }
+    let mut offset = hdr.blocks_in_row * 4;
+    if offset & 0xC != 0 {
+        offset += 0x10 - (offset & 0xC);
+    }
+    offset += sizes_start;
+
+    let q_step = (hdr.total_lines + 0xF) & !0xF;
+    let q_bases = if hdr.lossless {
+        None
+    } else {
+        let size = hdr.blocks_in_row * q_step;
+        if buf.len() < offset + size {
+            return Err(RawLoaderError::truncated(
+                "RAF: truncated lossy quantization data",
+            ));
+        }
+        let q_bases = &buf[offset..offset + size];
+        offset += size;
+        Some(q_bases)
+    };
+
+    let mut strips = Vec::with_capacity(hdr.blocks_in_row);
+    for strip in 0..hdr.blocks_in_row {
+        let size = BEu32(buf, sizes_start + strip * 4) as usize;
+        if offset + size > buf.len() {
+            return Err(RawLoaderError::truncated(
+                "RAF: compressed strip past the end of the file",
+            ));
+        }
+        let strip_width = if strip + 1 == hdr.blocks_in_row {
+            width - hdr.block_size * strip
+        } else {
+            hdr.block_size
+        };
+        strips.push((offset, strip_width, q_bases.map(|q| &q[strip * q_step..])));
+        offset += size;
+    }
+
+    let decoded = strips
+        .par_iter()
+        .enumerate()
+        .map(|(strip, (offset, strip_width, q_bases))| {
+            decode_strip(
+                &hdr,
+                &params,
+                cfa,
+                &buf[*offset..],
+                *q_bases,
+                strip,
+                *strip_width,
+            )
+        })
+        .collect::<Result<Vec<Vec<u16>>, RawLoaderError>>()?;
+
+    decode_threaded_multiline(
+        width,
+        height,
+        6,
+        None,
+        dummy,
+        &(|out: &mut [u16], row| {
+            for (i, line) in out.chunks_exact_mut(width).enumerate() {
+                let row = row + i;
+                let mut col = 0;
+                for (strip, (_, strip_width, _)) in decoded.iter().zip(strips.iter()) {
+                    line[col..col + strip_width]
+                        .copy_from_slice(&strip[row * strip_width..(row + 1) * strip_width]);
+                    col += strip_width;
+                }
+            }
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The header of a 14 bit lossless X-Trans file of 6048x4032 in eight stripes
+    const HEADER: [u8; 16] = [
+        0x49, 0x53, 1, 16, 14, 0x0f, 0xc0, 0x18, 0x00, 0x17, 0xa0, 0x03, 0x00, 8, 0x02, 0xa0,
+    ];
+
+    // Packs a string of 0s and 1s most significant bit first, with room for the pump
+    fn bits(s: &str) -> Vec<u8> {
+        let bits: Vec<u8> = s.bytes().filter(|b| *b != b' ').map(|b| b - b'0').collect();
+        let mut out: Vec<u8> = bits
+            .chunks(8)
+            .map(|c| {
+                c.iter()
+                    .enumerate()
+                    .fold(0, |acc, (i, b)| acc | b << (7 - i))
+            })
+            .collect();
+        out.extend_from_slice(&[0; 8]);
+        out
+    }
+
+    #[test]
+    fn header() {
+        let hdr = FujiHeader::new(&HEADER).unwrap();
+        assert!(hdr.lossless && hdr.xtrans);
+        assert_eq!(hdr.raw_bits, 14);
+        assert_eq!((hdr.raw_width, hdr.raw_height), (6048, 4032));
+        assert_eq!(
+            (hdr.block_size, hdr.blocks_in_row, hdr.total_lines),
+            (0x300, 8, 672)
+        );
+
+        for (pos, val) in [(0, 0x4a), (4, 16), (9, 0x14), (13, 7), (15, 0xa1)] {
+            let mut bad = HEADER;
+            bad[pos] = val;
+            assert!(
+                FujiHeader::new(&bad).is_err(),
+                "byte {} set to {}",
+                pos,
+                val
+            );
+        }
+    }
+
+    #[test]
+    fn quantization_tables() {
+        let params = Params::new(&FujiHeader::new(&HEADER).unwrap());
+        assert_eq!(params.line_width, 512);
+        assert_eq!(params.max_bits, 56);
+        assert_eq!(
+            (params.qt[0].total_values, params.qt[0].raw_bits),
+            (0x4000, 14)
+        );
+        for (diff, q) in [
+            (0, 0),
+            (1, 1),
+            (0x11, 1),
+            (0x12, 2),
+            (0x42, 2),
+            (0x43, 3),
+            (0x113, 3),
+            (0x114, 4),
+            (-1, -1),
+            (-0x11, -1),
+            (-0x12, -2),
+            (-0x43, -3),
+            (-0x114, -4),
+        ] {
+            assert_eq!(params.q(0, diff), q, "q of {}", diff);
+        }
+
+        // Lossy blocks widen the thresholds and shrink the range with the base
+        let mut params = params;
+        params.init_main_qtable(2);
+        assert_eq!(
+            (params.qt[0].total_values, params.qt[0].raw_bits),
+            (3278, 12)
+        );
+        assert_eq!(params.q(0, 2), 0);
+        assert_eq!(params.q(0, 3), 1);
+        assert_eq!(params.q(0, 0x18), 2);
+        assert_eq!(params.q(0, -0x122), -4);
+    }
+
+    #[test]
+    fn gradient_bits() {
+        assert_eq!(bit_diff(1, 1), 0);
+        assert_eq!(bit_diff(256, 1), 8);
+        assert_eq!(bit_diff(257, 1), 9);
+        assert_eq!(bit_diff(5, 2), 2);
+        assert_eq!(bit_diff(1 << 20, 1), 15);
+    }
+
+    #[test]
+    fn coded_values() {
+        let params = Params::new(&FujiHeader::new(&HEADER).unwrap());
+        // The gradient starts at 256 over 1 so the first value has 8 bits after the zeros,
+        // the second as 259 over 2 still has 8 and the last is an escaped raw value
+        let data = bits(&format!(
+            "1 00000101 001 00000100 {}1 {:014b}",
+            "0".repeat(41),
+            99
+        ));
+        let mut decoder = StripDecoder::new(&params, &data, true);
+        assert_eq!(decoder.decode_value(0, 0, 0, true).unwrap(), -3);
+        assert_eq!(decoder.even[0].grads[0], (259, 2));
+        assert_eq!(decoder.decode_value(0, 0, 0, true).unwrap(), 258);
+        assert_eq!(decoder.even[0].grads[0], (517, 3));
+        assert_eq!(decoder.decode_value(0, 0, 0, true).unwrap(), 50);
+        // The odd samples keep their own gradients
+        assert_eq!(decoder.odd[0].grads[0], (256, 1));
+    }
+
+    #[test]
+    fn stored_values_wrap_around() {
+        let params = Params::new(&FujiHeader::new(&HEADER).unwrap());
+        let mut decoder = StripDecoder::new(&params, &[], true);
+        decoder.store(0, 0, 1, 16380, 10);
+        assert_eq!(decoder.lines[0], 6);
+        decoder.store(0, 0, -1, 16380, 10);
+        assert_eq!(decoder.lines[0], 16370);
+        decoder.store(0, 0, 1, 5, -10);
+        assert_eq!(decoder.lines[0], 16379);
+    }
+
+    #[test]
+    fn truncated_stripes() {
+        let mut buf = HEADER.to_vec();
+        for _ in 0..8 {
+            buf.extend_from_slice(&16u32.to_be_bytes());
+        }
+        let cfa = CFA::new("");
+        assert!(decode_fuji_compressed(&buf, 6048, 4032, &cfa, false).is_err());
+        buf.resize(buf.len() + 8 * 16, 0);
+        assert!(decode_fuji_compressed(&buf, 6048, 4032, &cfa, false).is_err());
+    }
+}
@@ -20,7 +20,7 @@ impl<'a> IiqDecoder<'a> {
 }
 
 impl<'a> Decoder for IiqDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
 
         let off = LEu32(self.buffer, 16) as usize + 8;
@@ -49,11 +49,13 @@ impl<'a> Decoder for IiqDecoder<'a> {
         }
 
         if width == 0 || height == 0 {
-            return Err("IIQ: couldn't find width and height".to_string());
+            return Err(RawLoaderError::corrupt(
+                "IIQ: couldn't find width and height",
+            ));
         }
 
         let image =
-            Self::decode_compressed(self.buffer, data_offset, strip_offset, width, height, dummy);
+            Self::decode_compressed(self.buffer, data_offset, strip_offset, width, height, dummy)?;
 
         ok_image_with_blacklevels(
             camera,
@@ -64,10 +66,18 @@ impl<'a> Decoder for IiqDecoder<'a> {
             image,
         )
     }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
+    }
 }
 
 impl<'a> IiqDecoder<'a> {
-    fn get_wb(&self, wb_offset: usize) -> Result<[f32; 4], String> {
+    fn get_wb(&self, wb_offset: usize) -> Result<[f32; 4], RawLoaderError> {
         Ok([
             LEf32(self.buffer, wb_offset),
             LEf32(self.buffer, wb_offset + 4),
@@ -83,17 +93,18 @@ impl<'a> IiqDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Vec<u16> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let lens: [u32; 10] = [8, 7, 6, 9, 11, 10, 5, 12, 14, 13];
 
         decode_threaded(
             width,
             height,
+            None,
             dummy,
             &(|out: &mut [u16], row| {
                 let offset = data_offset + LEu32(buffer, strip_offset + row * 4) as usize;
 
-                let mut pump = BitPumpMSB32::new(&buffer[offset..]);
+                let mut pump = BitPumpMSB32::new(from_offset(buffer, offset));
                 let mut pred = [0_u32; 2];
                 let mut len = [0_u32; 2];
 
@@ -118,7 +129,9 @@ impl<'a> IiqDecoder<'a> {
                     pred[col & 1] = if i == 14 {
                         pump.get_bits(16)
                     } else {
-                        pred[col & 1] + pump.get_bits(i) + 1 - (1 << (i - 1))
+                        pred[col & 1]
+                            .wrapping_add(pump.get_bits(i) + 1)
+                            .wrapping_sub(1 << (i - 1))
                     };
 
                     pixout[0] = pred[col & 1] as u16;
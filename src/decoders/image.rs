@@ -1,5 +1,12 @@
 use crate::decoders::cfa::*;
+use crate::decoders::dcp::*;
+use crate::decoders::dngcolor::*;
+use crate::decoders::exif::*;
+use crate::decoders::opcodes::*;
 use crate::decoders::*;
+use rayon::prelude::*;
+use std::cmp;
+use std::ops::Range;
 
 /// All the data needed to process this raw image, including the image data itself as well
 /// as all the needed metadata
@@ -21,12 +28,22 @@ pub struct RawImage {
     pub cpp: usize,
     /// whitebalance coefficients encoded in the file in RGBE order
     pub wb_coeffs: [f32; 4],
-    /// image whitelevels in RGBE order
+    /// image whitelevels in RGBE order. For `RawImageData::Float` images the levels are in
+    /// the scale of the samples themselves, usually with a white of 1
     pub whitelevels: [u16; 4],
-    /// image blacklevels in RGBE order
+    /// image blacklevels in RGBE order. When the file has a more complex black level
+    /// these are its averages for each color, with the full model in `black`
     pub blacklevels: [u16; 4],
+    /// the full black level model for formats that have one (DNG) or when it was measured
+    /// per CFA position from the masked areas of the sensor. Use `black_at()` to get the
+    /// black level of a given pixel whatever the format
+    pub black: Option<BlackLevel>,
     /// matrix to convert XYZ to camera RGBE
     pub xyz_to_cam: [[f32; 3]; 4],
+    /// the full DNG color calibration for both illuminants, when the file has one
+    pub dng_color: Option<DngColor>,
+    /// camera profiles embedded in the file, the main one first
+    pub profiles: Vec<CameraProfile>,
     /// color filter array
     pub cfa: CFA,
     /// how much to crop the image to get all the usable area, order is top, right, bottom, left
@@ -38,10 +55,62 @@ pub struct RawImage {
 
     /// orientation of the image as indicated by the image metadata
     pub orientation: Orientation,
+    /// capture metadata like exposure, lens, date and GPS position
+    pub exif: Exif,
+    /// DNG opcode lists 1, 2 and 3 in that order, empty for other formats. They can be
+    /// applied with `apply_opcodes()` and `DngOpcode::apply_list()`
+    pub opcodes: [Vec<DngOpcode>; 3],
+    /// DNG linearization table that still has to be applied to the data. Files with an
+    /// OpcodeList1 keep it here, as that list works on the stored values, and
+    /// `apply_opcodes(1)` applies it right after the list
+    pub linearization: Option<Vec<u16>>,
     /// image data itself, has `width`\*`height`\*`cpp` elements
     pub data: RawImageData,
 }
 
+/// The metadata of a raw image without its pixels, as returned by `decode_metadata()`.
+/// The fields mean the same as in `RawImage`
+#[derive(Debug, Clone)]
+pub struct RawMetadata {
+    /// camera make as encoded in the file
+    pub make: String,
+    /// camera model as encoded in the file
+    pub model: String,
+    /// make cleaned up to be consistent and short
+    pub clean_make: String,
+    /// model cleaned up to be consistent and short
+    pub clean_model: String,
+    /// width of the full image
+    pub width: usize,
+    /// height of the full image
+    pub height: usize,
+    /// number of components per pixel (1 for bayer, 3 for RGB images)
+    pub cpp: usize,
+    /// whitebalance coefficients encoded in the file in RGBE order
+    pub wb_coeffs: [f32; 4],
+    /// image whitelevels in RGBE order
+    pub whitelevels: [u16; 4],
+    /// image blacklevels in RGBE order. Measuring the masked areas needs the pixels so
+    /// for cameras that do that these are the ones from the camera database instead
+    pub blacklevels: [u16; 4],
+    /// the full black level model for formats that have one (currently DNG)
+    pub black: Option<BlackLevel>,
+    /// matrix to convert XYZ to camera RGBE
+    pub xyz_to_cam: [[f32; 3]; 4],
+    /// the full DNG color calibration for both illuminants, when the file has one
+    pub dng_color: Option<DngColor>,
+    /// color filter array
+    pub cfa: CFA,
+    /// how much to crop the image to get all the usable area, order is top, right, bottom, left
+    pub crops: [usize; 4],
+    /// masked areas of the sensor as top, right, bottom, left
+    pub blackareas: Vec<(u64, u64, u64, u64)>,
+    /// orientation of the image as indicated by the image metadata
+    pub orientation: Orientation,
+    /// capture metadata like exposure, lens, date and GPS position
+    pub exif: Exif,
+}
+
 /// The actual image data, after decoding
 #[derive(Debug, Clone)]
 pub enum RawImageData {
@@ -51,7 +120,136 @@ pub enum RawImageData {
     Float(Vec<f32>),
 }
 
+/// A black level that repeats in a pattern over the image and can have per row and per
+/// column variations, as described by the DNG BlackLevel, BlackLevelRepeatDim,
+/// BlackLevelDeltaH and BlackLevelDeltaV tags
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlackLevel {
+    /// row and column where the pattern and the deltas start, usually the top left corner
+    /// of the active area
+    pub origin: (usize, usize),
+    /// size of the repeating pattern as rows and columns
+    pub repeat: (usize, usize),
+    /// number of samples per pixel in the pattern
+    pub cpp: usize,
+    /// the black levels of the pattern ordered by row, column and then sample
+    pub levels: Vec<f32>,
+    /// an extra black level for each column starting at the origin
+    pub delta_h: Vec<f32>,
+    /// an extra black level for each row starting at the origin
+    pub delta_v: Vec<f32>,
+}
+
+impl BlackLevel {
+    /// Black level of a given sample of a pixel, with the row and column relative to the
+    /// full image
+    pub fn at(&self, row: usize, col: usize, sample: usize) -> f32 {
+        let row = row.saturating_sub(self.origin.0);
+        let col = col.saturating_sub(self.origin.1);
+        let pos = ((row % self.repeat.0) * self.repeat.1 + col % self.repeat.1) * self.cpp
+            + sample % self.cpp;
+        self.levels.get(pos).copied().unwrap_or(0.0)
+            + self.delta_h.get(col).copied().unwrap_or(0.0)
+            + self.delta_v.get(row).copied().unwrap_or(0.0)
+    }
+
+    /// Averages the black level for each of the four colors of a CFA, or for each sample
+    /// when there's no CFA
+    pub fn averages(&self, cfa: &CFA) -> [f32; 4] {
+        let mut sums = [0.0f64; 4];
+        let mut counts = [0usize; 4];
+        let mosaic = self.cpp == 1 && cfa.is_valid();
+        let (rows, cols) = if mosaic {
+            (self.repeat.0 * cfa.height, self.repeat.1 * cfa.width)
+        } else {
+            self.repeat
+        };
+        for row in 0..rows {
+            for col in 0..cols {
+                let (r, c) = (row + self.origin.0, col + self.origin.1);
+                for sample in 0..self.cpp {
+                    let color = if mosaic { cfa.color_at(r, c) } else { sample };
+                    if color < 4 {
+                        let pos = ((row % self.repeat.0) * self.repeat.1 + col % self.repeat.1)
+                            * self.cpp
+                            + sample;
+                        sums[color] += self.levels.get(pos).copied().unwrap_or(0.0) as f64;
+                        counts[color] += 1;
+                    }
+                }
+            }
+        }
+        let mean = |v: &[f32]| {
+            if v.is_empty() {
+                0.0
+            } else {
+                v.iter().map(|x| *x as f64).sum::<f64>() / v.len() as f64
+            }
+        };
+        let deltas = mean(&self.delta_h) + mean(&self.delta_v);
+        let mut out = [0.0; 4];
+        for i in 0..4 {
+            if counts[i] > 0 {
+                out[i] = (sums[i] / counts[i] as f64 + deltas) as f32;
+            }
+        }
+        // Without a CFA or with a single sample every color gets the same level
+        if !mosaic && self.cpp == 1 {
+            out = [out[0]; 4];
+        }
+        out
+    }
+
+    // Move the origin after the image was cropped at top/left, rotating the pattern and
+    // dropping the deltas when the crop goes past the origin
+    fn crop(&mut self, top: usize, left: usize) {
+        let rows = top.saturating_sub(self.origin.0);
+        let cols = left.saturating_sub(self.origin.1);
+        self.origin = (
+            self.origin.0.saturating_sub(top),
+            self.origin.1.saturating_sub(left),
+        );
+        if rows == 0 && cols == 0 {
+            return;
+        }
+        let (rrows, rcols) = self.repeat;
+        let mut levels = Vec::with_capacity(self.levels.len());
+        for row in 0..rrows {
+            for col in 0..rcols {
+                let pos = (((row + rows) % rrows) * rcols + (col + cols) % rcols) * self.cpp;
+                levels.extend_from_slice(&self.levels[pos..pos + self.cpp]);
+            }
+        }
+        self.levels = levels;
+        self.delta_v.drain(..cmp::min(rows, self.delta_v.len()));
+        self.delta_h.drain(..cmp::min(cols, self.delta_h.len()));
+    }
+}
+
 impl RawImage {
+    pub(crate) fn into_metadata(self) -> RawMetadata {
+        RawMetadata {
+            make: self.make,
+            model: self.model,
+            clean_make: self.clean_make,
+            clean_model: self.clean_model,
+            width: self.width,
+            height: self.height,
+            cpp: self.cpp,
+            wb_coeffs: self.wb_coeffs,
+            whitelevels: self.whitelevels,
+            blacklevels: self.blacklevels,
+            black: self.black,
+            xyz_to_cam: self.xyz_to_cam,
+            dng_color: self.dng_color,
+            cfa: self.cfa,
+            crops: self.crops,
+            blackareas: self.blackareas,
+            orientation: self.orientation,
+            exif: self.exif,
+        }
+    }
+
     #[doc(hidden)]
     pub fn new(
         camera: Camera,
@@ -61,54 +259,40 @@ impl RawImage {
         image: Vec<u16>,
         dummy: bool,
     ) -> RawImage {
-        let blacks = if !dummy && (camera.blackareah.1 != 0 || camera.blackareav.1 != 0) {
-            let mut avg = [0 as f32; 4];
-            let mut count = [0 as f32; 4];
-            for row in camera.blackareah.0..camera.blackareah.0 + camera.blackareah.1 {
-                for col in 0..width {
-                    let color = camera.cfa.color_at(row, col);
-                    avg[color] += image[row * width + col] as f32;
-                    count[color] += 1.0;
-                }
-            }
-            for row in 0..height {
-                for col in camera.blackareav.0..camera.blackareav.0 + camera.blackareav.1 {
-                    let color = camera.cfa.color_at(row, col);
-                    avg[color] += image[row * width + col] as f32;
-                    count[color] += 1.0;
-                }
-            }
-            [
-                (avg[0] / count[0]) as u16,
-                (avg[1] / count[1]) as u16,
-                (avg[2] / count[2]) as u16,
-                (avg[3] / count[3]) as u16,
-            ]
-        } else {
-            camera.blacklevels
-        };
+        RawImage::with_cpp(camera, width, height, 1, wb_coeffs, image, dummy)
+    }
 
+    // Images that aren't a single sample per pixel (e.g., the YCbCr of Canon and Nikon
+    // sRAW) don't have raw values in the masked areas so they aren't measured
+    pub(crate) fn with_cpp(
+        camera: Camera,
+        width: usize,
+        height: usize,
+        cpp: usize,
+        wb_coeffs: [f32; 4],
+        image: Vec<u16>,
+        dummy: bool,
+    ) -> RawImage {
         // tuple format is top, right, bottom left
         let mut blackareas: Vec<(u64, u64, u64, u64)> = Vec::new();
-
-        if camera.blackareah.1 != 0 {
-            blackareas.push((
-                camera.blackareah.0 as u64,
-                width as u64,
-                (camera.blackareah.0 + camera.blackareah.1) as u64,
-                0,
-            ));
+        for &(row, rows) in &camera.blackareah {
+            blackareas.push((row as u64, width as u64, row.saturating_add(rows) as u64, 0));
         }
-
-        if camera.blackareav.1 != 0 {
+        for &(col, cols) in &camera.blackareav {
             blackareas.push((
                 0,
-                (camera.blackareav.0 + camera.blackareav.1) as u64,
+                col.saturating_add(cols) as u64,
                 height as u64,
-                camera.blackareav.0 as u64,
-            ))
+                col as u64,
+            ));
         }
 
+        let (blacks, black) = if !dummy && cpp == 1 && !blackareas.is_empty() {
+            measure_black(&camera, width, height, &image, &blackareas)
+        } else {
+            (camera.blacklevels, None)
+        };
+
         RawImage {
             make: camera.make.clone(),
             model: camera.model.clone(),
@@ -116,16 +300,22 @@ impl RawImage {
             clean_model: camera.clean_model.clone(),
             width,
             height,
-            cpp: 1,
+            cpp,
             wb_coeffs,
             data: RawImageData::Integer(image),
             blacklevels: blacks,
+            black,
             whitelevels: camera.whitelevels,
             xyz_to_cam: camera.xyz_to_cam,
+            dng_color: None,
+            profiles: Vec::new(),
             cfa: camera.cfa.clone(),
             crops: camera.crops,
             blackareas,
             orientation: camera.orientation,
+            exif: Exif::default(),
+            opcodes: Default::default(),
+            linearization: None,
         }
     }
 
@@ -165,12 +355,12 @@ impl RawImage {
         }
 
         let mut neutralwb = [0 as f32; 4];
-        for i in 0..4 {
+        for (wb, row) in neutralwb.iter_mut().zip(&rgb_to_cam) {
             let mut num = 0.0;
-            for j in 0..3 {
-                num += rgb_to_cam[i][j];
+            for val in row {
+                num += val;
             }
-            neutralwb[i] = 1.0 / num;
+            *wb = 1.0 / num;
         }
 
         [
@@ -205,29 +395,28 @@ impl RawImage {
     pub fn pseudoinverse(inm: [[f32; 3]; 4]) -> [[f32; 4]; 3] {
         let mut temp: [[f32; 6]; 3] = [[0.0; 6]; 3];
 
-        for i in 0..3 {
-            for j in 0..6 {
-                temp[i][j] = if j == i + 3 { 1.0 } else { 0.0 };
-            }
-            for j in 0..3 {
+        for (i, row) in temp.iter_mut().enumerate() {
+            row[i + 3] = 1.0;
+            for (j, val) in row.iter_mut().take(3).enumerate() {
                 for item in &inm {
-                    temp[i][j] += item[i] * item[j];
+                    *val += item[i] * item[j];
                 }
             }
         }
 
         for i in 0..3 {
-            let mut num = temp[i][i];
-            for j in 0..6 {
-                temp[i][j] /= num;
+            let num = temp[i][i];
+            for val in temp[i].iter_mut() {
+                *val /= num;
             }
-            for k in 0..3 {
+            let pivot = temp[i];
+            for (k, row) in temp.iter_mut().enumerate() {
                 if k == i {
                     continue;
                 }
-                num = temp[k][i];
-                for j in 0..6 {
-                    temp[k][j] -= temp[i][j] * num;
+                let num = row[i];
+                for (val, p) in row.iter_mut().zip(pivot) {
+                    *val -= p * num;
                 }
             }
         }
@@ -247,14 +436,761 @@ impl RawImage {
         out
     }
 
+    /// For images with a DNG color calibration outputs the matrix that converts pixels in
+    /// the camera colorspace into XYZ(D50), interpolated for the white balance of the image
+    pub fn dng_cam_to_xyz_d50(&self) -> Result<[[f32; 4]; 3], RawLoaderError> {
+        let color = self.dng_color.as_ref().ok_or_else(|| {
+            RawLoaderError::InvalidArgument("No DNG color calibration for this image".into())
+        })?;
+        let neutral: Vec<f32> = self
+            .wb_coeffs
+            .iter()
+            .take(color.channels)
+            .map(|v| 1.0 / v)
+            .collect();
+        if neutral.len() == color.channels && neutral.iter().all(|v| v.is_finite() && *v > 0.0) {
+            color.camera_to_xyz_d50(&neutral)
+        } else {
+            color.as_shot_camera_to_xyz_d50()
+        }
+    }
+
     /// Returns the CFA pattern after the crop has been applied (and thus the pattern
     /// potentially shifted)
     pub fn cropped_cfa(&self) -> CFA {
         self.cfa.shift(self.crops[3], self.crops[0])
     }
 
+    /// Black level of a given sample of a pixel. Uses the full black level model when the
+    /// format has one and otherwise the per color `blacklevels`
+    pub fn black_at(&self, row: usize, col: usize, sample: usize) -> f32 {
+        match &self.black {
+            Some(black) => black.at(row, col, sample),
+            None => {
+                let color = if self.cpp == 1 && self.cfa.is_valid() {
+                    self.cfa.color_at(row, col)
+                } else {
+                    sample
+                };
+                self.blacklevels[color & 3] as f32
+            }
+        }
+    }
+
     /// Checks if the image is monochrome
     pub fn is_monochrome(&self) -> bool {
         self.cpp == 1 && !self.cfa.is_valid()
     }
+
+    // Crops the image data to a rectangle for a region decode. The opcodes are relative to
+    // the full image so they get dropped, and any linearization that waited for them is
+    // applied
+    pub(crate) fn crop(&mut self, top: usize, left: usize, width: usize, height: usize) {
+        self.linearize();
+        fn rect<T: Copy>(
+            data: &[T],
+            stride: usize,
+            rows: Range<usize>,
+            cols: Range<usize>,
+        ) -> Vec<T> {
+            data.chunks_exact(stride)
+                .skip(rows.start)
+                .take(rows.len())
+                .flat_map(|row| row[cols.clone()].iter().copied())
+                .collect()
+        }
+        let stride = self.width * self.cpp;
+        let rows = top..top + height;
+        let cols = left * self.cpp..(left + width) * self.cpp;
+        self.data = match &self.data {
+            RawImageData::Integer(data) => RawImageData::Integer(rect(data, stride, rows, cols)),
+            RawImageData::Float(data) => RawImageData::Float(rect(data, stride, rows, cols)),
+        };
+        self.opcodes = Default::default();
+        self.move_origin(top, left, width, height);
+    }
+
+    // For decoders that got a window and only decoded that part of the image, makes the
+    // rest of the image match the data they output. Same as crop() without the copying
+    pub(crate) fn windowed(mut self, window: Option<&Window>) -> RawImage {
+        if let Some(w) = window {
+            self.linearize();
+            self.opcodes = Default::default();
+            self.move_origin(w.top, w.left, w.right - w.left, w.height());
+        }
+        self
+    }
+
+    // Bins each size x size block into a superpixel with the average of each color, using
+    // only the first `rows` rows of the block. Mosaic images end up with a sample per color
+    // and anything else keeps the samples it had. The full black level model and the
+    // opcodes are per pixel so they get dropped, and the values are linearized before
+    // they're averaged
+    pub(crate) fn bin(&mut self, size: usize, rows: usize) {
+        self.linearize();
+        let mosaic = self.cpp == 1 && self.cfa.is_valid();
+        let channels = if !mosaic {
+            self.cpp
+        } else if (0..self.cfa.height)
+            .any(|row| (0..self.cfa.width).any(|col| self.cfa.color_at(row, col) == 3))
+        {
+            4
+        } else {
+            3
+        };
+        let (width, height) = (self.width / size, self.height / size);
+        let binned = match &self.data {
+            RawImageData::Integer(data) => self.binned(data, size, rows, channels, mosaic),
+            RawImageData::Float(data) => self.binned(data, size, rows, channels, mosaic),
+        };
+        self.data = match self.data {
+            RawImageData::Integer(_) => RawImageData::Integer(
+                binned
+                    .iter()
+                    .map(|v| v.round().clamp(0.0, 65535.0) as u16)
+                    .collect(),
+            ),
+            RawImageData::Float(_) => RawImageData::Float(binned),
+        };
+        self.crops = self.crops.map(|crop| crop.div_ceil(size));
+        self.cfa = CFA::new("");
+        self.black = None;
+        self.blackareas = Vec::new();
+        self.opcodes = Default::default();
+        self.width = width;
+        self.height = height;
+        self.cpp = channels;
+    }
+
+    fn binned<T: Copy + Into<f32> + Sync>(
+        &self,
+        data: &[T],
+        size: usize,
+        rows: usize,
+        channels: usize,
+        mosaic: bool,
+    ) -> Vec<f32> {
+        let (width, height) = (self.width / size, self.height / size);
+        let mut out = vec![0.0; width * height * channels];
+        if width == 0 {
+            return out;
+        }
+        out.par_chunks_mut(width * channels)
+            .enumerate()
+            .for_each(|(orow, line)| {
+                for (ocol, pixel) in line.chunks_exact_mut(channels).enumerate() {
+                    let mut sums = [0f32; 4];
+                    let mut counts = [0f32; 4];
+                    for row in orow * size..orow * size + rows {
+                        for col in ocol * size..(ocol + 1) * size {
+                            let pos = (row * self.width + col) * self.cpp;
+                            if mosaic {
+                                let color = self.cfa.color_at(row, col);
+                                sums[color] += data[pos].into();
+                                counts[color] += 1.0;
+                            } else {
+                                for s in 0..self.cpp {
+                                    sums[s] += data[pos + s].into();
+                                    counts[s] += 1.0;
+                                }
+                            }
+                        }
+                    }
+                    for (c, value) in pixel.iter_mut().enumerate() {
+                        *value = if counts[c] > 0.0 {
+                            sums[c] / counts[c]
+                        } else {
+                            0.0
+                        };
+                    }
+                }
+            });
+        out
+    }
+
+    // Moves the metadata to a rectangle of the image that is now the whole image
+    fn move_origin(&mut self, top: usize, left: usize, width: usize, height: usize) {
+        let (bottom, right) = (top + height, left + width);
+        let active = (
+            self.crops[0],
+            self.crops[3],
+            self.height.saturating_sub(self.crops[2]),
+            self.width.saturating_sub(self.crops[1]),
+        );
+        self.crops = [
+            active.0.saturating_sub(top),
+            right.saturating_sub(active.3),
+            bottom.saturating_sub(active.2),
+            active.1.saturating_sub(left),
+        ];
+        self.cfa = self.cfa.shift(left, top);
+        if let Some(black) = self.black.as_mut() {
+            black.crop(top, left);
+        }
+        self.blackareas = Vec::new();
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Applies one of the DNG opcode lists to the image data. List 1 works on the values as
+    /// decoded and list 2 on the values normalized by the black and white levels, so it
+    /// should be applied after list 1 and before any black level subtraction. List 3 is
+    /// meant for demosaiced data and needs to be applied with `DngOpcode::apply_list()` on
+    /// the processed image. The list is emptied once applied so applying it twice is
+    /// harmless. Applying list 1 also applies the `linearization` table that comes after it.
+    pub fn apply_opcodes(&mut self, list: usize) -> Result<(), RawLoaderError> {
+        if list == 3 {
+            return Err(RawLoaderError::InvalidArgument(
+                "DNG: OpcodeList3 applies to demosaiced data, use DngOpcode::apply_list()".into(),
+            ));
+        }
+        if list != 1 && list != 2 {
+            return Err(RawLoaderError::InvalidArgument(format!(
+                "DNG: there's no opcode list {}",
+                list
+            )));
+        }
+        let opcodes = std::mem::take(&mut self.opcodes[list - 1]);
+        if opcodes.is_empty() {
+            if list == 1 {
+                self.linearize();
+            }
+            return Ok(());
+        }
+
+        // List 1 is relative to the whole image and works on the stored values, list 2 is
+        // relative to the active area with the values normalized between black and white
+        let (top, left, bwidth, bheight) = if list == 1 {
+            (0, 0, self.width, self.height)
+        } else {
+            (
+                self.crops[0],
+                self.crops[3],
+                self.width.saturating_sub(self.crops[1] + self.crops[3]),
+                self.height.saturating_sub(self.crops[0] + self.crops[2]),
+            )
+        };
+        if bwidth == 0 || bheight == 0 {
+            return Err(RawLoaderError::InvalidArgument(
+                "DNG: can't apply opcodes to an empty image".into(),
+            ));
+        }
+        let is_float = matches!(self.data, RawImageData::Float(_));
+        let levels = |img: &RawImage, pos: usize| -> (f32, f32) {
+            if list == 1 {
+                return (0.0, if is_float { 1.0 } else { 65535.0 });
+            }
+            let (pix, sample) = (pos / img.cpp, pos % img.cpp);
+            let (row, col) = (pix / img.width, pix % img.width);
+            let color = if img.cpp == 1 {
+                img.cfa.color_at(row, col)
+            } else {
+                sample
+            };
+            let black = img.black_at(row, col, sample);
+            (
+                black,
+                (img.whitelevels[color & 3] as f32 - black).max(f32::EPSILON),
+            )
+        };
+        let data: Vec<f32> = match &self.data {
+            RawImageData::Integer(data) => data
+                .iter()
+                .enumerate()
+                .map(|(i, v)| {
+                    let (black, range) = levels(self, i);
+                    (*v as f32 - black) / range
+                })
+                .collect(),
+            RawImageData::Float(data) => data
+                .iter()
+                .enumerate()
+                .map(|(i, v)| {
+                    let (black, range) = levels(self, i);
+                    (*v - black) / range
+                })
+                .collect(),
+        };
+
+        let (raw_black, raw_range) = levels(self, top * self.width * self.cpp + left * self.cpp);
+        let mut img = OpcodeImage {
+            data,
+            width: self.width,
+            height: self.height,
+            cpp: self.cpp,
+            top,
+            left,
+            bwidth,
+            bheight,
+            mosaic: self.cpp == 1 && self.cfa.is_valid(),
+            raw_scale: if is_float { 65535.0 } else { raw_range },
+            raw_black: if is_float { 0.0 } else { raw_black },
+            offset: (0, 0),
+        };
+        img.run(&opcodes)?;
+
+        // TrimBounds may have cropped the image so everything has to be moved
+        if img.width != self.width || img.height != self.height {
+            self.move_origin(img.offset.0, img.offset.1, img.width, img.height);
+        }
+
+        self.data = if is_float {
+            RawImageData::Float(
+                img.data
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| {
+                        let (black, range) = levels(self, i);
+                        v * range + black
+                    })
+                    .collect(),
+            )
+        } else {
+            RawImageData::Integer(
+                img.data
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| {
+                        let (black, range) = levels(self, i);
+                        (v * range + black).round().clamp(0.0, 65535.0) as u16
+                    })
+                    .collect(),
+            )
+        };
+        if list == 1 {
+            self.linearize();
+        }
+        Ok(())
+    }
+
+    // Runs the values through the linearization table that was waiting for OpcodeList1,
+    // values past the end of the table map to its last entry
+    fn linearize(&mut self) {
+        if let (Some(table), RawImageData::Integer(data)) =
+            (self.linearization.take(), &mut self.data)
+            && !table.is_empty()
+        {
+            data.par_iter_mut().for_each(|val| {
+                *val = table[cmp::min(*val as usize, table.len() - 1)];
+            });
+        }
+    }
+}
+
+// Measure the black level of each CFA position from the masked areas of the sensor. The
+// median keeps hot pixels and stray light at the edges of the areas from skewing it.
+fn measure_black(
+    camera: &Camera,
+    width: usize,
+    height: usize,
+    image: &[u16],
+    areas: &[(u64, u64, u64, u64)],
+) -> ([u16; 4], Option<BlackLevel>) {
+    let mosaic = camera.cfa.is_valid();
+    let (rrows, rcols) = if mosaic {
+        (camera.cfa.height, camera.cfa.width)
+    } else {
+        (1, 1)
+    };
+    let clip = |v: u64, max: usize| cmp::min(v, max as u64) as usize;
+
+    let mut samples = vec![Vec::new(); rrows * rcols];
+    for (i, &(top, right, bottom, left)) in areas.iter().enumerate() {
+        let (top, bottom) = (clip(top, height), clip(bottom, height));
+        let (left, right) = (clip(left, width), clip(right, width));
+        for row in top..bottom {
+            for col in left..right {
+                // Pixels where the areas overlap are only counted once
+                let seen = areas[..i].iter().any(|&(t, r, b, l)| {
+                    (t..b).contains(&(row as u64)) && (l..r).contains(&(col as u64))
+                });
+                if let (false, Some(pixel)) = (seen, image.get(row * width + col)) {
+                    samples[(row % rrows) * rcols + col % rcols].push(*pixel);
+                }
+            }
+        }
+    }
+
+    let median = |v: &mut Vec<u16>| -> Option<u16> {
+        if v.is_empty() {
+            return None;
+        }
+        let mid = v.len() / 2;
+        Some(*v.select_nth_unstable(mid).1)
+    };
+
+    let mut blacks = camera.blacklevels;
+    if mosaic {
+        for (color, black) in blacks.iter_mut().enumerate() {
+            let mut all: Vec<u16> = (0..rrows * rcols)
+                .filter(|pos| camera.cfa.color_at(pos / rcols, pos % rcols) == color)
+                .flat_map(|pos| samples[pos].iter().copied())
+                .collect();
+            if let Some(level) = median(&mut all) {
+                *black = level;
+            }
+        }
+    } else if let Some(level) = median(&mut samples[0]) {
+        blacks = [level; 4];
+    }
+
+    let black = if mosaic {
+        let levels = samples
+            .iter_mut()
+            .enumerate()
+            .map(|(pos, v)| {
+                let color = camera.cfa.color_at(pos / rcols, pos % rcols);
+                median(v).unwrap_or(blacks[color]) as f32
+            })
+            .collect();
+        Some(BlackLevel {
+            origin: (0, 0),
+            repeat: (rrows, rcols),
+            cpp: 1,
+            levels,
+            delta_h: Vec::new(),
+            delta_v: Vec::new(),
+        })
+    } else {
+        None
+    };
+    (blacks, black)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An 8x4 RGGB image with masked columns 0-1 and 6-7 and a masked top row
+    fn masked_camera() -> Camera {
+        let mut camera = Camera::new();
+        camera.cfa = CFA::new("RGGB");
+        camera.blacklevels = [1, 2, 3, 4];
+        camera.blackareav = vec![(0, 2), (6, 2)];
+        camera.blackareah = vec![(0, 1)];
+        camera
+    }
+
+    fn masked_image() -> Vec<u16> {
+        let mut image = vec![0; 8 * 4];
+        for row in 0..4 {
+            for col in 0..8 {
+                image[row * 8 + col] = match (row % 2, col % 2) {
+                    (0, 0) => 100,
+                    (1, 1) => 300,
+                    _ => 200,
+                };
+            }
+        }
+        // A hot pixel that a mean would pick up
+        image[2 * 8 + 6] = 60000;
+        image
+    }
+
+    #[test]
+    fn masked_areas_are_measured() {
+        let image = RawImage::new(masked_camera(), 8, 4, [1.0; 4], masked_image(), false);
+        assert_eq!(
+            image.blackareas,
+            vec![(0, 8, 1, 0), (0, 2, 4, 0), (0, 8, 4, 6)]
+        );
+        assert_eq!(image.blacklevels, [100, 200, 300, 4]);
+        let black = image.black.unwrap();
+        assert_eq!(black.repeat, (2, 2));
+        assert_eq!(black.levels, vec![100.0, 200.0, 200.0, 300.0]);
+    }
+
+    #[test]
+    fn overlapping_areas_count_once() {
+        let mut camera = masked_camera();
+        camera.blackareav = vec![(0, 3), (0, 1)];
+        camera.blackareah = Vec::new();
+        let mut image = masked_image();
+        // Counting the red samples of column 0 twice would pull the median down to them
+        image[2] = 5000;
+        image[2 * 8 + 2] = 5000;
+        let image = RawImage::new(camera, 8, 4, [1.0; 4], image, false);
+        assert_eq!(image.black.unwrap().levels[0], 5000.0);
+        assert_eq!(image.blacklevels[0], 5000);
+    }
+
+    #[test]
+    fn no_measuring_without_raw_samples() {
+        let camera = masked_camera();
+        let dummy = RawImage::new(camera.clone(), 8, 4, [1.0; 4], masked_image(), true);
+        assert_eq!(dummy.blacklevels, [1, 2, 3, 4]);
+        assert_eq!(dummy.black, None);
+
+        let image = vec![0; 8 * 4 * 3];
+        let rgb = RawImage::with_cpp(camera, 8, 4, 3, [1.0; 4], image, false);
+        assert_eq!(rgb.cpp, 3);
+        assert_eq!(rgb.blacklevels, [1, 2, 3, 4]);
+        assert_eq!(rgb.black, None);
+    }
+
+    #[test]
+    fn file_levels_replace_measured_ones() {
+        let image = masked_image();
+        let img = ok_image_with_blacklevels(masked_camera(), 8, 4, [1.0; 4], [7; 4], image);
+        let img = img.unwrap();
+        assert_eq!(img.black, None);
+        assert_eq!(img.black_at(1, 1, 0), 7.0);
+    }
+
+    // A 6x4 RGGB image numbered in order, with crops and a black level pattern that has
+    // per row and column deltas starting inside the image
+    fn patterned_image() -> RawImage {
+        let mut camera = Camera::new();
+        camera.cfa = CFA::new("RGGB");
+        camera.crops = [1, 1, 1, 2];
+        let mut image = RawImage::new(camera, 6, 4, [1.0; 4], (0..24).collect(), false);
+        image.black = Some(BlackLevel {
+            origin: (1, 1),
+            repeat: (2, 2),
+            cpp: 1,
+            levels: vec![10.0, 20.0, 30.0, 40.0],
+            delta_h: vec![1.0, 2.0, 3.0, 4.0, 5.0],
+            delta_v: vec![0.5, 1.5, 2.5],
+        });
+        image.blackareas = vec![(0, 6, 1, 0)];
+        image
+    }
+
+    fn integer_data(image: &RawImage) -> &[u16] {
+        match &image.data {
+            RawImageData::Integer(data) => data,
+            RawImageData::Float(_) => panic!("image isn't integer"),
+        }
+    }
+
+    // The cropped image has the same colors and black levels at every pixel as the
+    // original one at the same place
+    fn assert_same_pixels(cropped: &RawImage, full: &RawImage, top: usize, left: usize) {
+        let (black, full_black) = (
+            cropped.black.as_ref().unwrap(),
+            full.black.as_ref().unwrap(),
+        );
+        for row in 0..cropped.height {
+            for col in 0..cropped.width {
+                let (frow, fcol) = (row + top, col + left);
+                assert_eq!(
+                    integer_data(cropped)[row * cropped.width + col],
+                    integer_data(full)[frow * full.width + fcol]
+                );
+                assert_eq!(
+                    cropped.cfa.color_at(row, col),
+                    full.cfa.color_at(frow, fcol)
+                );
+                assert_eq!(black.at(row, col, 0), full_black.at(frow, fcol, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn crop_takes_a_rectangle() {
+        let full = patterned_image();
+        let mut image = full.clone();
+        image.crop(1, 2, 3, 2);
+        assert_eq!((image.width, image.height), (3, 2));
+        assert_eq!(integer_data(&image), &[8, 9, 10, 14, 15, 16]);
+        // The crop starts at an odd row so the pattern starts on a green and blue row
+        assert_eq!(image.cropped_cfa().color_at(0, 0), full.cfa.color_at(1, 2));
+        assert_eq!(image.cfa.color_at(0, 1), 2);
+        assert_same_pixels(&image, &full, 1, 2);
+        // The masked areas are outside of the crop
+        assert!(image.blackareas.is_empty());
+    }
+
+    #[test]
+    fn crop_moves_the_active_area() {
+        // The active area is rows 1 to 2 and columns 2 to 4
+        let mut image = patterned_image();
+        image.crop(0, 1, 4, 4);
+        assert_eq!(image.crops, [1, 0, 1, 1]);
+        let mut image = patterned_image();
+        image.crop(1, 2, 3, 2);
+        assert_eq!(image.crops, [0, 0, 0, 0]);
+        // Crops that go into the active area leave nothing to crop on that side
+        let mut image = patterned_image();
+        image.crop(2, 3, 1, 1);
+        assert_eq!(image.crops, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn crop_moves_the_black_level_origin() {
+        let full = patterned_image();
+
+        // Before the origin the pattern stays and the origin moves closer
+        let mut image = full.clone();
+        image.crop(0, 0, 5, 3);
+        let black = image.black.as_ref().unwrap();
+        assert_eq!(black.origin, (1, 1));
+        assert_eq!(black.levels, vec![10.0, 20.0, 30.0, 40.0]);
+        assert_same_pixels(&image, &full, 0, 0);
+
+        // Past the origin the pattern rotates and the deltas lose their first entries
+        let mut image = full.clone();
+        image.crop(2, 2, 4, 2);
+        let black = image.black.as_ref().unwrap();
+        assert_eq!(black.origin, (0, 0));
+        assert_eq!(black.levels, vec![40.0, 30.0, 20.0, 10.0]);
+        assert_eq!(black.delta_h, vec![2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(black.delta_v, vec![1.5, 2.5]);
+        assert_same_pixels(&image, &full, 2, 2);
+    }
+
+    #[test]
+    fn crop_drops_the_opcodes_and_linearizes() {
+        let mut image = patterned_image();
+        image.opcodes[1] = vec![DngOpcode {
+            optional: true,
+            preview_skip: true,
+            op: Opcode::FixVignetteRadial {
+                coefficients: [0.0; 5],
+                center: (0.5, 0.5),
+            },
+        }];
+        image.linearization = Some((0..24).map(|i| i * 3).collect());
+        image.crop(1, 1, 2, 1);
+        assert!(image.opcodes[1].is_empty());
+        assert!(image.linearization.is_none());
+        assert_eq!(integer_data(&image), &[21, 24]);
+    }
+
+    #[test]
+    fn windowed_matches_crop() {
+        let window = Window {
+            top: 1,
+            left: 3,
+            bottom: 3,
+            right: 6,
+            cpp: 1,
+            step: 1,
+            keep: 1,
+        };
+        let mut cropped = patterned_image();
+        cropped.crop(1, 3, 3, 2);
+        // A decoder that honoured the window only outputs its data
+        let mut image = patterned_image();
+        image.data = RawImageData::Integer(window.crop(integer_data(&image), 6).unwrap());
+        let image = image.windowed(Some(&window));
+        assert_eq!((image.width, image.height), (3, 2));
+        assert_eq!(integer_data(&image), integer_data(&cropped));
+        assert_eq!(image.crops, cropped.crops);
+        assert_eq!(image.cfa.color_at(0, 0), cropped.cfa.color_at(0, 0));
+        assert_eq!(image.black, cropped.black);
+        assert!(image.blackareas.is_empty());
+
+        // Without a window the image is left alone
+        let image = patterned_image().windowed(None);
+        assert_eq!((image.width, image.height), (6, 4));
+        assert_eq!(image.blackareas.len(), 1);
+    }
+
+    const XTRANS: &str = "GGRGGBGGBGGRBRGRBGGGBGGRGGRGGBRBGBRG";
+
+    fn mosaic(cfa: &str, width: usize, height: usize, data: Vec<u16>) -> RawImage {
+        let mut camera = Camera::new();
+        camera.cfa = CFA::new(cfa);
+        RawImage::new(camera, width, height, [1.0; 4], data, false)
+    }
+
+    #[test]
+    fn bin_bayer_into_rgb() {
+        // Each value is its row times 10 plus its column, and the last row and column
+        // don't make a full block so they're left out
+        let data = (0..5)
+            .flat_map(|row| (0..5).map(move |col| row * 10 + col))
+            .collect();
+        let mut image = mosaic("RGGB", 5, 5, data);
+        image.crops = [3, 1, 0, 1];
+        image.bin(2, 2);
+        assert_eq!((image.width, image.height, image.cpp), (2, 2, 3));
+        assert_eq!(
+            integer_data(&image),
+            &[0, 6, 11, 2, 8, 13, 20, 26, 31, 22, 28, 33]
+        );
+        // The crops round up so none of the area that was cropped comes back
+        assert_eq!(image.crops, [2, 1, 0, 1]);
+        assert!(!image.cfa.is_valid());
+        assert!(image.black.is_none());
+    }
+
+    #[test]
+    fn bin_only_the_first_rows() {
+        let data = (0..5)
+            .flat_map(|row| (0..5).map(move |col| row * 10 + col))
+            .collect();
+        let mut image = mosaic("RGGB", 5, 5, data);
+        image.bin(4, 2);
+        assert_eq!((image.width, image.height, image.cpp), (1, 1, 3));
+        assert_eq!(integer_data(&image), &[1, 7, 12]);
+    }
+
+    #[test]
+    fn bin_xtrans() {
+        // Every 3x3 block of X-Trans has all three colors
+        let cfa = CFA::new(XTRANS);
+        let data = (0..7)
+            .flat_map(|row| (0..7).map(move |col| row * 7 + col))
+            .map(|pos| (cfa.color_at(pos / 7, pos % 7) as u16 + 1) * 100)
+            .collect();
+        let mut image = mosaic(XTRANS, 7, 7, data);
+        image.bin(3, 3);
+        assert_eq!((image.width, image.height, image.cpp), (2, 2, 3));
+        assert_eq!(integer_data(&image), [100, 200, 300].repeat(4));
+    }
+
+    #[test]
+    fn bin_keeps_the_samples_of_other_images() {
+        // Four color mosaics get a fourth sample and RGB images keep theirs
+        let mut image = mosaic("RGBE", 2, 2, vec![1, 2, 3, 4]);
+        image.bin(2, 2);
+        assert_eq!(integer_data(&image), &[1, 2, 3, 4]);
+
+        let data = (0..2 * 2 * 3).collect();
+        let mut image = RawImage::with_cpp(Camera::new(), 2, 2, 3, [1.0; 4], data, false);
+        image.bin(2, 1);
+        assert_eq!((image.width, image.height, image.cpp), (1, 1, 3));
+        assert_eq!(integer_data(&image), &[2, 3, 4]);
+    }
+
+    // A 4x4 pattern of 0 to 15, twice the size of a Bayer CFA, with column deltas of 1 to 3
+    // and a row delta of 4
+    fn patterned_black() -> BlackLevel {
+        BlackLevel {
+            origin: (0, 0),
+            repeat: (4, 4),
+            cpp: 1,
+            levels: (0..16).map(|i| i as f32).collect(),
+            delta_h: vec![1.0, 2.0, 3.0],
+            delta_v: vec![4.0],
+        }
+    }
+
+    #[test]
+    fn black_level_lookup() {
+        let mut black = patterned_black();
+        assert_eq!(black.at(0, 0, 0), 5.0);
+        assert_eq!(black.at(1, 2, 0), 6.0 + 3.0);
+        // The pattern repeats but the deltas stop at their end
+        assert_eq!(black.at(5, 7, 0), 7.0);
+
+        black.origin = (1, 2);
+        assert_eq!(black.at(1, 2, 0), 5.0);
+        assert_eq!(black.at(2, 4, 0), 6.0 + 3.0);
+        // Before the origin counts as its first row and column
+        assert_eq!(black.at(0, 0, 0), 5.0);
+    }
+
+    #[test]
+    fn black_level_averages() {
+        let mut black = patterned_black();
+        let cfa = CFA::new("RGGB");
+        // Each color averages its places in the pattern and the mean deltas get added
+        assert_eq!(black.averages(&cfa), [11.0, 13.5, 16.0, 0.0]);
+        // Starting the pattern a row down puts the other colors over it
+        black.origin = (1, 0);
+        assert_eq!(black.averages(&cfa), [15.0, 13.5, 12.0, 0.0]);
+    }
 }
@@ -0,0 +1,331 @@
+// A small RFC 1950/1951 (zlib/deflate) decoder for the deflate compressed DNGs
+
+use crate::decoders::RawLoaderError;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// Order in which the code length code lengths are stored in a dynamic block
+const CLEN_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+struct BitReader<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+    bits: u64,
+    nbits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(src: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            buffer: src,
+            pos: 0,
+            bits: 0,
+            nbits: 0,
+        }
+    }
+
+    // Past the end of the buffer we feed zeroes and only fail when they get consumed
+    fn peek(&mut self, num: u32) -> u32 {
+        while self.nbits < num {
+            let byte = self.buffer.get(self.pos).copied().unwrap_or(0);
+            self.bits |= (byte as u64) << self.nbits;
+            self.pos += 1;
+            self.nbits += 8;
+        }
+        (self.bits & ((1 << num) - 1)) as u32
+    }
+
+    fn consume(&mut self, num: u32) -> Result<(), RawLoaderError> {
+        self.bits >>= num;
+        self.nbits -= num;
+        if self.pos > self.buffer.len() + (self.nbits as usize) / 8 {
+            return Err(RawLoaderError::truncated(
+                "inflate: compressed data is truncated",
+            ));
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, num: u32) -> Result<u32, RawLoaderError> {
+        let val = self.peek(num);
+        self.consume(num)?;
+        Ok(val)
+    }
+
+    fn align(&mut self) {
+        let extra = self.nbits % 8;
+        self.bits >>= extra;
+        self.nbits -= extra;
+    }
+}
+
+struct Huffman {
+    // Indexed by the next maxlen bits, gives (symbol, code length)
+    table: Vec<(u16, u8)>,
+    maxlen: u32,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, RawLoaderError> {
+        let mut counts = [0u32; 16];
+        for len in lengths {
+            counts[*len as usize] += 1;
+        }
+        counts[0] = 0;
+        let maxlen = (1..16).rev().find(|l| counts[*l] != 0).unwrap_or(1) as u32;
+
+        let mut next = [0u32; 16];
+        let mut code = 0;
+        let mut left: i32 = 1;
+        for len in 1..16 {
+            left = (left << 1) - counts[len] as i32;
+            if left < 0 {
+                return Err(RawLoaderError::corrupt(
+                    "inflate: oversubscribed huffman code",
+                ));
+            }
+            code = (code + counts[len - 1]) << 1;
+            next[len] = code;
+        }
+
+        let mut table = vec![(0u16, 0u8); 1 << maxlen];
+        for (symbol, len) in lengths.iter().enumerate() {
+            let len = *len as u32;
+            if len == 0 {
+                continue;
+            }
+            let code = next[len as usize];
+            next[len as usize] += 1;
+            // Codes are stored MSB first in an LSB first stream so reverse them
+            let reversed = code.reverse_bits() >> (32 - len);
+            let mut entry = reversed as usize;
+            while entry < table.len() {
+                table[entry] = (symbol as u16, len as u8);
+                entry += 1 << len;
+            }
+        }
+        Ok(Huffman { table, maxlen })
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<usize, RawLoaderError> {
+        let (symbol, len) = self.table[input.peek(self.maxlen) as usize];
+        if len == 0 {
+            return Err(RawLoaderError::corrupt("inflate: invalid huffman code"));
+        }
+        input.consume(len as u32)?;
+        Ok(symbol as usize)
+    }
+}
+
+// Decompress a zlib wrapped deflate stream. The size is only a hint for the allocation.
+pub fn inflate_zlib(src: &[u8], size: usize) -> Result<Vec<u8>, RawLoaderError> {
+    if src.len() < 2 {
+        return Err(RawLoaderError::truncated("inflate: zlib stream too short"));
+    }
+    let (cmf, flg) = (src[0] as usize, src[1] as usize);
+    if cmf & 0xf != 8 || (cmf << 8 | flg) % 31 != 0 || flg & 0x20 != 0 {
+        return Err(RawLoaderError::corrupt("inflate: invalid zlib header"));
+    }
+    inflate(&src[2..], size)
+}
+
+pub fn inflate(src: &[u8], size: usize) -> Result<Vec<u8>, RawLoaderError> {
+    let mut out: Vec<u8> = Vec::with_capacity(size);
+    let mut input = BitReader::new(src);
+
+    loop {
+        let last = input.get(1)?;
+        match input.get(2)? {
+            0 => {
+                input.align();
+                let len = input.get(16)? as usize;
+                let nlen = input.get(16)? as usize;
+                if len != !nlen & 0xffff {
+                    return Err(RawLoaderError::corrupt(
+                        "inflate: invalid stored block length",
+                    ));
+                }
+                for _ in 0..len {
+                    out.push(input.get(8)? as u8);
+                }
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                for (i, len) in lengths.iter_mut().enumerate() {
+                    *len = match i {
+                        0..=143 => 8,
+                        144..=255 => 9,
+                        256..=279 => 7,
+                        _ => 8,
+                    };
+                }
+                let litlen = Huffman::new(&lengths)?;
+                let dist = Huffman::new(&[5u8; 30])?;
+                inflate_block(&mut input, &mut out, &litlen, &dist)?;
+            }
+            2 => {
+                let (litlen, dist) = read_dynamic_tables(&mut input)?;
+                inflate_block(&mut input, &mut out, &litlen, &dist)?;
+            }
+            _ => return Err(RawLoaderError::corrupt("inflate: invalid block type")),
+        }
+        if last == 1 {
+            break;
+        }
+    }
+
+    Ok(out)
+}
+
+fn read_dynamic_tables(input: &mut BitReader) -> Result<(Huffman, Huffman), RawLoaderError> {
+    let nlen = input.get(5)? as usize + 257;
+    let ndist = input.get(5)? as usize + 1;
+    let nclen = input.get(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(RawLoaderError::corrupt(
+            "inflate: too many length or distance codes",
+        ));
+    }
+
+    let mut clens = [0u8; 19];
+    for i in 0..nclen {
+        clens[CLEN_ORDER[i]] = input.get(3)? as u8;
+    }
+    let clen = Huffman::new(&clens)?;
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = clen.decode(input)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if i == 0 {
+                    return Err(RawLoaderError::corrupt(
+                        "inflate: repeat with no previous length",
+                    ));
+                }
+                (lengths[i - 1], 3 + input.get(2)? as usize)
+            }
+            17 => (0, 3 + input.get(3)? as usize),
+            _ => (0, 11 + input.get(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err(RawLoaderError::corrupt("inflate: too many code lengths"));
+        }
+        for len in lengths[i..i + repeat].iter_mut() {
+            *len = value;
+        }
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(RawLoaderError::corrupt(
+            "inflate: missing end of block code",
+        ));
+    }
+
+    Ok((
+        Huffman::new(&lengths[..nlen])?,
+        Huffman::new(&lengths[nlen..])?,
+    ))
+}
+
+fn inflate_block(
+    input: &mut BitReader,
+    out: &mut Vec<u8>,
+    litlen: &Huffman,
+    dist: &Huffman,
+) -> Result<(), RawLoaderError> {
+    loop {
+        let symbol = litlen.decode(input)?;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let idx = symbol - 257;
+                let len = LENGTH_BASE[idx] as usize + input.get(LENGTH_EXTRA[idx] as u32)? as usize;
+                let didx = dist.decode(input)?;
+                if didx >= 30 {
+                    return Err(RawLoaderError::corrupt("inflate: invalid distance code"));
+                }
+                let distance =
+                    DIST_BASE[didx] as usize + input.get(DIST_EXTRA[didx] as u32)? as usize;
+                if distance > out.len() {
+                    return Err(RawLoaderError::corrupt(
+                        "inflate: distance past the start of the output",
+                    ));
+                }
+                let start = out.len() - distance;
+                for i in 0..len {
+                    out.push(out[start + i]);
+                }
+            }
+            _ => {
+                return Err(RawLoaderError::corrupt(
+                    "inflate: invalid literal/length code",
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_block() {
+        let src = [
+            0x78, 0x01, 0x01, 0x06, 0x00, 0xf9, 0xff, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x09,
+            0x3c, 0x02, 0x92,
+        ];
+        assert_eq!(inflate_zlib(&src, 6).unwrap(), b"stored");
+    }
+
+    #[test]
+    fn fixed_huffman_block() {
+        // Three literals and then a match that overlaps its own output
+        let src = [
+            0x78, 0xda, 0x4b, 0x4c, 0x4a, 0x4e, 0x44, 0x41, 0x00, 0x3a, 0x97, 0x06, 0x82,
+        ];
+        assert_eq!(inflate_zlib(&src, 17).unwrap(), b"abcabcabcabcabcab");
+    }
+
+    #[test]
+    fn dynamic_huffman_block() {
+        let src = [
+            0x78, 0xda, 0x15, 0x88, 0x49, 0x01, 0x00, 0x00, 0x0c, 0x82, 0xb2, 0x82, 0xeb, 0x9f,
+            0x61, 0xfa, 0xe2, 0x88, 0x89, 0x87, 0x30, 0x04, 0x28, 0xb0, 0x3d, 0xdd, 0x77, 0xe5,
+            0x03, 0x3a, 0x21, 0x0f, 0x49,
+        ];
+        assert_eq!(
+            inflate_zlib(&src, 40).unwrap(),
+            b"cbccbdabaacbdacaaabdaabbaaaaababaababbab"
+        );
+    }
+
+    #[test]
+    fn bad_streams() {
+        assert!(inflate_zlib(&[0x78], 1).is_err());
+        assert!(inflate_zlib(&[0x79, 0x01, 0x01], 1).is_err());
+        // A stored block whose length doesn't match its complement
+        assert!(inflate(&[0x01, 0x06, 0x00, 0xf8, 0xff, 0x73], 6).is_err());
+        // A fixed Huffman block cut short
+        assert!(inflate(&[0x4b, 0x4c], 17).is_err());
+    }
+}
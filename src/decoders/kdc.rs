@@ -20,23 +20,19 @@ impl<'a> KdcDecoder<'a> {
 }
 
 impl<'a> Decoder for KdcDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
 
         if camera.model == "Kodak DC120 ZOOM Digital Camera" {
             let width = 848;
             let height = 976;
-            let raw = self.tiff.find_ifds_with_tag(Tag::CFAPattern)[0];
+            let raw = fetch_ifd!(&self.tiff, Tag::CFAPattern);
             let off = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
-            let src = &self.buffer[off..];
+            let src = from_offset(self.buffer, off);
             let image = match fetch_tag!(raw, Tag::Compression).get_usize(0) {
-                1 => Self::decode_dc120(src, width, height, dummy),
+                1 => Self::decode_dc120(src, width, height, dummy)?,
                 c => {
-                    return Err(format!(
-                        "KDC: DC120: Don't know how to handle compression type {}",
-                        c
-                    )
-                    .to_string());
+                    return Err(RawLoaderError::compression("KDC", c as u32));
                 }
             };
 
@@ -47,7 +43,7 @@ impl<'a> Decoder for KdcDecoder<'a> {
         let height = fetch_tag!(self.tiff, Tag::KdcLength).get_usize(0) + 70;
         let offset = fetch_tag!(self.tiff, Tag::KdcOffset);
         if offset.count() < 13 {
-            panic!("KDC Decoder: Couldn't find the KDC offset");
+            return Err(RawLoaderError::corrupt("KDC: Couldn't find the KDC offset"));
         }
         let mut off = offset.get_usize(4) + offset.get_usize(12);
 
@@ -56,19 +52,29 @@ impl<'a> Decoder for KdcDecoder<'a> {
             off = if off < 0x15000 { 0x15000 } else { 0x17000 };
         }
 
-        let src = &self.buffer[off..];
-        let image = decode_12be(src, width, height, dummy);
+        let src = from_offset(self.buffer, off);
+        let window = Window::current();
+        let image = decode_12be(src, width, height, window.as_ref(), dummy)?;
 
         ok_image(camera, width, height, self.get_wb()?, image)
+            .map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
 
 impl<'a> KdcDecoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         match self.tiff.find_entry(Tag::KdcWB) {
             Some(levels) => {
                 if levels.count() != 3 {
-                    Err("KDC: Levels count is off".to_string())
+                    Err(RawLoaderError::corrupt("KDC: Levels count is off"))
                 } else {
                     Ok([
                         levels.get_f32(0),
@@ -81,7 +87,7 @@ impl<'a> KdcDecoder<'a> {
             None => {
                 let levels = fetch_tag!(self.tiff, Tag::KodakWB);
                 if levels.count() != 734 && levels.count() != 1502 {
-                    Err("KDC: Levels count is off".to_string())
+                    Err(RawLoaderError::corrupt("KDC: Levels count is off"))
                 } else {
                     let r = BEu16(levels.get_data(), 148) as f32;
                     let b = BEu16(levels.get_data(), 150) as f32;
@@ -91,7 +97,12 @@ impl<'a> KdcDecoder<'a> {
         }
     }
 
-    pub(crate) fn decode_dc120(src: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+    pub(crate) fn decode_dc120(
+        src: &[u8],
+        width: usize,
+        height: usize,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let mut out = alloc_image!(width, height, dummy);
 
         let mul: [usize; 4] = [162, 192, 187, 92];
@@ -99,10 +110,11 @@ impl<'a> KdcDecoder<'a> {
         for row in 0..height {
             let shift = row * mul[row & 3] + add[row & 3];
             for col in 0..width {
-                out[row * width + col] = src[row * width + ((col + shift) % 848)] as u16;
+                let pos = row * width + ((col + shift) % 848);
+                out[row * width + col] = src.get(pos).copied().unwrap_or(0) as u16;
             }
         }
 
-        out
+        Ok(out)
     }
 }
@@ -0,0 +1,544 @@
+use crate::decoders::RawLoaderError;
+use crate::decoders::basics::*;
+use crate::decoders::ljpeg::huffman::*;
+use std::f32::consts::PI;
+
+// Position in the 8x8 block of each coefficient in the order they're stored
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+#[derive(Debug, Copy, Clone)]
+struct DctComponent {
+    id: usize,
+    super_h: usize,
+    super_v: usize,
+    quant: usize,
+    dc_tbl: usize,
+    ac_tbl: usize,
+}
+
+// A baseline (SOF0/SOF1) 8 bit DCT JPEG decoder. It's used for the lossy DNGs
+// written by Lightroom and many phones where every tile is a full JPEG file.
+#[derive(Debug)]
+pub struct DctDecompressor<'a> {
+    buffer: &'a [u8],
+    width: usize,
+    height: usize,
+    components: Vec<DctComponent>,
+    quants: [[u16; 64]; 4],
+    dc_tables: Vec<HuffTable>,
+    ac_tables: Vec<HuffTable>,
+    restart_interval: usize,
+    transform: bool,
+}
+
+impl<'a> DctDecompressor<'a> {
+    pub fn new(src: &'a [u8]) -> Result<DctDecompressor<'a>, RawLoaderError> {
+        if src.len() < 4 || src[0] != 0xff || src[1] != 0xd8 {
+            return Err(RawLoaderError::corrupt(
+                "jpeg: Image did not start with SOI",
+            ));
+        }
+
+        let mut pos = 2;
+        let mut width = 0;
+        let mut height = 0;
+        let mut components: Vec<DctComponent> = Vec::new();
+        let mut quants = [[0u16; 64]; 4];
+        let mut dc_tables: Vec<HuffTable> = (0..4).map(|_| HuffTable::empty()).collect();
+        let mut ac_tables: Vec<HuffTable> = (0..4).map(|_| HuffTable::empty()).collect();
+        let mut restart_interval = 0;
+        let mut adobe_transform = None;
+
+        loop {
+            // Skip any fill bytes before the marker
+            while pos < src.len() && src[pos] == 0xff && src.get(pos + 1) == Some(&0xff) {
+                pos += 1;
+            }
+            if pos + 4 > src.len() || src[pos] != 0xff {
+                return Err(RawLoaderError::corrupt(
+                    "jpeg: couldn't find the next marker",
+                ));
+            }
+            let marker = src[pos + 1];
+            let len = BEu16(src, pos + 2) as usize;
+            if len < 2 || pos + 2 + len > src.len() {
+                return Err(RawLoaderError::corrupt(format!(
+                    "jpeg: invalid length for marker {:x}",
+                    marker
+                )));
+            }
+            let data = &src[pos + 4..pos + 2 + len];
+            pos += 2 + len;
+
+            match marker {
+                0xc0 | 0xc1 => {
+                    // Baseline and extended sequential with 8 bit samples
+                    if data.len() < 6 || data[0] != 8 {
+                        return Err(RawLoaderError::corrupt(
+                            "jpeg: only 8 bit DCT images are supported",
+                        ));
+                    }
+                    height = BEu16(data, 1) as usize;
+                    width = BEu16(data, 3) as usize;
+                    let cps = data[5] as usize;
+                    if cps == 0 || cps > 4 || data.len() != 6 + cps * 3 {
+                        return Err(RawLoaderError::corrupt("jpeg: invalid SOF header"));
+                    }
+                    for i in 0..cps {
+                        let comp = &data[6 + i * 3..9 + i * 3];
+                        let (super_h, super_v) =
+                            ((comp[1] >> 4) as usize, (comp[1] & 0xf) as usize);
+                        if !(1..=4).contains(&super_h) || !(1..=4).contains(&super_v) || comp[2] > 3
+                        {
+                            return Err(RawLoaderError::corrupt("jpeg: invalid component in SOF"));
+                        }
+                        components.push(DctComponent {
+                            id: comp[0] as usize,
+                            super_h,
+                            super_v,
+                            quant: comp[2] as usize,
+                            dc_tbl: 0,
+                            ac_tbl: 0,
+                        });
+                    }
+                }
+                0xc2 | 0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                    return Err(RawLoaderError::compression("JPEG", marker as u32));
+                }
+                0xc4 => {
+                    let mut data = data;
+                    while !data.is_empty() {
+                        if data.len() < 17 {
+                            return Err(RawLoaderError::corrupt("jpeg: invalid DHT table"));
+                        }
+                        let (class, id) = ((data[0] >> 4) as usize, (data[0] & 0xf) as usize);
+                        if class > 1 || id > 3 {
+                            return Err(RawLoaderError::corrupt("jpeg: invalid DHT table id"));
+                        }
+                        let mut bits = [0u32; 17];
+                        let mut count = 0;
+                        for i in 0..16 {
+                            bits[i + 1] = data[1 + i] as u32;
+                            count += data[1 + i] as usize;
+                        }
+                        if count > 256 || data.len() < 17 + count {
+                            return Err(RawLoaderError::corrupt("jpeg: invalid DHT table length"));
+                        }
+                        let mut huffval = [0u32; 256];
+                        for i in 0..count {
+                            huffval[i] = data[17 + i] as u32;
+                        }
+                        if class == 0 {
+                            if huffval[..count].iter().any(|v| *v > 11) {
+                                return Err(RawLoaderError::corrupt(
+                                    "jpeg: invalid DC huffman value",
+                                ));
+                            }
+                            dc_tables[id] = HuffTable::new(bits, huffval, false)?;
+                        } else {
+                            // AC values are run/size pairs that the decode cache can't handle
+                            let mut table = HuffTable::empty();
+                            table.bits = bits;
+                            table.huffval = huffval;
+                            table.disable_cache = true;
+                            table.initialize()?;
+                            ac_tables[id] = table;
+                        }
+                        data = &data[17 + count..];
+                    }
+                }
+                0xdb => {
+                    let mut data = data;
+                    while !data.is_empty() {
+                        let (precision, id) = ((data[0] >> 4) as usize, (data[0] & 0xf) as usize);
+                        let size = if precision == 0 { 64 } else { 128 };
+                        if id > 3 || precision > 1 || data.len() < 1 + size {
+                            return Err(RawLoaderError::corrupt("jpeg: invalid DQT table"));
+                        }
+                        for i in 0..64 {
+                            quants[id][ZIGZAG[i]] = if precision == 0 {
+                                data[1 + i] as u16
+                            } else {
+                                BEu16(data, 1 + i * 2)
+                            };
+                        }
+                        data = &data[1 + size..];
+                    }
+                }
+                0xdd => {
+                    if data.len() < 2 {
+                        return Err(RawLoaderError::corrupt("jpeg: invalid DRI marker"));
+                    }
+                    restart_interval = BEu16(data, 0) as usize;
+                }
+                0xee if data.len() >= 12 && &data[0..5] == b"Adobe" => {
+                    adobe_transform = Some(data[11]);
+                }
+                0xda => {
+                    if components.is_empty() {
+                        return Err(RawLoaderError::corrupt("jpeg: found SOS before SOF"));
+                    }
+                    let count = data.first().copied().unwrap_or(0) as usize;
+                    if count != components.len() || data.len() < 1 + count * 2 + 3 {
+                        return Err(RawLoaderError::corrupt(
+                            "jpeg: only single scan images are supported",
+                        ));
+                    }
+                    for i in 0..count {
+                        let id = data[1 + i * 2] as usize;
+                        let tables = data[2 + i * 2] as usize;
+                        let component =
+                            components.iter_mut().find(|c| c.id == id).ok_or_else(|| {
+                                RawLoaderError::corrupt(format!(
+                                    "jpeg: invalid component selector {}",
+                                    id
+                                ))
+                            })?;
+                        component.dc_tbl = (tables >> 4) & 3;
+                        component.ac_tbl = tables & 3;
+                    }
+                    break;
+                }
+                0xd9 => return Err(RawLoaderError::corrupt("jpeg: reached EOI before SOS")),
+                _ => {}
+            }
+        }
+
+        if width == 0 || height == 0 {
+            return Err(RawLoaderError::corrupt("jpeg: image has no size"));
+        }
+        for c in components.iter() {
+            let empty = |t: &HuffTable| t.bits.iter().all(|b| *b == 0);
+            if empty(&dc_tables[c.dc_tbl]) || empty(&ac_tables[c.ac_tbl]) {
+                return Err(RawLoaderError::corrupt(
+                    "jpeg: scan uses a missing huffman table",
+                ));
+            }
+        }
+        if src.len() < pos + 4 {
+            return Err(RawLoaderError::truncated("jpeg: scan data is too short"));
+        }
+
+        // Three component files are YCbCr unless an Adobe marker says otherwise or the
+        // components are labeled as RGB
+        let rgb_ids = components.len() == 3
+            && components[0].id == b'R' as usize
+            && components[1].id == b'G' as usize
+            && components[2].id == b'B' as usize;
+        let transform = components.len() == 3
+            && match adobe_transform {
+                Some(t) => t != 0,
+                None => !rgb_ids,
+            };
+
+        Ok(DctDecompressor {
+            buffer: &src[pos..],
+            width,
+            height,
+            components,
+            quants,
+            dc_tables,
+            ac_tables,
+            restart_interval,
+            transform,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+    pub fn cps(&self) -> usize {
+        self.components.len()
+    }
+
+    // Decodes the image into interleaved 8 bit values with any chroma subsampling
+    // undone and YCbCr turned back into RGB
+    pub fn decode(&self) -> Result<Vec<u16>, RawLoaderError> {
+        let cps = self.components.len();
+        let max_h = self.components.iter().map(|c| c.super_h).max().unwrap_or(1);
+        let max_v = self.components.iter().map(|c| c.super_v).max().unwrap_or(1);
+        // A single component scan is never interleaved so its MCU is always one block
+        let (max_h, max_v, mcu_h, mcu_v) = if cps == 1 {
+            (1, 1, [1; 4], [1; 4])
+        } else {
+            let mut mcu_h = [1; 4];
+            let mut mcu_v = [1; 4];
+            for (i, c) in self.components.iter().enumerate() {
+                mcu_h[i] = c.super_h;
+                mcu_v[i] = c.super_v;
+            }
+            (max_h, max_v, mcu_h, mcu_v)
+        };
+        let mcus_x = self.width.div_ceil(8 * max_h);
+        let mcus_y = self.height.div_ceil(8 * max_v);
+
+        // Decode every component into its own plane of full MCUs
+        let mut planes: Vec<(usize, Vec<u8>)> = (0..cps)
+            .map(|i| {
+                let pw = mcus_x * mcu_h[i] * 8;
+                (pw, vec![0u8; pw * mcus_y * mcu_v[i] * 8])
+            })
+            .collect();
+        let idct = IdctTable::new();
+
+        let total = mcus_x * mcus_y;
+        let interval = if self.restart_interval == 0 {
+            total
+        } else {
+            self.restart_interval
+        };
+        let mut segment = self.buffer;
+        let mut mcu = 0;
+        while mcu < total {
+            let mut pump = BitPumpJPEG::new(segment);
+            let mut preds = [0i32; 4];
+            for _ in 0..interval.min(total - mcu) {
+                let (mx, my) = (mcu % mcus_x, mcu / mcus_x);
+                for (i, comp) in self.components.iter().enumerate() {
+                    let (pw, plane) = &mut planes[i];
+                    for by in 0..mcu_v[i] {
+                        for bx in 0..mcu_h[i] {
+                            let coeffs = self.decode_block(&mut pump, comp, &mut preds[i])?;
+                            let x = (mx * mcu_h[i] + bx) * 8;
+                            let y = (my * mcu_v[i] + by) * 8;
+                            idct.transform(&coeffs, &mut plane[y * *pw + x..], *pw);
+                        }
+                    }
+                }
+                mcu += 1;
+            }
+            if mcu < total {
+                segment = DctDecompressor::next_restart(segment)?;
+            }
+        }
+
+        let mut out = vec![0u16; self.width * self.height * cps];
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let mut pix = [0f32; 4];
+                for i in 0..cps {
+                    let (pw, plane) = &planes[i];
+                    let x = col * mcu_h[i] / max_h;
+                    let y = row * mcu_v[i] / max_v;
+                    pix[i] = plane[y * pw + x] as f32;
+                }
+                let pos = (row * self.width + col) * cps;
+                if self.transform {
+                    let (y, cb, cr) = (pix[0], pix[1] - 128.0, pix[2] - 128.0);
+                    out[pos] = clamp_u8(y + 1.402 * cr);
+                    out[pos + 1] = clamp_u8(y - 0.344136 * cb - 0.714136 * cr);
+                    out[pos + 2] = clamp_u8(y + 1.772 * cb);
+                } else {
+                    for i in 0..cps {
+                        out[pos + i] = pix[i] as u16;
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    fn decode_block(
+        &self,
+        pump: &mut BitPumpJPEG,
+        comp: &DctComponent,
+        pred: &mut i32,
+    ) -> Result<[f32; 64], RawLoaderError> {
+        let quant = &self.quants[comp.quant];
+        let dc = &self.dc_tables[comp.dc_tbl];
+        let ac = &self.ac_tables[comp.ac_tbl];
+        let mut coeffs = [0f32; 64];
+
+        *pred += dc.huff_decode(pump)?;
+        coeffs[0] = (*pred * quant[0] as i32) as f32;
+
+        let mut k = 1;
+        while k < 64 {
+            let symbol = ac.huff_get_bits(pump);
+            let (run, size) = ((symbol >> 4) as usize, (symbol & 0xf) as u8);
+            if size == 0 {
+                if run == 15 {
+                    k += 16;
+                    continue;
+                }
+                break;
+            }
+            k += run;
+            if k > 63 {
+                return Err(RawLoaderError::corrupt(
+                    "jpeg: AC coefficient out of the block",
+                ));
+            }
+            let value = ac.huff_diff(pump, (0, size, 0));
+            coeffs[ZIGZAG[k]] = (value * quant[ZIGZAG[k]] as i32) as f32;
+            k += 1;
+        }
+        Ok(coeffs)
+    }
+
+    // Find the data after the next RSTn marker
+    fn next_restart(data: &[u8]) -> Result<&[u8], RawLoaderError> {
+        let mut pos = 0;
+        while pos + 1 < data.len() {
+            if data[pos] == 0xff && (0xd0..=0xd7).contains(&data[pos + 1]) && pos + 6 <= data.len()
+            {
+                return Ok(&data[pos + 2..]);
+            }
+            pos += 1;
+        }
+        Err(RawLoaderError::corrupt(
+            "jpeg: couldn't find restart marker",
+        ))
+    }
+}
+
+fn clamp_u8(val: f32) -> u16 {
+    val.round().clamp(0.0, 255.0) as u16
+}
+
+struct IdctTable {
+    cos: [[f32; 8]; 8],
+}
+
+impl IdctTable {
+    fn new() -> IdctTable {
+        let mut cos = [[0f32; 8]; 8];
+        for (x, row) in cos.iter_mut().enumerate() {
+            for (u, val) in row.iter_mut().enumerate() {
+                let scale = if u == 0 { 1.0 / 2f32.sqrt() } else { 1.0 };
+                *val = scale * (((2 * x + 1) * u) as f32 * PI / 16.0).cos() / 2.0;
+            }
+        }
+        IdctTable { cos }
+    }
+
+    // Separable 2D inverse DCT, first over the rows and then the columns
+    fn transform(&self, coeffs: &[f32; 64], out: &mut [u8], stride: usize) {
+        let mut tmp = [0f32; 64];
+        for v in 0..8 {
+            for x in 0..8 {
+                let mut sum = 0.0;
+                for u in 0..8 {
+                    sum += self.cos[x][u] * coeffs[v * 8 + u];
+                }
+                tmp[v * 8 + x] = sum;
+            }
+        }
+        for y in 0..8 {
+            for x in 0..8 {
+                let mut sum = 0.0;
+                for v in 0..8 {
+                    sum += self.cos[y][v] * tmp[v * 8 + x];
+                }
+                out[y * stride + x] = (sum + 128.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A baseline JPEG with 1x1 sampled components that all use the same tables. The DC
+    // table has the codes 00, 01 and 10 for categories 0, 5 and 6 and the AC one 00 for
+    // the end of block and 01 for a 7 bit coefficient. The DC quantizer is 8, so that
+    // each DC step is a step of the output, and the AC ones are 1.
+    fn jpeg(width: u16, height: u16, ids: &[u8], restart: u16, scan: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0xff, 0xd8, 0xff, 0xdb, 0x00, 0x43, 0x00, 8];
+        buf.extend_from_slice(&[1; 63]);
+        buf.extend_from_slice(&[0xff, 0xc4, 0x00, 0x16, 0x00, 0, 3]);
+        buf.extend_from_slice(&[0; 14]);
+        buf.extend_from_slice(&[0, 5, 6]);
+        buf.extend_from_slice(&[0xff, 0xc4, 0x00, 0x15, 0x10, 0, 2]);
+        buf.extend_from_slice(&[0; 14]);
+        buf.extend_from_slice(&[0x00, 0x07]);
+        if restart > 0 {
+            buf.extend_from_slice(&[0xff, 0xdd, 0x00, 0x04]);
+            buf.extend_from_slice(&restart.to_be_bytes());
+        }
+        buf.extend_from_slice(&[0xff, 0xc0, 0x00, 8 + 3 * ids.len() as u8, 8]);
+        buf.extend_from_slice(&height.to_be_bytes());
+        buf.extend_from_slice(&width.to_be_bytes());
+        buf.push(ids.len() as u8);
+        for id in ids {
+            buf.extend_from_slice(&[*id, 0x11, 0]);
+        }
+        buf.extend_from_slice(&[0xff, 0xda, 0x00, 6 + 2 * ids.len() as u8, ids.len() as u8]);
+        for id in ids {
+            buf.extend_from_slice(&[*id, 0x00]);
+        }
+        buf.extend_from_slice(&[0x00, 0x3f, 0x00]);
+        buf.extend_from_slice(scan);
+        buf.extend_from_slice(&[0xff, 0xd9]);
+        buf
+    }
+
+    fn decode(src: &[u8]) -> Vec<u16> {
+        DctDecompressor::new(src).unwrap().decode().unwrap()
+    }
+
+    #[test]
+    fn dc_prediction() {
+        // A DC of +20 and then -40 from it, padded with ones
+        // 01 10100 00 | 10 010111 00 | 11111
+        let data = decode(&jpeg(16, 8, &[1], 0, &[0x68, 0x4b, 0x9f]));
+        for row in data.chunks_exact(16) {
+            assert_eq!(row[..8], [148; 8]);
+            assert_eq!(row[8..], [108; 8]);
+        }
+    }
+
+    #[test]
+    fn ac_coefficient() {
+        // No DC and 64 for the first horizontal frequency, a half cosine along each row
+        // 00 | 01 1000000 00 | 111
+        let data = decode(&jpeg(8, 8, &[1], 0, &[0x18, 0x07]));
+        for row in data.chunks_exact(8) {
+            assert_eq!(row, [139, 137, 134, 130, 126, 122, 119, 117]);
+        }
+    }
+
+    #[test]
+    fn ycbcr_to_rgb() {
+        // Y of 148, Cb of 128 and Cr of 148
+        // 01 10100 00 | 00 00 | 01 10100 00 | 11
+        let data = decode(&jpeg(8, 8, &[1, 2, 3], 0, &[0x68, 0x03, 0x43]));
+        for pix in data.chunks_exact(3) {
+            assert_eq!(pix, [176, 134, 148]);
+        }
+
+        // Components labeled as RGB are left alone
+        let data = decode(&jpeg(8, 8, b"RGB", 0, &[0x68, 0x03, 0x43]));
+        for pix in data.chunks_exact(3) {
+            assert_eq!(pix, [148, 128, 148]);
+        }
+    }
+
+    #[test]
+    fn restarts_reset_prediction() {
+        // Two MCUs that each set a DC of +20 after a restart
+        // 01 10100 00 | 1111111 | RST0 | 01 10100 00 | 1111111
+        let data = decode(&jpeg(16, 8, &[1], 1, &[0x68, 0x7f, 0xff, 0xd0, 0x68, 0x7f]));
+        assert!(data.iter().all(|v| *v == 148));
+    }
+
+    #[test]
+    fn unsupported_images() {
+        let mut progressive = jpeg(8, 8, &[1], 0, &[0x18, 0x07]);
+        let sof = progressive
+            .windows(2)
+            .position(|w| w == [0xff, 0xc0])
+            .unwrap();
+        progressive[sof + 1] = 0xc2;
+        assert!(DctDecompressor::new(&progressive).is_err());
+        assert!(DctDecompressor::new(&[0xff, 0xd9, 0x00, 0x00]).is_err());
+    }
+}
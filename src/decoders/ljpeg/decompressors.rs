@@ -1,7 +1,33 @@
+use crate::decoders::RawLoaderError;
 use crate::decoders::basics::*;
 use crate::decoders::ljpeg::LjpegDecompressor;
 use crate::decoders::ljpeg::huffman::*;
 
+// Corrupted files can have frames that don't fit the output they get decoded into, so
+// check there's space for every pixel the decoders are going to write
+fn check_output(
+    out: &[u16],
+    x: usize,
+    stripwidth: usize,
+    width: usize,
+    height: usize,
+    cps: usize,
+) -> Result<(), RawLoaderError> {
+    let needed = match height {
+        0 => x + cps,
+        _ => (x + cps).max((height - 1) * stripwidth + x + width.next_multiple_of(cps)),
+    };
+    if out.len() < needed {
+        return Err(RawLoaderError::corrupt(format!(
+            "ljpeg: {}x{} doesn't fit in an output of {} pixels",
+            width,
+            height,
+            out.len()
+        )));
+    }
+    Ok(())
+}
+
 pub fn decode_ljpeg_2components(
     ljpeg: &LjpegDecompressor,
     out: &mut [u16],
@@ -9,17 +35,17 @@ pub fn decode_ljpeg_2components(
     stripwidth: usize,
     width: usize,
     height: usize,
-) -> Result<(), String> {
+) -> Result<(), RawLoaderError> {
     if ljpeg.sof.width * 2 < width || ljpeg.sof.height < height {
-        return Err(format!(
+        return Err(RawLoaderError::corrupt(format!(
             "ljpeg: trying to decode {}x{} into {}x{}",
             ljpeg.sof.width * 2,
             ljpeg.sof.height,
             width,
             height
-        )
-        .to_string());
+        )));
     }
+    check_output(out, x, stripwidth, width, height, 2)?;
     let htable1 = &ljpeg.dhts[ljpeg.sof.components[0].dc_tbl_num];
     let htable2 = &ljpeg.dhts[ljpeg.sof.components[1].dc_tbl_num];
     let mut pump = BitPumpJPEG::new(ljpeg.buffer);
@@ -68,17 +94,17 @@ pub fn decode_ljpeg_3components(
     stripwidth: usize,
     width: usize,
     height: usize,
-) -> Result<(), String> {
+) -> Result<(), RawLoaderError> {
     if ljpeg.sof.width * 3 < width || ljpeg.sof.height < height {
-        return Err(format!(
+        return Err(RawLoaderError::corrupt(format!(
             "ljpeg: trying to decode {}x{} into {}x{}",
             ljpeg.sof.width * 3,
             ljpeg.sof.height,
             width,
             height
-        )
-        .to_string());
+        )));
     }
+    check_output(out, x, stripwidth, width, height, 3)?;
 
     let htable1 = &ljpeg.dhts[ljpeg.sof.components[0].dc_tbl_num];
     let htable2 = &ljpeg.dhts[ljpeg.sof.components[1].dc_tbl_num];
@@ -126,17 +152,17 @@ pub fn decode_ljpeg_4components(
     out: &mut [u16],
     width: usize,
     height: usize,
-) -> Result<(), String> {
+) -> Result<(), RawLoaderError> {
     if ljpeg.sof.width * 4 < width || ljpeg.sof.height < height {
-        return Err(format!(
+        return Err(RawLoaderError::corrupt(format!(
             "ljpeg: trying to decode {}x{} into {}x{}",
             ljpeg.sof.width * 4,
             ljpeg.sof.height,
             width,
             height
-        )
-        .to_string());
+        )));
     }
+    check_output(out, 0, width, width, height, 4)?;
     let htable1 = &ljpeg.dhts[ljpeg.sof.components[0].dc_tbl_num];
     let htable2 = &ljpeg.dhts[ljpeg.sof.components[1].dc_tbl_num];
     let htable3 = &ljpeg.dhts[ljpeg.sof.components[2].dc_tbl_num];
@@ -220,16 +246,23 @@ pub fn decode_ljpeg_420(
     out: &mut [u16],
     width: usize,
     height: usize,
-) -> Result<(), String> {
-    if ljpeg.sof.width * 3 != width || ljpeg.sof.height != height {
-        return Err(format!(
+) -> Result<(), RawLoaderError> {
+    if ljpeg.sof.cps != 3
+        || ljpeg.sof.width * 3 != width
+        || ljpeg.sof.height != height
+        || width < 6
+        || !width.is_multiple_of(6)
+        || height < 2
+        || !height.is_multiple_of(2)
+        || out.len() < width * height
+    {
+        return Err(RawLoaderError::corrupt(format!(
             "ljpeg: trying to decode {}x{} into {}x{}",
             ljpeg.sof.width * 3,
             ljpeg.sof.height,
             width,
             height
-        )
-        .to_string());
+        )));
     }
 
     let htable1 = &ljpeg.dhts[ljpeg.sof.components[0].dc_tbl_num];
@@ -297,16 +330,22 @@ pub fn decode_ljpeg_422(
     out: &mut [u16],
     width: usize,
     height: usize,
-) -> Result<(), String> {
-    if ljpeg.sof.width * 3 != width || ljpeg.sof.height != height {
-        return Err(format!(
+) -> Result<(), RawLoaderError> {
+    if ljpeg.sof.cps != 3
+        || ljpeg.sof.width * 3 != width
+        || ljpeg.sof.height != height
+        || width < 6
+        || !width.is_multiple_of(6)
+        || height < 1
+        || out.len() < width * height
+    {
+        return Err(RawLoaderError::corrupt(format!(
             "ljpeg: trying to decode {}x{} into {}x{}",
             ljpeg.sof.width * 3,
             ljpeg.sof.height,
             width,
             height
-        )
-        .to_string());
+        )));
     }
     let htable1 = &ljpeg.dhts[ljpeg.sof.components[0].dc_tbl_num];
     let htable2 = &ljpeg.dhts[ljpeg.sof.components[1].dc_tbl_num];
@@ -347,11 +386,14 @@ pub fn decode_hasselblad(
     ljpeg: &LjpegDecompressor,
     out: &mut [u16],
     width: usize,
-) -> Result<(), String> {
+) -> Result<(), RawLoaderError> {
     // Pixels are packed two at a time, not like LJPEG:
     // [p1_length_as_huffman][p2_length_as_huffman][p0_diff_with_length][p1_diff_with_length]|NEXT PIXELS
     let mut pump = BitPumpMSB32::new(ljpeg.buffer);
     let htable = &ljpeg.dhts[ljpeg.sof.components[0].dc_tbl_num];
+    if width == 0 {
+        return Ok(());
+    }
 
     for line in out.chunks_exact_mut(width) {
         let mut p1: i32 = 0x8000;
@@ -359,8 +401,8 @@ pub fn decode_hasselblad(
         for o in line.chunks_exact_mut(2) {
             let len1 = htable.huff_len(&mut pump);
             let len2 = htable.huff_len(&mut pump);
-            p1 += htable.huff_diff(&mut pump, len1);
-            p2 += htable.huff_diff(&mut pump, len2);
+            p1 = p1.wrapping_add(htable.huff_diff(&mut pump, len1));
+            p2 = p2.wrapping_add(htable.huff_diff(&mut pump, len2));
             o[0] = p1 as u16;
             o[1] = p2 as u16;
         }
@@ -377,7 +419,8 @@ pub fn decode_leaf_strip(
     htable1: &HuffTable,
     htable2: &HuffTable,
     bpred: i32,
-) -> Result<(), String> {
+) -> Result<(), RawLoaderError> {
+    check_output(out, 0, width, width, height, 2)?;
     let mut pump = BitPumpJPEG::new(src);
     out[0] = (bpred + htable1.huff_decode(&mut pump)?) as u16;
     out[1] = (bpred + htable2.huff_decode(&mut pump)?) as u16;
@@ -1,3 +1,4 @@
+use crate::decoders::RawLoaderError;
 use crate::decoders::basics::*;
 use std::fmt;
 
@@ -81,7 +82,11 @@ impl HuffTable {
         }
     }
 
-    pub fn new(bits: [u32; 17], huffval: [u32; 256], dng_bug: bool) -> Result<HuffTable, String> {
+    pub fn new(
+        bits: [u32; 17],
+        huffval: [u32; 256],
+        dng_bug: bool,
+    ) -> Result<HuffTable, RawLoaderError> {
         let mut tbl = HuffTable {
             bits,
             huffval,
@@ -98,7 +103,7 @@ impl HuffTable {
         Ok(tbl)
     }
 
-    pub fn initialize(&mut self) -> Result<(), String> {
+    pub fn initialize(&mut self) -> Result<(), RawLoaderError> {
         // Find out the max code length and allocate a table with that size
         self.nbits = 16;
         for i in 0..16 {
@@ -114,7 +119,20 @@ impl HuffTable {
         let mut pos = 0;
         for len in 0..self.nbits {
             for _ in 0..self.bits[len as usize + 1] {
-                for _ in 0..(1 << (self.nbits - len - 1)) {
+                let codes = 1 << (self.nbits - len - 1);
+                if h + codes > self.hufftable.len() || pos >= self.huffval.len() {
+                    return Err(RawLoaderError::corrupt(
+                        "ljpeg: huffman table has more codes than fit its lengths",
+                    ));
+                }
+                // Tables used for differences can't go past 16 bits
+                if !self.disable_cache && (self.huffval[pos] > 16 || self.shiftval[pos] > 15) {
+                    return Err(RawLoaderError::corrupt(format!(
+                        "ljpeg: invalid huffman difference length {}",
+                        self.huffval[pos]
+                    )));
+                }
+                for _ in 0..codes {
                     self.hufftable[h] = (
                         len as u8 + 1,
                         self.huffval[pos] as u8,
@@ -149,7 +167,7 @@ impl HuffTable {
     }
 
     #[inline(always)]
-    pub fn huff_decode(&self, pump: &mut dyn BitPump) -> Result<i32, String> {
+    pub fn huff_decode(&self, pump: &mut dyn BitPump) -> Result<i32, RawLoaderError> {
         let code = pump.peek_bits(DECODE_CACHE_BITS) as usize;
         if let Some((bits, decode)) = self.decodecache[code] {
             pump.consume_bits(bits as u32);
@@ -1,10 +1,15 @@
+use crate::decoders::RawLoaderError;
 use crate::decoders::basics::*;
 use crate::decoders::ljpeg::decompressors::*;
 use crate::decoders::ljpeg::huffman::*;
+use rayon::prelude::*;
 
+mod dct;
 mod decompressors;
 pub mod huffman;
 
+pub use crate::decoders::ljpeg::dct::DctDecompressor;
+
 enum Marker {
     Stuff = 0x00,
     SOF3 = 0xc3, // lossless
@@ -55,7 +60,7 @@ impl SOFInfo {
         }
     }
 
-    fn parse_sof(&mut self, input: &mut ByteStream) -> Result<(), String> {
+    fn parse_sof(&mut self, input: &mut ByteStream) -> Result<(), RawLoaderError> {
         let header_length = input.get_u16() as usize;
         self.precision = input.get_u8() as usize;
         self.height = input.get_u16() as usize;
@@ -63,13 +68,17 @@ impl SOFInfo {
         self.cps = input.get_u8() as usize;
 
         if self.precision > 16 {
-            return Err("ljpeg: More than 16 bits per channel is not supported.".to_string());
+            return Err(RawLoaderError::corrupt(
+                "ljpeg: More than 16 bits per channel is not supported.",
+            ));
         }
         if self.cps > 4 || self.cps < 1 {
-            return Err("ljpeg: Only from 1 to 4 components are supported.".to_string());
+            return Err(RawLoaderError::corrupt(
+                "ljpeg: Only from 1 to 4 components are supported.",
+            ));
         }
         if header_length != 8 + self.cps * 3 {
-            return Err("ljpeg: Header size mismatch.".to_string());
+            return Err(RawLoaderError::corrupt("ljpeg: Header size mismatch."));
         }
 
         for _ in 0..self.cps {
@@ -88,14 +97,18 @@ impl SOFInfo {
         Ok(())
     }
 
-    fn parse_sos(&mut self, input: &mut ByteStream) -> Result<(usize, usize), String> {
+    fn parse_sos(&mut self, input: &mut ByteStream) -> Result<(usize, usize), RawLoaderError> {
         if self.width == 0 {
-            return Err("ljpeg: Trying to parse SOS before SOF".to_string());
+            return Err(RawLoaderError::corrupt(
+                "ljpeg: Trying to parse SOS before SOF",
+            ));
         }
         input.get_u16(); //skip header length
         let soscps = input.get_u8() as usize;
         if self.cps != soscps {
-            return Err("ljpeg: component number mismatch in SOS".to_string());
+            return Err(RawLoaderError::corrupt(
+                "ljpeg: component number mismatch in SOS",
+            ));
         }
         for cs in 0..self.cps {
             // At least some MOS cameras have this broken
@@ -103,11 +116,18 @@ impl SOFInfo {
             let cs = if self.csfix { cs } else { readcs };
             let component = match self.components.iter_mut().find(|&&mut c| c.id == cs) {
                 Some(val) => val,
-                None => return Err(format!("ljpeg: invalid component selector {}", cs).to_string()),
+                None => {
+                    return Err(RawLoaderError::corrupt(format!(
+                        "ljpeg: invalid component selector {}",
+                        cs
+                    )));
+                }
             };
             let td = (input.get_u8() as usize) >> 4;
             if td > 3 {
-                return Err("ljpeg: Invalid Huffman table selection".to_string());
+                return Err(RawLoaderError::corrupt(
+                    "ljpeg: Invalid Huffman table selection",
+                ));
             }
             component.dc_tbl_num = td;
         }
@@ -128,7 +148,7 @@ pub struct LjpegDecompressor<'a> {
 }
 
 impl<'a> LjpegDecompressor<'a> {
-    pub fn new(src: &'a [u8]) -> Result<LjpegDecompressor<'a>, String> {
+    pub fn new(src: &'a [u8]) -> Result<LjpegDecompressor<'a>, RawLoaderError> {
         LjpegDecompressor::new_full(src, false, false)
     }
 
@@ -136,10 +156,12 @@ impl<'a> LjpegDecompressor<'a> {
         src: &'a [u8],
         dng_bug: bool,
         csfix: bool,
-    ) -> Result<LjpegDecompressor<'a>, String> {
+    ) -> Result<LjpegDecompressor<'a>, RawLoaderError> {
         let mut input = ByteStream::new(src, BIG_ENDIAN);
         if LjpegDecompressor::get_next_marker(&mut input, false)? != m(Marker::SOI) {
-            return Err("ljpeg: Image did not start with SOI. Probably not LJPEG".to_string());
+            return Err(RawLoaderError::corrupt(
+                "ljpeg: Image did not start with SOI. Probably not LJPEG",
+            ));
         }
 
         let mut sof = SOFInfo::empty(csfix);
@@ -154,7 +176,10 @@ impl<'a> LjpegDecompressor<'a> {
                 // Start of the frame, giving us the basic info
                 sof.parse_sof(&mut input)?;
                 if sof.precision > 16 || sof.precision < 12 {
-                    return Err(format!("ljpeg: sof.precision {}", sof.precision).to_string());
+                    return Err(RawLoaderError::corrupt(format!(
+                        "ljpeg: sof.precision {}",
+                        sof.precision
+                    )));
                 }
             } else if marker == m(Marker::DHT) {
                 // Huffman table settings
@@ -172,12 +197,27 @@ impl<'a> LjpegDecompressor<'a> {
                 break;
             } else if marker == m(Marker::EOI) {
                 // Should never be reached as we stop at SOS
-                return Err("ljpeg: reached EOI before SOS".to_string());
+                return Err(RawLoaderError::corrupt("ljpeg: reached EOI before SOS"));
             } else if marker == m(Marker::DQT) {
-                return Err("ljpeg: not a valid raw file, found DQT".to_string());
+                return Err(RawLoaderError::corrupt(
+                    "ljpeg: not a valid raw file, found DQT",
+                ));
             }
         }
 
+        if pt >= sof.precision {
+            return Err(RawLoaderError::corrupt(format!(
+                "ljpeg: point transform {} for {} bits",
+                pt, sof.precision
+            )));
+        }
+        if let Some(c) = sof.components.iter().find(|c| !dht_init[c.dc_tbl_num]) {
+            return Err(RawLoaderError::corrupt(format!(
+                "ljpeg: component {} uses a missing huffman table",
+                c.id
+            )));
+        }
+
         let mut dhts = Vec::new();
         for i in 0..4 {
             dhts.push(if dht_init[i] {
@@ -189,7 +229,7 @@ impl<'a> LjpegDecompressor<'a> {
 
         let offset = input.get_pos();
         Ok(LjpegDecompressor {
-            buffer: &src[offset..],
+            buffer: from_offset(src, offset),
             sof,
             predictor: pred,
             point_transform: pt,
@@ -197,14 +237,18 @@ impl<'a> LjpegDecompressor<'a> {
         })
     }
 
-    fn get_next_marker(input: &mut ByteStream, allowskip: bool) -> Result<u8, String> {
+    fn get_next_marker(input: &mut ByteStream, allowskip: bool) -> Result<u8, RawLoaderError> {
         if !allowskip {
             if input.get_u8() != 0xff {
-                return Err("ljpeg: (noskip) expected marker not found".to_string());
+                return Err(RawLoaderError::corrupt(
+                    "ljpeg: (noskip) expected marker not found",
+                ));
             }
             let mark = input.get_u8();
             if mark == m(Marker::Stuff) || mark == m(Marker::Fill) {
-                return Err("ljpeg: (noskip) expected marker but found stuff or fill".to_string());
+                return Err(RawLoaderError::corrupt(
+                    "ljpeg: (noskip) expected marker but found stuff or fill",
+                ));
             }
             return Ok(mark);
         }
@@ -218,8 +262,10 @@ impl<'a> LjpegDecompressor<'a> {
         init: &mut [bool; 4],
         bits: &mut [[u32; 17]; 4],
         huffval: &mut [[u32; 256]; 4],
-    ) -> Result<(), String> {
-        let mut length = (input.get_u16() as usize) - 2;
+    ) -> Result<(), RawLoaderError> {
+        let mut length = (input.get_u16() as usize)
+            .checked_sub(2)
+            .ok_or_else(|| RawLoaderError::corrupt("ljpeg: invalid DHT length"))?;
 
         while length > 0 {
             let b = input.get_u8() as usize;
@@ -227,10 +273,15 @@ impl<'a> LjpegDecompressor<'a> {
             let th = b & 0xf;
 
             if tc != 0 {
-                return Err("ljpeg: unsuported table class in DHT".to_string());
+                return Err(RawLoaderError::corrupt(
+                    "ljpeg: unsuported table class in DHT",
+                ));
             }
             if th > 3 {
-                return Err(format!("ljpeg: unsuported table id {}", th).to_string());
+                return Err(RawLoaderError::corrupt(format!(
+                    "ljpeg: unsuported table id {}",
+                    th
+                )));
             }
 
             let mut acc: usize = 0;
@@ -241,15 +292,15 @@ impl<'a> LjpegDecompressor<'a> {
             bits[th][0] = 0;
 
             if acc > 256 {
-                return Err("ljpeg: invalid DHT table".to_string());
+                return Err(RawLoaderError::corrupt("ljpeg: invalid DHT table"));
             }
 
             if length < 1 + 16 + acc {
-                return Err("ljpeg: invalid DHT table length".to_string());
+                return Err(RawLoaderError::corrupt("ljpeg: invalid DHT table length"));
             }
 
-            for i in 0..acc {
-                huffval[th][i] = input.get_u8() as u32;
+            for val in huffval[th].iter_mut().take(acc) {
+                *val = input.get_u8() as u32;
             }
 
             init[th] = true;
@@ -267,7 +318,7 @@ impl<'a> LjpegDecompressor<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Result<(), String> {
+    ) -> Result<(), RawLoaderError> {
         if dummy {
             return Ok(());
         }
@@ -283,14 +334,14 @@ impl<'a> LjpegDecompressor<'a> {
                 2 => decode_ljpeg_2components(self, out, x, stripwidth, width, height),
                 3 => decode_ljpeg_3components(self, out, x, stripwidth, width, height),
                 4 => decode_ljpeg_4components(self, out, width, height),
-                c => Err(format!("ljpeg: {} component files not supported", c).to_string()),
+                c => Err(RawLoaderError::compression("LJPEG", c as u32)),
             },
             8 => decode_hasselblad(self, out, width),
-            p => Err(format!("ljpeg: predictor {} not supported", p).to_string()),
+            p => Err(RawLoaderError::compression("LJPEG", p as u32)),
         }
     }
 
-    pub fn decode_leaf(&self, width: usize, height: usize) -> Result<Vec<u16>, String> {
+    pub fn decode_leaf(&self, width: usize, height: usize) -> Result<Vec<u16>, RawLoaderError> {
         let mut offsets = vec![0_usize; 1];
         let mut input = ByteStream::new(self.buffer, BIG_ENDIAN);
 
@@ -302,27 +353,31 @@ impl<'a> LjpegDecompressor<'a> {
             offsets.push(input.get_pos());
         }
 
-        let nstrips = (height - 1) / 8 + 1;
+        let nstrips = height.div_ceil(8);
         if offsets.len() != nstrips {
-            return Err(
-                format!("MOS: expecting {} strips found {}", nstrips, offsets.len()).to_string(),
-            );
+            return Err(RawLoaderError::corrupt(format!(
+                "MOS: expecting {} strips found {}",
+                nstrips,
+                offsets.len()
+            )));
         }
 
+        if self.sof.cps < 2 {
+            return Err(RawLoaderError::corrupt("MOS: expecting 2 components"));
+        }
         let htable1 = &self.dhts[self.sof.components[0].dc_tbl_num];
         let htable2 = &self.dhts[self.sof.components[1].dc_tbl_num];
         let bpred = 1 << (self.sof.precision - self.point_transform - 1);
-        Ok(decode_threaded_multiline(
-            width,
-            height,
-            8,
-            false,
-            &(|strip: &mut [u16], block| {
-                let block = block / 8;
-                let offset = offsets[block];
+        let mut out: Vec<u16> = alloc_image!(width, height, false);
+        if width == 0 {
+            return Ok(out);
+        }
+        out.par_chunks_mut(width * 8)
+            .enumerate()
+            .try_for_each(|(block, strip)| {
                 let nlines = strip.len() / width;
                 decode_leaf_strip(
-                    &self.buffer[offset..],
+                    from_offset(self.buffer, offsets[block]),
                     strip,
                     width,
                     nlines,
@@ -330,9 +385,8 @@ impl<'a> LjpegDecompressor<'a> {
                     htable2,
                     bpred,
                 )
-                .unwrap();
-            }),
-        ))
+            })?;
+        Ok(out)
     }
 
     pub fn width(&self) -> usize {
@@ -20,15 +20,25 @@ impl<'a> MefDecoder<'a> {
 }
 
 impl<'a> Decoder for MefDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
         let raw = fetch_ifd!(&self.tiff, Tag::CFAPattern);
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
         let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
         let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
 
-        let image = decode_12be(src, width, height, dummy);
+        let window = Window::current();
+        let image = decode_12be(src, width, height, window.as_ref(), dummy)?;
         ok_image(camera, width, height, [f32::NAN; 4], image)
+            .map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
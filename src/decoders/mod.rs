@@ -3,50 +3,37 @@ use std::fs::File;
 use std::io::{BufReader, Read};
 use std::panic;
 use std::path::Path;
-use toml::Table;
+use toml::{Table, Value};
 
 macro_rules! fetch_tag {
     ($tiff:expr, $tag:expr) => {
-        $tiff
-            .find_entry($tag)
-            .ok_or(format!("Couldn't find tag {}", stringify!($tag)).to_string())?
+        $tiff.find_entry($tag).ok_or_else(|| {
+            RawLoaderError::corrupt(format!("Couldn't find tag {}", stringify!($tag)))
+        })?
     };
 }
 
 macro_rules! fetch_ifd {
     ($tiff:expr, $tag:expr) => {
-        $tiff
-            .find_first_ifd($tag)
-            .ok_or(format!("Couldn't find ifd with tag {}", stringify!($tag)).to_string())?
+        $tiff.find_first_ifd($tag).ok_or_else(|| {
+            RawLoaderError::corrupt(format!("Couldn't find ifd with tag {}", stringify!($tag)))
+        })?
     };
 }
 
 macro_rules! alloc_image_plain {
     ($width:expr, $height:expr, $dummy: expr) => {{
-        if $width * $height > 500000000 || $width > 50000 || $height > 50000 {
-            panic!(
-                "rawloader: surely there's no such thing as a >500MP or >50000 px wide/tall image!"
-            );
-        }
+        let (width, height): (usize, usize) = ($width, $height);
+        $crate::decoders::check_image_size(width, height)?;
         if $dummy {
             vec![0]
         } else {
-            vec![0; $width * $height]
+            vec![0; width * height]
         }
     }};
 }
 
 macro_rules! alloc_image {
-    ($width:expr, $height:expr, $dummy: expr) => {{
-        let out = alloc_image_plain!($width, $height, $dummy);
-        if $dummy {
-            return out;
-        }
-        out
-    }};
-}
-
-macro_rules! alloc_image_ok {
     ($width:expr, $height:expr, $dummy: expr) => {{
         let out = alloc_image_plain!($width, $height, $dummy);
         if $dummy {
@@ -59,16 +46,25 @@ macro_rules! alloc_image_ok {
 mod ari;
 mod arw;
 mod basics;
+mod bmff;
 pub mod cfa;
 mod ciff;
 mod cr2;
+mod cr3;
 mod crw;
+mod crx;
+pub mod dcp;
 mod dcr;
 mod dcs;
 mod dng;
+pub mod dngcolor;
 mod erf;
+mod error;
+pub mod exif;
+mod fuji_compressed;
 mod iiq;
 mod image;
+mod inflate;
 mod kdc;
 mod ljpeg;
 mod mef;
@@ -77,9 +73,12 @@ mod mrw;
 mod nef;
 mod nkd;
 mod nrw;
+pub mod opcodes;
+mod options;
 mod orf;
 mod packed;
 mod pef;
+mod preview;
 mod pumps;
 mod raf;
 mod rw2;
@@ -87,17 +86,66 @@ mod srw;
 mod tfr;
 mod tiff;
 mod x3f;
+use self::basics::Window;
+pub use self::error::RawLoaderError;
+pub use self::exif::Exif;
 pub use self::image::*;
+pub use self::options::{DecodeOptions, Scale};
+pub use self::preview::Preview;
 use self::tiff::*;
 mod unwrapped;
 
 pub static CAMERAS_TOML: &str = include_str!(concat!(env!("OUT_DIR"), "/all.toml"));
-pub static SAMPLE: &str = "\nPlease submit samples at https://raw.pixls.us/";
-pub static BUG: &str =
-    "\nPlease file a bug with a sample file at https://github.com/pedrocr/rawloader/issues/new";
+
+// Refuses sizes no camera produces, so corrupted headers can't get us to allocate and
+// decode gigabytes of nothing
+pub(crate) fn check_image_size(width: usize, height: usize) -> Result<(), RawLoaderError> {
+    if width
+        .checked_mul(height)
+        .is_none_or(|size| size > 500000000)
+        || width > 50000
+        || height > 50000
+    {
+        return Err(RawLoaderError::limit(format!(
+            "surely there's no such thing as a >500MP or >50000 px wide/tall image ({}x{})",
+            width, height
+        )));
+    }
+    Ok(())
+}
 
 pub trait Decoder {
-    fn image(&self, dummy: bool) -> Result<RawImage, String>;
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError>;
+
+    // Formats without capture metadata get an empty one
+    fn exif(&self) -> Exif {
+        Exif::default()
+    }
+
+    // Formats without embedded previews (or where we don't know how to find them) get none
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(Vec::new())
+    }
+
+    // Most files have a single raw frame, the one image() decodes
+    fn frame_count(&self) -> usize {
+        1
+    }
+
+    fn frame(&self, index: usize, dummy: bool) -> Result<RawImage, RawLoaderError> {
+        if index == 0 {
+            self.image(dummy)
+        } else {
+            Err(no_frame(index, self.frame_count()))
+        }
+    }
+}
+
+pub(crate) fn no_frame(index: usize, count: usize) -> RawLoaderError {
+    RawLoaderError::InvalidArgument(format!(
+        "there's no frame {} in a file with {}",
+        index, count
+    ))
 }
 
 /// Buffer to hold an image in memory with enough extra space at the end for speed optimizations
@@ -109,11 +157,9 @@ pub struct Buffer {
 
 impl Buffer {
     /// Creates a new buffer from anything that can be read
-    pub fn new(reader: &mut dyn Read) -> Result<Buffer, String> {
+    pub fn new(reader: &mut dyn Read) -> Result<Buffer, RawLoaderError> {
         let mut buffer = Vec::new();
-        if let Err(err) = reader.read_to_end(&mut buffer) {
-            return Err(format!("IOError: {}", err).to_string());
-        }
+        reader.read_to_end(&mut buffer)?;
         let size = buffer.len();
         buffer.extend([0; 16].iter().cloned());
         Ok(Buffer { buf: buffer, size })
@@ -134,8 +180,9 @@ pub struct Camera {
     pub orientation: Orientation,
     whitelevels: [u16; 4],
     blacklevels: [u16; 4],
-    blackareah: (usize, usize),
-    blackareav: (usize, usize),
+    sensors: Vec<SensorLevels>,
+    blackareah: Vec<(usize, usize)>,
+    blackareav: Vec<(usize, usize)>,
     xyz_to_cam: [[f32; 3]; 4],
     cfa: cfa::CFA,
     crops: [usize; 4],
@@ -145,6 +192,112 @@ pub struct Camera {
     hints: Vec<String>,
 }
 
+fn toml_error<S: Into<String>>(msg: S) -> RawLoaderError {
+    RawLoaderError::corrupt(format!("cameras TOML: {}", msg.into()))
+}
+
+fn toml_str<'t>(name: &str, val: &'t Value) -> Result<&'t str, RawLoaderError> {
+    val.as_str()
+        .ok_or_else(|| toml_error(format!("{} should be a string", name)))
+}
+
+fn toml_array<'t>(name: &str, val: &'t Value) -> Result<&'t [Value], RawLoaderError> {
+    val.as_array()
+        .map(|vals| vals.as_slice())
+        .ok_or_else(|| toml_error(format!("{} should be an array", name)))
+}
+
+fn toml_table<'t>(name: &str, val: &'t Value) -> Result<&'t Table, RawLoaderError> {
+    val.as_table()
+        .ok_or_else(|| toml_error(format!("{} should be a table", name)))
+}
+
+fn toml_int<T: TryFrom<i64>>(name: &str, val: &Value) -> Result<T, RawLoaderError> {
+    val.as_integer()
+        .and_then(|val| T::try_from(val).ok())
+        .ok_or_else(|| toml_error(format!("{} should be an integer in range", name)))
+}
+
+fn toml_ints<T: TryFrom<i64>>(name: &str, val: &Value) -> Result<Vec<T>, RawLoaderError> {
+    toml_array(name, val)?
+        .iter()
+        .map(|val| toml_int(name, val))
+        .collect()
+}
+
+// Masked areas as a list of [position, size] or a single one as just [position, size]
+fn toml_areas(name: &str, val: &Value) -> Result<Vec<(usize, usize)>, RawLoaderError> {
+    let vals = toml_array(name, val)?;
+    let area = |val: &Value| {
+        let area = toml_ints::<usize>(name, val)?;
+        if area.len() != 2 {
+            return Err(toml_error(format!("{} needs a position and a size", name)));
+        }
+        Ok((area[0], area[1]))
+    };
+    let areas = if vals.first().is_some_and(|val| val.is_array()) {
+        vals.iter().map(area).collect::<Result<Vec<_>, _>>()?
+    } else if vals.is_empty() {
+        Vec::new()
+    } else {
+        vec![area(val)?]
+    };
+    Ok(areas.into_iter().filter(|&(_, size)| size > 0).collect())
+}
+
+// The black and white levels of a camera for a range or list of ISOs
+#[derive(Debug, Clone)]
+struct SensorLevels {
+    black: u16,
+    white: u16,
+    black_colors: Vec<u16>,
+    iso_min: u32,
+    iso_max: u32,
+    iso_list: Vec<u32>,
+}
+
+impl SensorLevels {
+    fn from_toml(ct: &Table) -> Result<SensorLevels, RawLoaderError> {
+        let get = |name: &str| {
+            ct.get(name)
+                .ok_or_else(|| toml_error(format!("sensors need a {}", name)))
+        };
+        Ok(SensorLevels {
+            black: toml_int("black", get("black")?)?,
+            white: toml_int("white", get("white")?)?,
+            black_colors: match ct.get("black_colors") {
+                Some(val) => toml_ints("black_colors", val)?,
+                None => Vec::new(),
+            },
+            iso_min: match ct.get("iso_min") {
+                Some(val) => toml_int("iso_min", val)?,
+                None => 0,
+            },
+            iso_max: match ct.get("iso_max") {
+                Some(val) => toml_int("iso_max", val)?,
+                None => 0,
+            },
+            iso_list: match ct.get("iso_list") {
+                Some(val) => toml_ints("iso_list", val)?,
+                None => Vec::new(),
+            },
+        })
+    }
+
+    // Levels without an ISO range or list are the default for the ISOs no others cover
+    fn is_default(&self) -> bool {
+        self.iso_list.is_empty() && self.iso_min == 0 && self.iso_max == 0
+    }
+
+    fn covers(&self, iso: u32) -> bool {
+        if !self.iso_list.is_empty() {
+            self.iso_list.contains(&iso)
+        } else {
+            iso >= self.iso_min && (self.iso_max == 0 || iso <= self.iso_max)
+        }
+    }
+}
+
 impl Default for Camera {
     fn default() -> Self {
         Self::new()
@@ -156,88 +309,124 @@ impl Camera {
         self.hints.contains(&(hint.to_string()))
     }
 
-    pub fn update_from_toml(&mut self, ct: &Table) {
+    pub fn update_from_toml(&mut self, ct: &Table) -> Result<(), RawLoaderError> {
+        // Levels set without their ISO table replace the one of the camera this is based on
+        if (ct.contains_key("blackpoint") || ct.contains_key("whitepoint"))
+            && !ct.contains_key("sensors")
+        {
+            self.sensors = Vec::new();
+        }
         for (name, val) in ct {
             match name.as_ref() {
                 "make" => {
-                    self.make = val.as_str().unwrap().to_string().clone();
+                    self.make = toml_str(name, val)?.to_string();
                 }
                 "model" => {
-                    self.model = val.as_str().unwrap().to_string().clone();
+                    self.model = toml_str(name, val)?.to_string();
                 }
                 "mode" => {
-                    self.mode = val.as_str().unwrap().to_string().clone();
+                    self.mode = toml_str(name, val)?.to_string();
                 }
                 "clean_make" => {
-                    self.clean_make = val.as_str().unwrap().to_string().clone();
+                    self.clean_make = toml_str(name, val)?.to_string();
                 }
                 "clean_model" => {
-                    self.clean_model = val.as_str().unwrap().to_string().clone();
+                    self.clean_model = toml_str(name, val)?.to_string();
                 }
                 "whitepoint" => {
-                    let white = val.as_integer().unwrap() as u16;
-                    self.whitelevels = [white, white, white, white];
+                    self.whitelevels = [toml_int(name, val)?; 4];
                 }
                 "blackpoint" => {
-                    let black = val.as_integer().unwrap() as u16;
-                    self.blacklevels = [black, black, black, black];
+                    self.blacklevels = [toml_int(name, val)?; 4];
+                }
+                "sensors" => {
+                    self.sensors = toml_array(name, val)?
+                        .iter()
+                        .map(|val| SensorLevels::from_toml(toml_table(name, val)?))
+                        .collect::<Result<_, _>>()?;
                 }
                 "blackareah" => {
-                    let vals = val.as_array().unwrap();
-                    self.blackareah = (
-                        vals[0].as_integer().unwrap() as usize,
-                        vals[1].as_integer().unwrap() as usize,
-                    );
+                    self.blackareah = toml_areas(name, val)?;
                 }
                 "blackareav" => {
-                    let vals = val.as_array().unwrap();
-                    self.blackareav = (
-                        vals[0].as_integer().unwrap() as usize,
-                        vals[1].as_integer().unwrap() as usize,
-                    );
+                    self.blackareav = toml_areas(name, val)?;
                 }
                 "color_matrix" => {
-                    let matrix = val.as_array().unwrap();
-                    for (i, val) in matrix.iter().enumerate() {
-                        self.xyz_to_cam[i / 3][i % 3] = val.as_integer().unwrap() as f32;
+                    let matrix = toml_ints::<i64>(name, val)?;
+                    if matrix.len() > 12 {
+                        return Err(toml_error(format!("{} has over 12 values", name)));
+                    }
+                    for (i, val) in matrix.into_iter().enumerate() {
+                        self.xyz_to_cam[i / 3][i % 3] = val as f32;
                     }
                 }
                 "crops" => {
-                    let crop_vals = val.as_array().unwrap();
-                    for (i, val) in crop_vals.iter().enumerate() {
-                        self.crops[i] = val.as_integer().unwrap() as usize;
+                    let crops = toml_ints::<usize>(name, val)?;
+                    if crops.len() > 4 {
+                        return Err(toml_error(format!("{} has over 4 values", name)));
+                    }
+                    for (i, val) in crops.into_iter().enumerate() {
+                        self.crops[i] = val;
                     }
                 }
                 "color_pattern" => {
-                    self.cfa = cfa::CFA::new(val.as_str().unwrap());
+                    self.cfa = cfa::CFA::from_name(toml_str(name, val)?)?;
                 }
                 "bps" => {
-                    self.bps = val.as_integer().unwrap() as usize;
+                    self.bps = toml_int(name, val)?;
                 }
                 "wb_offset" => {
-                    self.wb_offset = val.as_integer().unwrap() as usize;
+                    self.wb_offset = toml_int(name, val)?;
                 }
                 "filesize" => {
-                    self.filesize = val.as_integer().unwrap() as usize;
+                    self.filesize = toml_int(name, val)?;
                 }
                 "raw_width" => {
-                    self.raw_width = val.as_integer().unwrap() as usize;
+                    self.raw_width = toml_int(name, val)?;
                 }
                 "raw_height" => {
-                    self.raw_height = val.as_integer().unwrap() as usize;
+                    self.raw_height = toml_int(name, val)?;
                 }
                 "highres_width" => {
-                    self.highres_width = val.as_integer().unwrap() as usize;
+                    self.highres_width = toml_int(name, val)?;
                 }
                 "hints" => {
                     self.hints = Vec::new();
-                    for hint in val.as_array().unwrap() {
-                        self.hints.push(hint.as_str().unwrap().to_string());
+                    for hint in toml_array(name, val)? {
+                        self.hints.push(toml_str(name, hint)?.to_string());
                     }
                 }
                 _ => {}
             }
         }
+        Ok(())
+    }
+
+    // Sets the black and white levels to the ones for the ISO of the capture, for the
+    // cameras where they change with it. When the ISO isn't known or none of the levels
+    // cover it the camera keeps the lowest black and highest white of all of them
+    pub(crate) fn set_iso(&mut self, iso: Option<u32>) {
+        let Some(iso) = iso else {
+            return;
+        };
+        let Some(sensor) = self
+            .sensors
+            .iter()
+            .filter(|sensor| sensor.covers(iso))
+            .min_by_key(|sensor| sensor.is_default())
+        else {
+            return;
+        };
+
+        self.whitelevels = [sensor.white; 4];
+        self.blacklevels = [sensor.black; 4];
+        // The separate levels are for each position of the 2x2 CFA, the first position of
+        // each color sets its level
+        if sensor.black_colors.len() == 4 && self.cfa.is_valid() {
+            for (i, black) in sensor.black_colors.iter().enumerate().rev() {
+                self.blacklevels[self.cfa.color_at(i / 2, i % 2) & 3] = *black;
+            }
+        }
     }
 
     pub fn new() -> Camera {
@@ -252,8 +441,9 @@ impl Camera {
             raw_height: 0,
             whitelevels: [0; 4],
             blacklevels: [0; 4],
-            blackareah: (0, 0),
-            blackareav: (0, 0),
+            sensors: Vec::new(),
+            blackareah: Vec::new(),
+            blackareav: Vec::new(),
             xyz_to_cam: [[0.0; 3]; 4],
             cfa: cfa::CFA::new(""),
             crops: [0, 0, 0, 0],
@@ -363,7 +553,7 @@ pub fn ok_image(
     height: usize,
     wb_coeffs: [f32; 4],
     image: Vec<u16>,
-) -> Result<RawImage, String> {
+) -> Result<RawImage, RawLoaderError> {
     Ok(RawImage::new(
         camera, width, height, wb_coeffs, image, false,
     ))
@@ -376,9 +566,11 @@ pub fn ok_image_with_blacklevels(
     wb_coeffs: [f32; 4],
     blacks: [u16; 4],
     image: Vec<u16>,
-) -> Result<RawImage, String> {
+) -> Result<RawImage, RawLoaderError> {
     let mut img = RawImage::new(camera, width, height, wb_coeffs, image, false);
+    // The file's own levels replace any measured from the masked areas
     img.blacklevels = blacks;
+    img.black = None;
     Ok(img)
 }
 
@@ -390,14 +582,23 @@ pub fn ok_image_with_black_white(
     black: u16,
     white: u16,
     image: Vec<u16>,
-) -> Result<RawImage, String> {
+) -> Result<RawImage, RawLoaderError> {
     let mut img = RawImage::new(camera, width, height, wb_coeffs, image, false);
     img.blacklevels = [black, black, black, black];
+    img.black = None;
     img.whitelevels = [white, white, white, white];
     Ok(img)
 }
 
-/// The struct that holds all the info about the cameras and is able to decode a file
+/// The struct that holds all the info about the cameras and is able to decode a file. The
+/// functions at the crate root use one with the included camera database, create one to
+/// add cameras or change their values
+///
+/// # Example
+/// ```rust,ignore
+/// let loader = rawloader2::RawLoader::from_cameras_xml(Path::new("cameras.xml")).unwrap();
+/// let image = loader.decode_file(Path::new("path/to/your/file.RAW")).unwrap();
+/// ```
 #[derive(Debug, Clone)]
 pub struct RawLoader {
     cameras: HashMap<(String, String, String), Camera>,
@@ -413,74 +614,108 @@ impl Default for RawLoader {
 impl RawLoader {
     /// Creates a new raw loader using the camera information included in the library
     pub fn new() -> RawLoader {
-        let toml = match CAMERAS_TOML.parse::<Table>() {
+        let loader = RawLoader {
+            cameras: HashMap::new(),
+            naked: HashMap::new(),
+        };
+        match loader.with_overrides(CAMERAS_TOML) {
             Ok(val) => val,
-            Err(e) => panic!("Error parsing all.toml: {:?}", e),
+            Err(e) => panic!("Error parsing all.toml: {}", e),
+        }
+    }
+
+    /// Creates a new raw loader with the cameras of a TOML file, in the same format as the
+    /// database included in the library, merged over the included ones
+    pub fn from_toml(toml: &str) -> Result<RawLoader, RawLoaderError> {
+        RawLoader::new().with_overrides(toml)
+    }
+
+    /// Creates a new raw loader with the cameras of a rawspeed cameras.xml file merged over
+    /// the ones included in the library, to support cameras newer than the library
+    pub fn from_cameras_xml(path: &Path) -> Result<RawLoader, RawLoaderError> {
+        let xml = std::fs::read_to_string(path)?;
+        let toml = crate::cameras_xml::to_toml(&xml)?;
+        RawLoader::from_toml(&toml)
+    }
+
+    /// Merges the cameras of a TOML file over the ones already in the loader. Cameras and
+    /// modes that are already known only get the values set in the file replaced, new
+    /// ones get added
+    pub fn with_overrides(mut self, toml: &str) -> Result<RawLoader, RawLoaderError> {
+        let toml = toml
+            .parse::<Table>()
+            .map_err(|e| toml_error(e.to_string()))?;
+        let Some(cameras) = toml.get("cameras") else {
+            return Ok(self);
         };
 
-        let mut cams = Vec::new();
-        for camera in toml.get("cameras").unwrap().as_array().unwrap() {
+        for camera in toml_array("cameras", cameras)? {
             // Create a list of all the camera modes including the base one
             let mut cammodes = Vec::new();
-            let ct = camera.as_table().unwrap();
+            let ct = toml_table("cameras", camera)?;
             cammodes.push(ct);
             if let Some(val) = ct.get("modes") {
-                for mode in val.as_array().unwrap() {
-                    cammodes.push(mode.as_table().unwrap());
+                for mode in toml_array("modes", val)? {
+                    cammodes.push(toml_table("modes", mode)?);
                 }
             }
 
             // Start with the basic camera
             let mut cam = Camera::new();
-            cam.update_from_toml(cammodes[0]);
+            cam.update_from_toml(cammodes[0])?;
             // Create a list of alias names including the base one
             let mut camnames = Vec::new();
-            camnames.push((cam.model.clone(), cam.clean_model.clone()));
+            camnames.push((cam.model.clone(), None));
             if let Some(val) = ct.get("model_aliases") {
-                for alias in val.as_array().unwrap() {
+                for alias in toml_array("model_aliases", val)? {
+                    let alias = toml_array("model_aliases", alias)?;
+                    if alias.len() != 2 {
+                        return Err(toml_error("model_aliases need a model and a clean model"));
+                    }
                     camnames.push((
-                        alias[0].as_str().unwrap().to_string().clone(),
-                        alias[1].as_str().unwrap().to_string().clone(),
+                        toml_str("model_aliases", &alias[0])?.to_string(),
+                        Some(toml_str("model_aliases", &alias[1])?.to_string()),
                     ));
                 }
             }
 
-            // For each combination of alias and mode (including the base ones) create Camera
+            // For each combination of alias and mode (including the base ones) create Camera,
+            // starting from the one we already have if it's known
             for (model, clean_model) in camnames {
-                for ct in cammodes.clone() {
+                for ct in cammodes.iter() {
                     let mut mcam = cam.clone();
-                    mcam.update_from_toml(ct);
+                    mcam.update_from_toml(ct)?;
+                    let key = (mcam.make.clone(), model.clone(), mcam.mode.clone());
+                    if let Some(known) = self.cameras.get(&key) {
+                        mcam = known.clone();
+                        mcam.update_from_toml(cammodes[0])?;
+                        mcam.update_from_toml(ct)?;
+                    }
                     mcam.model = model.clone();
-                    mcam.clean_model = clean_model.clone();
-                    cams.push(mcam);
+                    if let Some(clean_model) = &clean_model {
+                        mcam.clean_model = clean_model.clone();
+                    }
+                    if mcam.filesize > 0 {
+                        self.naked.insert(mcam.filesize, mcam.clone());
+                    }
+                    self.cameras.insert(key, mcam);
                 }
             }
         }
 
-        let mut map = HashMap::new();
-        let mut naked = HashMap::new();
-        for cam in cams {
-            map.insert(
-                (cam.make.clone(), cam.model.clone(), cam.mode.clone()),
-                cam.clone(),
-            );
-            if cam.filesize > 0 {
-                naked.insert(cam.filesize, cam);
-            }
-        }
-
-        RawLoader {
-            cameras: map,
-            naked,
-        }
+        Ok(self)
     }
 
     /// Returns a decoder for a given buffer
-    pub fn get_decoder<'b>(&'b self, buf: &'b Buffer) -> Result<Box<dyn Decoder + 'b>, String> {
+    #[doc(hidden)]
+    pub fn get_decoder<'b>(
+        &'b self,
+        buf: &'b Buffer,
+    ) -> Result<Box<dyn Decoder + 'b>, RawLoaderError> {
         let buffer = &buf.buf;
 
         if mrw::is_mrw(buffer) {
-            let dec = Box::new(mrw::MrwDecoder::new(buffer, self));
+            let dec = Box::new(mrw::MrwDecoder::new(buffer, self)?);
             return Ok(dec as Box<dyn Decoder>);
         }
 
@@ -490,13 +725,19 @@ impl RawLoader {
             return Ok(dec as Box<dyn Decoder>);
         }
 
+        if bmff::is_cr3(buffer) {
+            let bmff = bmff::BmffFile::new(buf)?;
+            let dec = Box::new(cr3::Cr3Decoder::new(buffer, bmff, self));
+            return Ok(dec as Box<dyn Decoder>);
+        }
+
         if ari::is_ari(buffer) {
             let dec = Box::new(ari::AriDecoder::new(buffer, self));
             return Ok(dec as Box<dyn Decoder>);
         }
 
         if x3f::is_x3f(buffer) {
-            let dec = Box::new(x3f::X3fDecoder::new(buf, self));
+            let dec = Box::new(x3f::X3fDecoder::new(buf, self)?);
             return Ok(dec as Box<dyn Decoder>);
         }
 
@@ -543,11 +784,13 @@ impl RawLoader {
                     "NIKON" => use_decoder!(nrw::NrwDecoder, buffer, tiff, self),
                     "Canon" => use_decoder!(cr2::Cr2Decoder, buffer, tiff, self),
                     "Phase One A/S" => use_decoder!(iiq::IiqDecoder, buffer, tiff, self),
-                    make => Err(format!(
-                        "Couldn't find a decoder for make \"{}\".{}",
-                        make, SAMPLE
-                    )
-                    .to_string()),
+                    make => Err(RawLoaderError::UnsupportedCamera {
+                        make: make.to_string(),
+                        model: tiff
+                            .find_entry(Tag::Model)
+                            .map_or(String::new(), |e| e.get_str().to_string()),
+                        mode: String::new(),
+                    }),
                 };
             } else if tiff.has_entry(Tag::Software) {
                 // Last ditch effort to identify Leaf cameras without Make and Model
@@ -562,7 +805,9 @@ impl RawLoader {
             return Ok(Box::new(nkd::NakedDecoder::new(buffer, cam.clone(), self)));
         }
 
-        Err(format!("Couldn't find a decoder for this file.{}", SAMPLE).to_string())
+        Err(RawLoaderError::UnknownFormat(
+            "Couldn't find a decoder for this file.".to_string(),
+        ))
     }
 
     fn check_supported_with_everything(
@@ -570,16 +815,17 @@ impl RawLoader {
         make: &str,
         model: &str,
         mode: &str,
-    ) -> Result<Camera, String> {
+    ) -> Result<Camera, RawLoaderError> {
         match self
             .cameras
             .get(&(make.to_string(), model.to_string(), mode.to_string()))
         {
             Some(cam) => Ok(cam.clone()),
-            None => Err(format!(
-                "Couldn't find camera \"{}\" \"{}\" mode \"{}\".{}",
-                make, model, mode, SAMPLE
-            )),
+            None => Err(RawLoaderError::UnsupportedCamera {
+                make: make.to_string(),
+                model: model.to_string(),
+                mode: mode.to_string(),
+            }),
         }
     }
 
@@ -587,7 +833,7 @@ impl RawLoader {
         &'a self,
         tiff: &'a TiffIFD,
         mode: &str,
-    ) -> Result<Camera, String> {
+    ) -> Result<Camera, RawLoaderError> {
         let make = fetch_tag!(tiff, Tag::Make).get_str();
         let model = fetch_tag!(tiff, Tag::Model).get_str();
 
@@ -596,48 +842,327 @@ impl RawLoader {
 
         // Lookup the orientation of the image for later image rotation
         camera.orientation = Orientation::from_tiff(tiff);
+        camera.set_iso(exif::iso(tiff));
 
         Ok(camera)
     }
 
-    fn check_supported<'a>(&'a self, tiff: &'a TiffIFD) -> Result<Camera, String> {
+    fn check_supported<'a>(&'a self, tiff: &'a TiffIFD) -> Result<Camera, RawLoaderError> {
         self.check_supported_with_mode(tiff, "")
     }
 
-    fn decode_unsafe(&self, buffer: &Buffer, dummy: bool) -> Result<RawImage, String> {
+    fn decode_unsafe(
+        &self,
+        buffer: &Buffer,
+        frame: usize,
+        dummy: bool,
+    ) -> Result<RawImage, RawLoaderError> {
         let decoder = self.get_decoder(buffer)?;
-        decoder.image(dummy)
+        let mut image = decoder.frame(frame, dummy)?;
+        // Sizes from tags don't always match what the decoder actually produced
+        let len = match &image.data {
+            RawImageData::Integer(data) => data.len(),
+            RawImageData::Float(data) => data.len(),
+        };
+        let needed = image
+            .width
+            .checked_mul(image.height)
+            .and_then(|size| size.checked_mul(image.cpp));
+        if !dummy && needed.is_none_or(|needed| needed > len) {
+            return Err(RawLoaderError::corrupt(format!(
+                "{}x{}x{} image with only {} values decoded",
+                image.width, image.height, image.cpp, len
+            )));
+        }
+        image.exif = decoder.exif();
+        Ok(image)
     }
 
-    /// Decodes an input into a RawImage
-    pub fn decode(&self, reader: &mut dyn Read, dummy: bool) -> Result<RawImage, String> {
+    /// Decodes an input into a RawImage. With `dummy` set the metadata is read but the image
+    /// data is left unpacked, which is only useful to test the parsers
+    pub fn decode(&self, reader: &mut dyn Read, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let buffer = Buffer::new(reader)?;
+        self.decode_caught(&buffer, 0, dummy)
+    }
 
-        match panic::catch_unwind(|| self.decode_unsafe(&buffer, dummy)) {
+    fn decode_caught(
+        &self,
+        buffer: &Buffer,
+        frame: usize,
+        dummy: bool,
+    ) -> Result<RawImage, RawLoaderError> {
+        // Bad input is always reported as an error, this only catches decoder bugs and only
+        // when built with panic = "unwind"
+        match panic::catch_unwind(|| self.decode_unsafe(buffer, frame, dummy)) {
             Ok(val) => val,
-            Err(_) => Err(format!("Caught a panic while decoding.{}", BUG).to_string()),
+            Err(_) => Err(RawLoaderError::InternalPanic),
         }
     }
 
-    /// Decodes a file into a RawImage
-    pub fn decode_file(&self, path: &Path) -> Result<RawImage, String> {
-        let file = match File::open(path) {
+    /// Counts the raw frames in an input, most files have a single one but some formats
+    /// can store several exposures or the separate frames of a pixel shift
+    pub fn frame_count(&self, reader: &mut dyn Read) -> Result<usize, RawLoaderError> {
+        let buffer = Buffer::new(reader)?;
+        self.frame_count_caught(&buffer)
+    }
+
+    fn frame_count_caught(&self, buffer: &Buffer) -> Result<usize, RawLoaderError> {
+        match panic::catch_unwind(|| self.get_decoder(buffer).map(|dec| dec.frame_count())) {
             Ok(val) => val,
-            Err(e) => return Err(e.to_string()),
-        };
+            Err(_) => Err(RawLoaderError::InternalPanic),
+        }
+    }
+
+    /// Decodes one of the raw frames of an input, with 0 being the one `decode()` returns
+    pub fn decode_frame(
+        &self,
+        reader: &mut dyn Read,
+        index: usize,
+    ) -> Result<RawImage, RawLoaderError> {
+        let buffer = Buffer::new(reader)?;
+        self.decode_caught(&buffer, index, false)
+    }
+
+    /// Decodes all the raw frames of an input, each with its own metadata
+    pub fn decode_all_frames(
+        &self,
+        reader: &mut dyn Read,
+    ) -> Result<Vec<RawImage>, RawLoaderError> {
+        let buffer = Buffer::new(reader)?;
+
+        (0..self.frame_count_caught(&buffer)?)
+            .map(|frame| self.decode_caught(&buffer, frame, false))
+            .collect()
+    }
+
+    /// Decodes an input into a RawImage with the given options
+    pub fn decode_with(
+        &self,
+        reader: &mut dyn Read,
+        options: &DecodeOptions,
+    ) -> Result<RawImage, RawLoaderError> {
+        let buffer = Buffer::new(reader)?;
+
+        options.run(|| {
+            let mut window = None;
+            if options.needs_size() {
+                // A dummy decode gets us the size without allocating the image
+                let image = self.decode_caught(&buffer, options.frame, true)?;
+                options.check_limits(&image)?;
+                window = options.window(&image)?;
+            }
+            let mut image =
+                Window::with(window, || self.decode_caught(&buffer, options.frame, false))?;
+            options.finish(&mut image);
+            Ok(image)
+        })
+    }
+
+    /// Reads the metadata of an input without decoding the image data
+    pub fn decode_metadata(&self, reader: &mut dyn Read) -> Result<RawMetadata, RawLoaderError> {
+        self.decode(reader, true).map(RawImage::into_metadata)
+    }
+
+    /// Extracts the previews and thumbnails embedded in an input, largest first
+    pub fn extract_previews(&self, reader: &mut dyn Read) -> Result<Vec<Preview>, RawLoaderError> {
+        let buffer = Buffer::new(reader)?;
+
+        match panic::catch_unwind(|| self.get_decoder(&buffer)?.previews()) {
+            Ok(val) => val.map(preview::sort_previews),
+            Err(_) => Err(RawLoaderError::InternalPanic),
+        }
+    }
+
+    /// Decodes a file into a RawImage
+    pub fn decode_file(&self, path: &Path) -> Result<RawImage, RawLoaderError> {
+        let file = File::open(path)?;
         let mut buffered_file = BufReader::new(file);
         self.decode(&mut buffered_file, false)
     }
 
+    /// Decodes a file into a RawImage with the given options
+    pub fn decode_file_with(
+        &self,
+        path: &Path,
+        options: &DecodeOptions,
+    ) -> Result<RawImage, RawLoaderError> {
+        let file = File::open(path)?;
+        let mut buffered_file = BufReader::new(file);
+        self.decode_with(&mut buffered_file, options)
+    }
+
     // Decodes an unwrapped input (just the image data with minimal metadata) into a RawImage
     // This is only useful for fuzzing really
     #[doc(hidden)]
-    pub fn decode_unwrapped(&self, reader: &mut dyn Read) -> Result<RawImageData, String> {
+    pub fn decode_unwrapped(&self, reader: &mut dyn Read) -> Result<RawImageData, RawLoaderError> {
         let buffer = Buffer::new(reader)?;
 
         match panic::catch_unwind(|| unwrapped::decode_unwrapped(&buffer)) {
             Ok(val) => val,
-            Err(_) => Err(format!("Caught a panic while decoding.{}", BUG).to_string()),
+            Err(_) => Err(RawLoaderError::InternalPanic),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES_XML: &str = r#"<Cameras version="1">
+	<Camera make="NIKON CORPORATION" model="NIKON D7000">
+		<ID make="Nikon" model="D7000">Nikon D7000</ID>
+		<Sensor black="0" white="15520"/>
+		<Sensor black="0" white="15000" iso_min="3200" iso_max="25600"/>
+	</Camera>
+	<Camera make="NIKON CORPORATION" model="NIKON D7000" mode="12bit-compressed">
+		<ID make="Nikon" model="D7000">Nikon D7000</ID>
+		<Sensor black="0" white="3880"/>
+	</Camera>
+</Cameras>"#;
+
+    fn levels(loader: &RawLoader, mode: &str, iso: u32) -> (u16, u16) {
+        let mut camera = loader
+            .check_supported_with_everything("NIKON CORPORATION", "NIKON D7000", mode)
+            .unwrap();
+        camera.set_iso(Some(iso));
+        (camera.blacklevels[0], camera.whitelevels[0])
+    }
+
+    #[test]
+    fn modes_keep_their_own_levels() {
+        let toml = crate::cameras_xml::to_toml(MODES_XML).unwrap();
+        assert!(toml.contains("[[cameras.modes.sensors]]"));
+        let loader = RawLoader::from_toml(&toml).unwrap();
+        assert_eq!(levels(&loader, "", 100), (0, 15520));
+        assert_eq!(levels(&loader, "", 6400), (0, 15000));
+        assert_eq!(levels(&loader, "12bit-compressed", 100), (0, 3880));
+        assert_eq!(levels(&loader, "12bit-compressed", 6400), (0, 3880));
+    }
+
+    #[test]
+    fn levels_replace_inherited_iso_table() {
+        let toml = crate::cameras_xml::to_toml(MODES_XML).unwrap();
+        let loader = RawLoader::from_toml(&toml)
+            .unwrap()
+            .with_overrides(
+                r#"
+                [[cameras]]
+                make = "NIKON CORPORATION"
+                model = "NIKON D7000"
+                whitepoint = 16000
+                "#,
+            )
+            .unwrap();
+        assert_eq!(levels(&loader, "", 6400), (0, 16000));
+        assert_eq!(levels(&loader, "12bit-compressed", 6400), (0, 3880));
+    }
+
+    const BASE_TOML: &str = r#"
+        [[cameras]]
+        make = "Maker"
+        model = "Model"
+        blackpoint = 100
+        whitepoint = 4000
+        crops = [1, 2, 3, 4]
+        bps = 12
+        "#;
+
+    fn maker_model(loader: &RawLoader) -> Camera {
+        loader
+            .check_supported_with_everything("Maker", "Model", "")
+            .unwrap()
+    }
+
+    #[test]
+    fn overrides_only_replace_their_values() {
+        let loader = RawLoader::new()
+            .with_overrides(BASE_TOML)
+            .unwrap()
+            .with_overrides(
+                r#"
+                [[cameras]]
+                make = "Maker"
+                model = "Model"
+                crops = [5, 6, 7, 8]
+                "#,
+            )
+            .unwrap();
+        let camera = maker_model(&loader);
+        assert_eq!(camera.crops, [5, 6, 7, 8]);
+        assert_eq!(camera.blacklevels, [100; 4]);
+        assert_eq!(camera.whitelevels, [4000; 4]);
+        assert_eq!(camera.bps, 12);
+    }
+
+    #[test]
+    fn later_overrides_win() {
+        let white = |whitepoint: u16| {
+            format!(
+                "[[cameras]]\nmake = \"Maker\"\nmodel = \"Model\"\nwhitepoint = {}\n",
+                whitepoint
+            )
+        };
+        let loader = RawLoader::from_toml(BASE_TOML)
+            .unwrap()
+            .with_overrides(&white(4095))
+            .unwrap()
+            .with_overrides(&white(16383))
+            .unwrap();
+        assert_eq!(maker_model(&loader).whitelevels, [16383; 4]);
+        assert_eq!(maker_model(&loader).blacklevels, [100; 4]);
+    }
+
+    #[test]
+    fn bad_cameras_xml_is_corrupt() {
+        let err = crate::cameras_xml::to_toml("<Cameras><Camera").unwrap_err();
+        assert!(matches!(err, RawLoaderError::CorruptData { .. }));
+    }
+
+    #[test]
+    fn cameras_xml_over_the_included_ones() {
+        let included = RawLoader::new();
+        let mut keys: Vec<_> = included.cameras.keys().cloned().collect();
+        keys.sort();
+        let (make, model, _) = keys.iter().find(|k| k.2.is_empty()).unwrap().clone();
+        let xml = format!(
+            r#"<Cameras version="1">
+	<Camera make="{0}" model="{1}">
+		<ID make="{0}" model="{1}">{0} {1}</ID>
+		<Sensor black="12" white="1234"/>
+	</Camera>
+	<Camera make="Maker" model="Model">
+		<ID make="Maker" model="Model">Maker Model</ID>
+		<Sensor black="0" white="4095"/>
+	</Camera>
+</Cameras>"#,
+            make, model
+        );
+        let path = std::env::temp_dir().join("rawloader2-overrides-cameras.xml");
+        std::fs::write(&path, xml).unwrap();
+        let loader = RawLoader::from_cameras_xml(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loader = loader.unwrap();
+
+        // The file wins for the cameras it has and the rest are still there
+        let camera = loader
+            .check_supported_with_everything(&make, &model, "")
+            .unwrap();
+        assert_eq!((camera.blacklevels[0], camera.whitelevels[0]), (12, 1234));
+        assert_eq!(maker_model(&loader).whitelevels, [4095; 4]);
+        for key in keys {
+            assert!(loader.cameras.contains_key(&key));
+        }
+    }
+
+    #[test]
+    fn bad_overrides_are_errors() {
+        let alias = r#"
+            [[cameras]]
+            make = "Maker"
+            model = "Model"
+            model_aliases = [["Alias"]]
+            "#;
+        assert!(RawLoader::new().with_overrides(alias).is_err());
+        assert!(RawLoader::new().with_overrides("[[cameras]\n").is_err());
+    }
+}
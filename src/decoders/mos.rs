@@ -21,38 +21,52 @@ impl<'a> MosDecoder<'a> {
 }
 
 impl<'a> Decoder for MosDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let make = self.xmp_tag("Make")?;
         let model_full = self.xmp_tag("Model")?.to_string();
-        let model = model_full.split_terminator("(").next().unwrap();
-        let camera = self
+        let model = model_full.split_terminator("(").next().unwrap_or("");
+        let mut camera = self
             .rawloader
             .check_supported_with_everything(&make, model, "")?;
+        camera.set_iso(exif::iso(&self.tiff));
 
         let raw = fetch_ifd!(&self.tiff, Tag::TileOffsets);
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
         let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
         let offset = fetch_tag!(raw, Tag::TileOffsets).get_usize(0);
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
 
+        let window = Window::current();
         let image = match fetch_tag!(raw, Tag::Compression).get_usize(0) {
             1 => {
                 if self.tiff.little_endian() {
-                    decode_16le(src, width, height, dummy)
+                    decode_16le(src, width, height, window.as_ref(), dummy)?
                 } else {
-                    decode_16be(src, width, height, dummy)
+                    decode_16be(src, width, height, window.as_ref(), dummy)?
                 }
             }
-            7 | 99 => self.decode_compressed(&camera, src, width, height, dummy)?,
-            x => return Err(format!("MOS: unsupported compression {}", x).to_string()),
+            7 | 99 => {
+                let image = self.decode_compressed(&camera, src, width, height, dummy)?;
+                crop_window(image, width, window.as_ref())?
+            }
+            x => return Err(RawLoaderError::compression("MOS", x as u32)),
         };
 
         ok_image(camera, width, height, self.get_wb()?, image)
+            .map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
 
 impl<'a> MosDecoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         let meta = fetch_tag!(self.tiff, Tag::LeafMetadata).get_data();
         let mut pos = 0;
         // We need at least 16+45+10 bytes for the NeutObj_neutrals section itself
@@ -80,13 +94,16 @@ impl<'a> MosDecoder<'a> {
         Ok([f32::NAN; 4])
     }
 
-    fn xmp_tag(&self, tag: &str) -> Result<String, String> {
+    fn xmp_tag(&self, tag: &str) -> Result<String, RawLoaderError> {
         let xmp = fetch_tag!(self.tiff, Tag::Xmp).get_str();
-        let error = format!("MOS: Couldn't find XMP tag {}", tag).to_string();
-        let start = xmp.find(&format!("<tiff:{}>", tag)).ok_or(error.clone())?;
-        let end = xmp.find(&format!("</tiff:{}>", tag)).ok_or(error.clone())?;
+        let error = || RawLoaderError::corrupt(format!("MOS: Couldn't find XMP tag {}", tag));
+        let start = xmp.find(&format!("<tiff:{}>", tag)).ok_or_else(error)?;
+        let end = xmp.find(&format!("</tiff:{}>", tag)).ok_or_else(error)?;
 
-        Ok(xmp[start + tag.len() + 7..end].to_string())
+        Ok(xmp
+            .get(start + tag.len() + 7..end)
+            .ok_or_else(error)?
+            .to_string())
     }
 
     pub fn decode_compressed(
@@ -96,7 +113,7 @@ impl<'a> MosDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Result<Vec<u16>, String> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let interlaced = cam.find_hint("interlaced");
         Self::do_decode(src, interlaced, width, height, dummy)
     }
@@ -107,15 +124,15 @@ impl<'a> MosDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Result<Vec<u16>, String> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         if dummy {
             return Ok(vec![0]);
         }
 
         let decompressor = LjpegDecompressor::new_full(src, true, true)?;
         let ljpegout = decompressor.decode_leaf(width, height)?;
-        if interlaced {
-            let mut out = alloc_image_ok!(width, height, dummy);
+        if interlaced && width > 0 {
+            let mut out = alloc_image!(width, height, dummy);
             for (row, line) in ljpegout.chunks_exact(width).enumerate() {
                 let orow = if row & 1 == 1 {
                     height - 1 - row / 2
@@ -17,12 +17,13 @@ pub struct MrwDecoder<'a> {
     raw_height: usize,
     packed: bool,
     wb_vals: [u16; 4],
+    tiffpos: usize,
     tiff: TiffIFD<'a>,
 }
 
 impl<'a> MrwDecoder<'a> {
-    pub fn new(buf: &'a [u8], rawloader: &'a RawLoader) -> MrwDecoder<'a> {
-        let data_offset: usize = (BEu32(buf, 4) + 8) as usize;
+    pub fn new(buf: &'a [u8], rawloader: &'a RawLoader) -> Result<MrwDecoder<'a>, RawLoaderError> {
+        let data_offset: usize = BEu32(buf, 4) as usize + 8;
         let mut raw_height: usize = 0;
         let mut raw_width: usize = 0;
         let mut packed = false;
@@ -31,7 +32,7 @@ impl<'a> MrwDecoder<'a> {
 
         let mut currpos: usize = 8;
         // At most we read 20 bytes from currpos so check we don't step outside that
-        while currpos + 20 < data_offset {
+        while currpos + 20 < data_offset.min(buf.len()) {
             let tag: u32 = BEu32(buf, currpos);
             let len: u32 = BEu32(buf, currpos + 4);
 
@@ -40,7 +41,7 @@ impl<'a> MrwDecoder<'a> {
                     // PRD
                     raw_height = BEu16(buf, currpos + 16) as usize;
                     raw_width = BEu16(buf, currpos + 18) as usize;
-                    packed = buf[currpos + 24] == 12;
+                    packed = buf.get(currpos + 24) == Some(&12);
                 }
                 0x574247 => {
                     // WBG
@@ -56,31 +57,33 @@ impl<'a> MrwDecoder<'a> {
                 }
                 _ => {}
             }
-            currpos += (len + 8) as usize;
+            currpos += len as usize + 8;
         }
 
-        MrwDecoder {
+        Ok(MrwDecoder {
             buffer: buf,
             data_offset,
             raw_width,
             raw_height,
             packed,
             wb_vals,
-            tiff: TiffIFD::new(&buf[tiffpos..], 8, 0, 0, 0, BIG_ENDIAN).unwrap(),
+            tiffpos,
+            tiff: TiffIFD::new(from_offset(buf, tiffpos), 8, 0, 0, 0, BIG_ENDIAN)?,
             rawloader,
-        }
+        })
     }
 }
 
 impl<'a> Decoder for MrwDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
-        let src = &self.buffer[self.data_offset..];
+        let src = from_offset(self.buffer, self.data_offset);
 
+        let window = Window::current();
         let buffer = if self.packed {
-            decode_12be(src, self.raw_width, self.raw_height, dummy)
+            decode_12be(src, self.raw_width, self.raw_height, window.as_ref(), dummy)?
         } else {
-            decode_12be_unpacked(src, self.raw_width, self.raw_height, dummy)
+            decode_12be_unpacked(src, self.raw_width, self.raw_height, window.as_ref(), dummy)?
         };
 
         let wb_coeffs = if camera.find_hint("swapped_wb") {
@@ -100,5 +103,18 @@ impl<'a> Decoder for MrwDecoder<'a> {
         };
 
         ok_image(camera, self.raw_width, self.raw_height, wb_coeffs, buffer)
+            .map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::new(&self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        // The TIFF offsets count from the TTW block
+        let buf = from_offset(self.buffer, self.tiffpos);
+        let mut previews = preview::tiff_previews(buf, &self.tiff);
+        previews.extend(preview::makernote_previews(buf, &self.tiff));
+        Ok(previews)
     }
 }
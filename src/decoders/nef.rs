@@ -102,7 +102,7 @@ impl<'a> NefDecoder<'a> {
 }
 
 impl<'a> Decoder for NefDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let raw = fetch_ifd!(&self.tiff, Tag::CFAPattern);
         let mut width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
         let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
@@ -117,58 +117,70 @@ impl<'a> Decoder for NefDecoder<'a> {
 
         let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
         let size = fetch_tag!(raw, Tag::StripByteCounts).get_usize(0);
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
         let mut cpp = 1;
         let coeffs = self.get_wb()?;
 
+        let window = Window::current();
         let image = if camera.model == "NIKON D100" {
             width = 3040;
-            decode_12be_wcontrol(src, width, height, dummy)
-        } else if compression == 1 || size == width * height * bps / 8 {
+            decode_12be_wcontrol(src, width, height, window.as_ref(), dummy)?
+        } else if compression == 1 || size == width.saturating_mul(height).saturating_mul(bps) / 8 {
             match bps {
                 14 => {
                     if self.tiff.little_endian() {
-                        decode_14le_unpacked(src, width, height, dummy)
+                        decode_14le_unpacked(src, width, height, window.as_ref(), dummy)?
                     } else {
-                        decode_14be_unpacked(src, width, height, dummy)
+                        decode_14be_unpacked(src, width, height, window.as_ref(), dummy)?
                     }
                 }
                 12 => {
                     if self.tiff.little_endian() {
-                        decode_12le(src, width, height, dummy)
+                        decode_12le(src, width, height, window.as_ref(), dummy)?
                     } else {
-                        decode_12be(src, width, height, dummy)
+                        decode_12be(src, width, height, window.as_ref(), dummy)?
                     }
                 }
-                x => return Err(format!("Don't know uncompressed bps {}", x).to_string()),
+                x => return Err(RawLoaderError::compression("NEF", x as u32)),
             }
-        } else if size == width * height * 3 {
+        } else if size == width.saturating_mul(height).saturating_mul(3) {
             cpp = 3;
-            Self::decode_snef_compressed(src, coeffs, width, height, dummy)
+            let image = Self::decode_snef_compressed(src, coeffs, width, height, dummy)?;
+            crop_window(image, width * 3, window.as_ref())?
         } else if compression == 34713 {
-            self.decode_compressed(src, width, height, bps, dummy)?
+            let image = self.decode_compressed(src, width, height, bps, dummy)?;
+            crop_window(image, width, window.as_ref())?
         } else {
-            return Err(format!("NEF: Don't know compression {}", compression).to_string());
+            return Err(RawLoaderError::compression("NEF", compression as u32));
         };
 
-        let mut img = RawImage::new(camera, width, height, coeffs, image, false);
+        let mut img = RawImage::with_cpp(camera, width, height, cpp, coeffs, image, false)
+            .windowed(window.as_ref());
         if cpp == 3 {
-            img.cpp = 3;
             img.blacklevels = [0, 0, 0, 0];
             img.whitelevels = [65535, 65535, 65535, 65535];
         }
         Ok(img)
     }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
+    }
 }
 
 impl<'a> NefDecoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         if let Some(levels) = self.tiff.find_entry(Tag::NefWB0) {
             Ok([levels.get_f32(0), 1.0, levels.get_f32(1), f32::NAN])
         } else if let Some(levels) = self.tiff.find_entry(Tag::NefWB1) {
             let mut version: u32 = 0;
             for i in 0..4 {
-                version = (version << 4) + (levels.get_data()[i] - b'0') as u32;
+                let digit = levels.get_data().get(i).copied().unwrap_or(b'0');
+                version = (version << 4) + digit.wrapping_sub(b'0') as u32;
             }
             match version {
                 0x100 => Ok([
@@ -192,33 +204,31 @@ impl<'a> NefDecoder<'a> {
                         if *item == 0 {
                             break;
                         }
-                        serialno = serialno * 10
-                            + if *item >= 48 && *item <= 57 {
-                                // "0" to "9"
-                                (*item - 48) as usize
-                            } else {
-                                (*item % 10) as usize
-                            };
+                        let digit = if *item >= 48 && *item <= 57 {
+                            // "0" to "9"
+                            (*item - 48) as usize
+                        } else {
+                            (*item % 10) as usize
+                        };
+                        serialno = serialno.wrapping_mul(10).wrapping_add(digit);
                     }
 
                     // Get the "decryption" key
                     let keydata = fetch_tag!(self.tiff, Tag::NefKey).get_data();
-                    let keyno = (keydata[0] ^ keydata[1] ^ keydata[2] ^ keydata[3]) as usize;
+                    let keyno = keydata.iter().take(4).fold(0, |acc, x| acc ^ x) as usize;
 
                     let src = if version == 0x204 {
-                        &levels.get_data()[284..]
+                        from_offset(levels.get_data(), 284)
                     } else {
-                        &levels.get_data()[4..]
+                        from_offset(levels.get_data(), 4)
                     };
 
                     let ci = WB_SERIALMAP[serialno & 0xff] as u32;
                     let mut cj = WB_KEYMAP[keyno & 0xff] as u32;
-                    let mut ck = 0x60_u32;
                     let mut buf = [0_u8; 280];
-                    for i in 0..280 {
-                        cj += ci * ck;
-                        ck += 1;
-                        buf[i] = src[i] ^ (cj as u8);
+                    for (ck, (i, out)) in (0x60_u32..).zip(buf.iter_mut().enumerate()) {
+                        cj = cj.wrapping_add(ci * ck);
+                        *out = src.get(i).copied().unwrap_or(0) ^ (cj as u8);
                     }
 
                     let off = if version == 0x204 { 6 } else { 14 };
@@ -229,20 +239,24 @@ impl<'a> NefDecoder<'a> {
                         f32::NAN,
                     ])
                 }
-                x => Err(format!("NEF: Don't know about WB version 0x{:x}", x).to_string()),
+                x => Err(RawLoaderError::corrupt(format!(
+                    "NEF: Don't know about WB version 0x{:x}",
+                    x
+                ))),
             }
         } else {
-            Err("NEF: Don't know how to fetch WB".to_string())
+            Err(RawLoaderError::corrupt("NEF: Don't know how to fetch WB"))
         }
     }
 
-    fn create_hufftable(num: usize) -> Result<HuffTable, String> {
+    fn create_hufftable(num: usize) -> Result<HuffTable, RawLoaderError> {
         let mut htable = HuffTable::empty();
 
+        let [bits, huffval, shiftval] = &NIKON_TREE[num];
         for i in 0..15 {
-            htable.bits[i] = NIKON_TREE[num][0][i] as u32;
-            htable.huffval[i] = NIKON_TREE[num][1][i] as u32;
-            htable.shiftval[i] = NIKON_TREE[num][2][i] as u32;
+            htable.bits[i] = bits[i] as u32;
+            htable.huffval[i] = huffval[i] as u32;
+            htable.shiftval[i] = shiftval[i] as u32;
         }
 
         htable.initialize()?;
@@ -256,7 +270,7 @@ impl<'a> NefDecoder<'a> {
         height: usize,
         bps: usize,
         dummy: bool,
-    ) -> Result<Vec<u16>, String> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let metaifd = fetch_ifd!(self.tiff, Tag::NefMeta1);
         let meta = if let Some(meta) = metaifd.find_entry(Tag::NefMeta2) {
             meta
@@ -282,8 +296,16 @@ impl<'a> NefDecoder<'a> {
         height: usize,
         bps: usize,
         dummy: bool,
-    ) -> Result<Vec<u16>, String> {
-        let mut out = alloc_image_ok!(width, height, dummy);
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        if bps != 12 && bps != 14 {
+            return Err(RawLoaderError::compression("NEF", bps as u32));
+        }
+        if !width.is_multiple_of(2) {
+            return Err(RawLoaderError::corrupt(
+                "NEF: odd width for compressed image",
+            ));
+        }
+        let mut out = alloc_image!(width, height, dummy);
         let mut stream = ByteStream::new(meta, endian);
         let v0 = stream.get_u8();
         let v1 = stream.get_u8();
@@ -344,14 +366,14 @@ impl<'a> NefDecoder<'a> {
             if split > 0 && row == split {
                 htable = Self::create_hufftable(huff_select + 1)?;
             }
-            pred_up1[row & 1] += htable.huff_decode(&mut pump)?;
-            pred_up2[row & 1] += htable.huff_decode(&mut pump)?;
+            pred_up1[row & 1] = pred_up1[row & 1].wrapping_add(htable.huff_decode(&mut pump)?);
+            pred_up2[row & 1] = pred_up2[row & 1].wrapping_add(htable.huff_decode(&mut pump)?);
             let mut pred_left1 = pred_up1[row & 1];
             let mut pred_left2 = pred_up2[row & 1];
             for col in (0..width).step_by(2) {
                 if col > 0 {
-                    pred_left1 += htable.huff_decode(&mut pump)?;
-                    pred_left2 += htable.huff_decode(&mut pump)?;
+                    pred_left1 = pred_left1.wrapping_add(htable.huff_decode(&mut pump)?);
+                    pred_left2 = pred_left2.wrapping_add(htable.huff_decode(&mut pump)?);
                 }
                 out[row * width + col] = curve.dither(clampbits(pred_left1, bps), &mut random);
                 out[row * width + col + 1] = curve.dither(clampbits(pred_left2, bps), &mut random);
@@ -369,7 +391,7 @@ impl<'a> NefDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Vec<u16> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let inv_wb_r = (1024.0 / coeffs[0]) as i32;
         let inv_wb_b = (1024.0 / coeffs[2]) as i32;
 
@@ -397,9 +419,10 @@ impl<'a> NefDecoder<'a> {
         decode_threaded(
             width * 3,
             height,
+            None,
             dummy,
             &(|out: &mut [u16], row| {
-                let inb = &src[row * width * 3..];
+                let inb = from_offset(src, row * width * 3);
                 let mut random = BEu32(inb, 0);
                 for (o, i) in out.chunks_exact_mut(6).zip(inb.chunks_exact(6)) {
                     let g1: u16 = i[0] as u16;
@@ -423,9 +446,15 @@ impl<'a> NefDecoder<'a> {
                     let b =
                         snef_curve.dither(clampbits((y1 + 1.732446 * cb) as i32, 12), &mut random);
                     // invert the white balance
-                    o[0] = clampbits((inv_wb_r * r as i32 + (1 << 9)) >> 10, 15);
+                    o[0] = clampbits(
+                        inv_wb_r.wrapping_mul(r as i32).wrapping_add(1 << 9) >> 10,
+                        15,
+                    );
                     o[1] = g;
-                    o[2] = clampbits((inv_wb_b * b as i32 + (1 << 9)) >> 10, 15);
+                    o[2] = clampbits(
+                        inv_wb_b.wrapping_mul(b as i32).wrapping_add(1 << 9) >> 10,
+                        15,
+                    );
 
                     let r =
                         snef_curve.dither(clampbits((y2 + 1.370705 * cr) as i32, 12), &mut random);
@@ -436,9 +465,15 @@ impl<'a> NefDecoder<'a> {
                     let b =
                         snef_curve.dither(clampbits((y2 + 1.732446 * cb) as i32, 12), &mut random);
                     // invert the white balance
-                    o[3] = clampbits((inv_wb_r * r as i32 + (1 << 9)) >> 10, 15);
+                    o[3] = clampbits(
+                        inv_wb_r.wrapping_mul(r as i32).wrapping_add(1 << 9) >> 10,
+                        15,
+                    );
                     o[4] = g;
-                    o[5] = clampbits((inv_wb_b * b as i32 + (1 << 9)) >> 10, 15);
+                    o[5] = clampbits(
+                        inv_wb_b.wrapping_mul(b as i32).wrapping_add(1 << 9) >> 10,
+                        15,
+                    );
                 }
             }),
         )
@@ -17,22 +17,27 @@ impl<'a> NakedDecoder<'a> {
 }
 
 impl<'a> Decoder for NakedDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let width = self.camera.raw_width;
         let height = self.camera.raw_height;
         let size = self.camera.filesize;
         let bits = size * 8 / width / height;
 
+        let window = Window::current();
         let image = if self.camera.find_hint("12le_16bitaligned") {
-            decode_12le_16bitaligned(self.buffer, width, height, dummy)
+            decode_12le_16bitaligned(self.buffer, width, height, window.as_ref(), dummy)?
         } else {
             match bits {
-                10 => decode_10le_lsb16(self.buffer, width, height, dummy),
-                12 => decode_12be_msb16(self.buffer, width, height, dummy),
-                _ => return Err(format!("Naked: Don't know about {} bps images", bits).to_string()),
+                10 => decode_10le_lsb16(self.buffer, width, height, window.as_ref(), dummy)?,
+                12 => {
+                    let image = decode_12be_msb16(self.buffer, width, height, dummy)?;
+                    crop_window(image, width, window.as_ref())?
+                }
+                _ => return Err(RawLoaderError::compression("Naked", bits as u32)),
             }
         };
 
         ok_image(self.camera.clone(), width, height, [f32::NAN; 4], image)
+            .map(|img| img.windowed(window.as_ref()))
     }
 }
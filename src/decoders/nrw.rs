@@ -20,47 +20,64 @@ impl<'a> NrwDecoder<'a> {
 }
 
 impl<'a> Decoder for NrwDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
         let data = self.tiff.find_ifds_with_tag(Tag::CFAPattern);
         let raw = data
             .iter()
-            .find(|&&ifd| ifd.find_entry(Tag::ImageWidth).unwrap().get_u32(0) > 1000)
-            .unwrap();
+            .find(|&&ifd| {
+                ifd.find_entry(Tag::ImageWidth)
+                    .is_some_and(|e| e.get_u32(0) > 1000)
+            })
+            .ok_or_else(|| RawLoaderError::corrupt("NRW: Couldn't find the raw image"))?;
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
         let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
         let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
 
+        let window = Window::current();
         let image = if camera.find_hint("coolpixsplit") {
-            decode_12be_interlaced_unaligned(src, width, height, dummy)
+            decode_12be_interlaced_unaligned(src, width, height, window.as_ref(), dummy)?
         } else if camera.find_hint("msb32") {
-            decode_12be_msb32(src, width, height, dummy)
+            let image = decode_12be_msb32(src, width, height, dummy)?;
+            crop_window(image, width, window.as_ref())?
         } else if camera.find_hint("unpacked") {
-            decode_16be(src, width, height, dummy)
+            decode_16be(src, width, height, window.as_ref(), dummy)?
         } else {
-            decode_12be(src, width, height, dummy)
+            decode_12be(src, width, height, window.as_ref(), dummy)?
         };
 
         let wb = self.get_wb(&camera)?;
-        ok_image(camera, width, height, wb, image)
+        ok_image(camera, width, height, wb, image).map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
 
 impl<'a> NrwDecoder<'a> {
-    fn get_wb(&self, cam: &Camera) -> Result<[f32; 4], String> {
+    fn get_wb(&self, cam: &Camera) -> Result<[f32; 4], RawLoaderError> {
         if cam.find_hint("nowb") {
             Ok([f32::NAN; 4])
         } else if let Some(levels) = self.tiff.find_entry(Tag::NefWB0) {
             Ok([levels.get_f32(0), 1.0, levels.get_f32(1), f32::NAN])
         } else if let Some(levels) = self.tiff.find_entry(Tag::NrwWB) {
             let data = levels.get_data();
-            if data[0..3] == b"NRW"[..] {
-                let offset = if data[4..8] == b"0100"[..] { 1556 } else { 56 };
+            if data.starts_with(b"NRW") {
+                let offset = if data.get(4..8) == Some(&b"0100"[..]) {
+                    1556
+                } else {
+                    56
+                };
 
                 Ok([
                     (LEu32(data, offset) << 2) as f32,
-                    (LEu32(data, offset + 4) + LEu32(data, offset + 8)) as f32,
+                    LEu32(data, offset + 4).wrapping_add(LEu32(data, offset + 8)) as f32,
                     (LEu32(data, offset + 12) << 2) as f32,
                     f32::NAN,
                 ])
@@ -73,7 +90,7 @@ impl<'a> NrwDecoder<'a> {
                 ])
             }
         } else {
-            Err("NRW: Don't know how to fetch WB".to_string())
+            Err(RawLoaderError::corrupt("NRW: Don't know how to fetch WB"))
         }
     }
 }
@@ -0,0 +1,824 @@
+use std::cmp;
+
+use crate::decoders::RawLoaderError;
+use crate::decoders::basics::*;
+
+/// The rectangle of the image an opcode applies to, as well as the planes and
+/// the pitch between the rows and columns it touches
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OpcodeArea {
+    /// first row of the area
+    pub top: usize,
+    /// first column of the area
+    pub left: usize,
+    /// row after the last one of the area
+    pub bottom: usize,
+    /// column after the last one of the area
+    pub right: usize,
+    /// first plane the opcode applies to
+    pub plane: usize,
+    /// number of planes the opcode applies to
+    pub planes: usize,
+    /// apply to every row_pitch rows
+    pub row_pitch: usize,
+    /// apply to every col_pitch columns
+    pub col_pitch: usize,
+}
+
+/// A grid of gains to multiply the image by, usually to correct lens shading
+#[derive(Debug, Clone, PartialEq)]
+pub struct GainMap {
+    /// area of the image the map applies to
+    pub area: OpcodeArea,
+    /// number of vertical points in the map
+    pub points_v: usize,
+    /// number of horizontal points in the map
+    pub points_h: usize,
+    /// vertical spacing between points relative to the image height
+    pub spacing_v: f64,
+    /// horizontal spacing between points relative to the image width
+    pub spacing_h: f64,
+    /// vertical position of the first point relative to the image height
+    pub origin_v: f64,
+    /// horizontal position of the first point relative to the image width
+    pub origin_h: f64,
+    /// number of planes in the map
+    pub map_planes: usize,
+    /// the gains themselves ordered by row, column and then plane
+    pub gains: Vec<f32>,
+}
+
+/// The operations a DNG opcode can perform
+#[derive(Debug, Clone, PartialEq)]
+pub enum Opcode {
+    /// Correct radial and tangential distortion, with a set of coefficients per plane
+    /// (kr0, kr1, kr2, kr3, kt0, kt1) and the optical center relative to the image size
+    WarpRectilinear {
+        /// the radial and tangential coefficients for each plane
+        coefficients: Vec<[f64; 6]>,
+        /// the optical center as (horizontal, vertical) relative to the image size
+        center: (f64, f64),
+    },
+    /// Unwrap a fisheye lens, with a set of coefficients per plane (kr0 to kr3)
+    WarpFisheye {
+        /// the radial coefficients for each plane
+        coefficients: Vec<[f64; 4]>,
+        /// the optical center as (horizontal, vertical) relative to the image size
+        center: (f64, f64),
+    },
+    /// Correct vignetting with a radial polynomial
+    FixVignetteRadial {
+        /// the polynomial coefficients k0 to k4 for the even powers of the radius
+        coefficients: [f64; 5],
+        /// the optical center as (horizontal, vertical) relative to the image size
+        center: (f64, f64),
+    },
+    /// Interpolate over all the pixels that have a certain value
+    FixBadPixelsConstant {
+        /// the value that marks a pixel as bad
+        constant: u32,
+        /// the position of the first red pixel of the bayer pattern
+        bayer_phase: u32,
+    },
+    /// Interpolate over a list of pixels and rectangles
+    FixBadPixelsList {
+        /// the position of the first red pixel of the bayer pattern
+        bayer_phase: u32,
+        /// bad pixels as (row, column)
+        points: Vec<(usize, usize)>,
+        /// bad rectangles as (top, left, bottom, right)
+        rects: Vec<(usize, usize, usize, usize)>,
+    },
+    /// Crop the image
+    TrimBounds {
+        /// first row to keep
+        top: usize,
+        /// first column to keep
+        left: usize,
+        /// row after the last one to keep
+        bottom: usize,
+        /// column after the last one to keep
+        right: usize,
+    },
+    /// Map the values through a table
+    MapTable {
+        /// area of the image to apply to
+        area: OpcodeArea,
+        /// the table of output values
+        table: Vec<u16>,
+    },
+    /// Map the values through a polynomial
+    MapPolynomial {
+        /// area of the image to apply to
+        area: OpcodeArea,
+        /// the polynomial coefficients from the constant term up
+        coefficients: Vec<f64>,
+    },
+    /// Multiply the image by an interpolated grid of gains
+    GainMap(GainMap),
+    /// Add a value to each row
+    DeltaPerRow {
+        /// area of the image to apply to
+        area: OpcodeArea,
+        /// the value for each row
+        deltas: Vec<f32>,
+    },
+    /// Add a value to each column
+    DeltaPerColumn {
+        /// area of the image to apply to
+        area: OpcodeArea,
+        /// the value for each column
+        deltas: Vec<f32>,
+    },
+    /// Multiply each row by a value
+    ScalePerRow {
+        /// area of the image to apply to
+        area: OpcodeArea,
+        /// the value for each row
+        scales: Vec<f32>,
+    },
+    /// Multiply each column by a value
+    ScalePerColumn {
+        /// area of the image to apply to
+        area: OpcodeArea,
+        /// the value for each column
+        scales: Vec<f32>,
+    },
+    /// An opcode we don't know about
+    Unknown {
+        /// the opcode id
+        id: u32,
+        /// the opcode parameters
+        data: Vec<u8>,
+    },
+}
+
+/// A single opcode from one of the DNG OpcodeList tags
+#[derive(Debug, Clone, PartialEq)]
+pub struct DngOpcode {
+    /// the opcode can be skipped by readers that don't support it
+    pub optional: bool,
+    /// the opcode can be skipped when rendering previews
+    pub preview_skip: bool,
+    /// what the opcode does
+    pub op: Opcode,
+}
+
+// Opcode lists are always big endian whatever the endianness of the file
+struct OpcodeReader<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> OpcodeReader<'a> {
+    fn new(buffer: &'a [u8]) -> OpcodeReader<'a> {
+        OpcodeReader { buffer, pos: 0 }
+    }
+
+    fn bytes(&mut self, num: usize) -> Result<&'a [u8], RawLoaderError> {
+        if self.pos + num > self.buffer.len() {
+            return Err(RawLoaderError::truncated("DNG: opcode list is truncated"));
+        }
+        let data = &self.buffer[self.pos..self.pos + num];
+        self.pos += num;
+        Ok(data)
+    }
+
+    fn u32(&mut self) -> Result<u32, RawLoaderError> {
+        Ok(BEu32(self.bytes(4)?, 0))
+    }
+
+    fn usize(&mut self) -> Result<usize, RawLoaderError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn f32(&mut self) -> Result<f32, RawLoaderError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn f64(&mut self) -> Result<f64, RawLoaderError> {
+        let bytes = self.bytes(8)?;
+        let mut val = [0u8; 8];
+        val.copy_from_slice(bytes);
+        Ok(f64::from_be_bytes(val))
+    }
+
+    // Counts come from the file so check them against the remaining data before allocating
+    fn count(&mut self, size: usize) -> Result<usize, RawLoaderError> {
+        let count = self.usize()?;
+        if count.saturating_mul(size) > self.buffer.len() - self.pos {
+            return Err(RawLoaderError::corrupt("DNG: opcode count is too large"));
+        }
+        Ok(count)
+    }
+
+    fn area(&mut self) -> Result<OpcodeArea, RawLoaderError> {
+        Ok(OpcodeArea {
+            top: self.usize()?,
+            left: self.usize()?,
+            bottom: self.usize()?,
+            right: self.usize()?,
+            plane: self.usize()?,
+            planes: self.usize()?,
+            row_pitch: cmp::max(self.usize()?, 1),
+            col_pitch: cmp::max(self.usize()?, 1),
+        })
+    }
+
+    fn center(&mut self) -> Result<(f64, f64), RawLoaderError> {
+        Ok((self.f64()?, self.f64()?))
+    }
+}
+
+impl DngOpcode {
+    /// Parses the contents of an OpcodeList1, OpcodeList2 or OpcodeList3 tag
+    pub fn parse_list(data: &[u8]) -> Result<Vec<DngOpcode>, RawLoaderError> {
+        let mut input = OpcodeReader::new(data);
+        let count = input.count(16)?;
+        let mut opcodes = Vec::with_capacity(count);
+        for _ in 0..count {
+            let id = input.u32()?;
+            let _version = input.u32()?;
+            let flags = input.u32()?;
+            let size = input.usize()?;
+            let mut params = OpcodeReader::new(input.bytes(size)?);
+            opcodes.push(DngOpcode {
+                optional: flags & 1 != 0,
+                preview_skip: flags & 2 != 0,
+                op: DngOpcode::parse_op(id, &mut params)?,
+            });
+        }
+        Ok(opcodes)
+    }
+
+    fn parse_op(id: u32, input: &mut OpcodeReader) -> Result<Opcode, RawLoaderError> {
+        Ok(match id {
+            1 => {
+                let planes = input.count(48)?;
+                let mut coefficients = Vec::with_capacity(planes);
+                for _ in 0..planes {
+                    let mut k = [0f64; 6];
+                    for val in k.iter_mut() {
+                        *val = input.f64()?;
+                    }
+                    coefficients.push(k);
+                }
+                Opcode::WarpRectilinear {
+                    coefficients,
+                    center: input.center()?,
+                }
+            }
+            2 => {
+                let planes = input.count(32)?;
+                let mut coefficients = Vec::with_capacity(planes);
+                for _ in 0..planes {
+                    let mut k = [0f64; 4];
+                    for val in k.iter_mut() {
+                        *val = input.f64()?;
+                    }
+                    coefficients.push(k);
+                }
+                Opcode::WarpFisheye {
+                    coefficients,
+                    center: input.center()?,
+                }
+            }
+            3 => {
+                let mut coefficients = [0f64; 5];
+                for val in coefficients.iter_mut() {
+                    *val = input.f64()?;
+                }
+                Opcode::FixVignetteRadial {
+                    coefficients,
+                    center: input.center()?,
+                }
+            }
+            4 => Opcode::FixBadPixelsConstant {
+                constant: input.u32()?,
+                bayer_phase: input.u32()?,
+            },
+            5 => {
+                let bayer_phase = input.u32()?;
+                let npoints = input.usize()?;
+                let nrects = input.usize()?;
+                if npoints
+                    .saturating_mul(8)
+                    .saturating_add(nrects.saturating_mul(16))
+                    > input.buffer.len()
+                {
+                    return Err(RawLoaderError::corrupt("DNG: opcode count is too large"));
+                }
+                let mut points = Vec::with_capacity(npoints);
+                for _ in 0..npoints {
+                    points.push((input.usize()?, input.usize()?));
+                }
+                let mut rects = Vec::with_capacity(nrects);
+                for _ in 0..nrects {
+                    rects.push((
+                        input.usize()?,
+                        input.usize()?,
+                        input.usize()?,
+                        input.usize()?,
+                    ));
+                }
+                Opcode::FixBadPixelsList {
+                    bayer_phase,
+                    points,
+                    rects,
+                }
+            }
+            6 => Opcode::TrimBounds {
+                top: input.usize()?,
+                left: input.usize()?,
+                bottom: input.usize()?,
+                right: input.usize()?,
+            },
+            7 => {
+                let area = input.area()?;
+                let count = input.count(2)?;
+                let mut table = Vec::with_capacity(count);
+                for _ in 0..count {
+                    table.push(BEu16(input.bytes(2)?, 0));
+                }
+                Opcode::MapTable { area, table }
+            }
+            8 => {
+                let area = input.area()?;
+                let degree = input.usize()?;
+                if degree > 8 {
+                    return Err(RawLoaderError::corrupt(format!(
+                        "DNG: polynomial of degree {}",
+                        degree
+                    )));
+                }
+                let mut coefficients = Vec::with_capacity(degree + 1);
+                for _ in 0..=degree {
+                    coefficients.push(input.f64()?);
+                }
+                Opcode::MapPolynomial { area, coefficients }
+            }
+            9 => {
+                let area = input.area()?;
+                let points_v = input.usize()?;
+                let points_h = input.usize()?;
+                let spacing_v = input.f64()?;
+                let spacing_h = input.f64()?;
+                let origin_v = input.f64()?;
+                let origin_h = input.f64()?;
+                let map_planes = input.usize()?;
+                let count = points_v
+                    .checked_mul(points_h)
+                    .and_then(|v| v.checked_mul(map_planes))
+                    .filter(|v| *v > 0 && v.saturating_mul(4) <= input.buffer.len())
+                    .ok_or_else(|| RawLoaderError::corrupt("DNG: invalid gain map size"))?;
+                let mut gains = Vec::with_capacity(count);
+                for _ in 0..count {
+                    gains.push(input.f32()?);
+                }
+                Opcode::GainMap(GainMap {
+                    area,
+                    points_v,
+                    points_h,
+                    spacing_v,
+                    spacing_h,
+                    origin_v,
+                    origin_h,
+                    map_planes,
+                    gains,
+                })
+            }
+            10..=13 => {
+                let area = input.area()?;
+                let count = input.count(4)?;
+                let mut values = Vec::with_capacity(count);
+                for _ in 0..count {
+                    values.push(input.f32()?);
+                }
+                match id {
+                    10 => Opcode::DeltaPerRow {
+                        area,
+                        deltas: values,
+                    },
+                    11 => Opcode::DeltaPerColumn {
+                        area,
+                        deltas: values,
+                    },
+                    12 => Opcode::ScalePerRow {
+                        area,
+                        scales: values,
+                    },
+                    _ => Opcode::ScalePerColumn {
+                        area,
+                        scales: values,
+                    },
+                }
+            }
+            _ => Opcode::Unknown {
+                id,
+                data: input.buffer.to_vec(),
+            },
+        })
+    }
+
+    /// Applies a list of opcodes to image data that's been normalized to 0-1, as is
+    /// needed for OpcodeList3 on demosaiced images. The data has `width`\*`height`\*`cpp`
+    /// values and TrimBounds can change its size.
+    pub fn apply_list(
+        opcodes: &[DngOpcode],
+        data: &mut Vec<f32>,
+        width: &mut usize,
+        height: &mut usize,
+        cpp: usize,
+    ) -> Result<(), RawLoaderError> {
+        let mut img = OpcodeImage {
+            data: std::mem::take(data),
+            width: *width,
+            height: *height,
+            cpp,
+            top: 0,
+            left: 0,
+            bwidth: *width,
+            bheight: *height,
+            mosaic: false,
+            raw_scale: 65535.0,
+            raw_black: 0.0,
+            offset: (0, 0),
+        };
+        let res = img.run(opcodes);
+        *data = img.data;
+        *width = img.width;
+        *height = img.height;
+        res
+    }
+}
+
+// The image the opcodes operate on. Opcode coordinates are relative to a bounding
+// rectangle (the active area for list 2) that starts at top/left.
+pub(crate) struct OpcodeImage {
+    pub data: Vec<f32>,
+    pub width: usize,
+    pub height: usize,
+    pub cpp: usize,
+    pub top: usize,
+    pub left: usize,
+    pub bwidth: usize,
+    pub bheight: usize,
+    // Warps can't be applied to CFA data
+    pub mosaic: bool,
+    // Multiplier and offset to get the normalized values back to the integers used
+    // by FixBadPixelsConstant and MapTable
+    pub raw_scale: f32,
+    pub raw_black: f32,
+    // Position of the data in the original image after any TrimBounds
+    pub offset: (usize, usize),
+}
+
+impl OpcodeImage {
+    pub fn run(&mut self, opcodes: &[DngOpcode]) -> Result<(), RawLoaderError> {
+        if self.bwidth == 0
+            || self.bheight == 0
+            || self.top + self.bheight > self.height
+            || self.left + self.bwidth > self.width
+            || self.data.len() != self.width * self.height * self.cpp
+        {
+            return Err(RawLoaderError::corrupt(
+                "DNG: image doesn't match the opcode bounds",
+            ));
+        }
+        for opcode in opcodes {
+            let done = match &opcode.op {
+                Opcode::WarpRectilinear {
+                    coefficients,
+                    center,
+                } if !self.mosaic => {
+                    self.warp_rectilinear(coefficients, *center);
+                    true
+                }
+                Opcode::FixVignetteRadial {
+                    coefficients,
+                    center,
+                } => {
+                    self.fix_vignette(coefficients, *center);
+                    true
+                }
+                Opcode::FixBadPixelsConstant {
+                    constant,
+                    bayer_phase,
+                } if self.cpp == 1 => {
+                    let constant = *constant as f32;
+                    let (scale, black) = (self.raw_scale, self.raw_black);
+                    self.fix_bad_pixels(*bayer_phase, |data, pos| {
+                        (data[pos] * scale + black).round() == constant
+                    });
+                    true
+                }
+                Opcode::FixBadPixelsList {
+                    bayer_phase,
+                    points,
+                    rects,
+                } if self.cpp == 1 => {
+                    let mut bad = vec![false; self.bwidth * self.bheight];
+                    for (row, col) in points {
+                        if *row < self.bheight && *col < self.bwidth {
+                            bad[row * self.bwidth + col] = true;
+                        }
+                    }
+                    for (top, left, bottom, right) in rects {
+                        for row in *top..cmp::min(*bottom, self.bheight) {
+                            for col in *left..cmp::min(*right, self.bwidth) {
+                                bad[row * self.bwidth + col] = true;
+                            }
+                        }
+                    }
+                    let (top, left, width, bwidth) = (self.top, self.left, self.width, self.bwidth);
+                    self.fix_bad_pixels(*bayer_phase, |_, pos| {
+                        let (row, col) = (pos / width - top, pos % width - left);
+                        bad[row * bwidth + col]
+                    });
+                    true
+                }
+                Opcode::TrimBounds {
+                    top,
+                    left,
+                    bottom,
+                    right,
+                } => {
+                    self.trim(*top, *left, *bottom, *right)?;
+                    true
+                }
+                Opcode::MapTable { area, table } if !table.is_empty() => {
+                    let (scale, black) = (self.raw_scale, self.raw_black);
+                    self.map_area(area, |val, _, _| {
+                        let idx = (val * scale + black)
+                            .round()
+                            .clamp(0.0, (table.len() - 1) as f32);
+                        (table[idx as usize] as f32 - black) / scale
+                    });
+                    true
+                }
+                Opcode::MapPolynomial { area, coefficients } => {
+                    self.map_area(area, |val, _, _| {
+                        let val = val as f64;
+                        let res = coefficients.iter().rev().fold(0.0, |acc, c| acc * val + c);
+                        res.clamp(0.0, 1.0) as f32
+                    });
+                    true
+                }
+                Opcode::GainMap(map) => {
+                    self.gain_map(map);
+                    true
+                }
+                Opcode::DeltaPerRow { area, deltas } => {
+                    self.map_area(area, |val, row, _| {
+                        val + deltas.get(row / area.row_pitch).copied().unwrap_or(0.0)
+                    });
+                    true
+                }
+                Opcode::DeltaPerColumn { area, deltas } => {
+                    self.map_area(area, |val, _, col| {
+                        val + deltas.get(col / area.col_pitch).copied().unwrap_or(0.0)
+                    });
+                    true
+                }
+                Opcode::ScalePerRow { area, scales } => {
+                    self.map_area(area, |val, row, _| {
+                        val * scales.get(row / area.row_pitch).copied().unwrap_or(1.0)
+                    });
+                    true
+                }
+                Opcode::ScalePerColumn { area, scales } => {
+                    self.map_area(area, |val, _, col| {
+                        val * scales.get(col / area.col_pitch).copied().unwrap_or(1.0)
+                    });
+                    true
+                }
+                _ => false,
+            };
+            if !done && !opcode.optional {
+                return Err(RawLoaderError::corrupt(format!(
+                    "DNG: can't apply required opcode {:?}",
+                    opcode.op
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Run a function over every value of an area, giving it the row and column
+    // relative to the start of the area
+    fn map_area<F>(&mut self, area: &OpcodeArea, func: F)
+    where
+        F: Fn(f32, usize, usize) -> f32,
+    {
+        let bottom = cmp::min(area.bottom, self.bheight);
+        let right = cmp::min(area.right, self.bwidth);
+        let planes = cmp::min(area.plane + area.planes, self.cpp);
+        for row in (area.top..bottom).step_by(area.row_pitch) {
+            for col in (area.left..right).step_by(area.col_pitch) {
+                let pos = ((row + self.top) * self.width + col + self.left) * self.cpp;
+                for plane in area.plane..planes {
+                    self.data[pos + plane] =
+                        func(self.data[pos + plane], row - area.top, col - area.left);
+                }
+            }
+        }
+    }
+
+    fn gain_map(&mut self, map: &GainMap) {
+        let (bwidth, bheight) = (self.bwidth as f64, self.bheight as f64);
+        let planes = map.map_planes;
+        let index = |pos: f64, origin: f64, spacing: f64, points: usize| -> (usize, f64) {
+            if points < 2 || spacing <= 0.0 {
+                return (0, 0.0);
+            }
+            let idx = ((pos - origin) / spacing).clamp(0.0, (points - 1) as f64);
+            let base = cmp::min(idx as usize, points - 2);
+            (base, idx - base as f64)
+        };
+        let gain = |row: usize, col: usize, plane: usize| -> f32 {
+            let (v, fv) = index(
+                (row as f64 + 0.5) / bheight,
+                map.origin_v,
+                map.spacing_v,
+                map.points_v,
+            );
+            let (h, fh) = index(
+                (col as f64 + 0.5) / bwidth,
+                map.origin_h,
+                map.spacing_h,
+                map.points_h,
+            );
+            let plane = cmp::min(plane, planes - 1);
+            let at = |v: usize, h: usize| -> f64 {
+                let v = cmp::min(v, map.points_v - 1);
+                let h = cmp::min(h, map.points_h - 1);
+                map.gains[(v * map.points_h + h) * planes + plane] as f64
+            };
+            let top = at(v, h) * (1.0 - fh) + at(v, h + 1) * fh;
+            let bottom = at(v + 1, h) * (1.0 - fh) + at(v + 1, h + 1) * fh;
+            (top * (1.0 - fv) + bottom * fv) as f32
+        };
+
+        let area = &map.area;
+        let bottom = cmp::min(area.bottom, self.bheight);
+        let right = cmp::min(area.right, self.bwidth);
+        let nplanes = cmp::min(area.plane + area.planes, self.cpp);
+        for row in (area.top..bottom).step_by(area.row_pitch) {
+            for col in (area.left..right).step_by(area.col_pitch) {
+                let pos = ((row + self.top) * self.width + col + self.left) * self.cpp;
+                for plane in area.plane..nplanes {
+                    let val = self.data[pos + plane] * gain(row, col, plane - area.plane);
+                    self.data[pos + plane] = val.min(1.0);
+                }
+            }
+        }
+    }
+
+    // The optical center in pixels and the distance from it to the furthest corner
+    fn center_radius(&self, center: (f64, f64)) -> (f64, f64, f64) {
+        let cx = center.0 * (self.bwidth - 1) as f64;
+        let cy = center.1 * (self.bheight - 1) as f64;
+        let dx = cx.max((self.bwidth - 1) as f64 - cx);
+        let dy = cy.max((self.bheight - 1) as f64 - cy);
+        (cx, cy, (dx * dx + dy * dy).sqrt().max(1.0))
+    }
+
+    fn fix_vignette(&mut self, k: &[f64; 5], center: (f64, f64)) {
+        let (cx, cy, radius) = self.center_radius(center);
+        for row in 0..self.bheight {
+            for col in 0..self.bwidth {
+                let dx = (col as f64 - cx) / radius;
+                let dy = (row as f64 - cy) / radius;
+                let r2 = dx * dx + dy * dy;
+                let gain = 1.0 + r2 * (k[0] + r2 * (k[1] + r2 * (k[2] + r2 * (k[3] + r2 * k[4]))));
+                let pos = ((row + self.top) * self.width + col + self.left) * self.cpp;
+                for val in self.data[pos..pos + self.cpp].iter_mut() {
+                    *val = (*val * gain as f32).min(1.0);
+                }
+            }
+        }
+    }
+
+    fn warp_rectilinear(&mut self, coefficients: &[[f64; 6]], center: (f64, f64)) {
+        if coefficients.is_empty() {
+            return;
+        }
+        let (cx, cy, radius) = self.center_radius(center);
+        let src = self.data.clone();
+        let (width, cpp) = (self.width, self.cpp);
+        let sample = |x: f64, y: f64, plane: usize| -> f32 {
+            let x = x.clamp(0.0, (self.bwidth - 1) as f64);
+            let y = y.clamp(0.0, (self.bheight - 1) as f64);
+            let (x0, y0) = (x as usize, y as usize);
+            let (x1, y1) = (
+                cmp::min(x0 + 1, self.bwidth - 1),
+                cmp::min(y0 + 1, self.bheight - 1),
+            );
+            let (fx, fy) = ((x - x0 as f64) as f32, (y - y0 as f64) as f32);
+            let at =
+                |x: usize, y: usize| src[((y + self.top) * width + x + self.left) * cpp + plane];
+            let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+            let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+            top * (1.0 - fy) + bottom * fy
+        };
+        for row in 0..self.bheight {
+            for col in 0..self.bwidth {
+                let dx = (col as f64 - cx) / radius;
+                let dy = (row as f64 - cy) / radius;
+                let r2 = dx * dx + dy * dy;
+                let pos = ((row + self.top) * width + col + self.left) * cpp;
+                for plane in 0..cpp {
+                    let k = &coefficients[cmp::min(plane, coefficients.len() - 1)];
+                    let f = k[0] + r2 * (k[1] + r2 * (k[2] + r2 * k[3]));
+                    let x = f * dx + k[4] * 2.0 * dx * dy + k[5] * (r2 + 2.0 * dx * dx);
+                    let y = f * dy + k[5] * 2.0 * dx * dy + k[4] * (r2 + 2.0 * dy * dy);
+                    self.data[pos + plane] = sample(cx + x * radius, cy + y * radius, plane);
+                }
+            }
+        }
+    }
+
+    // Replace bad pixels by the average of the good pixels of the same color around them
+    fn fix_bad_pixels<F>(&mut self, bayer_phase: u32, is_bad: F)
+    where
+        F: Fn(&[f32], usize) -> bool,
+    {
+        let (width, bwidth, bheight) = (self.width, self.bwidth as isize, self.bheight as isize);
+        let pos = |row: isize, col: isize| -> usize {
+            (row as usize + self.top) * width + col as usize + self.left
+        };
+        let mut bad = Vec::new();
+        for row in 0..bheight {
+            for col in 0..bwidth {
+                if is_bad(&self.data, pos(row, col)) {
+                    bad.push((row, col));
+                }
+            }
+        }
+        if bad.is_empty() {
+            return;
+        }
+        let mut badmap = vec![false; self.data.len()];
+        for (row, col) in bad.iter() {
+            badmap[pos(*row, *col)] = true;
+        }
+
+        // Phases 1 and 2 start with green so the greens are where row+col is even
+        let green_parity = if bayer_phase == 1 || bayer_phase == 2 {
+            0
+        } else {
+            1
+        };
+        let mut fixes = Vec::with_capacity(bad.len());
+        for (row, col) in bad.iter() {
+            let green = (row + col) & 1 == green_parity;
+            let mut neighbours = vec![(-2, 0), (2, 0), (0, -2), (0, 2)];
+            if green {
+                neighbours.extend_from_slice(&[(-1, -1), (-1, 1), (1, -1), (1, 1)]);
+            } else {
+                neighbours.extend_from_slice(&[(-2, -2), (-2, 2), (2, -2), (2, 2)]);
+            }
+            let mut sum = 0.0;
+            let mut count = 0;
+            for (dy, dx) in neighbours {
+                let (r, c) = (row + dy, col + dx);
+                if r >= 0 && r < bheight && c >= 0 && c < bwidth && !badmap[pos(r, c)] {
+                    sum += self.data[pos(r, c)];
+                    count += 1;
+                }
+            }
+            fixes.push(if count > 0 { sum / count as f32 } else { 0.0 });
+        }
+        for ((row, col), val) in bad.iter().zip(fixes) {
+            self.data[pos(*row, *col)] = val;
+        }
+    }
+
+    fn trim(
+        &mut self,
+        top: usize,
+        left: usize,
+        bottom: usize,
+        right: usize,
+    ) -> Result<(), RawLoaderError> {
+        if top >= bottom || left >= right || bottom > self.bheight || right > self.bwidth {
+            return Err(RawLoaderError::corrupt("DNG: invalid TrimBounds"));
+        }
+        let (ntop, nleft) = (self.top + top, self.left + left);
+        let (nwidth, nheight) = (right - left, bottom - top);
+        let mut out = Vec::with_capacity(nwidth * nheight * self.cpp);
+        for row in ntop..ntop + nheight {
+            let start = (row * self.width + nleft) * self.cpp;
+            out.extend_from_slice(&self.data[start..start + nwidth * self.cpp]);
+        }
+        self.data = out;
+        self.offset = (self.offset.0 + ntop, self.offset.1 + nleft);
+        self.width = nwidth;
+        self.height = nheight;
+        self.bwidth = nwidth;
+        self.bheight = nheight;
+        self.top = 0;
+        self.left = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,424 @@
+use std::cell::OnceCell;
+use std::io;
+use std::sync::Arc;
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::decoders::basics::{Window, with_dither};
+use crate::decoders::x3f::with_quattro_upsample;
+use crate::decoders::*;
+
+thread_local! {
+    // The pool for single threaded decodes, kept so that every decode doesn't spawn a new
+    // thread. Each calling thread gets its own so their decodes don't wait on each other
+    static SINGLE: OnceCell<ThreadPool> = const { OnceCell::new() };
+}
+
+#[derive(Debug, Clone)]
+enum Threads {
+    Global,
+    Single,
+    Pool(Arc<ThreadPool>),
+}
+
+/// How much to scale down an image while decoding it, for thumbnails and previews. The
+/// mosaic gets binned into superpixels so the result has a value for each color (`cpp` of 3
+/// for RGB sensors) and no CFA
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scale {
+    /// the image as it is
+    Full,
+    /// half the width and height, averaging each 2x2 block (3x3 for X-Trans)
+    Half,
+    /// a quarter of the width and height, averaging the top half of each 4x4 block (6x6 for
+    /// X-Trans) so that formats that allow it only decode half of the rows
+    Quarter,
+}
+
+/// Options to control how an image gets decoded, built up from the defaults
+///
+/// # Example
+/// ```rust,no_run
+/// let options = rawloader2::DecodeOptions::new()
+///   .max_pixels(100_000_000)
+///   .single_threaded()
+///   .dither(false);
+/// let image = rawloader2::decode_file_with("path/to/your/file.RAW", &options).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct DecodeOptions {
+    max_pixels: Option<usize>,
+    max_memory: Option<usize>,
+    region: Option<(usize, usize, usize, usize)>,
+    scale: Scale,
+    pub(crate) frame: usize,
+    threads: Threads,
+    dither: bool,
+    quattro_upsample: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DecodeOptions {
+            max_pixels: None,
+            max_memory: None,
+            region: None,
+            scale: Scale::Full,
+            frame: 0,
+            threads: Threads::Global,
+            dither: true,
+            quattro_upsample: true,
+        }
+    }
+}
+
+impl DecodeOptions {
+    /// The default options: no limits besides the built-in sanity ones, decoding in the
+    /// global rayon thread pool and dithering linearization curves
+    pub fn new() -> DecodeOptions {
+        DecodeOptions::default()
+    }
+
+    /// Refuse images with more than this many pixels, counting every sample of
+    /// multi-component images
+    pub fn max_pixels(mut self, pixels: usize) -> DecodeOptions {
+        self.max_pixels = Some(pixels);
+        self
+    }
+
+    /// Refuse images whose decoded data would take more than this many bytes
+    pub fn max_memory(mut self, bytes: usize) -> DecodeOptions {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Only decode a rectangle of the image, given in pixels of the full image. The
+    /// returned image is just that rectangle, with the crops, CFA and black levels moved to
+    /// match. Formats that allow it skip decoding the rest of the image, which makes this
+    /// much faster than cropping after the fact. The DNG opcodes are relative to the full
+    /// image so they don't come along
+    pub fn region(mut self, top: usize, left: usize, width: usize, height: usize) -> DecodeOptions {
+        self.region = Some((top, left, width, height));
+        self
+    }
+
+    /// Scale down the image, after taking the `region()` if there's one
+    pub fn scale(mut self, scale: Scale) -> DecodeOptions {
+        self.scale = scale;
+        self
+    }
+
+    /// Decode another of the raw frames in the file instead of the first one, see
+    /// `frame_count()`
+    pub fn frame(mut self, index: usize) -> DecodeOptions {
+        self.frame = index;
+        self
+    }
+
+    /// Decode in the given thread pool instead of the global rayon one
+    pub fn thread_pool(mut self, pool: Arc<ThreadPool>) -> DecodeOptions {
+        self.threads = Threads::Pool(pool);
+        self
+    }
+
+    /// Decode using a single thread
+    pub fn single_threaded(mut self) -> DecodeOptions {
+        self.threads = Threads::Single;
+        self
+    }
+
+    /// Whether to dither the values of the linearization curves some Sony, Nikon, Kodak
+    /// and DNG files use. Without it the output only depends on the file, which is useful
+    /// when comparing results bit by bit
+    pub fn dither(mut self, dither: bool) -> DecodeOptions {
+        self.dither = dither;
+        self
+    }
+
+    /// How to decode Sigma Quattro files, whose top layer has twice the resolution of the
+    /// two lower ones. By default the lower layers get upsampled to the full resolution by
+    /// repeating each of their values over a 2x2 block, without any interpolation. When
+    /// disabled the top layer is instead binned down to the resolution of the lower layers
+    pub fn quattro_upsample(mut self, upsample: bool) -> DecodeOptions {
+        self.quattro_upsample = upsample;
+        self
+    }
+
+    // Whether a dummy decode is needed to know the size of the image beforehand
+    pub(crate) fn needs_size(&self) -> bool {
+        self.max_pixels.is_some()
+            || self.max_memory.is_some()
+            || self.region.is_some()
+            || self.scale != Scale::Full
+    }
+
+    // The size of the blocks that get binned and how many of their rows get used
+    fn block(&self, image: &RawImage) -> Option<(usize, usize)> {
+        let xtrans = image.cpp == 1 && image.cfa.width == 6 && image.cfa.height == 6;
+        match (self.scale, xtrans) {
+            (Scale::Full, _) => None,
+            (Scale::Half, false) => Some((2, 2)),
+            (Scale::Half, true) => Some((3, 3)),
+            (Scale::Quarter, false) => Some((4, 2)),
+            (Scale::Quarter, true) => Some((6, 3)),
+        }
+    }
+
+    // The window the decoders can restrict themselves to, given the image decoded in dummy mode
+    pub(crate) fn window(&self, image: &RawImage) -> Result<Option<Window>, RawLoaderError> {
+        let block = self.block(image);
+        if self.region.is_none() && block.is_none() {
+            return Ok(None);
+        }
+        let (top, left, width, height) = self.region.unwrap_or((0, 0, image.width, image.height));
+        if width == 0
+            || height == 0
+            || left.saturating_add(width) > image.width
+            || top.saturating_add(height) > image.height
+        {
+            return Err(RawLoaderError::InvalidArgument(format!(
+                "region {}x{} at {},{} isn't inside the {}x{} image",
+                width, height, left, top, image.width, image.height
+            )));
+        }
+        let (step, keep) = block.unwrap_or((1, 1));
+        if width < step || height < step {
+            return Err(RawLoaderError::InvalidArgument(format!(
+                "{}x{} image is too small to scale down",
+                width, height
+            )));
+        }
+        // Black levels measured from the masked areas need all of the image
+        if image.black.is_none() && !image.blackareas.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Window {
+            top,
+            left,
+            bottom: top + height,
+            right: left + width,
+            cpp: image.cpp,
+            step,
+            keep,
+        }))
+    }
+
+    // Turns the decoded image into the region and scale that were asked for. Decoders that
+    // honoured the window already output just the region
+    pub(crate) fn finish(&self, image: &mut RawImage) {
+        if let Some((top, left, width, height)) = self.region
+            && (image.width, image.height) != (width, height)
+        {
+            image.crop(top, left, width, height);
+        }
+        if let Some((size, rows)) = self.block(image) {
+            image.bin(size, rows);
+        }
+    }
+
+    // Checks the limits against an image decoded in dummy mode, so before any of the image
+    // data gets allocated
+    pub(crate) fn check_limits(&self, image: &RawImage) -> Result<(), RawLoaderError> {
+        let pixels = image
+            .width
+            .saturating_mul(image.height)
+            .saturating_mul(image.cpp);
+        if let Some(max) = self.max_pixels
+            && pixels > max
+        {
+            return Err(RawLoaderError::limit(format!(
+                "{}x{}x{} image is over the limit of {} pixels",
+                image.width, image.height, image.cpp, max
+            )));
+        }
+        let bytes = match image.data {
+            RawImageData::Integer(_) => pixels.saturating_mul(2),
+            RawImageData::Float(_) => pixels.saturating_mul(4),
+        };
+        if let Some(max) = self.max_memory
+            && bytes > max
+        {
+            return Err(RawLoaderError::limit(format!(
+                "{}x{}x{} image needs {} bytes, over the limit of {}",
+                image.width, image.height, image.cpp, bytes, max
+            )));
+        }
+        Ok(())
+    }
+
+    // Runs a decode in the requested threads and with the requested dithering and Quattro
+    // upsampling
+    pub(crate) fn run<T, F>(&self, func: F) -> Result<T, RawLoaderError>
+    where
+        T: Send,
+        F: FnOnce() -> Result<T, RawLoaderError> + Send,
+    {
+        let (dither, upsample) = (self.dither, self.quattro_upsample);
+        let func = move || with_quattro_upsample(upsample, func);
+        match &self.threads {
+            Threads::Global => with_dither(dither, func),
+            Threads::Pool(pool) => pool.install(|| with_dither(dither, func)),
+            Threads::Single => SINGLE.with(|single| {
+                let pool = match single.get() {
+                    Some(pool) => pool,
+                    None => {
+                        let pool = ThreadPoolBuilder::new()
+                            .num_threads(1)
+                            .build()
+                            .map_err(|err| RawLoaderError::Io(io::Error::other(err)))?;
+                        single.get_or_init(|| pool)
+                    }
+                };
+                pool.install(|| with_dither(dither, func))
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoders::cfa::CFA;
+    use std::io::Cursor;
+    use std::thread;
+
+    // An 8x8 RGGB DNG of 8 bit values 0 to 63 that go through a linearization table with
+    // steps of 100, big enough for the dithering to show
+    fn dng() -> Vec<u8> {
+        let entries: [(u16, u16, u32, u32); 16] = [
+            (0x0100, 4, 1, 8),
+            (0x0101, 4, 1, 8),
+            (0x0102, 3, 1, 8),
+            (0x0103, 3, 1, 1),
+            (0x0106, 3, 1, 32803),
+            (0x010F, 2, 2, b'A' as u32),
+            (0x0110, 2, 2, b'B' as u32),
+            (0x0111, 4, 1, 0),
+            (0x0115, 3, 1, 1),
+            (0x0116, 4, 1, 8),
+            (0x0117, 4, 1, 64),
+            (0x828D, 3, 2, 2 | 2 << 16),
+            (0x828E, 1, 4, u32::from_le_bytes([0, 1, 1, 2])),
+            (0xC612, 1, 4, u32::from_le_bytes([1, 4, 0, 0])),
+            (0xC618, 3, 256, 0),
+            (0xC61D, 4, 1, 65535),
+        ];
+        let table = 8 + 2 + entries.len() * 12 + 4;
+        let strip = table + 512;
+        let mut buf = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        buf.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (tag, typ, count, value) in entries {
+            let value = match tag {
+                0x0111 => strip as u32,
+                0xC618 => table as u32,
+                _ => value,
+            };
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&typ.to_le_bytes());
+            buf.extend_from_slice(&count.to_le_bytes());
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf.extend_from_slice(&[0; 4]);
+        for i in 0..256u16 {
+            buf.extend_from_slice(&(i * 100).to_le_bytes());
+        }
+        buf.extend(0..64u8);
+        buf
+    }
+
+    fn decode(options: &DecodeOptions) -> Result<RawImage, RawLoaderError> {
+        RawLoader::new().decode_with(&mut Cursor::new(dng()), options)
+    }
+
+    fn data(image: &RawImage) -> Vec<u16> {
+        match &image.data {
+            RawImageData::Integer(data) => data.clone(),
+            RawImageData::Float(_) => panic!("image isn't integer"),
+        }
+    }
+
+    #[test]
+    fn limits_refuse_big_images() {
+        let err = decode(&DecodeOptions::new().max_pixels(63)).unwrap_err();
+        assert!(matches!(err, RawLoaderError::LimitExceeded(_)), "{:?}", err);
+        assert!(decode(&DecodeOptions::new().max_pixels(64)).is_ok());
+
+        let err = decode(&DecodeOptions::new().max_memory(127)).unwrap_err();
+        assert!(matches!(err, RawLoaderError::LimitExceeded(_)), "{:?}", err);
+        assert!(decode(&DecodeOptions::new().max_memory(128)).is_ok());
+    }
+
+    #[test]
+    fn no_dithering_is_bit_exact() {
+        let expected: Vec<u16> = (0..64).map(|i| i * 100).collect();
+        let options = DecodeOptions::new().dither(false);
+        assert_eq!(data(&decode(&options).unwrap()), expected);
+        assert_eq!(data(&decode(&options).unwrap()), expected);
+
+        // With dithering the values spread out between the steps of the table
+        let dithered = data(&decode(&DecodeOptions::new()).unwrap());
+        assert_ne!(dithered, expected);
+        for (value, step) in dithered.iter().zip(&expected) {
+            assert!(value.abs_diff(*step) <= 100, "{} from {}", value, step);
+        }
+    }
+
+    #[test]
+    fn single_threaded_decodes() {
+        let options = DecodeOptions::new().single_threaded().dither(false);
+        let image = decode(&options).unwrap();
+        assert_eq!(data(&image), (0..64).map(|i| i * 100).collect::<Vec<u16>>());
+
+        // Every decode from a thread runs in the same single thread pool
+        let run = || {
+            options
+                .run(|| Ok((rayon::current_num_threads(), thread::current().id())))
+                .unwrap()
+        };
+        let (threads, first) = run();
+        assert_eq!(threads, 1);
+        assert_ne!(first, thread::current().id());
+        assert_eq!(run().1, first);
+        // and another thread gets its own
+        let other = thread::scope(|s| s.spawn(run).join().unwrap());
+        assert_ne!(other.1, first);
+    }
+
+    #[test]
+    fn blocks_follow_the_cfa() {
+        let mut image = decode(&DecodeOptions::new()).unwrap();
+        let half = DecodeOptions::new().scale(Scale::Half);
+        let quarter = DecodeOptions::new().scale(Scale::Quarter);
+        assert_eq!(DecodeOptions::new().block(&image), None);
+        assert_eq!(half.block(&image), Some((2, 2)));
+        assert_eq!(quarter.block(&image), Some((4, 2)));
+
+        image.cfa = CFA::new("GGRGGBGGBGGRBRGRBGGGBGGRGGRGGBRBGBRG");
+        assert_eq!(half.block(&image), Some((3, 3)));
+        assert_eq!(quarter.block(&image), Some((6, 3)));
+    }
+
+    #[test]
+    fn scaled_decodes() {
+        // The values of the image are 100 times their position
+        let options = DecodeOptions::new().dither(false);
+        let image = decode(&options.clone().scale(Scale::Half)).unwrap();
+        assert_eq!((image.width, image.height, image.cpp), (4, 4, 3));
+        assert_eq!(data(&image)[..3], [0, 450, 900]);
+
+        // Only the top two rows of each block count
+        let image = decode(&options.clone().scale(Scale::Quarter)).unwrap();
+        assert_eq!((image.width, image.height, image.cpp), (2, 2, 3));
+        assert_eq!(
+            data(&image),
+            vec![
+                100, 550, 1000, 500, 950, 1400, 3300, 3750, 4200, 3700, 4150, 4600
+            ]
+        );
+
+        // A region at an odd position starts the blocks on a blue pixel
+        let image = decode(&options.region(1, 1, 6, 6).scale(Scale::Half)).unwrap();
+        assert_eq!((image.width, image.height, image.cpp), (3, 3, 3));
+        assert_eq!(data(&image)[..3], [1800, 1350, 900]);
+    }
+}
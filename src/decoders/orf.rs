@@ -22,7 +22,7 @@ impl<'a> OrfDecoder<'a> {
 }
 
 impl<'a> Decoder for OrfDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
         let raw = fetch_ifd!(&self.tiff, Tag::StripOffsets);
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
@@ -31,7 +31,7 @@ impl<'a> Decoder for OrfDecoder<'a> {
         let counts = fetch_tag!(raw, Tag::StripByteCounts);
         let mut size: usize = 0;
         for i in 0..counts.count() {
-            size += counts.get_u32(i) as usize;
+            size = size.saturating_add(counts.get_u32(i) as usize);
         }
 
         let camera = if width >= camera.highres_width {
@@ -41,31 +41,47 @@ impl<'a> Decoder for OrfDecoder<'a> {
             camera
         };
 
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
+        let pixels = width.saturating_mul(height);
 
-        let image = if size >= width * height * 2 {
+        let window = Window::current();
+        let image = if size >= pixels.saturating_mul(2) {
             if self.tiff.little_endian() {
-                decode_12le_unpacked_left_aligned(src, width, height, dummy)
+                decode_12le_unpacked_left_aligned(src, width, height, window.as_ref(), dummy)?
             } else {
-                decode_12be_unpacked_left_aligned(src, width, height, dummy)
+                decode_12be_unpacked_left_aligned(src, width, height, window.as_ref(), dummy)?
             }
-        } else if size >= width * height / 10 * 16 {
-            decode_12le_wcontrol(src, width, height, dummy)
-        } else if size >= width * height * 12 / 8 {
+        } else if size >= pixels / 10 * 16 {
+            decode_12le_wcontrol(src, width, height, window.as_ref(), dummy)?
+        } else if size >= pixels.saturating_mul(12) / 8 {
             if width < 3500 {
                 // The interlaced stuff is all old and smaller
-                decode_12be_interlaced(src, width, height, dummy)
+                decode_12be_interlaced(src, width, height, window.as_ref(), dummy)?
             } else {
-                decode_12be_msb32(src, width, height, dummy)
+                let image = decode_12be_msb32(src, width, height, dummy)?;
+                crop_window(image, width, window.as_ref())?
             }
         } else {
-            OrfDecoder::decode_compressed(src, width, height, dummy)
+            let image = OrfDecoder::decode_compressed(src, width, height, dummy)?;
+            crop_window(image, width, window.as_ref())?
         };
 
-        match self.get_blacks() {
+        let image = match self.get_blacks() {
             Ok(val) => ok_image_with_blacklevels(camera, width, height, self.get_wb()?, val, image),
             Err(_) => ok_image(camera, width, height, self.get_wb()?, image),
-        }
+        };
+        image.map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        // The larger previews live in the makernote camera settings
+        let mut previews = preview::tiff_previews(self.buffer, &self.tiff);
+        previews.extend(preview::makernote_previews(self.buffer, &self.tiff));
+        Ok(previews)
     }
 }
 
@@ -77,7 +93,12 @@ impl<'a> OrfDecoder<'a> {
      * is based on the output of all previous pixel (bar the first four)
      */
 
-    pub fn decode_compressed(buf: &'a [u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+    pub fn decode_compressed(
+        buf: &'a [u8],
+        width: usize,
+        height: usize,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let mut out: Vec<u16> = alloc_image!(width, height, dummy);
 
         /* Build a table to quickly look up "high" value */
@@ -97,7 +118,7 @@ impl<'a> OrfDecoder<'a> {
 
         let mut left: [i32; 2] = [0; 2];
         let mut nw: [i32; 2] = [0; 2];
-        let mut pump = BitPumpMSB::new(&buf[7..]);
+        let mut pump = BitPumpMSB::new(from_offset(buf, 7));
 
         for row in 0..height {
             let mut acarry: [[i32; 3]; 2] = [[0; 3]; 2];
@@ -127,8 +148,8 @@ impl<'a> OrfDecoder<'a> {
                     }
 
                     acarry[s][0] = (high << nbits) | pump.get_ibits(nbits);
-                    let diff = (acarry[s][0] ^ sign) + acarry[s][1];
-                    acarry[s][1] = (diff * 3 + acarry[s][1]) >> 5;
+                    let diff = (acarry[s][0] ^ sign).wrapping_add(acarry[s][1]);
+                    acarry[s][1] = diff.wrapping_mul(3).wrapping_add(acarry[s][1]) >> 5;
                     acarry[s][2] = if acarry[s][0] > 16 {
                         0
                     } else {
@@ -148,36 +169,37 @@ impl<'a> OrfDecoder<'a> {
                             nw[s] = out[(row - 2) * width + (col + s)] as i32;
                             nw[s]
                         };
-                        left[s] = pred + ((diff << 2) | low);
+                        left[s] = pred.wrapping_add((diff << 2) | low);
                         out[row * width + (col + s)] = left[s] as u16;
                     } else {
                         let up: i32 = out[(row - 2) * width + (col + s)] as i32;
-                        let left_minus_nw: i32 = left[s] - nw[s];
+                        let left_minus_nw: i32 = left[s].wrapping_sub(nw[s]);
                         let up_minus_nw: i32 = up - nw[s];
                         // Check if sign is different, and one is not zero
-                        let pred = if left_minus_nw * up_minus_nw < 0 {
-                            if left_minus_nw.abs() > 32 || up_minus_nw.abs() > 32 {
-                                left[s] + up_minus_nw
+                        let pred = if left_minus_nw.wrapping_mul(up_minus_nw) < 0 {
+                            if left_minus_nw.unsigned_abs() > 32 || up_minus_nw.unsigned_abs() > 32
+                            {
+                                left[s].wrapping_add(up_minus_nw)
                             } else {
-                                (left[s] + up) >> 1
+                                left[s].wrapping_add(up) >> 1
                             }
-                        } else if left_minus_nw.abs() > up_minus_nw.abs() {
+                        } else if left_minus_nw.unsigned_abs() > up_minus_nw.unsigned_abs() {
                             left[s]
                         } else {
                             up
                         };
 
-                        left[s] = pred + ((diff << 2) | low);
+                        left[s] = pred.wrapping_add((diff << 2) | low);
                         nw[s] = up;
                         out[row * width + (col + s)] = left[s] as u16;
                     }
                 }
             }
         }
-        out
+        Ok(out)
     }
 
-    fn get_blacks(&self) -> Result<[u16; 4], String> {
+    fn get_blacks(&self) -> Result<[u16; 4], RawLoaderError> {
         let blacks = fetch_tag!(self.tiff, Tag::OrfBlackLevels);
         Ok([
             blacks.get_u16(0),
@@ -187,7 +209,7 @@ impl<'a> OrfDecoder<'a> {
         ])
     }
 
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         let redmul = self.tiff.find_entry(Tag::OlympusRedMul);
         let bluemul = self.tiff.find_entry(Tag::OlympusBlueMul);
 
@@ -201,7 +223,7 @@ impl<'a> OrfDecoder<'a> {
         } else {
             let ifd = self.tiff.find_ifds_with_tag(Tag::OrfBlackLevels);
             if ifd.is_empty() {
-                return Err("ORF: Couldn't find ImgProc IFD".to_string());
+                return Err(RawLoaderError::corrupt("ORF: Couldn't find ImgProc IFD"));
             }
             let wbs = fetch_tag!(ifd[0], Tag::ImageWidth);
             Ok([wbs.get_f32(0), 256.0, wbs.get_f32(1), f32::NAN])
@@ -1,3 +1,4 @@
+use crate::decoders::RawLoaderError;
 use crate::decoders::basics::*;
 
 pub fn decode_8bit_wtable(
@@ -5,14 +6,16 @@ pub fn decode_8bit_wtable(
     tbl: &LookupTable,
     width: usize,
     height: usize,
+    window: Option<&Window>,
     dummy: bool,
-) -> Vec<u16> {
+) -> Result<Vec<u16>, RawLoaderError> {
     decode_threaded(
         width,
         height,
+        window,
         dummy,
         &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width)..];
+            let inb = from_offset(buf, row * width);
             let mut random = LEu32(inb, 0);
 
             for (o, i) in out.chunks_exact_mut(1).zip(inb.chunks_exact(1)) {
@@ -22,13 +25,21 @@ pub fn decode_8bit_wtable(
     )
 }
 
-pub fn decode_10le_lsb16(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
-    decode_threaded(
+pub fn decode_10le_lsb16(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        8,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 10 / 8)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 10 / 8 + col * 10 / 8);
 
             for (o, i) in out.chunks_exact_mut(8).zip(inb.chunks_exact(10)) {
                 let g1: u16 = i[0] as u16;
@@ -55,13 +66,21 @@ pub fn decode_10le_lsb16(buf: &[u8], width: usize, height: usize, dummy: bool) -
     )
 }
 
-pub fn decode_10le(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
-    decode_threaded(
+pub fn decode_10le(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        4,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 10 / 8)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 10 / 8 + col * 10 / 8);
 
             for (o, i) in out.chunks_exact_mut(4).zip(inb.chunks_exact(5)) {
                 let g1: u16 = i[0] as u16;
@@ -79,13 +98,21 @@ pub fn decode_10le(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<
     )
 }
 
-pub fn decode_12be(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
-    decode_threaded(
+pub fn decode_12be(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        2,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 12 / 8)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 12 / 8 + col * 12 / 8);
 
             for (o, i) in out.chunks_exact_mut(2).zip(inb.chunks_exact(3)) {
                 let g1: u16 = i[0] as u16;
@@ -99,7 +126,12 @@ pub fn decode_12be(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<
     )
 }
 
-pub fn decode_12be_msb16(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+pub fn decode_12be_msb16(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
     let mut out: Vec<u16> = alloc_image!(width, height, dummy);
 
     for (o, i) in out.chunks_exact_mut(4).zip(buf.chunks_exact(6)) {
@@ -116,17 +148,25 @@ pub fn decode_12be_msb16(buf: &[u8], width: usize, height: usize, dummy: bool) -
         o[3] = ((g6 & 0x0f) << 8) | g5;
     }
 
-    out
+    Ok(out)
 }
 
-pub fn decode_12le_16bitaligned(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+pub fn decode_12le_16bitaligned(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
     let stride = ((width * 12 / 8 + 1) >> 1) << 1;
-    decode_threaded(
+    decode_threaded_cols(
         width,
         height,
+        2,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[row * stride..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * stride + col * 12 / 8);
             for (o, i) in out.chunks_exact_mut(2).zip(inb.chunks_exact(3)) {
                 let g1: u16 = i[0] as u16;
                 let g2: u16 = i[1] as u16;
@@ -139,7 +179,12 @@ pub fn decode_12le_16bitaligned(buf: &[u8], width: usize, height: usize, dummy:
     )
 }
 
-pub fn decode_12be_msb32(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+pub fn decode_12be_msb32(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
     let mut out: Vec<u16> = alloc_image!(width, height, dummy);
 
     for (o, i) in out.chunks_exact_mut(8).zip(buf.chunks_exact(12)) {
@@ -166,19 +211,27 @@ pub fn decode_12be_msb32(buf: &[u8], width: usize, height: usize, dummy: bool) -
         o[7] = ((g10 & 0x0f) << 8) | g9;
     }
 
-    out
+    Ok(out)
 }
 
-pub fn decode_12le_wcontrol(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+pub fn decode_12le_wcontrol(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
     // Calulate expected bytes per line.
     let perline = width * 12 / 8 + ((width + 2) / 10);
 
-    decode_threaded(
+    decode_threaded_cols(
         width,
         height,
+        10,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * perline)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * perline + col / 10 * 16);
 
             for (oc, ic) in out.chunks_exact_mut(10).zip(inb.chunks_exact(16)) {
                 for (o, i) in oc.chunks_exact_mut(2).zip(ic.chunks_exact(3)) {
@@ -194,16 +247,24 @@ pub fn decode_12le_wcontrol(buf: &[u8], width: usize, height: usize, dummy: bool
     )
 }
 
-pub fn decode_12be_wcontrol(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+pub fn decode_12be_wcontrol(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
     // Calulate expected bytes per line.
     let perline = width * 12 / 8 + ((width + 2) / 10);
 
-    decode_threaded(
+    decode_threaded_cols(
         width,
         height,
+        10,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * perline)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * perline + col / 10 * 16);
 
             for (oc, ic) in out.chunks_exact_mut(10).zip(inb.chunks_exact(16)) {
                 for (o, i) in oc.chunks_exact_mut(2).zip(ic.chunks_exact(3)) {
@@ -219,22 +280,30 @@ pub fn decode_12be_wcontrol(buf: &[u8], width: usize, height: usize, dummy: bool
     )
 }
 
-pub fn decode_12be_interlaced(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+pub fn decode_12be_interlaced(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
     let half = (height + 1) >> 1;
     // Second field is 2048 byte aligned
     let second_field_offset = (((half * width * 3 / 2) >> 11) + 1) << 11;
-    let second_field = &buf[second_field_offset..];
+    let second_field = from_offset(buf, second_field_offset);
 
-    decode_threaded(
+    decode_threaded_cols(
         width,
         height,
+        2,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let off = row / 2 * width * 12 / 8;
+        &(|out: &mut [u16], row, col| {
+            let off = row / 2 * width * 12 / 8 + col * 12 / 8;
             let inb = if (row % 2) == 0 {
-                &buf[off..]
+                from_offset(buf, off)
             } else {
-                &second_field[off..]
+                from_offset(second_field, off)
             };
 
             for (o, i) in out.chunks_exact_mut(2).zip(inb.chunks_exact(3)) {
@@ -253,21 +322,24 @@ pub fn decode_12be_interlaced_unaligned(
     buf: &[u8],
     width: usize,
     height: usize,
+    window: Option<&Window>,
     dummy: bool,
-) -> Vec<u16> {
+) -> Result<Vec<u16>, RawLoaderError> {
     let half = (height + 1) >> 1;
-    let second_field = &buf[half * width * 12 / 8..];
+    let second_field = from_offset(buf, half * width * 12 / 8);
 
-    decode_threaded(
+    decode_threaded_cols(
         width,
         height,
+        2,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let off = row / 2 * width * 12 / 8;
+        &(|out: &mut [u16], row, col| {
+            let off = row / 2 * width * 12 / 8 + col * 12 / 8;
             let inb = if (row % 2) == 0 {
-                &buf[off..]
+                from_offset(buf, off)
             } else {
-                &second_field[off..]
+                from_offset(second_field, off)
             };
 
             for (o, i) in out.chunks_exact_mut(2).zip(inb.chunks_exact(3)) {
@@ -282,13 +354,21 @@ pub fn decode_12be_interlaced_unaligned(
     )
 }
 
-pub fn decode_12le(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
-    decode_threaded(
+pub fn decode_12le(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        2,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 12 / 8)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 12 / 8 + col * 12 / 8);
 
             for (o, i) in out.chunks_exact_mut(2).zip(inb.chunks_exact(3)) {
                 let g1: u16 = i[0] as u16;
@@ -302,31 +382,47 @@ pub fn decode_12le(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<
     )
 }
 
-pub fn decode_12le_unpacked(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
-    decode_threaded(
+pub fn decode_12le_unpacked(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        1,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 2)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 2 + col * 2);
 
-            for (i, bytes) in (0..width).zip(inb.chunks_exact(2)) {
-                out[i] = LEu16(bytes, 0) & 0x0fff;
+            for (o, bytes) in out.iter_mut().zip(inb.chunks_exact(2)) {
+                *o = LEu16(bytes, 0) & 0x0fff;
             }
         }),
     )
 }
 
-pub fn decode_12be_unpacked(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
-    decode_threaded(
+pub fn decode_12be_unpacked(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        1,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 2)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 2 + col * 2);
 
-            for (i, bytes) in (0..width).zip(inb.chunks_exact(2)) {
-                out[i] = BEu16(bytes, 0) & 0x0fff;
+            for (o, bytes) in out.iter_mut().zip(inb.chunks_exact(2)) {
+                *o = BEu16(bytes, 0) & 0x0fff;
             }
         }),
     )
@@ -336,17 +432,20 @@ pub fn decode_12be_unpacked_left_aligned(
     buf: &[u8],
     width: usize,
     height: usize,
+    window: Option<&Window>,
     dummy: bool,
-) -> Vec<u16> {
-    decode_threaded(
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        1,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 2)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 2 + col * 2);
 
-            for (i, bytes) in (0..width).zip(inb.chunks_exact(2)) {
-                out[i] = BEu16(bytes, 0) >> 4;
+            for (o, bytes) in out.iter_mut().zip(inb.chunks_exact(2)) {
+                *o = BEu16(bytes, 0) >> 4;
             }
         }),
     )
@@ -356,92 +455,135 @@ pub fn decode_12le_unpacked_left_aligned(
     buf: &[u8],
     width: usize,
     height: usize,
+    window: Option<&Window>,
     dummy: bool,
-) -> Vec<u16> {
-    decode_threaded(
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        1,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 2)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 2 + col * 2);
 
-            for (i, bytes) in (0..width).zip(inb.chunks_exact(2)) {
-                out[i] = LEu16(bytes, 0) >> 4;
+            for (o, bytes) in out.iter_mut().zip(inb.chunks_exact(2)) {
+                *o = LEu16(bytes, 0) >> 4;
             }
         }),
     )
 }
 
-pub fn decode_14le_unpacked(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
-    decode_threaded(
+pub fn decode_14le_unpacked(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        1,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 2)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 2 + col * 2);
 
-            for (i, bytes) in (0..width).zip(inb.chunks_exact(2)) {
-                out[i] = LEu16(bytes, 0) & 0x3fff;
+            for (o, bytes) in out.iter_mut().zip(inb.chunks_exact(2)) {
+                *o = LEu16(bytes, 0) & 0x3fff;
             }
         }),
     )
 }
 
-pub fn decode_14be_unpacked(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
-    decode_threaded(
+pub fn decode_14be_unpacked(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        1,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 2)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 2 + col * 2);
 
-            for (i, bytes) in (0..width).zip(inb.chunks_exact(2)) {
-                out[i] = BEu16(bytes, 0) & 0x3fff;
+            for (o, bytes) in out.iter_mut().zip(inb.chunks_exact(2)) {
+                *o = BEu16(bytes, 0) & 0x3fff;
             }
         }),
     )
 }
 
-pub fn decode_16le(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
-    decode_threaded(
+pub fn decode_16le(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        1,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 2)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 2 + col * 2);
 
-            for (i, bytes) in (0..width).zip(inb.chunks_exact(2)) {
-                out[i] = LEu16(bytes, 0);
+            for (o, bytes) in out.iter_mut().zip(inb.chunks_exact(2)) {
+                *o = LEu16(bytes, 0);
             }
         }),
     )
 }
 
-pub fn decode_16le_skiplines(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
-    decode_threaded(
+pub fn decode_16le_skiplines(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        1,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 4)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 4 + col * 2);
 
-            for (i, bytes) in (0..width).zip(inb.chunks_exact(2)) {
-                out[i] = LEu16(bytes, 0);
+            for (o, bytes) in out.iter_mut().zip(inb.chunks_exact(2)) {
+                *o = LEu16(bytes, 0);
             }
         }),
     )
 }
 
-pub fn decode_16be(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
-    decode_threaded(
+pub fn decode_16be(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    window: Option<&Window>,
+    dummy: bool,
+) -> Result<Vec<u16>, RawLoaderError> {
+    decode_threaded_cols(
         width,
         height,
+        1,
+        window,
         dummy,
-        &(|out: &mut [u16], row| {
-            let inb = &buf[(row * width * 2)..];
+        &(|out: &mut [u16], row, col| {
+            let inb = from_offset(buf, row * width * 2 + col * 2);
 
-            for (i, bytes) in (0..width).zip(inb.chunks_exact(2)) {
-                out[i] = BEu16(bytes, 0);
+            for (o, bytes) in out.iter_mut().zip(inb.chunks_exact(2)) {
+                *o = BEu16(bytes, 0);
             }
         }),
     )
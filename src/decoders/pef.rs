@@ -21,28 +21,72 @@ impl<'a> PefDecoder<'a> {
 }
 
 impl<'a> Decoder for PefDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
+        self.frame(0, dummy)
+    }
+
+    fn frame_count(&self) -> usize {
+        self.frames().map_or(1, |frames| frames.len())
+    }
+
+    fn frame(&self, index: usize, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
-        let raw = fetch_ifd!(&self.tiff, Tag::StripOffsets);
+        let frames = self.frames()?;
+        let raw = *frames
+            .get(index)
+            .ok_or_else(|| no_frame(index, frames.len()))?;
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
         let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
         let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
 
+        let window = Window::current();
         let image = match fetch_tag!(raw, Tag::Compression).get_u32(0) {
-            1 => decode_16be(src, width, height, dummy),
-            32773 => decode_12be(src, width, height, dummy),
-            65535 => self.decode_compressed(src, width, height, dummy)?,
-            c => return Err(format!("PEF: Don't know how to read compression {}", c).to_string()),
+            1 => decode_16be(src, width, height, window.as_ref(), dummy)?,
+            32773 => decode_12be(src, width, height, window.as_ref(), dummy)?,
+            65535 => {
+                let image = self.decode_compressed(src, width, height, dummy)?;
+                crop_window(image, width, window.as_ref())?
+            }
+            c => return Err(RawLoaderError::compression("PEF", c)),
         };
 
         let blacklevels = self.get_blacklevels().unwrap_or(camera.blacklevels);
         ok_image_with_blacklevels(camera, width, height, self.get_wb()?, blacklevels, image)
+            .map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
 
 impl<'a> PefDecoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    // The main raw image followed by any other full resolution raw IFDs of the same size
+    // with their own data. That's where the other exposures of pixel shift files are
+    // expected, but without sample files it hasn't been checked against real ones. Files
+    // that store them some other way just decode as a single frame
+    fn frames(&self) -> Result<Vec<&TiffIFD<'_>>, RawLoaderError> {
+        let main = fetch_ifd!(&self.tiff, Tag::StripOffsets);
+        let value = |ifd: &TiffIFD, tag| ifd.find_own_entry(tag).map(|e| e.get_usize(0));
+        let raw = |ifd: &TiffIFD| {
+            value(ifd, Tag::StripOffsets).is_some()
+                && value(ifd, Tag::NewSubFileType).unwrap_or(0) == 0
+                && matches!(value(ifd, Tag::Compression), Some(1 | 32773 | 65535))
+                && value(ifd, Tag::ImageWidth) == value(main, Tag::ImageWidth)
+                && value(ifd, Tag::ImageLength) == value(main, Tag::ImageLength)
+                && value(ifd, Tag::StripOffsets) != value(main, Tag::StripOffsets)
+        };
+        let mut frames = vec![main];
+        frames.extend(self.tiff.ifds().into_iter().filter(|ifd| raw(ifd)));
+        Ok(frames)
+    }
+
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         let levels = fetch_tag!(self.tiff, Tag::PefWB);
         Ok([
             levels.get_f32(0),
@@ -69,7 +113,7 @@ impl<'a> PefDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Result<Vec<u16>, String> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         if let Some(huff) = self.tiff.find_entry(Tag::PefHuffman) {
             Self::do_decode(
                 src,
@@ -89,8 +133,14 @@ impl<'a> PefDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Result<Vec<u16>, String> {
-        let mut out = alloc_image_ok!(width, height, dummy);
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        if width < 2 || !width.is_multiple_of(2) {
+            return Err(RawLoaderError::corrupt(format!(
+                "PEF: can't decode width {}",
+                width
+            )));
+        }
+        let mut out = alloc_image!(width, height, dummy);
         let mut htable = HuffTable::empty();
 
         /* Attempt to read huffman table, if found in makernote */
@@ -114,6 +164,9 @@ impl<'a> PefDecoder<'a> {
             // Calculate codes and store bitcounts
             let mut v2: [u32; 16] = [0; 16];
             for c in 0..depth {
+                if v1[c] > 12 {
+                    return Err(RawLoaderError::corrupt("PEF: invalid huffman code length"));
+                }
                 v2[c] = v0[c] >> (12 - v1[c]);
                 htable.bits[v1[c] as usize] += 1;
             }
@@ -160,8 +213,8 @@ impl<'a> PefDecoder<'a> {
         let mut pred_left2: i32;
 
         for row in 0..height {
-            pred_up1[row & 1] += htable.huff_decode(&mut pump)?;
-            pred_up2[row & 1] += htable.huff_decode(&mut pump)?;
+            pred_up1[row & 1] = pred_up1[row & 1].wrapping_add(htable.huff_decode(&mut pump)?);
+            pred_up2[row & 1] = pred_up2[row & 1].wrapping_add(htable.huff_decode(&mut pump)?);
 
             pred_left1 = pred_up1[row & 1];
             pred_left2 = pred_up2[row & 1];
@@ -170,8 +223,8 @@ impl<'a> PefDecoder<'a> {
             out[row * width + 1] = pred_left2 as u16;
 
             for col in (2..width).step_by(2) {
-                pred_left1 += htable.huff_decode(&mut pump)?;
-                pred_left2 += htable.huff_decode(&mut pump)?;
+                pred_left1 = pred_left1.wrapping_add(htable.huff_decode(&mut pump)?);
+                pred_left2 = pred_left2.wrapping_add(htable.huff_decode(&mut pump)?);
 
                 out[row * width + col] = pred_left1 as u16;
                 out[row * width + col + 1] = pred_left2 as u16;
@@ -180,3 +233,108 @@ impl<'a> PefDecoder<'a> {
         Ok(out)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn ifd(entries: &[(u16, u16, u32, u32)], next: usize) -> Vec<u8> {
+        let mut buf = (entries.len() as u16).to_le_bytes().to_vec();
+        for &(tag, typ, count, value) in entries {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&typ.to_le_bytes());
+            buf.extend_from_slice(&count.to_le_bytes());
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf.extend_from_slice(&(next as u32).to_le_bytes());
+        buf
+    }
+
+    // A little endian PEF with a 2x2 16 bit image in IFD0 followed by a chain of other
+    // IFDs, each given as its NewSubFileType, width, compression and which of the blocks of
+    // data it points to
+    fn pef(others: &[(u32, u32, u32, usize)]) -> Vec<u8> {
+        let blocks = [[1u16, 2, 3, 4], [5, 6, 7, 8], [9, 9, 9, 9]];
+        let sizes: Vec<usize> = std::iter::once(7)
+            .chain(others.iter().map(|_| 5))
+            .map(|entries| 2 + entries * 12 + 4)
+            .collect();
+        let make = 8 + sizes.iter().sum::<usize>();
+        let (wb, strips) = (make + 8, make + 16);
+
+        let mut buf = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        let mut next = 8 + sizes[0];
+        let main = [
+            (0x0100, 4, 1, 2),
+            (0x0101, 4, 1, 2),
+            (0x0103, 3, 1, 1),
+            (0x010F, 2, 7, make as u32),
+            (0x0110, 2, 2, u32::from_le_bytes(*b"K\0\0\0")),
+            (0x0111, 4, 1, strips as u32),
+            (0x0201, 3, 4, wb as u32),
+        ];
+        buf.extend(ifd(&main, if others.is_empty() { 0 } else { next }));
+        for (i, &(subfile, width, compression, block)) in others.iter().enumerate() {
+            next += sizes[i + 1];
+            let entries = [
+                (0x00FE, 4, 1, subfile),
+                (0x0100, 4, 1, width),
+                (0x0101, 4, 1, 2),
+                (0x0103, 3, 1, compression),
+                (0x0111, 4, 1, (strips + block * 8) as u32),
+            ];
+            buf.extend(ifd(&entries, if i + 1 == others.len() { 0 } else { next }));
+        }
+        buf.extend_from_slice(b"PENTAX\0\0");
+        for level in [2u16, 1, 1, 2] {
+            buf.extend_from_slice(&level.to_le_bytes());
+        }
+        for value in blocks.iter().flatten() {
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+        buf
+    }
+
+    fn data(image: RawImage) -> Vec<u16> {
+        match image.data {
+            RawImageData::Integer(data) => data,
+            RawImageData::Float(_) => panic!("PEF decoded to floats"),
+        }
+    }
+
+    #[test]
+    fn frames_are_the_other_full_size_raws() {
+        // Two more frames around a reduced resolution image, a JPEG, the main image again
+        // and a smaller image that aren't frames
+        let buf = pef(&[
+            (0, 2, 1, 1),
+            (1, 2, 1, 2),
+            (0, 2, 7, 2),
+            (0, 2, 1, 0),
+            (0, 1, 1, 2),
+            (0, 2, 1, 2),
+        ]);
+        let loader = RawLoader::from_toml(
+            r#"
+            [[cameras]]
+            make = "PENTAX"
+            model = "K"
+            whitepoint = 4095
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(loader.frame_count(&mut Cursor::new(&buf)).unwrap(), 3);
+        for (index, values) in [(0, [1, 2, 3, 4]), (1, [5, 6, 7, 8]), (2, [9; 4])] {
+            let image = loader.decode_frame(&mut Cursor::new(&buf), index).unwrap();
+            assert_eq!(data(image), values);
+        }
+        let err = loader.decode_frame(&mut Cursor::new(&buf), 3).unwrap_err();
+        assert!(matches!(err, RawLoaderError::InvalidArgument(_)));
+
+        // Without other raw IFDs there's only the main image
+        let buf = pef(&[]);
+        assert_eq!(loader.frame_count(&mut Cursor::new(&buf)).unwrap(), 1);
+    }
+}
@@ -0,0 +1,392 @@
+use crate::decoders::basics::*;
+use crate::decoders::tiff::*;
+use crate::decoders::*;
+
+/// A preview or thumbnail embedded in a raw file
+///
+/// The data is always a complete image file. JPEG previews are the bytes stored in the
+/// raw file as they are. A few formats instead store small thumbnails as uncompressed 8 bit
+/// RGB in TIFF strips, and those get returned as a binary PPM: a `P6` header with the width,
+/// height and a maximum value of 255 followed by the strips joined together and cut to
+/// `width * height * 3` bytes. The pixel values themselves don't get touched so the
+/// original bytes are everything after the header.
+#[derive(Debug, Clone)]
+pub struct Preview {
+    /// the encoded image, see above for how uncompressed thumbnails get encoded
+    pub data: Vec<u8>,
+    /// width of the preview
+    pub width: usize,
+    /// height of the preview
+    pub height: usize,
+    /// MIME type of the data, "image/jpeg" for almost all previews and
+    /// "image/x-portable-pixmap" for the uncompressed RGB thumbnails
+    pub mime: &'static str,
+    /// orientation of the image as indicated by the file metadata
+    pub orientation: Orientation,
+}
+
+// Walks the markers of a JPEG returning its width, height and length. The length is
+// None when there's no end of image marker. Only baseline, extended and progressive
+// JPEGs are accepted so lossless raw data never passes as a preview.
+fn jpeg_info(data: &[u8]) -> Option<(usize, usize, Option<usize>)> {
+    if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+        return None;
+    }
+    let mut size = None;
+    let mut pos = 2;
+    while pos + 2 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        match marker {
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            0xD9 => return size.map(|(w, h)| (w, h, Some(pos + 2))),
+            0x01 | 0xD0..=0xD7 => {
+                pos += 2;
+                continue;
+            }
+            _ => {}
+        }
+        if pos + 4 > data.len() {
+            break;
+        }
+        let len = BEu16(data, pos + 2) as usize;
+        if len < 2 {
+            return None;
+        }
+        match marker {
+            0xC0..=0xC2 if pos + 9 <= data.len() => {
+                let height = BEu16(data, pos + 5) as usize;
+                let width = BEu16(data, pos + 7) as usize;
+                if width == 0 || height == 0 {
+                    return None;
+                }
+                size = Some((width, height));
+            }
+            // Any other frame type is either lossless or something we don't handle
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => return None,
+            0xDA => {
+                // Skip the entropy coded data up to the next marker
+                pos += 2 + len;
+                while pos + 1 < data.len()
+                    && !(data[pos] == 0xFF
+                        && data[pos + 1] != 0
+                        && !(0xD0..=0xD7).contains(&data[pos + 1]))
+                {
+                    pos += 1;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        pos += 2 + len;
+    }
+    size.map(|(w, h)| (w, h, None))
+}
+
+fn jpeg_preview(data: &[u8], orientation: Orientation) -> Option<Preview> {
+    let (width, height, len) = jpeg_info(data)?;
+    Some(Preview {
+        data: data[..len.unwrap_or(data.len())].to_vec(),
+        width,
+        height,
+        mime: "image/jpeg",
+        orientation,
+    })
+}
+
+// Uncompressed 8 bit RGB thumbnails get a PPM header so the output is a usable image,
+// the strips go after it as they are
+fn rgb_preview(buf: &[u8], ifd: &TiffIFD, orientation: Orientation) -> Option<Preview> {
+    let width = ifd.find_own_entry(Tag::ImageWidth)?.get_usize(0);
+    let height = ifd.find_own_entry(Tag::ImageLength)?.get_usize(0);
+    let offsets = ifd.find_own_entry(Tag::StripOffsets)?;
+    let counts = ifd.find_own_entry(Tag::StripByteCounts)?;
+    let size = width.checked_mul(height)?.checked_mul(3)?;
+    if size == 0 || size > buf.len() {
+        return None;
+    }
+    let mut data = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    let header = data.len();
+    for i in 0..std::cmp::min(offsets.count(), counts.count()) {
+        let start = offsets.get_usize(i) + ifd.start_offset();
+        let end = start.checked_add(counts.get_usize(i))?;
+        data.extend_from_slice(buf.get(start..end)?);
+    }
+    if data.len() < header + size {
+        return None;
+    }
+    data.truncate(header + size);
+    Some(Preview {
+        data,
+        width,
+        height,
+        mime: "image/x-portable-pixmap",
+        orientation,
+    })
+}
+
+// Drops the previews found more than once and orders them from the largest to the smallest.
+// The size comes from the data so sorting by it and then the data puts duplicates together
+pub fn sort_previews(mut previews: Vec<Preview>) -> Vec<Preview> {
+    previews.sort_by(|a, b| {
+        (b.width * b.height)
+            .cmp(&(a.width * a.height))
+            .then_with(|| a.data.cmp(&b.data))
+    });
+    previews.dedup_by(|a, b| a.data == b.data);
+    previews
+}
+
+// Finds the previews in all the IFDs of a TIFF based file, both as JPEGInterchangeFormat
+// pointers and as IFDs of their own, either JPEG compressed or uncompressed RGB
+pub fn tiff_previews(buf: &[u8], tiff: &TiffIFD) -> Vec<Preview> {
+    let orientation = Orientation::from_tiff(tiff);
+    let mut previews = Vec::new();
+
+    for ifd in tiff.ifds() {
+        if let (Some(offset), Some(len)) = (
+            ifd.find_own_entry(Tag::JPEGInterchangeFormat),
+            ifd.find_own_entry(Tag::JPEGInterchangeFormatLength),
+        ) && offset.count() == 1
+        {
+            let offset = offset.get_usize(0) + ifd.start_offset();
+            previews.extend(jpeg_at(buf, offset, len.get_usize(0), orientation));
+        }
+
+        if let Some(jpeg) = ifd.find_own_entry(Tag::PanaJpgFromRaw)
+            && let Some(preview) = jpeg_preview(jpeg.get_data(), orientation)
+        {
+            previews.push(preview);
+        }
+
+        let (offsets, counts) = match (
+            ifd.find_own_entry(Tag::StripOffsets),
+            ifd.find_own_entry(Tag::StripByteCounts),
+        ) {
+            (Some(offsets), Some(counts)) => (offsets, counts),
+            _ => continue,
+        };
+        let compression = ifd
+            .find_own_entry(Tag::Compression)
+            .map_or(1, |e| e.get_u32(0));
+        let photometric = ifd
+            .find_own_entry(Tag::PhotometricInt)
+            .map_or(0, |e| e.get_u32(0));
+        let bps = ifd
+            .find_own_entry(Tag::BitsPerSample)
+            .map_or(0, |e| e.get_u32(0));
+        let cpp = ifd
+            .find_own_entry(Tag::SamplesPerPixel)
+            .map_or(1, |e| e.get_u32(0));
+        match compression {
+            6 | 7 if offsets.count() == 1 => {
+                let offset = offsets.get_usize(0) + ifd.start_offset();
+                previews.extend(jpeg_at(buf, offset, counts.get_usize(0), orientation));
+            }
+            1 if photometric == 2 && bps == 8 && cpp == 3 => {
+                if let Some(preview) = rgb_preview(buf, ifd, orientation) {
+                    previews.push(preview);
+                }
+            }
+            _ => {}
+        }
+    }
+    previews
+}
+
+// Olympus and Minolta makernote tags for the previews, numbered on their own
+const MAKERNOTE_PREVIEW_DATA: u16 = 0x0081;
+const MAKERNOTE_PREVIEW_START: u16 = 0x0088;
+const MAKERNOTE_PREVIEW_LENGTH: u16 = 0x0089;
+const MAKERNOTE_THUMBNAIL: u16 = 0x0100;
+const MAKERNOTE_CAMERA_SETTINGS: u16 = 0x2020;
+const SETTINGS_PREVIEW_START: u16 = 0x0101;
+const SETTINGS_PREVIEW_LENGTH: u16 = 0x0102;
+
+// Finds the previews Olympus and Minolta keep in their makernote, either embedded in a tag
+// or pointed to with a start and a length. The newer Olympus makernotes have offsets from
+// their own start while the older ones and Minolta's have them from the start of the TIFF
+pub fn makernote_previews(buf: &[u8], tiff: &TiffIFD) -> Vec<Preview> {
+    let orientation = Orientation::from_tiff(tiff);
+    let Some(ifd) = tiff.find_first_ifd(Tag::Makernote) else {
+        return Vec::new();
+    };
+    let Some(note) = ifd.find_own_entry(Tag::Makernote) else {
+        return Vec::new();
+    };
+    let data = note.get_data();
+    let (base, main) = if data.starts_with(b"OLYMPUS\0") {
+        let endian = if from_offset(data, 8).starts_with(b"II") {
+            LITTLE_ENDIAN
+        } else {
+            BIG_ENDIAN
+        };
+        (data, TiffIFD::new_plain(data, 12, 0, 0, endian))
+    } else {
+        let skip = if data.starts_with(b"OLYMP") { 8 } else { 0 };
+        let base = from_offset(buf, ifd.start_offset());
+        let main = TiffIFD::new_plain(base, note.doffset() + skip, 0, 0, ifd.get_endian());
+        (base, main)
+    };
+
+    let pointed = |ifd: &TiffIFD, start: u16, len: u16| -> Option<Preview> {
+        let start = ifd.find_raw_entry(start)?.get_usize(0);
+        let len = ifd.find_raw_entry(len)?.get_usize(0);
+        jpeg_at(base, start, len, orientation)
+    };
+
+    let mut previews = Vec::new();
+    for tag in [MAKERNOTE_PREVIEW_DATA, MAKERNOTE_THUMBNAIL] {
+        if let Some(entry) = main.find_raw_entry(tag)
+            && let Some(preview) = jpeg_preview(entry.get_data(), orientation)
+        {
+            previews.push(preview);
+        }
+    }
+    previews.extend(pointed(
+        &main,
+        MAKERNOTE_PREVIEW_START,
+        MAKERNOTE_PREVIEW_LENGTH,
+    ));
+    // The camera settings are either a pointer to their IFD or the IFD itself
+    if let Some(settings) = main.find_raw_entry(MAKERNOTE_CAMERA_SETTINGS) {
+        let offset = if settings.count() == 1 {
+            settings.get_usize(0)
+        } else {
+            settings.doffset()
+        };
+        let settings = TiffIFD::new_plain(base, offset, 0, 0, main.get_endian());
+        previews.extend(pointed(
+            &settings,
+            SETTINGS_PREVIEW_START,
+            SETTINGS_PREVIEW_LENGTH,
+        ));
+    }
+    previews
+}
+
+// A JPEG at a known place in the file, for formats with their own containers
+pub fn jpeg_at(buf: &[u8], offset: usize, len: usize, orientation: Orientation) -> Option<Preview> {
+    let end = offset.checked_add(len)?.min(buf.len());
+    jpeg_preview(buf.get(offset..end)?, orientation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 32x16 baseline JPEG with only the start, frame header and end markers
+    const JPEG: [u8; 17] = [
+        0xFF, 0xD8, 0xFF, 0xC0, 0x00, 0x0B, 0x08, 0x00, 0x10, 0x00, 0x20, 0x01, 0x01, 0x11, 0x00,
+        0xFF, 0xD9,
+    ];
+
+    fn ifd(entries: &[(u16, u16, u32, u32)]) -> Vec<u8> {
+        let mut buf = (entries.len() as u16).to_le_bytes().to_vec();
+        for &(tag, typ, count, value) in entries {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&typ.to_le_bytes());
+            buf.extend_from_slice(&count.to_le_bytes());
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf.extend_from_slice(&[0; 4]);
+        buf
+    }
+
+    // A little endian TIFF with IFD0 holding only a makernote at offset 26
+    fn makernote_tiff(note: &[u8]) -> Vec<u8> {
+        let mut buf = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        buf.extend(ifd(&[(0x927C, 7, note.len() as u32, 26)]));
+        buf.extend_from_slice(note);
+        buf
+    }
+
+    fn preview(width: usize, data: &[u8]) -> Preview {
+        Preview {
+            data: data.to_vec(),
+            width,
+            height: 1,
+            mime: "image/jpeg",
+            orientation: Orientation::Normal,
+        }
+    }
+
+    #[test]
+    fn olympus_camera_settings_preview() {
+        // Offsets in the new style makernote count from its start
+        let mut note = b"OLYMPUS\0II\x03\x00".to_vec();
+        note.extend(ifd(&[(0x2020, 13, 1, 30)]));
+        note.extend(ifd(&[
+            (0x0101, 4, 1, 60),
+            (0x0102, 4, 1, JPEG.len() as u32),
+        ]));
+        note.extend_from_slice(&JPEG);
+        let buf = makernote_tiff(&note);
+        let tiff = TiffIFD::new_file(&buf).unwrap();
+
+        let previews = makernote_previews(&buf, &tiff);
+        assert_eq!(previews.len(), 1);
+        assert_eq!((previews[0].width, previews[0].height), (32, 16));
+        assert_eq!(previews[0].data, JPEG);
+    }
+
+    #[test]
+    fn minolta_preview_from_tiff_start() {
+        // Offsets in the Minolta makernote count from the start of the TIFF
+        let mut note = ifd(&[(0x0088, 4, 1, 56), (0x0089, 4, 1, JPEG.len() as u32)]);
+        note.extend_from_slice(&JPEG);
+        let buf = makernote_tiff(&note);
+        let tiff = TiffIFD::new_file(&buf).unwrap();
+
+        let previews = makernote_previews(&buf, &tiff);
+        assert_eq!(previews.len(), 1);
+        assert_eq!(previews[0].data, JPEG);
+    }
+
+    #[test]
+    fn rgb_thumbnail_as_ppm() {
+        // A 2x2 thumbnail in two strips of a row each, the second with an extra byte
+        let mut buf = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        buf.extend(ifd(&[
+            (0x0100, 4, 1, 2),
+            (0x0101, 4, 1, 2),
+            (0x0111, 4, 2, 62),
+            (0x0117, 4, 2, 70),
+        ]));
+        for value in [78u32, 84, 6, 7] {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf.extend(1..=13u8);
+        let tiff = TiffIFD::new_file(&buf).unwrap();
+
+        // IFD0 is the first IFD under the root
+        let preview = rgb_preview(&buf, tiff.ifds()[1], Orientation::Normal).unwrap();
+        assert_eq!((preview.width, preview.height), (2, 2));
+        assert_eq!(preview.mime, "image/x-portable-pixmap");
+        let mut ppm = b"P6\n2 2\n255\n".to_vec();
+        ppm.extend(1..=12u8);
+        assert_eq!(preview.data, ppm);
+
+        // Strips that don't cover the whole thumbnail aren't one
+        buf.truncate(buf.len() - 2);
+        let tiff = TiffIFD::new_file(&buf).unwrap();
+        assert!(rgb_preview(&buf, tiff.ifds()[1], Orientation::Normal).is_none());
+    }
+
+    #[test]
+    fn sort_drops_all_duplicates() {
+        let previews = sort_previews(vec![
+            preview(10, b"a"),
+            preview(10, b"b"),
+            preview(20, b"c"),
+            preview(10, b"a"),
+        ]);
+        let data: Vec<&[u8]> = previews.iter().map(|p| &p.data[..]).collect();
+        assert_eq!(data, [&b"c"[..], b"a", b"b"]);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::decoders::RawLoaderError;
 use crate::decoders::basics::*;
 
 #[derive(Debug, Copy, Clone)]
@@ -178,7 +179,7 @@ impl<'a> BitPump for BitPumpJPEG<'a> {
     #[inline(always)]
     fn peek_bits(&mut self, num: u32) -> u32 {
         if num > self.nbits && !self.finished {
-            if self.pos < self.buffer.len() - 4
+            if self.pos + 4 < self.buffer.len()
                 && self.buffer[self.pos] != 0xff
                 && self.buffer[self.pos + 1] != 0xff
                 && self.buffer[self.pos + 2] != 0xff
@@ -200,7 +201,7 @@ impl<'a> BitPump for BitPumpJPEG<'a> {
                             let nextbyte = self.buffer[self.pos];
                             if nextbyte != 0xff {
                                 nextbyte
-                            } else if self.buffer[self.pos + 1] == 0x00 {
+                            } else if self.buffer.get(self.pos + 1) == Some(&0x00) {
                                 self.pos += 1; // Skip the extra byte used to mark 255
                                 nextbyte
                             } else {
@@ -255,7 +256,7 @@ impl<'a> ByteStream<'a> {
 
     #[inline(always)]
     pub fn peek_u8(&self) -> u8 {
-        self.buffer[self.pos]
+        self.buffer.get(self.pos).copied().unwrap_or(0)
     }
     #[inline(always)]
     pub fn get_u8(&mut self) -> u8 {
@@ -290,17 +291,20 @@ impl<'a> ByteStream<'a> {
     }
 
     #[inline(always)]
-    pub fn skip_to_marker(&mut self) -> Result<usize, String> {
+    pub fn skip_to_marker(&mut self) -> Result<usize, RawLoaderError> {
         let mut skip_count = 0;
-        while !(self.buffer[self.pos] == 0xFF
-            && self.buffer[self.pos + 1] != 0
-            && self.buffer[self.pos + 1] != 0xFF)
-        {
+        loop {
+            match self.buffer.get(self.pos..self.pos + 2) {
+                Some(&[0xFF, next]) if next != 0 && next != 0xFF => break,
+                Some(_) => {}
+                None => {
+                    return Err(RawLoaderError::truncated(
+                        "No marker found inside rest of buffer",
+                    ));
+                }
+            }
             self.pos += 1;
             skip_count += 1;
-            if self.pos >= self.buffer.len() {
-                return Err("No marker found inside rest of buffer".to_string());
-            }
         }
         self.pos += 1; // Make the next byte the marker
         Ok(skip_count + 1)
@@ -1,4 +1,5 @@
 use crate::decoders::basics::*;
+use crate::decoders::fuji_compressed::*;
 use crate::decoders::tiff::*;
 use crate::decoders::*;
 
@@ -20,8 +21,27 @@ impl<'a> RafDecoder<'a> {
 }
 
 impl<'a> Decoder for RafDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
+        self.frame(0, dummy)
+    }
+
+    fn frame_count(&self) -> usize {
+        match self.rawloader.check_supported(&self.tiff) {
+            Ok(camera) if camera.find_hint("double_width") => 2,
+            _ => 1,
+        }
+    }
+
+    fn frame(&self, index: usize, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
+        let frames = if camera.find_hint("double_width") {
+            2
+        } else {
+            1
+        };
+        if index >= frames {
+            return Err(no_frame(index, frames));
+        }
         let raw = fetch_ifd!(&self.tiff, Tag::RafOffsets);
         let (width, height) = if raw.has_entry(Tag::RafImageWidth) {
             (
@@ -37,38 +57,49 @@ impl<'a> Decoder for RafDecoder<'a> {
             Some(val) => val.get_u32(0) as usize,
             None => 16,
         };
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
 
+        // The rotated images get cropped after the rotation
+        let rotated = camera.find_hint("fuji_rotation") || camera.find_hint("fuji_rotation_alt");
+        let window = if rotated { None } else { Window::current() };
         let image = if camera.find_hint("double_width") {
             // Some fuji SuperCCD cameras include a second raw image next to the first one
             // that is identical but darker to the first. The two combined can produce
-            // a higher dynamic range image. Right now we're ignoring it.
-            decode_16le_skiplines(src, width, height, dummy)
+            // a higher dynamic range image. Each row has the line of the first one followed
+            // by the line of the second one
+            decode_16le_skiplines(
+                from_offset(src, index * width * 2),
+                width,
+                height,
+                window.as_ref(),
+                dummy,
+            )?
         } else if camera.find_hint("jpeg32") {
-            decode_12be_msb32(src, width, height, dummy)
+            let image = decode_12be_msb32(src, width, height, dummy)?;
+            crop_window(image, width, window.as_ref())?
+        } else if src.len() < bps.saturating_mul(width).saturating_mul(height) / 8 {
+            let image = decode_fuji_compressed(src, width, height, &camera.cfa, dummy)?;
+            crop_window(image, width, window.as_ref())?
         } else {
-            if src.len() < bps * width * height / 8 {
-                return Err("RAF: Don't know how to decode compressed yet".to_string());
-            }
             match bps {
-                12 => decode_12le(src, width, height, dummy),
-                14 => decode_14le_unpacked(src, width, height, dummy),
+                12 => decode_12le(src, width, height, window.as_ref(), dummy)?,
+                14 => decode_14le_unpacked(src, width, height, window.as_ref(), dummy)?,
                 16 => {
                     if self.tiff.little_endian() {
-                        decode_16le(src, width, height, dummy)
+                        decode_16le(src, width, height, window.as_ref(), dummy)?
                     } else {
-                        decode_16be(src, width, height, dummy)
+                        decode_16be(src, width, height, window.as_ref(), dummy)?
                     }
                 }
                 _ => {
-                    return Err(format!("RAF: Don't know how to decode bps {}", bps).to_string());
+                    return Err(RawLoaderError::compression("RAF", bps as u32));
                 }
             }
         };
 
-        if camera.find_hint("fuji_rotation") || camera.find_hint("fuji_rotation_alt") {
+        if rotated {
             let (width, height, image) =
-                RafDecoder::rotate_image(&image, &camera, width, height, dummy);
+                RafDecoder::rotate_image(&image, &camera, width, height, dummy)?;
             Ok(RawImage {
                 make: camera.make.clone(),
                 model: camera.model.clone(),
@@ -80,21 +111,42 @@ impl<'a> Decoder for RafDecoder<'a> {
                 wb_coeffs: self.get_wb()?,
                 data: RawImageData::Integer(image),
                 blacklevels: camera.blacklevels,
+                black: None,
                 whitelevels: camera.whitelevels,
                 xyz_to_cam: camera.xyz_to_cam,
+                dng_color: None,
+                profiles: Vec::new(),
                 cfa: camera.cfa.clone(),
                 crops: [0, 0, 0, 0],
                 blackareas: Vec::new(),
                 orientation: camera.orientation,
+                exif: Exif::default(),
+                opcodes: Default::default(),
+                linearization: None,
             })
         } else {
             ok_image(camera, width, height, self.get_wb()?, image)
+                .map(|img| img.windowed(window.as_ref()))
         }
     }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        // The header points to a full size JPEG and its EXIF has the thumbnail
+        let orientation = Orientation::from_tiff(&self.tiff);
+        let offset = BEu32(self.buffer, 84) as usize;
+        let len = BEu32(self.buffer, 88) as usize;
+        let mut previews = preview::tiff_previews(self.buffer, &self.tiff);
+        previews.extend(preview::jpeg_at(self.buffer, offset, len, orientation));
+        Ok(previews)
+    }
 }
 
 impl<'a> RafDecoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         match self.tiff.find_entry(Tag::RafWBGRB) {
             Some(levels) => Ok([
                 levels.get_f32(1),
@@ -120,11 +172,21 @@ impl<'a> RafDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> (usize, usize, Vec<u16>) {
+    ) -> Result<(usize, usize, Vec<u16>), RawLoaderError> {
         let x = camera.crops[3];
         let y = camera.crops[0];
-        let cropwidth = width - camera.crops[1] - x;
-        let cropheight = height - camera.crops[2] - y;
+        let (cropwidth, cropheight) = match (
+            width.checked_sub(camera.crops[1] + x),
+            height.checked_sub(camera.crops[2] + y),
+        ) {
+            (Some(w), Some(h)) if w > 0 && h > 0 => (w, h),
+            _ => {
+                return Err(RawLoaderError::corrupt(format!(
+                    "RAF: image of {}x{} is smaller than the crops",
+                    width, height
+                )));
+            }
+        };
 
         if camera.find_hint("fuji_rotation_alt") {
             let rotatedwidth = cropheight + cropwidth / 2;
@@ -136,14 +198,20 @@ impl<'a> RafDecoder<'a> {
                     let inb = &src[(row + y) * width + x..];
 
                     for (col, _) in inb.iter().enumerate().take(cropwidth) {
-                        let out_row = rotatedwidth - (cropheight + 1 - row + (col >> 1));
+                        let Some(out_row) =
+                            rotatedwidth.checked_sub(cropheight + 1 - row + (col >> 1))
+                        else {
+                            continue;
+                        };
                         let out_col = ((col + 1) >> 1) + row;
-                        out[out_row * rotatedwidth + out_col] = inb[col];
+                        if let Some(pixel) = out.get_mut(out_row * rotatedwidth + out_col) {
+                            *pixel = inb[col];
+                        }
                     }
                 }
             }
 
-            (rotatedwidth, rotatedheight, out)
+            Ok((rotatedwidth, rotatedheight, out))
         } else {
             let rotatedwidth = cropwidth + cropheight / 2;
             let rotatedheight = rotatedwidth - 1;
@@ -156,12 +224,14 @@ impl<'a> RafDecoder<'a> {
                     for (col, _) in inb.iter().enumerate().take(cropwidth) {
                         let out_row = cropwidth - 1 - col + (row >> 1);
                         let out_col = ((row + 1) >> 1) + col;
-                        out[out_row * rotatedwidth + out_col] = inb[col];
+                        if let Some(pixel) = out.get_mut(out_row * rotatedwidth + out_col) {
+                            *pixel = inb[col];
+                        }
                     }
                 }
             }
 
-            (rotatedwidth, rotatedheight, out)
+            Ok((rotatedwidth, rotatedheight, out))
         }
     }
 }
@@ -20,7 +20,9 @@ impl<'a> Rw2Decoder<'a> {
 }
 
 impl<'a> Decoder for Rw2Decoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
+        let window = Window::current();
+        let window = window.as_ref();
         let width: usize;
         let height: usize;
         let image = {
@@ -30,27 +32,39 @@ impl<'a> Decoder for Rw2Decoder<'a> {
                 width = fetch_tag!(raw, Tag::PanaWidth).get_usize(0);
                 height = fetch_tag!(raw, Tag::PanaLength).get_usize(0);
                 let offset = fetch_tag!(raw, Tag::PanaOffsets).get_usize(0);
-                let src = &self.buffer[offset..];
-                Rw2Decoder::decode_panasonic(src, width, height, true, dummy)
+                let src = from_offset(self.buffer, offset);
+                let format = raw
+                    .find_entry(Tag::PanaRawFormat)
+                    .map_or(4, |e| e.get_u32(0));
+                let bps = raw
+                    .find_entry(Tag::PanaBitsPerSample)
+                    .map_or(12, |e| e.get_usize(0));
+                match format {
+                    5 => Rw2Decoder::decode_panasonic_v5(src, width, height, bps, window, dummy)?,
+                    6 => Rw2Decoder::decode_panasonic_v6(src, width, height, bps, window, dummy)?,
+                    7 => Rw2Decoder::decode_panasonic_v7(src, width, height, bps, window, dummy)?,
+                    _ => Rw2Decoder::decode_panasonic(src, width, height, true, window, dummy)?,
+                }
             } else {
                 let raw = fetch_ifd!(&self.tiff, Tag::StripOffsets);
                 width = fetch_tag!(raw, Tag::PanaWidth).get_usize(0);
                 height = fetch_tag!(raw, Tag::PanaLength).get_usize(0);
                 let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
-                let src = &self.buffer[offset..];
+                let src = from_offset(self.buffer, offset);
 
-                if src.len() >= width * height * 2 {
-                    decode_12le_unpacked_left_aligned(src, width, height, dummy)
-                } else if src.len() >= width * height * 3 / 2 {
-                    decode_12le_wcontrol(src, width, height, dummy)
+                let pixels = width.saturating_mul(height);
+                if src.len() >= pixels.saturating_mul(2) {
+                    decode_12le_unpacked_left_aligned(src, width, height, window, dummy)?
+                } else if src.len() >= pixels.saturating_mul(3) / 2 {
+                    decode_12le_wcontrol(src, width, height, window, dummy)?
                 } else {
-                    Rw2Decoder::decode_panasonic(src, width, height, false, dummy)
+                    Rw2Decoder::decode_panasonic(src, width, height, false, window, dummy)?
                 }
             }
         };
 
         let mode = {
-            let ratio = width * 100 / height;
+            let ratio = width * 100 / height.max(1);
             if ratio < 125 {
                 "1:1"
             } else if ratio < 145 {
@@ -63,12 +77,35 @@ impl<'a> Decoder for Rw2Decoder<'a> {
         };
         let camera = self.rawloader.check_supported_with_mode(&self.tiff, mode)?;
 
-        ok_image(camera, width, height, self.get_wb()?, image)
+        ok_image(camera, width, height, self.get_wb()?, image).map(|img| img.windowed(window))
+    }
+
+    fn exif(&self) -> Exif {
+        // Panasonic keeps the EXIF in the embedded JPEG and only the ISO in the raw IFD
+        let mut exif = match self.tiff.find_entry(Tag::PanaJpgFromRaw) {
+            Some(jpeg) if jpeg.get_data().get(6..10) == Some(&b"Exif"[..]) => {
+                match TiffIFD::new_root(jpeg.get_data(), 12) {
+                    Ok(tiff) => Exif::from_tiff(jpeg.get_data(), &tiff),
+                    Err(_) => Exif::from_tiff(self.buffer, &self.tiff),
+                }
+            }
+            _ => Exif::from_tiff(self.buffer, &self.tiff),
+        };
+        if exif.iso.is_none()
+            && let Some(iso) = self.tiff.find_entry(Tag::PanaISO)
+        {
+            exif.iso = Some(iso.get_u32(0));
+        }
+        exif
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
 
 impl<'a> Rw2Decoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         if self.tiff.has_entry(Tag::PanaWBsR) && self.tiff.has_entry(Tag::PanaWBsB) {
             let r = fetch_tag!(self.tiff, Tag::PanaWBsR).get_u32(0) as f32;
             let b = fetch_tag!(self.tiff, Tag::PanaWBsB).get_u32(0) as f32;
@@ -82,7 +119,7 @@ impl<'a> Rw2Decoder<'a> {
             let b = fetch_tag!(self.tiff, Tag::PanaWBs2B).get_u32(0) as f32;
             Ok([r, g, b, f32::NAN])
         } else {
-            Err("Couldn't find WB".to_string())
+            Err(RawLoaderError::corrupt("Couldn't find WB"))
         }
     }
 
@@ -91,17 +128,19 @@ impl<'a> Rw2Decoder<'a> {
         width: usize,
         height: usize,
         split: bool,
+        window: Option<&Window>,
         dummy: bool,
-    ) -> Vec<u16> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         decode_threaded_multiline(
             width,
             height,
             5,
+            window,
             dummy,
             &(|out: &mut [u16], row| {
                 let skip = ((width * row * 9) + (width / 14 * 2 * row)) / 8;
                 let blocks = skip / 0x4000;
-                let src = &buf[blocks * 0x4000..];
+                let src = from_offset(buf, blocks * 0x4000);
                 let mut pump = BitPumpPanasonic::new(src, split);
                 for _ in 0..(skip % 0x4000) {
                     pump.get_bits(8);
@@ -139,6 +178,169 @@ impl<'a> Rw2Decoder<'a> {
     }
 }
 
+impl<'a> Rw2Decoder<'a> {
+    // The v5 format packs plain 12 or 14 bit values LSB first into 16 byte packets,
+    // padding out the end of each packet. The data is split into 0x4000 byte blocks
+    // that have their two halves swapped like in the classic format.
+    pub(crate) fn decode_panasonic_v5(
+        buf: &[u8],
+        width: usize,
+        height: usize,
+        bps: usize,
+        window: Option<&Window>,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        Rw2Decoder::decode_packets(buf, width, height, bps, true, window, dummy)
+    }
+
+    // The v7 format is the same packing as v5 but without the block swapping
+    pub(crate) fn decode_panasonic_v7(
+        buf: &[u8],
+        width: usize,
+        height: usize,
+        bps: usize,
+        window: Option<&Window>,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        Rw2Decoder::decode_packets(buf, width, height, bps, false, window, dummy)
+    }
+
+    fn decode_packets(
+        buf: &[u8],
+        width: usize,
+        height: usize,
+        bps: usize,
+        swapped: bool,
+        window: Option<&Window>,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        if bps != 12 && bps != 14 {
+            return Err(RawLoaderError::compression("RW2", bps as u32));
+        }
+        let per_packet = 128 / bps;
+        if !width.is_multiple_of(per_packet) {
+            return Err(RawLoaderError::corrupt(format!(
+                "RW2: width {} is not a multiple of {} pixels",
+                width, per_packet
+            )));
+        }
+        let row_bytes = width / per_packet * 16;
+        let mask = (1u128 << bps) - 1;
+
+        decode_threaded(
+            width,
+            height,
+            window,
+            dummy,
+            &(|out: &mut [u16], row| {
+                for (i, pixels) in out.chunks_exact_mut(per_packet).enumerate() {
+                    let start = row * row_bytes + i * 16;
+                    let mut bytes = [0u8; 16];
+                    for (j, byte) in bytes.iter_mut().enumerate() {
+                        let pos = if swapped {
+                            let pos = start + j;
+                            pos - pos % 0x4000 + (pos % 0x4000 + 0x1ff8) % 0x4000
+                        } else {
+                            start + j
+                        };
+                        *byte = buf.get(pos).copied().unwrap_or(0);
+                    }
+                    let packet = u128::from_le_bytes(bytes);
+                    for (j, pixel) in pixels.iter_mut().enumerate() {
+                        *pixel = ((packet >> (j * bps)) & mask) as u16;
+                    }
+                }
+            }),
+        )
+    }
+
+    // The v6 format stores 11 (14 bit) or 14 (12 bit) pixels per 16 byte block read
+    // MSB first. Each colour gets a full value at the start of the block and after
+    // that every three pixels share a scale for their shorter values.
+    pub(crate) fn decode_panasonic_v6(
+        buf: &[u8],
+        width: usize,
+        height: usize,
+        bps: usize,
+        window: Option<&Window>,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        let (per_block, short_bits, base_zero, base_limit) = match bps {
+            14 => (11, 10, 0x200u32, 0x2000u32),
+            12 => (14, 8, 0x80u32, 0x800u32),
+            _ => {
+                return Err(RawLoaderError::compression("RW2", bps as u32));
+            }
+        };
+        if !width.is_multiple_of(per_block) {
+            return Err(RawLoaderError::corrupt(format!(
+                "RW2: width {} is not a multiple of {} pixels",
+                width, per_block
+            )));
+        }
+        let row_bytes = width / per_block * 16;
+        if buf.len() < row_bytes.saturating_mul(height) {
+            return Err(RawLoaderError::truncated("RW2: image data is too short"));
+        }
+
+        decode_threaded(
+            width,
+            height,
+            window,
+            dummy,
+            &(|out: &mut [u16], row| {
+                for (i, pixels) in out.chunks_exact_mut(per_block).enumerate() {
+                    let start = row * row_bytes + i * 16;
+                    let mut bytes = [0u8; 16];
+                    bytes.copy_from_slice(&buf[start..start + 16]);
+                    let block = u128::from_le_bytes(bytes);
+                    let mut left = 128;
+                    let mut next = |bits: u32| {
+                        left -= bits;
+                        ((block >> left) as u32) & ((1 << bits) - 1)
+                    };
+
+                    let mut first = [0u32; 2];
+                    let mut last = [0u32; 2];
+                    let mut base = 0;
+                    let mut mul = 0;
+                    for (pix, out) in pixels.iter_mut().enumerate() {
+                        let bits = if pix < 2 { bps as u32 } else { short_bits };
+                        if pix % 3 == 2 {
+                            let scale = match next(2) {
+                                3 => 4,
+                                x => x,
+                            };
+                            base = base_zero << scale;
+                            mul = 1 << scale;
+                        }
+                        let mut value = next(bits);
+                        if first[pix % 2] != 0 {
+                            value *= mul;
+                            if base < base_limit && last[pix % 2] > base {
+                                value += last[pix % 2] - base;
+                            }
+                            last[pix % 2] = value;
+                        } else {
+                            first[pix % 2] = value;
+                            if value != 0 {
+                                last[pix % 2] = value;
+                            } else {
+                                value = last[pix % 2];
+                            }
+                        }
+                        *out = if value >= 0xf {
+                            (value - 0xf) as u16
+                        } else {
+                            0
+                        };
+                    }
+                }
+            }),
+        )
+    }
+}
+
 pub struct BitPumpPanasonic<'a> {
     buffer: &'a [u8],
     pos: usize,
@@ -175,3 +377,94 @@ impl<'a> BitPump for BitPumpPanasonic<'a> {
         self.nbits -= num;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 values of 12 bits packed LSB first and then 4 bits of padding
+    const PACKET_12: [u8; 16] = [
+        0x23, 0x61, 0x45, 0x89, 0xc7, 0xab, 0xef, 0x1d, 0x00, 0x10, 0x00, 0x10, 0xff, 0x0f, 0x80,
+        0xff,
+    ];
+    const VALUES_12: [u16; 10] = [
+        0x123, 0x456, 0x789, 0xabc, 0xdef, 0x001, 0x010, 0x100, 0xfff, 0x800,
+    ];
+    // 9 values of 14 bits packed LSB first and then 2 bits of padding
+    const PACKET_14: [u8; 16] = [
+        0x34, 0x12, 0xaf, 0xfa, 0xff, 0x03, 0x00, 0x01, 0x00, 0x00, 0xf8, 0xf0, 0xcc, 0xcc, 0x55,
+        0xd5,
+    ];
+    const VALUES_14: [u16; 9] = [0x1234, 0x2abc, 0x3fff, 0, 1, 0x2000, 0x0f0f, 0x3333, 0x1555];
+
+    #[test]
+    fn v7_packets() {
+        let buf = [PACKET_12, PACKET_12].concat();
+        let out = Rw2Decoder::decode_panasonic_v7(&buf, 10, 2, 12, None, false).unwrap();
+        assert_eq!(out, [VALUES_12, VALUES_12].concat());
+
+        let buf = [PACKET_14, PACKET_14].concat();
+        let out = Rw2Decoder::decode_panasonic_v7(&buf, 18, 1, 14, None, false).unwrap();
+        assert_eq!(out, [VALUES_14, VALUES_14].concat());
+    }
+
+    #[test]
+    fn v5_packets_in_swapped_blocks() {
+        // The first half of each 0x4000 byte block is stored 0x1ff8 bytes into it and the
+        // rest wraps around to the start, so row 0 sits at 0x1ff8 and row 512 is split
+        // between the end of the block and its start
+        let mut buf = vec![0u8; 0x4000];
+        buf[0x1ff8..0x2008].copy_from_slice(&PACKET_12);
+        buf[0x3ff8..].copy_from_slice(&PACKET_12[..8]);
+        buf[..8].copy_from_slice(&PACKET_12[8..]);
+        let out = Rw2Decoder::decode_panasonic_v5(&buf, 10, 513, 12, None, false).unwrap();
+        assert_eq!(out[..10], VALUES_12);
+        assert_eq!(out[512 * 10..], VALUES_12);
+        assert!(out[10..512 * 10].iter().all(|v| *v == 0));
+
+        let mut buf = vec![0u8; 0x4000];
+        buf[0x1ff8..0x2008].copy_from_slice(&PACKET_14);
+        let out = Rw2Decoder::decode_panasonic_v5(&buf, 9, 1, 14, None, false).unwrap();
+        assert_eq!(out, VALUES_14);
+    }
+
+    #[test]
+    fn v5_needs_whole_packets() {
+        assert!(Rw2Decoder::decode_panasonic_v5(&PACKET_12, 12, 1, 12, None, false).is_err());
+        assert!(Rw2Decoder::decode_panasonic_v5(&PACKET_12, 10, 1, 10, None, false).is_err());
+    }
+
+    #[test]
+    fn v6_14bit_blocks() {
+        // Two full values and then three groups of a scale and three 10 bit values, the
+        // first group adding to the previous value of each color, the second scaled by 16
+        // and the third by 2
+        let block = [
+            0x00, 0xe0, 0x00, 0x50, 0x04, 0x00, 0x08, 0x10, 0xfc, 0x3f, 0x20, 0x00, 0x01, 0x80,
+            0x00, 0x40,
+        ];
+        let out = Rw2Decoder::decode_panasonic_v6(&block, 11, 1, 14, None, false).unwrap();
+        assert_eq!(
+            out,
+            vec![4081, 2033, 3825, 1649, 4336, 241, 497, 0, 145, 0, 1009]
+        );
+    }
+
+    #[test]
+    fn v6_12bit_blocks() {
+        // A zero first value of a color makes the next value of that color the first one
+        let block = [
+            0x00, 0x05, 0xaa, 0x01, 0x09, 0x04, 0x0c, 0x08, 0x71, 0xe0, 0x3f, 0x0c, 0x08, 0x00,
+            0x00, 0x40,
+        ];
+        let buf = [block, block].concat();
+        let out = Rw2Decoder::decode_panasonic_v6(&buf, 14, 2, 12, None, false).unwrap();
+        let row = [
+            1009, 0, 913, 33, 1040, 13, 592, 497, 1, 17, 1009, 325, 763, 69,
+        ];
+        assert_eq!(out, [row, row].concat());
+
+        // Every row has to be there
+        assert!(Rw2Decoder::decode_panasonic_v6(&block, 14, 2, 12, None, false).is_err());
+    }
+}
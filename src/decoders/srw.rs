@@ -22,7 +22,7 @@ impl<'a> SrwDecoder<'a> {
 }
 
 impl<'a> Decoder for SrwDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
         let raw = fetch_ifd!(&self.tiff, Tag::StripOffsets);
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
@@ -30,38 +30,55 @@ impl<'a> Decoder for SrwDecoder<'a> {
         let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
         let compression = fetch_tag!(raw, Tag::Compression).get_u32(0);
         let bits = fetch_tag!(raw, Tag::BitsPerSample).get_u32(0);
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
 
+        let window = Window::current();
         let image = match compression {
             32769 => match bits {
-                12 => decode_12le_unpacked(src, width, height, dummy),
-                14 => decode_14le_unpacked(src, width, height, dummy),
-                x => return Err(format!("SRW: Don't know how to handle bps {}", x).to_string()),
+                12 => decode_12le_unpacked(src, width, height, window.as_ref(), dummy)?,
+                14 => decode_14le_unpacked(src, width, height, window.as_ref(), dummy)?,
+                x => return Err(RawLoaderError::compression("SRW", x)),
             },
             32770 => match raw.find_entry(Tag::SrwSensorAreas) {
                 None => match bits {
                     12 => {
                         if camera.find_hint("little_endian") {
-                            decode_12le(src, width, height, dummy)
+                            decode_12le(src, width, height, window.as_ref(), dummy)?
                         } else {
-                            decode_12be(src, width, height, dummy)
+                            decode_12be(src, width, height, window.as_ref(), dummy)?
                         }
                     }
-                    14 => decode_14le_unpacked(src, width, height, dummy),
-                    x => return Err(format!("SRW: Don't know how to handle bps {}", x).to_string()),
+                    14 => decode_14le_unpacked(src, width, height, window.as_ref(), dummy)?,
+                    x => return Err(RawLoaderError::compression("SRW", x)),
                 },
                 Some(x) => {
                     let coffset = x.get_usize(0);
-                    let loffsets = &self.buffer[coffset..];
-                    SrwDecoder::decode_srw1(src, loffsets, width, height, dummy)
+                    let loffsets = from_offset(self.buffer, coffset);
+                    let image = SrwDecoder::decode_srw1(src, loffsets, width, height, dummy)?;
+                    crop_window(image, width, window.as_ref())?
                 }
             },
-            32772 => SrwDecoder::decode_srw2(src, width, height, dummy),
-            32773 => SrwDecoder::decode_srw3(src, width, height, dummy),
-            x => return Err(format!("SRW: Don't know how to handle compression {}", x).to_string()),
+            32772 => {
+                let image = SrwDecoder::decode_srw2(src, width, height, dummy)?;
+                crop_window(image, width, window.as_ref())?
+            }
+            32773 => {
+                let image = SrwDecoder::decode_srw3(src, width, height, dummy)?;
+                crop_window(image, width, window.as_ref())?
+            }
+            x => return Err(RawLoaderError::compression("SRW", x)),
         };
 
         ok_image(camera, width, height, self.get_wb()?, image)
+            .map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
 
@@ -72,13 +89,13 @@ impl<'a> SrwDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Vec<u16> {
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let mut out: Vec<u16> = alloc_image!(width, height, dummy);
 
         for row in 0..height {
             let mut len: [u32; 4] = [if row < 2 { 7 } else { 4 }; 4];
             let loffset = LEu32(loffsets, row * 4) as usize;
-            let mut pump = BitPumpMSB32::new(&buf[loffset..]);
+            let mut pump = BitPumpMSB32::new(from_offset(buf, loffset));
 
             let img = width * row;
             let img_up = width * (cmp::max(1, row) - 1);
@@ -100,28 +117,31 @@ impl<'a> SrwDecoder<'a> {
                             len[i] = pump.get_bits(4);
                         }
                         2 => {
-                            len[i] -= 1;
+                            len[i] = len[i].wrapping_sub(1);
                         }
                         1 => {
                             len[i] += 1;
                         }
                         _ => {}
                     }
+                    if len[i] > 16 {
+                        return Err(RawLoaderError::corrupt("SRW: Too many difference bits"));
+                    }
                 }
 
                 // First decode even pixels
                 for c in (0..16).step_by(2) {
                     let l = len[c >> 3];
                     let adj = pump.get_ibits_sextended(l);
-                    let predictor = if dir {
-                        // Upward prediction
-                        out[img_up + col + c]
-                    } else {
-                        // Left to right prediction
-                        if col == 0 { 128 } else { out[img + col - 2] }
-                    };
                     if col + c < width {
                         // No point in decoding pixels outside the image
+                        let predictor = if dir {
+                            // Upward prediction
+                            out[img_up + col + c]
+                        } else {
+                            // Left to right prediction
+                            if col == 0 { 128 } else { out[img + col - 2] }
+                        };
                         out[img + col + c] = ((predictor as i32) + adj) as u16;
                     }
                 }
@@ -129,15 +149,15 @@ impl<'a> SrwDecoder<'a> {
                 for c in (1..16).step_by(2) {
                     let l = len[2 | (c >> 3)];
                     let adj = pump.get_ibits_sextended(l);
-                    let predictor = if dir {
-                        // Upward prediction
-                        out[img_up2 + col + c]
-                    } else {
-                        // Left to right prediction
-                        if col == 0 { 128 } else { out[img + col - 1] }
-                    };
                     if col + c < width {
                         // No point in decoding pixels outside the image
+                        let predictor = if dir {
+                            // Upward prediction
+                            out[img_up2 + col + c]
+                        } else {
+                            // Left to right prediction
+                            if col == 0 { 128 } else { out[img + col - 1] }
+                        };
                         out[img + col + c] = ((predictor as i32) + adj) as u16;
                     }
                 }
@@ -147,16 +167,21 @@ impl<'a> SrwDecoder<'a> {
         // SRW1 apparently has red and blue swapped, just changing the CFA pattern to
         // match causes color fringing in high contrast areas because the actual pixel
         // locations would not match the CFA pattern
-        for row in (0..height).step_by(2) {
-            for col in (0..width).step_by(2) {
+        for row in (0..height.saturating_sub(1)).step_by(2) {
+            for col in (0..width.saturating_sub(1)).step_by(2) {
                 out.swap(row * width + col + 1, (row + 1) * width + col);
             }
         }
 
-        out
+        Ok(out)
     }
 
-    pub fn decode_srw2(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+    pub fn decode_srw2(
+        buf: &[u8],
+        width: usize,
+        height: usize,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
         let mut out: Vec<u16> = alloc_image!(width, height, dummy);
 
         // This format has a variable length encoding of how many bits are needed
@@ -209,16 +234,16 @@ impl<'a> SrwDecoder<'a> {
             for col in 0..width {
                 let diff = SrwDecoder::srw2_diff(&mut pump, &tbl);
                 if col < 2 {
-                    vpred[row & 1][col] += diff;
+                    vpred[row & 1][col] = vpred[row & 1][col].wrapping_add(diff);
                     hpred[col] = vpred[row & 1][col];
                 } else {
-                    hpred[col & 1] += diff;
+                    hpred[col & 1] = hpred[col & 1].wrapping_add(diff);
                 }
                 out[row * width + col] = hpred[col & 1] as u16;
             }
         }
 
-        out
+        Ok(out)
     }
 
     pub fn srw2_diff(pump: &mut BitPumpMSB, tbl: &[[u32; 2]; 1024]) -> i32 {
@@ -236,7 +261,12 @@ impl<'a> SrwDecoder<'a> {
         diff
     }
 
-    pub fn decode_srw3(buf: &[u8], width: usize, height: usize, dummy: bool) -> Vec<u16> {
+    pub fn decode_srw3(
+        buf: &[u8],
+        width: usize,
+        height: usize,
+        dummy: bool,
+    ) -> Result<Vec<u16>, RawLoaderError> {
         // Decoder for third generation compressed SRW files (NX1)
         // Seriously Samsung just use lossless jpeg already, it compresses better too :)
 
@@ -244,6 +274,12 @@ impl<'a> SrwDecoder<'a> {
         // and Loring von Palleske (Samsung) for pointing to the open-source code of
         // Samsung's DNG converter at http://opensource.samsung.com/
 
+        if !width.is_multiple_of(16) {
+            return Err(RawLoaderError::corrupt(format!(
+                "SRW: width {} is not a multiple of 16",
+                width
+            )));
+        }
         let mut out: Vec<u16> = alloc_image!(width, height, dummy);
         let mut pump = BitPumpMSB32::new(buf);
 
@@ -285,7 +321,7 @@ impl<'a> SrwDecoder<'a> {
             if (line_offset & 0x0f) != 0 {
                 line_offset += 16 - (line_offset & 0xf);
             }
-            pump = BitPumpMSB32::new(&buf[line_offset..]);
+            pump = BitPumpMSB32::new(from_offset(buf, line_offset));
 
             let img = width * row;
             let img_up = width * (cmp::max(1, row) - 1);
@@ -326,7 +362,9 @@ impl<'a> SrwDecoder<'a> {
                 }
 
                 if row < 2 && motion != 7 {
-                    panic!("SRW Decoder: At start of image and motion isn't 7. File corrupted?")
+                    return Err(RawLoaderError::corrupt(
+                        "SRW: At start of image and motion isn't 7",
+                    ));
                 }
 
                 if motion == 7 {
@@ -341,32 +379,29 @@ impl<'a> SrwDecoder<'a> {
                     }
                 } else {
                     // The complex case, we now need to actually lookup one or two lines above
-                    if row < 2 {
-                        panic!(
-                            "SRW: Got a previous line lookup on first two lines. File corrupted?"
-                        );
-                    }
                     let motion_offset: [isize; 7] = [-4, -2, -2, 0, 0, 2, 4];
                     let motion_average: [i32; 7] = [0, 0, 1, 0, 1, 0, 0];
                     let slide_offset = motion_offset[motion];
 
                     for i in 0..16 {
-                        let refpixel: usize = if ((row + i) & 0x1) != 0 {
+                        let refpixel: isize = if ((row + i) & 0x1) != 0 {
                             // Red or blue pixels use same color two lines up
-                            ((img_up2 + col + i) as isize + slide_offset) as usize
+                            (img_up2 + col + i) as isize + slide_offset
                         } else {
                             // Green pixel N uses Green pixel N from row above (top left or top right)
                             if (i % 2) != 0 {
-                                ((img_up + col + i - 1) as isize + slide_offset) as usize
+                                (img_up + col + i - 1) as isize + slide_offset
                             } else {
-                                ((img_up + col + i + 1) as isize + slide_offset) as usize
+                                (img_up + col + i + 1) as isize + slide_offset
                             }
                         };
                         // In some cases we use as reference interpolation of this pixel and the next
                         out[img + col + i] = if motion_average[motion] != 0 {
-                            (out[refpixel] + out[refpixel + 2] + 1) >> 1
+                            let sum = Self::srw3_ref(&out, refpixel) as u32
+                                + Self::srw3_ref(&out, refpixel + 2) as u32;
+                            ((sum + 1) >> 1) as u16
                         } else {
-                            out[refpixel]
+                            Self::srw3_ref(&out, refpixel)
                         }
                     }
                 }
@@ -395,7 +430,7 @@ impl<'a> SrwDecoder<'a> {
                                 diff_bits[i] = diff_bits_mode[colornum][0] + 1;
                             }
                             2 => {
-                                diff_bits[i] = diff_bits_mode[colornum][0] - 1;
+                                diff_bits[i] = diff_bits_mode[colornum][0].wrapping_sub(1);
                             }
                             3 => {
                                 diff_bits[i] = pump.get_bits(4);
@@ -405,7 +440,7 @@ impl<'a> SrwDecoder<'a> {
                         diff_bits_mode[colornum][0] = diff_bits_mode[colornum][1];
                         diff_bits_mode[colornum][1] = diff_bits[i];
                         if diff_bits[i] > bit_depth + 1 {
-                            panic!("SRW Decoder: Too many difference bits. File corrupted?");
+                            return Err(RawLoaderError::corrupt("SRW: Too many difference bits"));
                         }
                     }
                 }
@@ -428,14 +463,25 @@ impl<'a> SrwDecoder<'a> {
             }
         }
 
-        out
+        Ok(out)
+    }
+
+    // Reference pixels from a corrupted motion vector can point outside the image
+    fn srw3_ref(out: &[u16], pos: isize) -> u16 {
+        usize::try_from(pos)
+            .ok()
+            .and_then(|pos| out.get(pos))
+            .copied()
+            .unwrap_or(0)
     }
 
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         let rggb_levels = fetch_tag!(self.tiff, Tag::SrwRGGBLevels);
         let rggb_blacks = fetch_tag!(self.tiff, Tag::SrwRGGBBlacks);
         if rggb_levels.count() != 4 || rggb_blacks.count() != 4 {
-            Err("SRW: RGGB Levels and Blacks don't have 4 elements".to_string())
+            Err(RawLoaderError::corrupt(
+                "SRW: RGGB Levels and Blacks don't have 4 elements",
+            ))
         } else {
             let nlevels = &rggb_levels.copy_offset_from_parent(self.buffer);
             let nblacks = &rggb_blacks.copy_offset_from_parent(self.buffer);
@@ -21,26 +21,37 @@ impl<'a> TfrDecoder<'a> {
 }
 
 impl<'a> Decoder for TfrDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let camera = self.rawloader.check_supported(&self.tiff)?;
         let raw = fetch_ifd!(&self.tiff, Tag::WhiteLevel);
         let width = fetch_tag!(raw, Tag::ImageWidth).get_usize(0);
         let height = fetch_tag!(raw, Tag::ImageLength).get_usize(0);
         let offset = fetch_tag!(raw, Tag::StripOffsets).get_usize(0);
-        let src = &self.buffer[offset..];
+        let src = from_offset(self.buffer, offset);
 
+        let window = Window::current();
         let image = if camera.find_hint("uncompressed") {
-            decode_16le(src, width, height, dummy)
+            decode_16le(src, width, height, window.as_ref(), dummy)?
         } else {
-            self.decode_compressed(src, width, height, dummy)?
+            let image = self.decode_compressed(src, width, height, dummy)?;
+            crop_window(image, width, window.as_ref())?
         };
 
         ok_image(camera, width, height, self.get_wb()?, image)
+            .map(|img| img.windowed(window.as_ref()))
+    }
+
+    fn exif(&self) -> Exif {
+        Exif::from_tiff(self.buffer, &self.tiff)
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        Ok(preview::tiff_previews(self.buffer, &self.tiff))
     }
 }
 
 impl<'a> TfrDecoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         let levels = fetch_tag!(self.tiff, Tag::AsShotNeutral);
         Ok([
             1.0 / levels.get_f32(0),
@@ -56,8 +67,8 @@ impl<'a> TfrDecoder<'a> {
         width: usize,
         height: usize,
         dummy: bool,
-    ) -> Result<Vec<u16>, String> {
-        let mut out = alloc_image_ok!(width, height, dummy);
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        let mut out = alloc_image!(width, height, dummy);
         let decompressor = LjpegDecompressor::new_full(src, true, false)?;
         decompressor.decode(&mut out, 0, width, width, height, dummy)?;
         Ok(out)
@@ -1,6 +1,8 @@
+use std::cmp;
 use std::collections::HashMap;
 use std::str;
 
+use crate::decoders::RawLoaderError;
 use crate::decoders::basics::*;
 
 #[derive(Debug, Copy, Clone, PartialEq, enumn::N)]
@@ -8,15 +10,19 @@ use crate::decoders::basics::*;
 pub enum Tag {
     PanaWidth = 0x0002,
     PanaLength = 0x0003,
+    PanaBitsPerSample = 0x000A,
     NefWB0 = 0x000C,
     PanaWBsR = 0x0011,
     PanaWBsB = 0x0012,
     NrwWB = 0x0014,
+    PanaISO = 0x0017,
     NefSerial = 0x001d,
     PanaWBs2R = 0x0024,
     PanaWBs2G = 0x0025,
     PanaWBs2B = 0x0026,
     Cr2PowerShotWB = 0x0029,
+    PanaRawFormat = 0x002D,
+    PanaJpgFromRaw = 0x002E,
     NewSubFileType = 0x00FE,
     Cr2OldOffset = 0x0081,
     NefMeta1 = 0x008c,
@@ -34,16 +40,23 @@ pub enum Tag {
     StripOffsets = 0x0111,
     Orientation = 0x0112,
     SamplesPerPixel = 0x0115,
+    RowsPerStrip = 0x0116,
     StripByteCounts = 0x0117,
     PanaOffsets = 0x0118,
     GrayResponse = 0x0123,
     Software = 0x0131,
+    DateTime = 0x0132,
+    Artist = 0x013B,
+    Predictor = 0x013D,
     TileWidth = 0x0142,
     TileLength = 0x0143,
     TileOffsets = 0x0144,
+    TileByteCounts = 0x0145,
     SubIFDs = 0x014A,
+    SampleFormat = 0x0153,
     PefBlackLevels = 0x0200,
     PefWB = 0x0201,
+    JPEGInterchangeFormatLength = 0x0202,
     PefHuffman = 0x0220,
     Xmp = 0x02BC,
     DcrWB = 0x03FD,
@@ -64,24 +77,68 @@ pub enum Tag {
     SonyRGGB = 0x7313,
     CFAPattern = 0x828E,
     KodakIFD = 0x8290,
+    Copyright = 0x8298,
+    ExposureTime = 0x829A,
+    FNumber = 0x829D,
     LeafMetadata = 0x8606,
     ExifIFDPointer = 0x8769,
+    GPSInfo = 0x8825,
+    ISOSpeedRatings = 0x8827,
+    RecommendedExposureIndex = 0x8832,
+    DateTimeOriginal = 0x9003,
+    OffsetTimeOriginal = 0x9011,
+    FocalLength = 0x920A,
     Makernote = 0x927C,
+    SubSecTimeOriginal = 0x9291,
     SrwSensorAreas = 0xA010,
     SrwRGGBLevels = 0xA021,
     SrwRGGBBlacks = 0xA028,
+    BodySerialNumber = 0xA431,
+    LensMake = 0xA433,
+    LensModel = 0xA434,
     Cr2Id = 0xc5d8,
     DNGVersion = 0xC612,
+    UniqueCameraModel = 0xC614,
     Linearization = 0xC618,
+    BlackLevelRepeatDim = 0xC619,
     BlackLevels = 0xC61A,
+    BlackLevelDeltaH = 0xC61B,
+    BlackLevelDeltaV = 0xC61C,
     WhiteLevel = 0xC61D,
     ColorMatrix1 = 0xC621,
     ColorMatrix2 = 0xC622,
+    CameraCalibration1 = 0xC623,
+    CameraCalibration2 = 0xC624,
+    AnalogBalance = 0xC627,
     AsShotNeutral = 0xC628,
+    AsShotWhiteXY = 0xC629,
+    CameraSerialNumber = 0xC62F,
     DNGPrivateArea = 0xC634,
     Cr2StripeWidths = 0xC640,
+    CalibrationIlluminant1 = 0xC65A,
+    CalibrationIlluminant2 = 0xC65B,
     ActiveArea = 0xC68D,
     MaskedAreas = 0xC68E,
+    ProfileCalibrationSignature = 0xC6F4,
+    ExtraCameraProfiles = 0xC6F5,
+    ProfileName = 0xC6F8,
+    ProfileHueSatMapDims = 0xC6F9,
+    ProfileHueSatMapData1 = 0xC6FA,
+    ProfileHueSatMapData2 = 0xC6FB,
+    ProfileToneCurve = 0xC6FC,
+    ProfileEmbedPolicy = 0xC6FD,
+    ProfileCopyright = 0xC6FE,
+    ForwardMatrix1 = 0xC714,
+    ForwardMatrix2 = 0xC715,
+    ProfileLookTableDims = 0xC725,
+    ProfileLookTableData = 0xC726,
+    OpcodeList1 = 0xC740,
+    OpcodeList2 = 0xC741,
+    OpcodeList3 = 0xC74E,
+    ProfileHueSatMapEncoding = 0xC7A3,
+    ProfileLookTableEncoding = 0xC7A4,
+    BaselineExposureOffset = 0xC7A5,
+    DefaultBlackRender = 0xC7A6,
     RafRawSubIFD = 0xF000,
     RafImageWidth = 0xF001,
     RafImageLength = 0xF002,
@@ -95,6 +152,24 @@ pub enum Tag {
     KdcIFD = 0xFE00,
 }
 
+impl Tag {
+    // Outside of the Pentax makernote 0x0201 is the offset of an embedded JPEG
+    #[allow(non_upper_case_globals)]
+    pub const JPEGInterchangeFormat: Tag = Tag::PefWB;
+}
+
+// The tags of the GPS IFD, which has its own numbering that overlaps the main one
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u16)]
+pub enum GpsTag {
+    LatitudeRef = 0x0001,
+    Latitude = 0x0002,
+    LongitudeRef = 0x0003,
+    Longitude = 0x0004,
+    AltitudeRef = 0x0005,
+    Altitude = 0x0006,
+}
+
 // 0-1-2-3-4-5-6-7-8-9-10-11-12-13
 const DATASHIFTS: [u8; 14] = [0, 0, 0, 1, 2, 3, 0, 0, 1, 2, 3, 2, 3, 2];
 
@@ -123,9 +198,9 @@ pub struct TiffIFD<'a> {
 }
 
 impl<'a> TiffIFD<'a> {
-    pub fn new_file(buf: &'a [u8]) -> Result<TiffIFD<'a>, String> {
-        if buf[0..8] == b"FUJIFILM"[..] {
-            let ifd1 = TiffIFD::new_root(buf, (BEu32(buf, 84) + 12) as usize)?;
+    pub fn new_file(buf: &'a [u8]) -> Result<TiffIFD<'a>, RawLoaderError> {
+        if buf.starts_with(b"FUJIFILM") {
+            let ifd1 = TiffIFD::new_root(buf, BEu32(buf, 84) as usize + 12)?;
             let endian = ifd1.get_endian();
             let mut subifds = vec![ifd1];
             let mut entries = HashMap::new();
@@ -144,7 +219,7 @@ impl<'a> TiffIFD<'a> {
                             count: 1,
                             parent_offset: 0,
                             doffset: 100,
-                            data: &buf[100..104],
+                            data: buf.get(100..104).unwrap_or(&[]),
                             endian: BIG_ENDIAN,
                         },
                     );
@@ -164,18 +239,25 @@ impl<'a> TiffIFD<'a> {
         }
     }
 
-    pub fn new_root(buf: &'a [u8], offset: usize) -> Result<TiffIFD<'a>, String> {
+    fn root_endian(buf: &[u8], offset: usize) -> Result<Endian, RawLoaderError> {
+        match LEu16(buf, offset) {
+            0x4949 => Ok(LITTLE_ENDIAN),
+            0x4d4d => Ok(BIG_ENDIAN),
+            x => Err(RawLoaderError::corrupt_at(
+                format!("TIFF: don't know marker 0x{:x}", x),
+                offset,
+            )),
+        }
+    }
+
+    pub fn new_root(buf: &'a [u8], offset: usize) -> Result<TiffIFD<'a>, RawLoaderError> {
         let mut subifds = Vec::new();
 
-        let endian = match LEu16(buf, offset) {
-            0x4949 => LITTLE_ENDIAN,
-            0x4d4d => BIG_ENDIAN,
-            x => return Err(format!("TIFF: don't know marker 0x{:x}", x).to_string()),
-        };
+        let endian = TiffIFD::root_endian(buf, offset)?;
         let mut nextifd = endian.ru32(buf, offset + 4) as usize;
         for _ in 0..100 {
             // Never read more than 100 IFDs
-            let ifd = TiffIFD::new(&buf[offset..], nextifd, 0, offset, 0, endian)?;
+            let ifd = TiffIFD::new(from_offset(buf, offset), nextifd, 0, offset, 0, endian)?;
             nextifd = ifd.nextifd;
             subifds.push(ifd);
             if nextifd == 0 {
@@ -192,6 +274,27 @@ impl<'a> TiffIFD<'a> {
         })
     }
 
+    // Joins several standalone TIFF structures (e.g., the CMT boxes of CR3 files) into a
+    // single tree so tags can be looked up across all of them
+    pub fn new_multi_root(buf: &'a [u8], offsets: &[usize]) -> Result<TiffIFD<'a>, RawLoaderError> {
+        let mut subifds = Vec::new();
+        for offset in offsets {
+            subifds.push(TiffIFD::new_root(buf, *offset)?);
+        }
+        let endian = match subifds.first() {
+            Some(ifd) => ifd.get_endian(),
+            None => return Err(RawLoaderError::corrupt("TIFF: no IFDs to join")),
+        };
+
+        Ok(TiffIFD {
+            entries: HashMap::new(),
+            subifds,
+            nextifd: 0,
+            start_offset: 0,
+            endian,
+        })
+    }
+
     pub fn new(
         buf: &'a [u8],
         offset: usize,
@@ -199,13 +302,16 @@ impl<'a> TiffIFD<'a> {
         start_offset: usize,
         depth: u32,
         e: Endian,
-    ) -> Result<TiffIFD<'a>, String> {
+    ) -> Result<TiffIFD<'a>, RawLoaderError> {
         let mut entries = HashMap::new();
         let mut subifds = Vec::new();
 
         let num = e.ru16(buf, offset); // Directory entries in this IFD
         if num > 4000 {
-            return Err(format!("too many entries in IFD ({})", num).to_string());
+            return Err(RawLoaderError::limit(format!(
+                "too many entries in IFD ({})",
+                num
+            )));
         }
         for i in 0..num {
             let entry_offset: usize = offset + 2 + (i as usize) * 12;
@@ -213,7 +319,11 @@ impl<'a> TiffIFD<'a> {
                 // Skip entries we don't know about to speedup decoding
                 continue;
             }
-            let entry = TiffEntry::new(buf, entry_offset, base_offset, offset, e);
+            let entry = match TiffEntry::new(buf, entry_offset, base_offset, offset, e) {
+                Some(entry) => entry,
+                // Skip entries that point outside the file
+                None => continue,
+            };
 
             if entry.tag == t(Tag::SubIFDs)
                 || entry.tag == t(Tag::ExifIFDPointer)
@@ -250,6 +360,8 @@ impl<'a> TiffIFD<'a> {
                     match ifd {
                         Ok(val) => {
                             subifds.push(val);
+                            // Kept for the makernote tags we don't parse into the tree
+                            entries.insert(entry.tag, entry);
                         }
                         Err(_) => {
                             entries.insert(entry.tag, entry);
@@ -270,21 +382,61 @@ impl<'a> TiffIFD<'a> {
         })
     }
 
+    // A standalone TIFF structure whose first IFD is a GPS one (e.g., the CMT4 box of CR3)
+    pub fn new_gps_root(buf: &'a [u8], offset: usize) -> Result<TiffIFD<'a>, RawLoaderError> {
+        let endian = TiffIFD::root_endian(buf, offset)?;
+        let ifd = endian.ru32(buf, offset + 4) as usize;
+        Ok(TiffIFD::new_plain(
+            from_offset(buf, offset),
+            ifd,
+            0,
+            offset,
+            endian,
+        ))
+    }
+
+    // IFDs with their own tag numbers (e.g., GPS or the makernote previews) are read on
+    // their own keeping all their entries, without subifds
+    pub fn new_plain(
+        buf: &'a [u8],
+        offset: usize,
+        base_offset: usize,
+        start_offset: usize,
+        e: Endian,
+    ) -> TiffIFD<'a> {
+        let num = cmp::min(e.ru16(buf, offset), 4000);
+        let mut entries = HashMap::new();
+        for i in 0..num {
+            let entry_offset: usize = offset + 2 + (i as usize) * 12;
+            if let Some(entry) = TiffEntry::new(buf, entry_offset, base_offset, offset, e) {
+                entries.insert(entry.tag, entry);
+            }
+        }
+
+        TiffIFD {
+            entries,
+            subifds: Vec::new(),
+            nextifd: 0,
+            start_offset,
+            endian: e,
+        }
+    }
+
     pub fn new_makernote(
         buf: &'a [u8],
         offset: usize,
         base_offset: usize,
         depth: u32,
         e: Endian,
-    ) -> Result<TiffIFD<'a>, String> {
+    ) -> Result<TiffIFD<'a>, RawLoaderError> {
         let mut off = 0;
-        let data = &buf[offset..];
+        let data = from_offset(buf, offset);
         let mut endian = e;
 
         // Olympus starts the makernote with their own name, sometimes truncated
-        if data[0..5] == b"OLYMP"[..] {
+        if data.starts_with(b"OLYMP") {
             off += 8;
-            if data[0..7] == b"OLYMPUS"[..] {
+            if data.starts_with(b"OLYMPUS") {
                 off += 4;
             }
 
@@ -298,7 +450,8 @@ impl<'a> TiffIFD<'a> {
                     0
                 };
                 if ioff != 0 {
-                    let iprocifd = TiffIFD::new(&buf[offset + ioff..], 0, ioff, 0, depth, endian)?;
+                    let iprocifd =
+                        TiffIFD::new(from_offset(buf, offset + ioff), 0, ioff, 0, depth, endian)?;
                     mainifd.subifds.push(iprocifd);
                 }
             }
@@ -307,42 +460,42 @@ impl<'a> TiffIFD<'a> {
         }
 
         // Epson starts the makernote with its own name
-        if data[0..5] == b"EPSON"[..] {
+        if data.starts_with(b"EPSON") {
             off += 8;
         }
 
         // Pentax makernote starts with AOC\0 - If it's there, skip it
-        if data[0..4] == b"AOC\0"[..] {
+        if data.starts_with(b"AOC\0") {
             off += 4;
         }
 
         // Pentax can also start with PENTAX and in that case uses different offsets
-        if data[0..6] == b"PENTAX"[..] {
+        if data.starts_with(b"PENTAX") {
             off += 8;
-            let endian = if data[off..off + 2] == b"II"[..] {
+            let endian = if from_offset(data, off).starts_with(b"II") {
                 LITTLE_ENDIAN
             } else {
                 BIG_ENDIAN
             };
-            return TiffIFD::new(&buf[offset..], 10, base_offset, 0, depth, endian);
+            return TiffIFD::new(data, 10, base_offset, 0, depth, endian);
         }
 
-        if data[0..7] == b"Nikon\0\x02"[..] {
+        if data.starts_with(b"Nikon\0\x02") {
             off += 10;
-            let endian = if data[off..off + 2] == b"II"[..] {
+            let endian = if from_offset(data, off).starts_with(b"II") {
                 LITTLE_ENDIAN
             } else {
                 BIG_ENDIAN
             };
-            return TiffIFD::new(&buf[off + offset..], 8, base_offset, 0, depth, endian);
+            return TiffIFD::new(from_offset(data, off), 8, base_offset, 0, depth, endian);
         }
 
         // Some have MM or II to indicate endianness - read that
-        if data[off..off + 2] == b"II"[..] {
+        if from_offset(data, off).starts_with(b"II") {
             off += 2;
             endian = LITTLE_ENDIAN;
         }
-        if data[off..off + 2] == b"MM"[..] {
+        if from_offset(data, off).starts_with(b"MM") {
             off += 2;
             endian = BIG_ENDIAN;
         }
@@ -350,17 +503,22 @@ impl<'a> TiffIFD<'a> {
         TiffIFD::new(buf, offset + off, base_offset, 0, depth, endian)
     }
 
-    pub fn new_fuji(buf: &'a [u8], offset: usize) -> Result<TiffIFD<'a>, String> {
+    pub fn new_fuji(buf: &'a [u8], offset: usize) -> Result<TiffIFD<'a>, RawLoaderError> {
         let mut entries = HashMap::new();
         let num = BEu32(buf, offset); // Directory entries in this IFD
         if num > 4000 {
-            return Err(format!("too many entries in IFD ({})", num).to_string());
+            return Err(RawLoaderError::limit(format!(
+                "too many entries in IFD ({})",
+                num
+            )));
         }
         let mut off = offset + 4;
         for _ in 0..num {
             let tag = BEu16(buf, off);
             let len = BEu16(buf, off + 2);
-            if tag == t(Tag::ImageWidth) {
+            if tag == t(Tag::ImageWidth)
+                && let Some(data) = buf.get(off + 4..off + 8)
+            {
                 entries.insert(
                     t(Tag::ImageWidth),
                     TiffEntry {
@@ -369,11 +527,13 @@ impl<'a> TiffIFD<'a> {
                         count: 2,
                         parent_offset: 0,
                         doffset: off + 4,
-                        data: &buf[off + 4..off + 8],
+                        data,
                         endian: BIG_ENDIAN,
                     },
                 );
-            } else if tag == t(Tag::RafOldWB) {
+            } else if tag == t(Tag::RafOldWB)
+                && let Some(data) = buf.get(off + 4..off + 12)
+            {
                 entries.insert(
                     t(Tag::RafOldWB),
                     TiffEntry {
@@ -382,7 +542,7 @@ impl<'a> TiffIFD<'a> {
                         count: 4,
                         parent_offset: 0,
                         doffset: off + 4,
-                        data: &buf[off + 4..off + 12],
+                        data,
                         endian: BIG_ENDIAN,
                     },
                 );
@@ -410,6 +570,44 @@ impl<'a> TiffIFD<'a> {
         }
     }
 
+    // Same as find_entry but without looking into the subifds
+    pub fn find_own_entry(&self, tag: Tag) -> Option<&TiffEntry<'_>> {
+        self.entries.get(&t(tag))
+    }
+
+    pub fn find_gps_entry(&self, tag: GpsTag) -> Option<&TiffEntry<'_>> {
+        self.find_raw_entry(tag as u16)
+    }
+
+    // For IFDs read with new_plain whose tags aren't in Tag
+    pub fn find_raw_entry(&self, tag: u16) -> Option<&TiffEntry<'_>> {
+        self.entries.get(&tag)
+    }
+
+    // This IFD and all the ones below it, depth first
+    pub fn ifds(&self) -> Vec<&TiffIFD<'_>> {
+        let mut ifds = vec![self];
+        for ifd in &self.subifds {
+            ifds.extend(ifd.ifds());
+        }
+        ifds
+    }
+
+    // The GPS IFD isn't read as a subifd as its low numbered tags would clash with the
+    // makernote ones, so it gets parsed on request from the buffer the tree came from
+    pub fn gps_ifd(&self, buf: &'a [u8]) -> Option<TiffIFD<'a>> {
+        let ifd = self.find_first_ifd(Tag::GPSInfo)?;
+        let offset = ifd.find_own_entry(Tag::GPSInfo)?.get_usize(0);
+        let data = buf.get(ifd.start_offset..)?;
+        Some(TiffIFD::new_plain(
+            data,
+            offset,
+            0,
+            ifd.start_offset,
+            ifd.endian,
+        ))
+    }
+
     pub fn has_entry(&self, tag: Tag) -> bool {
         self.find_entry(tag).is_some()
     }
@@ -451,7 +649,7 @@ impl<'a> TiffEntry<'a> {
         base_offset: usize,
         parent_offset: usize,
         e: Endian,
-    ) -> TiffEntry<'a> {
+    ) -> Option<TiffEntry<'a>> {
         let tag = e.ru16(buf, offset);
         let mut typ = e.ru16(buf, offset + 2);
         let count = e.ru32(buf, offset + 4) as usize;
@@ -465,18 +663,18 @@ impl<'a> TiffEntry<'a> {
         let doffset: usize = if bytesize <= 4 {
             offset + 8
         } else {
-            (e.ru32(buf, offset + 8) as usize) - base_offset
+            (e.ru32(buf, offset + 8) as usize).checked_sub(base_offset)?
         };
 
-        TiffEntry {
+        Some(TiffEntry {
             tag,
             typ,
             count,
             parent_offset,
             doffset,
-            data: &buf[doffset..doffset + bytesize],
+            data: buf.get(doffset..doffset.checked_add(bytesize)?)?,
             endian: e,
-        }
+        })
     }
 
     pub fn copy_with_new_data(&self, data: &'a [u8]) -> TiffEntry<'a> {
@@ -486,7 +684,7 @@ impl<'a> TiffEntry<'a> {
     }
 
     pub fn copy_offset_from_parent(&self, buffer: &'a [u8]) -> TiffEntry<'a> {
-        self.copy_with_new_data(&buffer[self.parent_offset + self.doffset..])
+        self.copy_with_new_data(from_offset(buffer, self.parent_offset + self.doffset))
     }
 
     pub fn doffset(&self) -> usize {
@@ -502,9 +700,11 @@ impl<'a> TiffEntry<'a> {
 
     pub fn get_u16(&self, idx: usize) -> u16 {
         match self.typ {
-            1 => self.data[idx] as u16,
+            1 => self.data.get(idx).copied().unwrap_or(0) as u16,
             3 | 8 => self.get_force_u16(idx),
-            _ => panic!("Trying to read typ {} for a u32", self.typ),
+            4 | 7 | 9 | 13 => self.get_force_u32(idx) as u16,
+            // Not an integer type, only a corrupted file would ask for it
+            _ => 0,
         }
     }
 
@@ -512,7 +712,7 @@ impl<'a> TiffEntry<'a> {
         match self.typ {
             1 | 3 | 8 => self.get_u16(idx) as u32,
             4 | 7 | 9 | 13 => self.get_force_u32(idx),
-            _ => panic!("Trying to read typ {} for a u32", self.typ),
+            _ => 0,
         }
     }
 
@@ -539,6 +739,19 @@ impl<'a> TiffEntry<'a> {
             let a = self.endian.ri32(self.data, idx * 8) as f32;
             let b = self.endian.ri32(self.data, idx * 8 + 4) as f32;
             a / b
+        } else if self.typ == 11 {
+            // Float
+            f32::from_bits(self.get_force_u32(idx))
+        } else if self.typ == 12 {
+            // Double
+            let first = self.endian.ru32(self.data, idx * 8) as u64;
+            let second = self.endian.ru32(self.data, idx * 8 + 4) as u64;
+            let bits = if self.endian.little() {
+                second << 32 | first
+            } else {
+                first << 32 | second
+            };
+            f64::from_bits(bits) as f32
         } else {
             self.get_u32(idx) as f32
         }
@@ -550,9 +763,13 @@ impl<'a> TiffEntry<'a> {
             Some(p) => p,
             None => self.data.len(),
         };
-        match str::from_utf8(&self.data[0..len]) {
+        // Keep only the valid part of strings that aren't UTF-8
+        let data = &self.data[0..len];
+        match str::from_utf8(data) {
             Ok(val) => val.trim(),
-            Err(err) => std::panic::panic_any(err),
+            Err(err) => str::from_utf8(&data[..err.valid_up_to()])
+                .unwrap_or("")
+                .trim(),
         }
     }
 
@@ -1,14 +1,14 @@
 use crate::decoders::basics::*;
 use crate::decoders::*;
 
-pub fn decode_unwrapped(buffer: &Buffer) -> Result<RawImageData, String> {
+pub fn decode_unwrapped(buffer: &Buffer) -> Result<RawImageData, RawLoaderError> {
     let decoder = LEu16(&buffer.buf, 0);
     let width = LEu16(&buffer.buf, 2) as usize;
     let height = LEu16(&buffer.buf, 4) as usize;
-    let data = &buffer.buf[6..];
+    let data = from_offset(&buffer.buf, 6);
 
     if width > 64 || height > 64 {
-        panic!("Trying an image larger than 64x64");
+        return Err(RawLoaderError::limit("Trying an image larger than 64x64"));
     }
 
     match decoder {
@@ -22,74 +22,74 @@ pub fn decode_unwrapped(buffer: &Buffer) -> Result<RawImageData, String> {
 
                 LookupTable::new(&t)
             };
-            let data = &data[512..];
+            let data = from_offset(data, 512);
             Ok(RawImageData::Integer(decode_8bit_wtable(
-                data, &table, width, height, false,
-            )))
+                data, &table, width, height, None, false,
+            )?))
         }
         1 => Ok(RawImageData::Integer(decode_10le_lsb16(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         2 => Ok(RawImageData::Integer(decode_10le(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         3 => Ok(RawImageData::Integer(decode_12be(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         4 => Ok(RawImageData::Integer(decode_12be_msb16(
             data, width, height, false,
-        ))),
+        )?)),
         5 => Ok(RawImageData::Integer(decode_12le_16bitaligned(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         6 => Ok(RawImageData::Integer(decode_12be_msb32(
             data, width, height, false,
-        ))),
+        )?)),
         7 => Ok(RawImageData::Integer(decode_12le_wcontrol(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         8 => Ok(RawImageData::Integer(decode_12be_wcontrol(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         9 => Ok(RawImageData::Integer(decode_12be_interlaced(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         10 => Ok(RawImageData::Integer(decode_12be_interlaced_unaligned(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         11 => Ok(RawImageData::Integer(decode_12le(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         12 => Ok(RawImageData::Integer(decode_12le_unpacked(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         13 => Ok(RawImageData::Integer(decode_12be_unpacked(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         14 => Ok(RawImageData::Integer(decode_12be_unpacked_left_aligned(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         15 => Ok(RawImageData::Integer(decode_12le_unpacked_left_aligned(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         16 => Ok(RawImageData::Integer(decode_14le_unpacked(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         17 => Ok(RawImageData::Integer(decode_14be_unpacked(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         18 => Ok(RawImageData::Integer(decode_16le(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         19 => Ok(RawImageData::Integer(decode_16le_skiplines(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         20 => Ok(RawImageData::Integer(decode_16be(
-            data, width, height, false,
-        ))),
+            data, width, height, None, false,
+        )?)),
         21 => Ok(RawImageData::Integer(arw::ArwDecoder::decode_arw1(
             data, width, height, false,
-        ))),
+        )?)),
         22 => {
             let mut curve: [usize; 6] = [0, 0, 0, 0, 0, 4095];
             for i in 0..4 {
@@ -97,18 +97,20 @@ pub fn decode_unwrapped(buffer: &Buffer) -> Result<RawImageData, String> {
             }
 
             let curve = arw::ArwDecoder::calculate_curve(curve);
-            let data = &data[8..];
+            let data = from_offset(data, 8);
             Ok(RawImageData::Integer(arw::ArwDecoder::decode_arw2(
-                data, width, height, &curve, false,
-            )))
+                data, width, height, &curve, None, false,
+            )?))
         }
         23 => {
             let key = LEu32(data, 0);
             let length = LEu16(data, 4) as usize;
-            let data = &data[10..];
+            let data = from_offset(data, 10);
 
             if length > 5000 {
-                panic!("Trying an SRF style image that's too big");
+                return Err(RawLoaderError::limit(
+                    "Trying an SRF style image that's too big",
+                ));
             }
 
             let image_data = arw::ArwDecoder::sony_decrypt(data, 0, length, key);
@@ -116,34 +118,35 @@ pub fn decode_unwrapped(buffer: &Buffer) -> Result<RawImageData, String> {
                 &image_data,
                 width,
                 height,
+                None,
                 false,
-            )))
+            )?))
         }
         24 => Ok(RawImageData::Integer(orf::OrfDecoder::decode_compressed(
             data, width, height, false,
-        ))),
+        )?)),
         25 => {
             let loffsets = data;
-            let data = &data[height * 4..];
+            let data = from_offset(data, height * 4);
             Ok(RawImageData::Integer(srw::SrwDecoder::decode_srw1(
                 data, loffsets, width, height, false,
-            )))
+            )?))
         }
         26 => Ok(RawImageData::Integer(srw::SrwDecoder::decode_srw2(
             data, width, height, false,
-        ))),
+        )?)),
         27 => Ok(RawImageData::Integer(srw::SrwDecoder::decode_srw3(
             data, width, height, false,
-        ))),
+        )?)),
         28 => Ok(RawImageData::Integer(kdc::KdcDecoder::decode_dc120(
             data, width, height, false,
-        ))),
+        )?)),
         29 => Ok(RawImageData::Integer(rw2::Rw2Decoder::decode_panasonic(
-            data, width, height, false, false,
-        ))),
+            data, width, height, false, None, false,
+        )?)),
         30 => Ok(RawImageData::Integer(rw2::Rw2Decoder::decode_panasonic(
-            data, width, height, true, false,
-        ))),
+            data, width, height, true, None, false,
+        )?)),
         31 => {
             let table = {
                 let mut t = [0u16; 1024];
@@ -154,58 +157,64 @@ pub fn decode_unwrapped(buffer: &Buffer) -> Result<RawImageData, String> {
 
                 LookupTable::new(&t)
             };
-            let data = &data[2048..];
+            let data = from_offset(data, 2048);
             Ok(RawImageData::Integer(dcr::DcrDecoder::decode_kodak65000(
                 data, &table, width, height, false,
-            )))
+            )?))
         }
         32 => decode_ljpeg(data, width, height, false, false),
         33 => decode_ljpeg(data, width, height, false, true),
         34 => decode_ljpeg(data, width, height, true, false),
         35 => decode_ljpeg(data, width, height, true, true),
-        36 => Ok(RawImageData::Integer(
-            pef::PefDecoder::do_decode(data, None, width, height, false).unwrap(),
-        )),
+        36 => Ok(RawImageData::Integer(pef::PefDecoder::do_decode(
+            data, None, width, height, false,
+        )?)),
         37 => {
             let huff = data;
-            let data = &data[64..];
-            Ok(RawImageData::Integer(
-                pef::PefDecoder::do_decode(data, Some((huff, LITTLE_ENDIAN)), width, height, false)
-                    .unwrap(),
-            ))
+            let data = from_offset(data, 64);
+            Ok(RawImageData::Integer(pef::PefDecoder::do_decode(
+                data,
+                Some((huff, LITTLE_ENDIAN)),
+                width,
+                height,
+                false,
+            )?))
         }
         38 => {
             let huff = data;
-            let data = &data[64..];
-            Ok(RawImageData::Integer(
-                pef::PefDecoder::do_decode(data, Some((huff, BIG_ENDIAN)), width, height, false)
-                    .unwrap(),
-            ))
+            let data = from_offset(data, 64);
+            Ok(RawImageData::Integer(pef::PefDecoder::do_decode(
+                data,
+                Some((huff, BIG_ENDIAN)),
+                width,
+                height,
+                false,
+            )?))
         }
         39 => Ok(RawImageData::Integer(crw::CrwDecoder::do_decode(
             data, false, 0, width, height, false,
-        ))),
+        )?)),
         40 => Ok(RawImageData::Integer(crw::CrwDecoder::do_decode(
             data, false, 1, width, height, false,
-        ))),
+        )?)),
         41 => Ok(RawImageData::Integer(crw::CrwDecoder::do_decode(
             data, false, 2, width, height, false,
-        ))),
+        )?)),
         42 => Ok(RawImageData::Integer(crw::CrwDecoder::do_decode(
             data, true, 0, width, height, false,
-        ))),
+        )?)),
         43 => Ok(RawImageData::Integer(crw::CrwDecoder::do_decode(
             data, true, 1, width, height, false,
-        ))),
+        )?)),
         44 => Ok(RawImageData::Integer(crw::CrwDecoder::do_decode(
             data, true, 2, width, height, false,
-        ))),
-        45 => Ok(RawImageData::Integer(
-            mos::MosDecoder::do_decode(data, false, width, height, false).unwrap(),
-        )),
-        46 => Ok(RawImageData::Integer(
-            mos::MosDecoder::do_decode(data, true, width, height, false).unwrap(),
-        )),
+        )?)),
+        45 => Ok(RawImageData::Integer(mos::MosDecoder::do_decode(
+            data, false, width, height, false,
+        )?)),
+        46 => Ok(RawImageData::Integer(mos::MosDecoder::do_decode(
+            data, true, width, height, false,
+        )?)),
         47 => Ok(RawImageData::Integer(iiq::IiqDecoder::decode_compressed(
             data,
             height * 4,
@@ -213,7 +222,7 @@ pub fn decode_unwrapped(buffer: &Buffer) -> Result<RawImageData, String> {
             width,
             height,
             false,
-        ))),
+        )?)),
         48 => decode_nef(data, width, height, LITTLE_ENDIAN, 12),
         49 => decode_nef(data, width, height, LITTLE_ENDIAN, 14),
         50 => decode_nef(data, width, height, BIG_ENDIAN, 12),
@@ -225,12 +234,12 @@ pub fn decode_unwrapped(buffer: &Buffer) -> Result<RawImageData, String> {
                 LEf32(data, 8),
                 LEf32(data, 12),
             ];
-            let data = &data[16..];
+            let data = from_offset(data, 16);
             Ok(RawImageData::Integer(
-                nef::NefDecoder::decode_snef_compressed(data, coeffs, width, height, false),
+                nef::NefDecoder::decode_snef_compressed(data, coeffs, width, height, false)?,
             ))
         }
-        _ => Err("No such decoder".to_string()),
+        _ => Err(RawLoaderError::corrupt("No such decoder")),
     }
 }
 
@@ -240,7 +249,7 @@ fn decode_ljpeg(
     height: usize,
     dng_bug: bool,
     csfix: bool,
-) -> Result<RawImageData, String> {
+) -> Result<RawImageData, RawLoaderError> {
     let mut out = vec![0u16; width * height];
     let decompressor = ljpeg::LjpegDecompressor::new_full(src, dng_bug, csfix)?;
     decompressor.decode(&mut out, 0, width, width, height, false)?;
@@ -253,10 +262,10 @@ fn decode_nef(
     height: usize,
     endian: Endian,
     bps: usize,
-) -> Result<RawImageData, String> {
+) -> Result<RawImageData, RawLoaderError> {
     let meta = data;
-    let data = &data[4096..];
-    Ok(RawImageData::Integer(
-        nef::NefDecoder::do_decode(data, meta, endian, width, height, bps, false).unwrap(),
-    ))
+    let data = from_offset(data, 4096);
+    Ok(RawImageData::Integer(nef::NefDecoder::do_decode(
+        data, meta, endian, width, height, bps, false,
+    )?))
 }
@@ -1,9 +1,24 @@
 use crate::decoders::basics::*;
 use crate::decoders::tiff::*;
 use crate::decoders::*;
+use rayon::prelude::*;
+use std::cell::Cell;
+
+thread_local! {
+    // Whether the lower layers of Quattro files get upsampled, set for the length of a
+    // decode from DecodeOptions
+    static UPSAMPLE: Cell<bool> = const { Cell::new(true) };
+}
+
+pub(crate) fn with_quattro_upsample<T>(upsample: bool, func: impl FnOnce() -> T) -> T {
+    let old = UPSAMPLE.replace(upsample);
+    let out = func();
+    UPSAMPLE.set(old);
+    out
+}
 
 pub fn is_x3f(buf: &[u8]) -> bool {
-    buf[0..4] == b"FOVb"[..]
+    buf.starts_with(b"FOVb")
 }
 
 //#[derive(Debug, Clone)]
@@ -30,17 +45,25 @@ struct X3fImage {
 }
 
 impl X3fFile {
-    fn new(buf: &Buffer) -> Result<X3fFile, String> {
-        let offset = LEu32(&buf.buf, buf.size - 4) as usize;
-        let data = &buf.buf[offset..];
+    fn new(buf: &Buffer) -> Result<X3fFile, RawLoaderError> {
+        let offset = LEu32(&buf.buf, buf.size.saturating_sub(4)) as usize;
+        let data = from_offset(&buf.buf, offset);
         let version = LEu32(data, 4);
         if version < 0x00020000 {
-            return Err(format!("X3F: Directory version too old {}", version).to_string());
+            return Err(RawLoaderError::corrupt_at(
+                format!("X3F: Directory version too old {}", version),
+                offset,
+            ));
         }
         let entries = LEu32(data, 8) as usize;
         let mut dirs = Vec::new();
         let mut images = Vec::new();
         for i in 0..entries {
+            if 12 + i * 12 >= data.len() {
+                return Err(RawLoaderError::truncated(
+                    "X3F: directory past the end of the file",
+                ));
+            }
             let dir = X3fDirectory::new(data, 12 + i * 12)?;
             if dir.id == "IMA2" {
                 let img = X3fImage::new(&buf.buf, dir.offset)?;
@@ -57,11 +80,11 @@ impl X3fFile {
 }
 
 impl X3fDirectory {
-    fn new(buf: &[u8], offset: usize) -> Result<X3fDirectory, String> {
-        let data = &buf[offset..];
+    fn new(buf: &[u8], offset: usize) -> Result<X3fDirectory, RawLoaderError> {
+        let data = from_offset(buf, offset);
         let off = LEu32(data, 0) as usize;
         //let len = LEu32(data, 4) as usize;
-        let name = String::from_utf8_lossy(&data[8..12]).to_string();
+        let name = String::from_utf8_lossy(data.get(8..12).unwrap_or(&[])).to_string();
 
         Ok(X3fDirectory {
             offset: off,
@@ -72,8 +95,8 @@ impl X3fDirectory {
 }
 
 impl X3fImage {
-    fn new(buf: &[u8], offset: usize) -> Result<X3fImage, String> {
-        let data = &buf[offset..];
+    fn new(buf: &[u8], offset: usize) -> Result<X3fImage, RawLoaderError> {
+        let data = from_offset(buf, offset);
 
         Ok(X3fImage {
             typ: LEu32(data, 8) as usize,
@@ -94,28 +117,33 @@ pub struct X3fDecoder<'a> {
 }
 
 impl<'a> X3fDecoder<'a> {
-    pub fn new(buf: &'a Buffer, rawloader: &'a RawLoader) -> X3fDecoder<'a> {
-        let dir = X3fFile::new(buf).unwrap();
+    pub fn new(
+        buf: &'a Buffer,
+        rawloader: &'a RawLoader,
+    ) -> Result<X3fDecoder<'a>, RawLoaderError> {
+        let dir = X3fFile::new(buf)?;
 
-        X3fDecoder {
+        Ok(X3fDecoder {
             buffer: &buf.buf,
             rawloader,
             dir,
-        }
+        })
     }
 }
 
 impl<'a> Decoder for X3fDecoder<'a> {
-    fn image(&self, dummy: bool) -> Result<RawImage, String> {
+    fn image(&self, dummy: bool) -> Result<RawImage, RawLoaderError> {
         let caminfo = self
             .dir
             .images
             .iter()
             .find(|i| i.typ == 2 && i.format == 0x12)
-            .ok_or("X3F: Couldn't find camera info".to_string())?;
-        let data = &self.buffer[caminfo.doffset + 6..];
-        if data[0..4] != b"Exif"[..] {
-            return Err("X3F: Couldn't find EXIF info".to_string());
+            .ok_or_else(|| RawLoaderError::corrupt("X3F: Couldn't find camera info"))?;
+        if self.buffer.get(caminfo.doffset + 6..caminfo.doffset + 10) != Some(&b"Exif"[..]) {
+            return Err(RawLoaderError::corrupt_at(
+                "X3F: Couldn't find EXIF info",
+                caminfo.doffset + 6,
+            ));
         }
         let tiff = TiffIFD::new_root(self.buffer, caminfo.doffset + 12)?;
         let camera = self.rawloader.check_supported(&tiff)?;
@@ -125,35 +153,400 @@ impl<'a> Decoder for X3fDecoder<'a> {
             .images
             .iter()
             .find(|i| i.typ == 1 || i.typ == 3)
-            .ok_or("X3F: Couldn't find image".to_string())?;
-        let width = imginfo.width;
-        let height = imginfo.height;
-        let offset = imginfo.doffset;
-        let src = &self.buffer[offset..];
-
-        let image = match imginfo.format {
-            35 => self.decode_compressed(src, width, height, dummy)?,
-            x => return Err(format!("X3F Don't know how to decode format {}", x).to_string()),
+            .ok_or_else(|| RawLoaderError::corrupt("X3F: Couldn't find image"))?;
+        let src = from_offset(self.buffer, imginfo.doffset);
+
+        let (width, height, image, binned) = match imginfo.format {
+            30 | 35 => self.decode_true(src, imginfo, dummy)?,
+            x => return Err(RawLoaderError::compression("X3F", x as u32)),
         };
 
-        let mut img = RawImage::new(camera, width, height, self.get_wb()?, image, dummy);
-        img.cpp = 3;
+        let mut img = RawImage::with_cpp(camera, width, height, 3, self.get_wb()?, image, dummy);
+        if binned {
+            for crop in img.crops.iter_mut() {
+                *crop /= 2;
+            }
+        }
         Ok(img)
     }
+
+    fn exif(&self) -> Exif {
+        // The EXIF is in the JPEG preview that also identifies the camera
+        match self
+            .dir
+            .images
+            .iter()
+            .find(|i| i.typ == 2 && i.format == 0x12)
+        {
+            Some(caminfo)
+                if self.buffer.get(caminfo.doffset + 6..caminfo.doffset + 10)
+                    == Some(&b"Exif"[..]) =>
+            {
+                match TiffIFD::new_root(self.buffer, caminfo.doffset + 12) {
+                    Ok(tiff) => Exif::from_tiff(self.buffer, &tiff),
+                    Err(_) => Exif::default(),
+                }
+            }
+            _ => Exif::default(),
+        }
+    }
+
+    fn previews(&self) -> Result<Vec<Preview>, RawLoaderError> {
+        // The JPEG previews are IMA2 sections of their own, the EXIF in them has the orientation
+        let mut previews = Vec::new();
+        for img in self
+            .dir
+            .images
+            .iter()
+            .filter(|i| i.typ == 2 && i.format == 0x12)
+        {
+            let exif = self.buffer.get(img.doffset + 6..img.doffset + 10) == Some(&b"Exif"[..]);
+            let orientation =
+                if exif && let Ok(tiff) = TiffIFD::new_root(self.buffer, img.doffset + 12) {
+                    Orientation::from_tiff(&tiff)
+                } else {
+                    Orientation::Unknown
+                };
+            let len = self.buffer.len().saturating_sub(img.doffset);
+            previews.extend(preview::jpeg_at(self.buffer, img.doffset, len, orientation));
+        }
+        Ok(previews)
+    }
 }
 
 impl<'a> X3fDecoder<'a> {
-    fn get_wb(&self) -> Result<[f32; 4], String> {
+    fn get_wb(&self) -> Result<[f32; 4], RawLoaderError> {
         Ok([f32::NAN; 4])
     }
 
-    fn decode_compressed(
+    // TRUE II and III images store each of the three layers as a separate plane of
+    // huffman coded differences. Quattro sensors have a top layer with twice the
+    // resolution of the other two, so those get stored at their own sizes. Upsampling
+    // them repeats each of their values over a 2x2 block, there's no interpolation.
+    fn decode_true(
         &self,
-        _buf: &[u8],
-        _width: usize,
-        _height: usize,
-        _dummy: bool,
-    ) -> Result<Vec<u16>, String> {
-        Err("X3F decoding not implemented yet".to_string())
+        buf: &[u8],
+        imginfo: &X3fImage,
+        dummy: bool,
+    ) -> Result<(usize, usize, Vec<u16>, bool), RawLoaderError> {
+        let quattro = imginfo.format == 35;
+        let short = || RawLoaderError::truncated("X3F: TRUE header past the end of the file");
+
+        let mut pos = 0;
+        let mut planes = [(imginfo.width, imginfo.height); 3];
+        if quattro {
+            if buf.len() < 12 {
+                return Err(short());
+            }
+            for plane in planes.iter_mut() {
+                *plane = (LEu16(buf, pos) as usize, LEu16(buf, pos + 2) as usize);
+                pos += 4;
+            }
+        }
+
+        if buf.len() < pos + 8 {
+            return Err(short());
+        }
+        let seeds = [
+            LEu16(buf, pos) as i32,
+            LEu16(buf, pos + 2) as i32,
+            LEu16(buf, pos + 4) as i32,
+        ];
+        pos += 8;
+
+        // The huffman table lists (code length, left aligned code) pairs where the
+        // position in the table is the number of bits of the difference that follows
+        let mut table = [(0u32, 0u32); 256];
+        for i in 0.. {
+            if buf.len() < pos + 2 {
+                return Err(short());
+            }
+            let (len, code) = (buf[pos] as u32, buf[pos + 1] as u32);
+            pos += 2;
+            if len == 0 {
+                break;
+            }
+            if len > 8 || i > 16 {
+                return Err(RawLoaderError::corrupt_at(
+                    "X3F: invalid TRUE huffman table",
+                    imginfo.doffset + pos - 2,
+                ));
+            }
+            let shift = 8 - len;
+            let first = ((code >> shift) << shift) as usize;
+            for entry in table[first..first + (1 << shift)].iter_mut() {
+                *entry = (len, i);
+            }
+        }
+
+        if quattro {
+            pos += 4;
+        }
+        if buf.len() < pos + 12 {
+            return Err(short());
+        }
+        let mut offsets = [0usize; 3];
+        let mut start = pos + 12;
+        for (i, offset) in offsets.iter_mut().enumerate() {
+            let size = LEu32(buf, pos + i * 4) as usize;
+            if start + size > buf.len() {
+                return Err(RawLoaderError::truncated(
+                    "X3F: TRUE plane past the end of the file",
+                ));
+            }
+            *offset = start;
+            start += size.div_ceil(16) * 16;
+        }
+
+        // Normal TRUE files have all three planes at the image size. Quattro files
+        // have the lower two at half size and the top one at the image size.
+        let (cols, rows) = planes[0];
+        let layered = quattro && rows == imginfo.height / 2;
+        let sizes = if layered {
+            [(cols, rows), (cols, rows), (cols * 2, rows * 2)]
+        } else {
+            [(imginfo.width, imginfo.height); 3]
+        };
+        for i in 0..3 {
+            if planes[i].0 < sizes[i].0 || planes[i].1 < sizes[i].1 {
+                return Err(RawLoaderError::truncated(
+                    "X3F: TRUE plane smaller than the image",
+                ));
+            }
+        }
+
+        let binned = layered && !UPSAMPLE.get();
+        let (width, height) = if binned { sizes[0] } else { sizes[2] };
+        if dummy {
+            return Ok((
+                width,
+                height,
+                alloc_image_plain!(width, height, dummy),
+                binned,
+            ));
+        }
+
+        let decoded = (0..3)
+            .into_par_iter()
+            .map(|i| {
+                X3fDecoder::decode_true_plane(
+                    &buf[offsets[i]..],
+                    &table,
+                    seeds[i],
+                    planes[i],
+                    sizes[i],
+                )
+            })
+            .collect::<Result<Vec<Vec<u16>>, RawLoaderError>>()?;
+
+        let image = decode_threaded(
+            width * 3,
+            height,
+            None,
+            dummy,
+            &(|out: &mut [u16], row| {
+                for (col, pix) in out.chunks_exact_mut(3).enumerate() {
+                    if !layered {
+                        for i in 0..3 {
+                            pix[i] = decoded[i][row * width + col];
+                        }
+                    } else if binned {
+                        let top = &decoded[2];
+                        let pos = row * 2 * width * 2 + col * 2;
+                        pix[0] = decoded[0][row * width + col];
+                        pix[1] = decoded[1][row * width + col];
+                        pix[2] = ((top[pos] as u32
+                            + top[pos + 1] as u32
+                            + top[pos + width * 2] as u32
+                            + top[pos + width * 2 + 1] as u32
+                            + 2)
+                            >> 2) as u16;
+                    } else {
+                        let pos = (row / 2) * cols + col / 2;
+                        pix[0] = decoded[0][pos];
+                        pix[1] = decoded[1][pos];
+                        pix[2] = decoded[2][row * width + col];
+                    }
+                }
+            }),
+        )?;
+
+        Ok((width, height, image, binned))
+    }
+
+    // Each value is predicted from the one two columns back so that the even and odd
+    // columns form separate chains. The first two values of a row are predicted from
+    // the start of the row two rows up, with the plane seed for the first rows.
+    fn decode_true_plane(
+        buf: &[u8],
+        table: &[(u32, u32); 256],
+        seed: i32,
+        plane: (usize, usize),
+        size: (usize, usize),
+    ) -> Result<Vec<u16>, RawLoaderError> {
+        let (pcols, _) = plane;
+        let (width, height) = size;
+        let mut out: Vec<u16> = alloc_image_plain!(width, height, false);
+        let mut pump = BitPumpMSB::new(buf);
+        let mut row_start = [[seed; 2]; 2];
+
+        for row in 0..height {
+            let mut acc = [0i32; 2];
+            for col in 0..pcols {
+                let (len, nbits) = table[pump.peek_bits(8) as usize];
+                if len == 0 {
+                    return Err(RawLoaderError::corrupt("X3F: invalid TRUE huffman code"));
+                }
+                pump.consume_bits(len);
+                let diff = if nbits == 0 {
+                    0
+                } else {
+                    let val = pump.get_bits(nbits) as i32;
+                    if val >> (nbits - 1) == 0 {
+                        val - (1 << nbits) + 1
+                    } else {
+                        val
+                    }
+                };
+
+                let value = if col < 2 {
+                    let value = row_start[row & 1][col & 1].wrapping_add(diff);
+                    row_start[row & 1][col & 1] = value;
+                    value
+                } else {
+                    acc[col & 1].wrapping_add(diff)
+                };
+                acc[col & 1] = value;
+
+                // Planes can carry a few extra columns on the right that we discard
+                if col < width {
+                    out[row * width + col] = value.clamp(0, 65535) as u16;
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A TRUE image block with codes 00, 01 and 10 for differences of 0, 1 and 2 bits,
+    // and each plane padded to 16 bytes
+    fn true_block(
+        quattro: Option<[(u16, u16); 3]>,
+        seeds: [u16; 3],
+        planes: [&[u8]; 3],
+    ) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (width, height) in quattro.iter().flatten() {
+            buf.extend_from_slice(&width.to_le_bytes());
+            buf.extend_from_slice(&height.to_le_bytes());
+        }
+        for seed in seeds {
+            buf.extend_from_slice(&seed.to_le_bytes());
+        }
+        buf.extend_from_slice(&[0, 0, 2, 0x00, 2, 0x40, 2, 0x80, 0, 0]);
+        if quattro.is_some() {
+            buf.extend_from_slice(&[0; 4]);
+        }
+        for plane in planes {
+            buf.extend_from_slice(&(plane.len() as u32).to_le_bytes());
+        }
+        for plane in planes {
+            let mut plane = plane.to_vec();
+            plane.resize(16, 0);
+            buf.extend_from_slice(&plane);
+        }
+        buf
+    }
+
+    fn decode(
+        loader: &RawLoader,
+        buf: &[u8],
+        format: usize,
+        size: (usize, usize),
+    ) -> Result<(usize, usize, Vec<u16>, bool), RawLoaderError> {
+        let decoder = X3fDecoder {
+            buffer: &[],
+            rawloader: loader,
+            dir: X3fFile { images: Vec::new() },
+        };
+        let imginfo = X3fImage {
+            typ: 1,
+            format,
+            width: size.0,
+            height: size.1,
+            doffset: 0,
+        };
+        decoder.decode_true(buf, &imginfo, false)
+    }
+
+    // Rows of differences +1 -1 +2 -1, 0 +2 -2 0 and 0 0 +1 -1, that is
+    // 011 010 1010 010 | 00 1010 1001 00 | 00 00 011 010
+    const PLANE: [u8; 5] = [0x6a, 0x91, 0x52, 0x03, 0x40];
+
+    #[test]
+    fn true_ii_planes() {
+        let buf = true_block(None, [100, 200, 300], [&PLANE, &[0; 3], &[0; 3]]);
+        let (width, height, image, binned) = decode(&RawLoader::new(), &buf, 30, (4, 3)).unwrap();
+        assert_eq!((width, height, binned), (4, 3, false));
+        let first: Vec<u16> = image.chunks_exact(3).map(|p| p[0]).collect();
+        // Even and odd columns are separate chains and rows start from two rows up
+        assert_eq!(
+            first,
+            [101, 99, 103, 98, 100, 102, 98, 102, 101, 99, 102, 98]
+        );
+        assert!(image.chunks_exact(3).all(|p| p[1] == 200 && p[2] == 300));
+    }
+
+    #[test]
+    fn quattro_layers() {
+        // The lower layers at 2x1 and the top one at 4x2 with the first two rows of PLANE
+        let buf = true_block(
+            Some([(2, 1), (2, 1), (4, 2)]),
+            [10, 20, 30],
+            [&[0x68], &[0x00], &PLANE[..4]],
+        );
+        let loader = RawLoader::new();
+        let (width, height, image, binned) = decode(&loader, &buf, 35, (4, 2)).unwrap();
+        assert_eq!((width, height, binned), (4, 2, false));
+        assert_eq!(
+            image,
+            [
+                11, 20, 31, 11, 20, 29, 9, 20, 33, 9, 20, 28, //
+                11, 20, 30, 11, 20, 32, 9, 20, 28, 9, 20, 32,
+            ]
+        );
+
+        let (width, height, image, binned) =
+            with_quattro_upsample(false, || decode(&loader, &buf, 35, (4, 2))).unwrap();
+        assert_eq!((width, height, binned), (2, 1, true));
+        assert_eq!(image, [11, 20, 31, 9, 20, 30]);
+    }
+
+    #[test]
+    fn bad_huffman_table_offset() {
+        // The second table entry, after the seeds and their padding, has a 9 bit code
+        let mut buf = true_block(None, [100, 200, 300], [&PLANE, &[0; 3], &[0; 3]]);
+        buf[10] = 9;
+        let err = decode(&RawLoader::new(), &buf, 30, (4, 3)).unwrap_err();
+        assert!(matches!(
+            err,
+            RawLoaderError::CorruptData {
+                offset: Some(10),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn truncated_true_block() {
+        let buf = true_block(None, [100, 200, 300], [&PLANE, &[0; 3], &[0; 3]]);
+        let loader = RawLoader::new();
+        assert!(decode(&loader, &buf[..40], 30, (4, 3)).is_err());
+        assert!(decode(&loader, &buf[..10], 30, (4, 3)).is_err());
     }
 }
@@ -45,50 +45,33 @@
 
 use lazy_static::lazy_static;
 
+mod cameras_xml;
 mod decoders;
+pub use decoders::BlackLevel;
 #[doc(hidden)]
 pub use decoders::Buffer;
+pub use decoders::DecodeOptions;
 pub use decoders::Orientation;
+pub use decoders::Preview;
 pub use decoders::RawImage;
 pub use decoders::RawImageData;
-#[doc(hidden)]
 pub use decoders::RawLoader;
+pub use decoders::RawLoaderError;
+pub use decoders::RawMetadata;
+pub use decoders::Scale;
 pub use decoders::cfa::CFA;
+pub use decoders::dcp::{CameraProfile, HueSatMap};
+pub use decoders::dngcolor::DngColor;
+pub use decoders::exif::{Exif, Gps};
+pub use decoders::opcodes::{DngOpcode, GainMap, Opcode, OpcodeArea};
 
 lazy_static! {
     static ref LOADER: RawLoader = RawLoader::new();
 }
 
-use std::error::Error;
-use std::fmt;
 use std::io::Read;
 use std::path::Path;
 
-/// Error type for any reason for the decode to fail
-#[derive(Debug)]
-pub struct RawLoaderError {
-    msg: String,
-}
-
-impl fmt::Display for RawLoaderError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "RawLoaderError: \"{}\"", self.msg)
-    }
-}
-
-impl Error for RawLoaderError {
-    // Implement description so that older versions of rust still work
-    fn description(&self) -> &str {
-        "description() is deprecated; use Display"
-    }
-}
-
-impl RawLoaderError {
-    fn new(msg: String) -> Self {
-        Self { msg }
-    }
-}
-
 /// Take a path to a raw file and return a decoded image or an error
 ///
 /// # Example
@@ -99,9 +82,7 @@ impl RawLoaderError {
 /// };
 /// ```
 pub fn decode_file<P: AsRef<Path>>(path: P) -> Result<RawImage, RawLoaderError> {
-    LOADER
-        .decode_file(path.as_ref())
-        .map_err(RawLoaderError::new)
+    LOADER.decode_file(path.as_ref())
 }
 
 /// Take a readable source and return a decoded image or an error
@@ -115,7 +96,116 @@ pub fn decode_file<P: AsRef<Path>>(path: P) -> Result<RawImage, RawLoaderError>
 /// };
 /// ```
 pub fn decode(reader: &mut dyn Read) -> Result<RawImage, RawLoaderError> {
-    LOADER.decode(reader, false).map_err(RawLoaderError::new)
+    LOADER.decode(reader, false)
+}
+
+/// Take a path to a raw file and return a decoded image or an error, decoding it with the
+/// given limits, threads and dithering
+///
+/// # Example
+/// ```rust,ignore
+/// let options = rawloader2::DecodeOptions::new().max_pixels(100_000_000);
+/// let image = match rawloader2::decode_file_with("path/to/your/file.RAW", &options) {
+///   Ok(val) => val,
+///   Err(e) => ... some appropriate action when the file is unreadable or too large ...
+/// };
+/// ```
+pub fn decode_file_with<P: AsRef<Path>>(
+    path: P,
+    options: &DecodeOptions,
+) -> Result<RawImage, RawLoaderError> {
+    LOADER.decode_file_with(path.as_ref(), options)
+}
+
+/// Take a readable source and return a decoded image or an error, decoding it with the
+/// given limits, threads and dithering
+///
+/// # Example
+/// ```rust,no_run
+/// # use std::fs::File;
+/// let mut file = File::open("path/to/your/file.RAW").unwrap();
+/// let options = rawloader2::DecodeOptions::new().single_threaded().dither(false);
+/// let image = rawloader2::decode_with(&mut file, &options).unwrap();
+/// ```
+pub fn decode_with(
+    reader: &mut dyn Read,
+    options: &DecodeOptions,
+) -> Result<RawImage, RawLoaderError> {
+    LOADER.decode_with(reader, options)
+}
+
+/// Take a readable source and return its dimensions, CFA, levels, whitebalance, color
+/// matrix, crops, orientation and camera identity without decoding the image data, which
+/// is much faster when only the metadata is needed
+///
+/// # Example
+/// ```rust,no_run
+/// # use std::fs::File;
+/// let mut file = File::open("path/to/your/file.RAW").unwrap();
+/// let meta = rawloader2::decode_metadata(&mut file).unwrap();
+/// println!("{} {} {}x{}", meta.clean_make, meta.clean_model, meta.width, meta.height);
+/// ```
+pub fn decode_metadata(reader: &mut dyn Read) -> Result<RawMetadata, RawLoaderError> {
+    LOADER.decode_metadata(reader)
+}
+
+/// Take a readable source and return how many raw frames it has. Most files have a single
+/// one but some store several, like the second exposure of Fuji SuperCCD files, the extra
+/// images of DNGs, the dual pixel data of Canon files or the other full size raw images of
+/// Pentax files, where the exposures of pixel shift files are expected
+///
+/// # Example
+/// ```rust,no_run
+/// # use std::fs::File;
+/// let mut file = File::open("path/to/your/file.RAW").unwrap();
+/// let frames = rawloader2::frame_count(&mut file).unwrap();
+/// ```
+pub fn frame_count(reader: &mut dyn Read) -> Result<usize, RawLoaderError> {
+    LOADER.frame_count(reader)
+}
+
+/// Take a readable source and return one of its raw frames, with 0 being the one that
+/// `decode()` returns, or an error
+///
+/// # Example
+/// ```rust,no_run
+/// # use std::fs::File;
+/// let mut file = File::open("path/to/your/file.RAW").unwrap();
+/// let second = rawloader2::decode_frame(&mut file, 1).unwrap();
+/// ```
+pub fn decode_frame(reader: &mut dyn Read, index: usize) -> Result<RawImage, RawLoaderError> {
+    LOADER.decode_frame(reader, index)
+}
+
+/// Take a readable source and return all of its raw frames in order, or an error if any
+/// of them fails to decode
+///
+/// # Example
+/// ```rust,no_run
+/// # use std::fs::File;
+/// let mut file = File::open("path/to/your/file.RAW").unwrap();
+/// for image in rawloader2::decode_all_frames(&mut file).unwrap() {
+///   println!("{}x{}", image.width, image.height);
+/// }
+/// ```
+pub fn decode_all_frames(reader: &mut dyn Read) -> Result<Vec<RawImage>, RawLoaderError> {
+    LOADER.decode_all_frames(reader)
+}
+
+/// Take a readable source and return all the previews and thumbnails embedded in it,
+/// largest first, without decoding the raw data. Files that can't be parsed have none.
+/// Uncompressed RGB thumbnails come back as PPM files, see `Preview` for the details.
+///
+/// # Example
+/// ```rust,no_run
+/// # use std::fs::File;
+/// let mut file = File::open("path/to/your/file.RAW").unwrap();
+/// for preview in rawloader2::extract_previews(&mut file) {
+///   println!("{}x{} {}", preview.width, preview.height, preview.mime);
+/// }
+/// ```
+pub fn extract_previews(reader: &mut dyn Read) -> Vec<Preview> {
+    LOADER.extract_previews(reader).unwrap_or_default()
 }
 
 // Used to force lazy_static initializations. Useful for fuzzing.
@@ -128,11 +218,11 @@ pub fn force_initialization() {
 // with all their TIFF and other crazyness
 #[doc(hidden)]
 pub fn decode_unwrapped(reader: &mut dyn Read) -> Result<RawImageData, RawLoaderError> {
-    LOADER.decode_unwrapped(reader).map_err(RawLoaderError::new)
+    LOADER.decode_unwrapped(reader)
 }
 
 // Used for fuzzing everything but the decoders themselves
 #[doc(hidden)]
 pub fn decode_dummy(reader: &mut dyn Read) -> Result<RawImage, RawLoaderError> {
-    LOADER.decode(reader, true).map_err(RawLoaderError::new)
+    LOADER.decode(reader, true)
 }
@@ -13,7 +13,11 @@ pub struct Camera {
     // Required fields present in every camera entry
     pub make: String,
     pub model: String,
+    // Left out when empty so merging a converted cameras.xml over the built-in cameras
+    // doesn't clear their names
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub clean_make: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub clean_model: String,
     pub color_matrix: Vec<i64>,
     pub color_pattern: String,
@@ -21,13 +25,18 @@ pub struct Camera {
     // Optional fields that may not be present for every camera.
     pub blackpoint: Option<i64>,
     pub whitepoint: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sensors: Vec<Sensor>,
     pub crops: Option<Vec<i64>>,
     // We use `#[serde(default)]` for Vecs so that if the key is missing,
     // we get an empty vector instead of an error.
     #[serde(default)]
     pub model_aliases: Vec<Vec<String>>,
-    pub blackareav: Option<Vec<i64>>,
-    pub blackareah: Option<Vec<i64>>,
+    // Every masked area as its position and size
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blackareav: Vec<Vec<i64>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blackareah: Vec<Vec<i64>>,
     pub wb_offset: Option<i64>,
     pub hints: Option<Vec<String>>,
     pub filesize: Option<i64>,
@@ -47,7 +56,26 @@ pub struct Mode {
     // These fields are optional within a mode definition.
     pub blackpoint: Option<i64>,
     pub whitepoint: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sensors: Vec<Sensor>,
     pub color_pattern: Option<String>,
     pub crops: Option<Vec<i64>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blackareav: Vec<Vec<i64>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blackareah: Vec<Vec<i64>>,
     pub highres_width: Option<i64>,
 }
+
+// Represents a single [[cameras.sensors]] table, the black and white levels for some ISOs.
+// Without an ISO range or list it's the default for the ones no other table covers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sensor {
+    pub black: i64,
+    pub white: i64,
+    // Black levels for each position of a 2x2 CFA
+    pub black_colors: Option<Vec<i64>>,
+    pub iso_min: Option<i64>,
+    pub iso_max: Option<i64>,
+    pub iso_list: Option<Vec<i64>>,
+}
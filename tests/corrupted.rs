@@ -0,0 +1,813 @@
+// Feeds truncated and corrupted files to the decoders to make sure they always return an
+// error instead of panicking, as with panic = "abort" a panic takes the whole process down
+
+use std::panic::{self, AssertUnwindSafe};
+
+use rawloader2::{RawImage, RawLoader, RawLoaderError};
+
+#[derive(Clone)]
+enum Value {
+    Byte(Vec<u8>),
+    Ascii(&'static str),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<(u32, u32)>),
+    Undefined(Vec<u8>),
+    SRational(Vec<(i32, i32)>),
+    Float(Vec<f32>),
+    // Offsets of positions inside the data appended after the IFDs
+    Data(Vec<u32>),
+    Ifd(Vec<(u16, Value)>),
+}
+
+// Builds a little endian TIFF with a single IFD followed by the given data
+fn tiff(entries: Vec<(u16, Value)>, data: &[u8]) -> Vec<u8> {
+    let mut buf = b"II*\0\x08\0\0\0".to_vec();
+    let mut fixups = Vec::new();
+    write_ifd(&mut buf, entries, &mut fixups);
+    let base = buf.len() as u32;
+    for (pos, offset) in fixups {
+        buf[pos..pos + 4].copy_from_slice(&(base + offset).to_le_bytes());
+    }
+    buf.extend_from_slice(data);
+    buf
+}
+
+fn write_ifd(buf: &mut Vec<u8>, mut entries: Vec<(u16, Value)>, fixups: &mut Vec<(usize, u32)>) {
+    entries.sort_by_key(|(tag, _)| *tag);
+    let start = buf.len();
+    buf.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    buf.resize(start + 2 + entries.len() * 12 + 4, 0);
+    for (i, (tag, value)) in entries.into_iter().enumerate() {
+        let pos = start + 2 + i * 12;
+        let mut offsets = Vec::new();
+        let (typ, count, bytes): (u16, usize, Vec<u8>) = match value {
+            Value::Byte(v) => (1, v.len(), v),
+            Value::Ascii(s) => (2, s.len() + 1, [s.as_bytes(), b"\0"].concat()),
+            Value::Short(v) => (3, v.len(), v.iter().flat_map(|x| x.to_le_bytes()).collect()),
+            Value::Long(v) => (4, v.len(), v.iter().flat_map(|x| x.to_le_bytes()).collect()),
+            Value::Rational(v) => (
+                5,
+                v.len(),
+                v.iter()
+                    .flat_map(|(n, d)| [n.to_le_bytes(), d.to_le_bytes()].concat())
+                    .collect(),
+            ),
+            Value::Undefined(v) => (7, v.len(), v),
+            Value::SRational(v) => (
+                10,
+                v.len(),
+                v.iter()
+                    .flat_map(|(n, d)| [n.to_le_bytes(), d.to_le_bytes()].concat())
+                    .collect(),
+            ),
+            Value::Float(v) => (
+                11,
+                v.len(),
+                v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            ),
+            Value::Data(v) => {
+                offsets = v;
+                (4, offsets.len(), vec![0; offsets.len() * 4])
+            }
+            Value::Ifd(sub) => {
+                buf.resize(buf.len() + (buf.len() & 1), 0);
+                let offset = buf.len() as u32;
+                write_ifd(buf, sub, fixups);
+                (4, 1, offset.to_le_bytes().to_vec())
+            }
+        };
+        buf[pos..pos + 2].copy_from_slice(&tag.to_le_bytes());
+        buf[pos + 2..pos + 4].copy_from_slice(&typ.to_le_bytes());
+        buf[pos + 4..pos + 8].copy_from_slice(&(count as u32).to_le_bytes());
+        let at = if bytes.len() <= 4 {
+            buf[pos + 8..pos + 8 + bytes.len()].copy_from_slice(&bytes);
+            pos + 8
+        } else {
+            buf.resize(buf.len() + (buf.len() & 1), 0);
+            let offset = buf.len();
+            buf.extend_from_slice(&bytes);
+            buf[pos + 8..pos + 12].copy_from_slice(&(offset as u32).to_le_bytes());
+            offset
+        };
+        for (i, offset) in offsets.into_iter().enumerate() {
+            fixups.push((at + i * 4, offset));
+        }
+    }
+}
+
+// A lossless JPEG where every difference is coded as a single zero bit
+fn ljpeg(width: u16, height: u16, components: u8) -> Vec<u8> {
+    let mut out = vec![0xff, 0xd8, 0xff, 0xc3];
+    out.extend_from_slice(&(8 + 3 * components as u16).to_be_bytes());
+    out.push(12);
+    out.extend_from_slice(&height.to_be_bytes());
+    out.extend_from_slice(&width.to_be_bytes());
+    out.push(components);
+    for c in 0..components {
+        out.extend_from_slice(&[c + 1, 0x11, 0]);
+    }
+    out.extend_from_slice(&[0xff, 0xc4, 0, 20, 0x00, 1]);
+    out.extend_from_slice(&[0; 15]);
+    out.push(0);
+    out.extend_from_slice(&[0xff, 0xda]);
+    out.extend_from_slice(&(6 + 2 * components as u16).to_be_bytes());
+    out.push(components);
+    for c in 0..components {
+        out.extend_from_slice(&[c + 1, 0x00]);
+    }
+    out.extend_from_slice(&[1, 0, 0]);
+    let bits = width as usize * height as usize * components as usize;
+    out.resize(out.len() + bits.div_ceil(8) + 4, 0);
+    out.extend_from_slice(&[0xff, 0xd9]);
+    out
+}
+
+// A baseline 8x8 grayscale JPEG with a single flat block
+fn jpeg() -> Vec<u8> {
+    let mut out = vec![0xff, 0xd8, 0xff, 0xdb, 0, 67, 0];
+    out.extend_from_slice(&[1; 64]);
+    out.extend_from_slice(&[0xff, 0xc0, 0, 11, 8, 0, 8, 0, 8, 1, 1, 0x11, 0]);
+    for class in [0x00, 0x10] {
+        out.extend_from_slice(&[0xff, 0xc4, 0, 20, class, 1]);
+        out.extend_from_slice(&[0; 15]);
+        out.push(0);
+    }
+    out.extend_from_slice(&[0xff, 0xda, 0, 8, 1, 1, 0x00, 0, 63, 0]);
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&[0xff, 0xd9]);
+    out
+}
+
+// A zlib stream with the data in a single stored block
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01, 0x01];
+    out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(!(data.len() as u16)).to_le_bytes());
+    out.extend_from_slice(data);
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    out
+}
+
+fn opcode_list() -> Vec<u8> {
+    fn opcode(out: &mut Vec<u8>, id: u32, params: &[u32]) {
+        for val in [id, 0x01030000, 1, params.len() as u32 * 4] {
+            out.extend_from_slice(&val.to_be_bytes());
+        }
+        for val in params {
+            out.extend_from_slice(&val.to_be_bytes());
+        }
+    }
+    let area = [0, 0, 8, 8, 0, 1, 1, 1];
+    let one = 1f32.to_bits();
+    let mut out = 7u32.to_be_bytes().to_vec();
+    opcode(&mut out, 4, &[0, 1]);
+    opcode(&mut out, 5, &[1, 1, 1, 2, 2, 0, 0, 2, 2]);
+    opcode(&mut out, 7, &[area.as_slice(), &[2, 0x0000ffff]].concat());
+    opcode(
+        &mut out,
+        9,
+        &[
+            area.as_slice(),
+            &[
+                2, 2, 0, 0x3ff00000, 0, 0x3ff00000, 0, 0, 0, 0, 1, one, one, one, one,
+            ],
+        ]
+        .concat(),
+    );
+    opcode(&mut out, 10, &[area.as_slice(), &[1, one]].concat());
+    opcode(&mut out, 13, &[area.as_slice(), &[1, one]].concat());
+    opcode(&mut out, 6, &[1, 1, 7, 7]);
+    out
+}
+
+fn dng(mut entries: Vec<(u16, Value)>, data: &[u8]) -> Vec<u8> {
+    let mut all = vec![
+        (0x010f, Value::Ascii("Canon")),
+        (0x0110, Value::Ascii("Canon EOS 5D Mark III")),
+        (0x0112, Value::Short(vec![6])),
+        (0x0115, Value::Short(vec![1])),
+        (0x0106, Value::Short(vec![32803])),
+        (0x828d, Value::Short(vec![2, 2])),
+        (0x828e, Value::Byte(vec![0, 1, 1, 2])),
+        (0xc612, Value::Byte(vec![1, 4, 0, 0])),
+        (
+            0xc61a,
+            Value::Rational(vec![(256, 1), (257, 1), (258, 1), (259, 1)]),
+        ),
+        (0xc619, Value::Short(vec![2, 2])),
+        (0xc61b, Value::SRational(vec![(1, 2); 8])),
+        (0xc61c, Value::SRational(vec![(-1, 2); 8])),
+        (0xc61d, Value::Long(vec![65535])),
+        (0xc68d, Value::Long(vec![1, 1, 7, 7])),
+        (0xc68e, Value::Long(vec![0, 0, 1, 8])),
+        (
+            0xc621,
+            Value::SRational(vec![
+                (1, 1),
+                (0, 1),
+                (0, 1),
+                (0, 1),
+                (1, 1),
+                (0, 1),
+                (0, 1),
+                (0, 1),
+                (1, 1),
+            ]),
+        ),
+        (0xc714, Value::SRational(vec![(1, 2); 9])),
+        (0xc65a, Value::Short(vec![21])),
+        (0xc628, Value::Rational(vec![(1, 2), (1, 1), (1, 2)])),
+        (0xc6f9, Value::Long(vec![2, 2, 1])),
+        (
+            0xc6fa,
+            Value::Float(vec![
+                0.0, 1.0, 1.0, 10.0, 1.0, 1.0, 0.0, 1.0, 1.0, 10.0, 1.0, 1.0,
+            ]),
+        ),
+        (0xc740, Value::Undefined(opcode_list())),
+        (0xc741, Value::Undefined(opcode_list())),
+        (
+            0x8769,
+            Value::Ifd(vec![
+                (0x829a, Value::Rational(vec![(1, 100)])),
+                (0x8827, Value::Short(vec![100])),
+                (0x9003, Value::Ascii("2020:01:01 10:00:00")),
+                (0x920a, Value::Rational(vec![(50, 1)])),
+            ]),
+        ),
+        (
+            0x8825,
+            Value::Ifd(vec![
+                (0x0001, Value::Ascii("N")),
+                (0x0002, Value::Rational(vec![(1, 1), (2, 1), (3, 1)])),
+                (0x0005, Value::Byte(vec![0])),
+                (0x0006, Value::Rational(vec![(100, 1)])),
+            ]),
+        ),
+    ];
+    all.retain(|(tag, _)| !entries.iter().any(|(t, _)| t == tag));
+    all.append(&mut entries);
+    tiff(all, data)
+}
+
+fn image_entries(bps: u16, compression: u16, count: u32) -> Vec<(u16, Value)> {
+    vec![
+        (0x0100, Value::Long(vec![8])),
+        (0x0101, Value::Long(vec![8])),
+        (0x0102, Value::Short(vec![bps])),
+        (0x0103, Value::Short(vec![compression])),
+        (0x0111, Value::Data(vec![0])),
+        (0x0116, Value::Long(vec![8])),
+        (0x0117, Value::Long(vec![count])),
+    ]
+}
+
+fn dng_seeds() -> Vec<(String, Vec<u8>)> {
+    let pixels = (0..128).map(|i| (i * 37) as u8).collect::<Vec<u8>>();
+    let mut seeds = Vec::new();
+    seeds.push((
+        "dng 16 bit".to_string(),
+        dng(image_entries(16, 1, 128), &pixels),
+    ));
+    let mut entries = image_entries(12, 1, 96);
+    entries.push((
+        0xc618,
+        Value::Short((0..4096).map(|i| (i * 2) as u16).collect()),
+    ));
+    seeds.push(("dng 12 bit".to_string(), dng(entries, &pixels)));
+    let mut entries = image_entries(8, 1, 64);
+    entries.push((
+        0xc618,
+        Value::Short((0..256).map(|i| (i * 100) as u16).collect()),
+    ));
+    seeds.push(("dng 8 bit".to_string(), dng(entries, &pixels)));
+    let lossless = ljpeg(4, 8, 2);
+    seeds.push((
+        "dng ljpeg".to_string(),
+        dng(image_entries(16, 7, lossless.len() as u32), &lossless),
+    ));
+    let mut tiles = ljpeg(2, 4, 2);
+    let tile_len = tiles.len() as u32;
+    for _ in 1..4 {
+        tiles.extend_from_slice(&ljpeg(2, 4, 2));
+    }
+    let mut entries = image_entries(16, 7, 0);
+    entries.retain(|(tag, _)| ![0x0111, 0x0116, 0x0117].contains(tag));
+    entries.extend([
+        (0x0142, Value::Long(vec![4])),
+        (0x0143, Value::Long(vec![4])),
+        (
+            0x0144,
+            Value::Data(vec![0, tile_len, tile_len * 2, tile_len * 3]),
+        ),
+        (0x0145, Value::Long(vec![tile_len; 4])),
+    ]);
+    seeds.push(("dng ljpeg tiles".to_string(), dng(entries, &tiles)));
+    let deflated = zlib(&pixels);
+    let mut entries = image_entries(16, 8, deflated.len() as u32);
+    entries.push((0x013d, Value::Short(vec![2])));
+    seeds.push(("dng deflate".to_string(), dng(entries, &deflated)));
+    let floats = zlib(&[0x00, 0x3c].repeat(64));
+    let mut entries = image_entries(16, 8, floats.len() as u32);
+    entries.extend([
+        (0x0153, Value::Short(vec![3])),
+        (0x013d, Value::Short(vec![3])),
+        (0xc61d, Value::Float(vec![1.0])),
+    ]);
+    seeds.push(("dng float".to_string(), dng(entries, &floats)));
+    let lossy = jpeg();
+    let mut entries = image_entries(8, 34892, lossy.len() as u32);
+    entries.push((
+        0xc618,
+        Value::Short((0..256).map(|i| (i * 100) as u16).collect()),
+    ));
+    seeds.push(("dng lossy".to_string(), dng(entries, &lossy)));
+    let mut entries = image_entries(16, 1, 384);
+    entries.extend([
+        (0x0106, Value::Short(vec![34892])),
+        (0x0115, Value::Short(vec![3])),
+        (0xc740, Value::Undefined(vec![0; 4])),
+        (0xc741, Value::Undefined(vec![0; 4])),
+    ]);
+    seeds.push(("dng linear".to_string(), dng(entries, &pixels.repeat(3))));
+    let mut entries = image_entries(16, 1, 128);
+    let mut second = image_entries(8, 1, 64);
+    second.extend([
+        (0x00fe, Value::Long(vec![0])),
+        (0x0106, Value::Short(vec![32803])),
+        (0x0115, Value::Short(vec![1])),
+        (0x828d, Value::Short(vec![2, 2])),
+        (0x828e, Value::Byte(vec![0, 1, 1, 2])),
+        (0xc61d, Value::Long(vec![255])),
+    ]);
+    entries.push((0x014a, Value::Ifd(second)));
+    seeds.push(("dng two frames".to_string(), dng(entries, &pixels)));
+    seeds
+}
+
+fn vendor_seeds() -> Vec<(String, Vec<u8>)> {
+    let cameras = [
+        ("SONY", "ILCE-7M3", 12),
+        ("OLYMPUS CORPORATION", "E-M1MarkII", 12),
+        ("OLYMPUS IMAGING CORP.", "E-M5", 16),
+        ("SAMSUNG", "NX1", 12),
+        ("SEIKO EPSON CORP.", "R-D1", 12),
+        (
+            "EASTMAN KODAK COMPANY",
+            "KODAK EASYSHARE Z980 DIGITAL CAMERA",
+            12,
+        ),
+        ("KODAK", "DCS460D", 8),
+        ("Kodak", "DCS Pro 14N", 12),
+        ("Panasonic", "DMC-GH4", 12),
+        ("FUJIFILM", "X-T2", 14),
+        ("PENTAX", "PENTAX K-3", 14),
+        ("Leaf", "Credo 40", 16),
+        ("Hasselblad", "Hasselblad H3D", 16),
+        ("NIKON CORPORATION", "NIKON D750", 14),
+        ("NIKON", "COOLPIX P7800", 12),
+        ("Canon", "Canon EOS 5D Mark III", 14),
+        ("Mamiya-OP Co.,Ltd.", "Mamiya ZD", 12),
+        ("Phase One A/S", "IQ180", 16),
+    ];
+    let data = (0..2048)
+        .map(|i| (i * 13 + i / 7) as u8)
+        .collect::<Vec<u8>>();
+    let mut seeds = Vec::new();
+    for (make, model, bps) in cameras {
+        for compression in [1, 6, 7, 32767, 32769, 32770, 32773, 34713, 65000, 65535] {
+            // The raw image and the vendor specific tags all the decoders look for
+            let raw = vec![
+                (0x0100, Value::Long(vec![1024])),
+                (0x0101, Value::Long(vec![4])),
+                (0x0102, Value::Short(vec![bps])),
+                (0x0103, Value::Short(vec![compression])),
+                (0x0111, Value::Data(vec![0])),
+                (0x0117, Value::Long(vec![1536])),
+                (0x828e, Value::Byte(vec![0, 1, 1, 2])),
+                (0xc61d, Value::Long(vec![4095])),
+            ];
+            let entries = vec![
+                (0x010f, Value::Ascii(make)),
+                (0x0110, Value::Ascii(model)),
+                (0x0100, Value::Long(vec![32])),
+                (0x0101, Value::Long(vec![16])),
+                (0x0102, Value::Short(vec![bps])),
+                (0x0103, Value::Short(vec![compression])),
+                (0x0111, Value::Data(vec![0])),
+                (0x0117, Value::Long(vec![1024])),
+                (0x0201, Value::Data(vec![1024])),
+                (0x0202, Value::Long(vec![512])),
+                (0x828e, Value::Byte(vec![0, 1, 1, 2])),
+                (0x0002, Value::Short(vec![32])),
+                (0x0003, Value::Short(vec![16])),
+                (0x000a, Value::Short(vec![bps])),
+                (
+                    0x000c,
+                    Value::Rational(vec![(2, 1), (1, 1), (3, 2), (1, 1)]),
+                ),
+                (0x0011, Value::Short(vec![512])),
+                (0x0012, Value::Short(vec![400])),
+                (0x002d, Value::Short(vec![4])),
+                (0x0118, Value::Data(vec![0])),
+                (0x03fd, Value::Short(vec![256; 72])),
+                (0x090d, Value::Short((0..1024).collect())),
+                (0x0e80, Value::Short(vec![512; 32])),
+                (0x0f00, Value::Short(vec![512; 4])),
+                (0x1017, Value::Short(vec![512])),
+                (0x1018, Value::Short(vec![400])),
+                (0x2040, Value::Ifd(raw.clone())),
+                (0x8290, Value::Ifd(raw.clone())),
+                (0xa010, Value::Long(vec![0, 0, 32, 16])),
+                (0xa021, Value::Long(vec![512; 4])),
+                (0x0123, Value::Short((0..256).map(|i| i * 16).collect())),
+                (0xa028, Value::Long(vec![64; 4])),
+                (0xc634, Value::Data(vec![1024])),
+                (0xc61d, Value::Long(vec![4095])),
+                (
+                    0xf000,
+                    Value::Ifd(vec![
+                        (0xf001, Value::Long(vec![32])),
+                        (0xf002, Value::Long(vec![16])),
+                        (0xf003, Value::Long(vec![bps as u32])),
+                        (0xf007, Value::Data(vec![0])),
+                        (0xf00e, Value::Short(vec![302, 512, 400])),
+                    ]),
+                ),
+                (0xfd00, Value::Long(vec![32])),
+                (0xfd01, Value::Long(vec![16])),
+                (0xfd04, Value::Long(vec![0, 0, 0, 0, 256])),
+                (0xfa2a, Value::Short(vec![512; 4])),
+                (
+                    0x8769,
+                    Value::Ifd(vec![
+                        (0x8827, Value::Short(vec![200])),
+                        (
+                            0x927c,
+                            Value::Undefined(b"Nikon\0\x02\x10\0\0II*\0\x08\0\0\0\0\0".to_vec()),
+                        ),
+                    ]),
+                ),
+                (0x014a, Value::Ifd(raw)),
+            ];
+            seeds.push((
+                format!("{} {} {}", make, model, compression),
+                tiff(entries, &data),
+            ));
+        }
+    }
+    seeds
+}
+
+fn container_seeds() -> Vec<(String, Vec<u8>)> {
+    let mut seeds = Vec::new();
+
+    // MRW files have a big endian TIFF with just the make and model
+    let mut inner = b"MM\0*\0\0\0\x08\0\x02".to_vec();
+    let strings = [&b"KONICA MINOLTA\0"[..], &b"DYNAX 7D\0"[..]];
+    let mut offset = 8 + 2 + 2 * 12 + 4;
+    for (tag, string) in [0x010fu16, 0x0110].iter().zip(strings) {
+        inner.extend_from_slice(&tag.to_be_bytes());
+        inner.extend_from_slice(&2u16.to_be_bytes());
+        inner.extend_from_slice(&(string.len() as u32).to_be_bytes());
+        inner.extend_from_slice(&(offset as u32).to_be_bytes());
+        offset += string.len();
+    }
+    inner.extend_from_slice(&[0; 4]);
+    inner.extend_from_slice(&strings.concat());
+    let mut mrw = b"\0MRM".to_vec();
+    let blocks_len = 8 + 28 + 8 + 12 + 8 + inner.len();
+    mrw.extend_from_slice(&(blocks_len as u32).to_be_bytes());
+    mrw.extend_from_slice(b"\0PRD\0\0\0\x14");
+    mrw.extend_from_slice(&[
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 16, 0, 32, 0, 16, 0, 32, 12, 12, 0x59, 0, 0, 0,
+    ]);
+    mrw.extend_from_slice(&[0; 6]);
+    mrw.extend_from_slice(b"\0WBG\0\0\0\x0c\0\0\0\0\x01\0\x01\0\x01\0\x01\0");
+    mrw.extend_from_slice(b"\0TTW");
+    mrw.extend_from_slice(&(inner.len() as u32).to_be_bytes());
+    mrw.extend_from_slice(&inner);
+    mrw.extend((0..768).map(|i| i as u8));
+    seeds.push(("mrw".to_string(), mrw));
+
+    let mut ciff = b"II\x1a\0\0\0HEAPCCDR\0\0\x01\0\0\0\0\0\0\0\0\0".to_vec();
+    let heap_start = ciff.len();
+    let sensor: Vec<u8> = [0u16, 64, 16, 0, 0, 0]
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect();
+    let mut records = Vec::new();
+    for (tag, data) in [
+        (0x080a, b"Canon\0Canon PowerShot G1\0".to_vec()),
+        (0x1031, sensor),
+        (0x2005, (0..1024).map(|i| (i * 7) as u8).collect()),
+        (0x2007, jpeg()),
+    ] {
+        records.push((tag, data.len(), ciff.len() - heap_start));
+        ciff.extend_from_slice(&data);
+    }
+    let dir_at = ciff.len() - heap_start;
+    ciff.extend_from_slice(&(records.len() as u16 + 1).to_le_bytes());
+    for (tag, len, offset) in records {
+        ciff.extend_from_slice(&(tag as u16).to_le_bytes());
+        ciff.extend_from_slice(&(len as u32).to_le_bytes());
+        ciff.extend_from_slice(&(offset as u32).to_le_bytes());
+    }
+    // The decoder table is small enough to be stored in the record itself
+    ciff.extend_from_slice(&(0x4000u16 | 0x1835).to_le_bytes());
+    ciff.extend_from_slice(&[0; 8]);
+    ciff.extend_from_slice(&(dir_at as u32).to_le_bytes());
+    seeds.push(("ciff".to_string(), ciff));
+
+    seeds.push(("cr3".to_string(), cr3()));
+
+    let mut ari = b"ARRI\x12\x34\x56\x78".to_vec();
+    ari.resize(4096, 0);
+    ari[8..12].copy_from_slice(&4096u32.to_le_bytes());
+    ari[20..24].copy_from_slice(&16u32.to_le_bytes());
+    ari[24..28].copy_from_slice(&8u32.to_le_bytes());
+    ari[668..680].copy_from_slice(b"ALEXA\0\0\0\0\0\0\0");
+    ari.extend((0..192).map(|i| i as u8));
+    seeds.push(("ari".to_string(), ari));
+
+    for (format, planes) in [(30, None), (35, Some([(8u16, 4u16), (8, 4), (16, 8)]))] {
+        seeds.push((format!("x3f {}", format), x3f(format, planes)));
+    }
+
+    seeds
+}
+
+// A Sigma file with the camera info and a 16x8 TRUE image where every difference is zero
+fn x3f(format: u32, planes: Option<[(u16, u16); 3]>) -> Vec<u8> {
+    let section = |typ: u32, format: u32, width: u32, height: u32, data: &[u8]| {
+        let mut out = b"SECi\0\0\x02\0".to_vec();
+        for val in [typ, format, width, height, 0, 0] {
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+        out.truncate(28);
+        out.extend_from_slice(data);
+        out
+    };
+    let mut exif = vec![0xff, 0xd8, 0xff, 0xe1, 0, 0];
+    exif.extend_from_slice(b"Exif\0\0");
+    exif.extend_from_slice(&tiff(
+        vec![
+            (0x010f, Value::Ascii("SIGMA")),
+            (0x0110, Value::Ascii("SIGMA dp2 Quattro")),
+        ],
+        &[],
+    ));
+    exif.extend_from_slice(&[0xff, 0xd9]);
+
+    let mut true_data = Vec::new();
+    for (width, height) in planes.iter().flatten() {
+        true_data.extend_from_slice(&width.to_le_bytes());
+        true_data.extend_from_slice(&height.to_le_bytes());
+    }
+    true_data.extend_from_slice(&[0, 2, 0, 2, 0, 2, 0, 0]);
+    true_data.extend_from_slice(&[1, 0, 0, 0]);
+    if planes.is_some() {
+        true_data.extend_from_slice(&[0; 4]);
+    }
+    for _ in 0..3 {
+        true_data.extend_from_slice(&32u32.to_le_bytes());
+    }
+    true_data.extend_from_slice(&[0; 96]);
+
+    let mut file = b"FOVb\0\0\x04\0".to_vec();
+    file.resize(64, 0);
+    let mut entries = Vec::new();
+    for section in [
+        section(2, 0x12, 16, 8, &exif),
+        section(3, format, 16, 8, &true_data),
+    ] {
+        entries.push((file.len() as u32, section.len() as u32));
+        file.extend_from_slice(&section);
+    }
+    let dir = file.len() as u32;
+    file.extend_from_slice(b"SECd\0\0\x02\0");
+    file.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for (offset, len) in entries {
+        file.extend_from_slice(&offset.to_le_bytes());
+        file.extend_from_slice(&len.to_le_bytes());
+        file.extend_from_slice(b"IMA2");
+    }
+    file.extend_from_slice(&dir.to_le_bytes());
+    file
+}
+
+fn bmff_box(name: &[u8; 4], contents: &[&[u8]]) -> Vec<u8> {
+    let contents = contents.concat();
+    let mut out = (contents.len() as u32 + 8).to_be_bytes().to_vec();
+    out.extend_from_slice(name);
+    out.extend_from_slice(&contents);
+    out
+}
+
+// A Canon CR3 with a single 64x48 four plane CRX track filled with noise
+fn cr3() -> Vec<u8> {
+    let cmt = tiff(
+        vec![
+            (0x010f, Value::Ascii("Canon")),
+            (0x0110, Value::Ascii("Canon EOS R5")),
+        ],
+        &[],
+    );
+    let canon_uuid = b"\x85\xc0\xb6\x87\x82\x0f\x11\xe0\x81\x11\xf4\xce\x46\x2b\x6a\x48";
+    let uuid = bmff_box(b"uuid", &[canon_uuid, &bmff_box(b"CMT1", &[&cmt])]);
+
+    // One tile with four planes of a single band each
+    let band_size = 4096u32;
+    let mut header = vec![0xff, 0x01, 0, 8];
+    header.extend_from_slice(&(band_size * 4).to_be_bytes());
+    header.extend_from_slice(&[0; 4]);
+    for plane in 0..4u8 {
+        header.extend_from_slice(&[0xff, 0x02, 0, 8]);
+        header.extend_from_slice(&band_size.to_be_bytes());
+        header.extend_from_slice(&[plane << 4, 0, 0, 0]);
+        header.extend_from_slice(&[0xff, 0x03, 0, 8]);
+        header.extend_from_slice(&band_size.to_be_bytes());
+        header.extend_from_slice(&[0; 4]);
+    }
+    let mut rng = Rng(0x853c49e6748fea9b);
+    let mut mdat = header.clone();
+    mdat.extend((0..band_size * 4).map(|_| rng.next() as u8));
+
+    let mut cmp1 = vec![0u8; 0x30];
+    cmp1[2..4].copy_from_slice(&0x30u16.to_be_bytes());
+    cmp1[4..6].copy_from_slice(&0x100u16.to_be_bytes());
+    for (i, val) in [64u32, 48, 64, 48].iter().enumerate() {
+        cmp1[8 + i * 4..12 + i * 4].copy_from_slice(&val.to_be_bytes());
+    }
+    cmp1[24] = 14;
+    cmp1[25] = 0x40;
+    cmp1[28..32].copy_from_slice(&(header.len() as u32).to_be_bytes());
+    let craw = bmff_box(b"CRAW", &[&[0; 82], &bmff_box(b"CMP1", &[&cmp1])]);
+    let stsd = bmff_box(b"stsd", &[&[0, 0, 0, 0, 0, 0, 0, 1], &craw]);
+    let stsz = bmff_box(
+        b"stsz",
+        &[
+            &[0; 4],
+            &(mdat.len() as u32).to_be_bytes(),
+            &1u32.to_be_bytes(),
+        ],
+    );
+
+    let ftyp = bmff_box(b"ftyp", &[b"crx \0\0\0\x01crx isom"]);
+    let moov = |offset: u64| {
+        let co64 = bmff_box(
+            b"co64",
+            &[&[0; 4], &1u32.to_be_bytes(), &offset.to_be_bytes()],
+        );
+        let stbl = bmff_box(b"stbl", &[&stsd, &stsz, &co64]);
+        let trak = bmff_box(
+            b"trak",
+            &[&bmff_box(b"mdia", &[&bmff_box(b"minf", &[&stbl])])],
+        );
+        bmff_box(b"moov", &[&uuid, &trak])
+    };
+    let offset = (ftyp.len() + moov(0).len() + 8) as u64;
+    [ftyp, moov(offset), bmff_box(b"mdat", &[&mdat])].concat()
+}
+
+fn unwrapped_seeds() -> Vec<(String, Vec<u8>)> {
+    let mut rng = Rng(0x2545f4914f6cdd1d);
+    (0..53u16)
+        .map(|decoder| {
+            let mut file = Vec::new();
+            for val in [decoder, 16, 8] {
+                file.extend_from_slice(&val.to_le_bytes());
+            }
+            if (32..36).contains(&decoder) {
+                file.extend_from_slice(&ljpeg(8, 8, 2));
+            } else {
+                file.extend((0..1024).map(|_| rng.next() as u8));
+            }
+            (format!("unwrapped {}", decoder), file)
+        })
+        .collect()
+}
+
+// xorshift so the mutations are the same on every run
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, max: usize) -> usize {
+        (self.next() % max.max(1) as u64) as usize
+    }
+}
+
+// Truncations at regular intervals plus random byte changes, mostly in the headers
+fn mutations(seed: &[u8], count: usize) -> Vec<(String, Vec<u8>)> {
+    let mut rng = Rng((seed.len() as u64).wrapping_mul(0x9e3779b97f4a7c15) | 1);
+    let mut out = Vec::new();
+    for i in 0..16 {
+        let len = seed.len() * i / 16;
+        out.push((format!("truncated to {}", len), seed[..len].to_vec()));
+    }
+    for i in 0..count {
+        let mut data = seed.to_vec();
+        for _ in 0..1 + rng.below(4) {
+            let pos = if rng.below(4) > 0 {
+                rng.below(data.len().min(512))
+            } else {
+                rng.below(data.len())
+            };
+            if let Some(byte) = data.get_mut(pos) {
+                *byte = match rng.below(4) {
+                    0 => 0,
+                    1 => 0xff,
+                    2 => *byte ^ (1 << rng.below(8)),
+                    _ => rng.next() as u8,
+                };
+            }
+        }
+        out.push((format!("mutation {}", i), data));
+    }
+    out
+}
+
+fn check<T>(
+    failures: &mut Vec<String>,
+    name: &str,
+    func: impl FnOnce() -> Result<T, RawLoaderError>,
+) {
+    match panic::catch_unwind(AssertUnwindSafe(func)) {
+        Ok(Err(RawLoaderError::InternalPanic)) | Err(_) => failures.push(name.to_string()),
+        Ok(_) => {}
+    }
+}
+
+fn run(loader: &RawLoader, failures: &mut Vec<String>, name: &str, data: &[u8]) {
+    check(failures, &format!("{}: decode", name), || {
+        let image = loader.decode(&mut &data[..], false)?;
+        apply_opcodes(image)
+    });
+    check(failures, &format!("{}: decode dummy", name), || {
+        loader.decode(&mut &data[..], true)
+    });
+    check(failures, &format!("{}: second frame", name), || {
+        loader.frame_count(&mut &data[..])?;
+        loader.decode_frame(&mut &data[..], 1)
+    });
+    check(failures, &format!("{}: previews", name), || {
+        loader.extract_previews(&mut &data[..])
+    });
+}
+
+fn apply_opcodes(mut image: RawImage) -> Result<(), RawLoaderError> {
+    image.apply_opcodes(1)?;
+    image.apply_opcodes(2)
+}
+
+#[test]
+fn corrupted_files_dont_panic() {
+    let loader = RawLoader::new();
+    let mut failures = Vec::new();
+
+    // Make sure the generic seeds are actually decodable so the mutations get deep
+    for (name, seed) in dng_seeds() {
+        if let Err(err) = loader.decode(&mut &seed[..], false) {
+            panic!("seed \"{}\" doesn't decode: {}", name, err);
+        }
+    }
+
+    let seeds = [dng_seeds(), vendor_seeds(), container_seeds()].concat();
+    for (name, seed) in seeds {
+        run(&loader, &mut failures, &name, &seed);
+        for (mutation, data) in mutations(&seed, 200) {
+            run(
+                &loader,
+                &mut failures,
+                &format!("{} {}", name, mutation),
+                &data,
+            );
+        }
+    }
+
+    for (name, seed) in unwrapped_seeds() {
+        for (mutation, data) in [(String::new(), seed.clone())]
+            .into_iter()
+            .chain(mutations(&seed, 30))
+        {
+            check(&mut failures, &format!("{} {}", name, mutation), || {
+                loader.decode_unwrapped(&mut &data[..])
+            });
+        }
+    }
+
+    assert!(failures.is_empty(), "panicked on {:#?}", failures);
+}